@@ -0,0 +1,97 @@
+//! Block storage devices like SD cards, which are read and written in whole blocks.
+//!
+//! The helpers here give byte addressed access on top of the blocks. They do not depend on any
+//! hardware, so they can be tested on the host.
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Storage read and written in blocks of `BLOCK_SIZE` bytes, addressed by logical block address.
+pub trait BlockStorage {
+    type Error;
+
+    /// Number of blocks of the device.
+    fn num_blocks(&self) -> u32;
+
+    /// Read the blocks starting at `lba` to `buf`, whose length is a multiple of `BLOCK_SIZE`.
+    fn read_blocks(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write `buf`, whose length is a multiple of `BLOCK_SIZE`, to the blocks starting at `lba`.
+    fn write_blocks(&mut self, lba: u32, buf: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Read `buf.len()` bytes at byte address `addr`. Whole blocks are read directly into `buf`, the
+/// partial blocks at either end through a block on the stack.
+pub fn read_bytes<D: BlockStorage>(
+    device: &mut D,
+    addr: u32,
+    buf: &mut [u8],
+) -> Result<(), D::Error> {
+    let mut block = [0; BLOCK_SIZE];
+    let mut lba = addr / BLOCK_SIZE as u32;
+    let mut buf = buf;
+
+    // Partial first block
+    let offset = addr as usize % BLOCK_SIZE;
+    if offset > 0 && !buf.is_empty() {
+        let len = buf.len().min(BLOCK_SIZE - offset);
+        device.read_blocks(lba, &mut block)?;
+        buf[..len].copy_from_slice(&block[offset..offset + len]);
+        buf = &mut buf[len..];
+        lba += 1;
+    }
+
+    // Whole blocks
+    let whole = buf.len() / BLOCK_SIZE * BLOCK_SIZE;
+    if whole > 0 {
+        let (blocks, rest) = buf.split_at_mut(whole);
+        device.read_blocks(lba, blocks)?;
+        buf = rest;
+        lba += (whole / BLOCK_SIZE) as u32;
+    }
+
+    // Partial last block
+    if !buf.is_empty() {
+        device.read_blocks(lba, &mut block)?;
+        let len = buf.len();
+        buf.copy_from_slice(&block[..len]);
+    }
+
+    Ok(())
+}
+
+/// Write `buf` at byte address `addr`. Whole blocks are written directly from `buf`, the partial
+/// blocks at either end are read, modified and written back.
+pub fn write_bytes<D: BlockStorage>(device: &mut D, addr: u32, buf: &[u8]) -> Result<(), D::Error> {
+    let mut block = [0; BLOCK_SIZE];
+    let mut lba = addr / BLOCK_SIZE as u32;
+    let mut buf = buf;
+
+    // Partial first block
+    let offset = addr as usize % BLOCK_SIZE;
+    if offset > 0 && !buf.is_empty() {
+        let len = buf.len().min(BLOCK_SIZE - offset);
+        device.read_blocks(lba, &mut block)?;
+        block[offset..offset + len].copy_from_slice(&buf[..len]);
+        device.write_blocks(lba, &block)?;
+        buf = &buf[len..];
+        lba += 1;
+    }
+
+    // Whole blocks
+    let whole = buf.len() / BLOCK_SIZE * BLOCK_SIZE;
+    if whole > 0 {
+        device.write_blocks(lba, &buf[..whole])?;
+        buf = &buf[whole..];
+        lba += (whole / BLOCK_SIZE) as u32;
+    }
+
+    // Partial last block
+    if !buf.is_empty() {
+        device.read_blocks(lba, &mut block)?;
+        block[..buf.len()].copy_from_slice(buf);
+        device.write_blocks(lba, &block)?;
+    }
+
+    Ok(())
+}
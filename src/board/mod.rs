@@ -3,6 +3,8 @@
 
 pub mod display;
 pub mod qspi;
+#[cfg(feature = "sdcard")]
+pub mod sdmmc;
 pub mod sdram;
 
 use stm32f7xx_hal::{
@@ -14,6 +14,8 @@ use stm32f7xx_hal::{
 pub struct QspiDriver {
     /// QSPI peripheral registers.
     qspi: QUADSPI,
+    /// Called after each subsector of an erase, see `set_erase_progress`.
+    erase_progress: fn(),
 }
 
 /// QSPI driver mode  of operation: DMA or polling.
@@ -94,6 +96,15 @@ const DMA_STREAM: usize = 7;
 const DMA_CHANNEL: u8 = 3;
 const QUADSPI_DR_ADDR: u32 = 0xA000_1000 + 0x20;
 
+/// The last 64 KB of the flash device are scratch space for the driver tests.
+pub const TEST_ADDR: u32 = FlashDevice::DEVICE_MAX_ADDRESS + 1 - 0x10000;
+
+/// The flash filesystem uses the rest of the flash device.
+pub const FS_SIZE: u32 = TEST_ADDR;
+
+/// Largest DMA transfer in bytes, the transfer size register counts 32-bit words.
+const DMA_MAX_LEN: usize = 0xFFFF * 4;
+
 impl QspiDriver {
     /// Initialize and configure the QSPI flash driver.
     pub fn new(rcc: &mut RCC, gpiob: GPIOB, gpiod: GPIOD, gpioe: GPIOE, qspi: QUADSPI) -> Self {
@@ -153,7 +164,16 @@ impl QspiDriver {
             qspi.dcr.write_with_zero(|w| w.fsize().bits(23));
         }
 
-        QspiDriver { qspi }
+        QspiDriver {
+            qspi,
+            erase_progress: || (),
+        }
+    }
+
+    /// Call `progress` after each subsector of an erase. Erasing a subsector takes up to 400 ms,
+    /// so long erases can report their progress, for example to the watchdog.
+    pub fn set_erase_progress(&mut self, progress: fn()) {
+        self.erase_progress = progress;
     }
 
     /// Check the identification bytes of the flash device to validate communication.
@@ -257,6 +277,7 @@ impl QspiDriver {
     /// Erase `len` bytes at address `src` sector-by-sector. If `src` is not sector aligned, the
     /// start of sector it resides in will be the starting address for the erase. A pair is
     /// returned containing the total number of bytes erased and the erase starting address.
+    /// The erase progress callback is called after each subsector.
     pub fn erase(&mut self, src: u32, len: usize) -> Result<(u32, u32), QspiError> {
         assert!(len > 0);
         assert!(src + (len as u32) <= FlashDevice::DEVICE_MAX_ADDRESS);
@@ -286,6 +307,7 @@ impl QspiDriver {
             addr += FlashDevice::DEVICE_SUBSECTOR_SIZE;
 
             self.poll_status()?;
+            (self.erase_progress)();
         }
 
         Ok((num_erased_bytes, start_addr))
@@ -503,14 +525,34 @@ impl QspiDriver {
 impl Mem for QspiDriver {
     type Error = QspiError;
 
-    /// Blocking read implementation for QSPI flash (DMA).
-    fn read(&mut self, dst: u32, src: u32, len: usize) -> Result<(), QspiError> {
-        self.read(QspiDriverMode::DmaMode(dst), src, len)
+    /// Blocking read implementation for QSPI flash. DMA is used for word aligned buffers.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), QspiError> {
+        let len = buf.len();
+        if len == 0 {
+            Ok(())
+        } else if buf.as_ptr() as u32 % 4 == 0 && len % 4 == 0 {
+            for (i, chunk) in buf.chunks_mut(DMA_MAX_LEN).enumerate() {
+                let dst = chunk.as_mut_ptr() as u32;
+                let src = addr + (i * DMA_MAX_LEN) as u32;
+                self.read(QspiDriverMode::DmaMode(dst), src, chunk.len())?;
+            }
+            Ok(())
+        } else {
+            self.read(QspiDriverMode::PollingRead(buf), addr, len)
+        }
     }
 
-    /// Blocking write implementation for QSPI flash (DMA).
-    fn write(&mut self, dst: u32, src: u32, len: usize) -> Result<(), QspiError> {
-        self.write(dst, QspiDriverMode::DmaMode(src), len)
+    /// Blocking write implementation for QSPI flash. DMA is used for word aligned buffers and
+    /// addresses.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), QspiError> {
+        if buf.is_empty() {
+            Ok(())
+        } else if addr % 4 == 0 && buf.as_ptr() as u32 % 4 == 0 && buf.len() % 4 == 0 {
+            let src = buf.as_ptr() as u32;
+            self.write(addr, QspiDriverMode::DmaMode(src), buf.len())
+        } else {
+            self.write(addr, QspiDriverMode::PollingWrite(buf), buf.len())
+        }
     }
 
     /// Blocking erase implementation for QSPI flash. This takes several seconds.
@@ -531,6 +573,11 @@ impl Mem for QspiDriver {
         self.polling_read(&mut dummy, transaction)?;
         self.poll_status()
     }
+
+    /// Blocking erase implementation for a region of QSPI flash, one subsector at a time.
+    fn erase_region(&mut self, addr: u32, len: usize) -> Result<(), QspiError> {
+        self.erase(addr, len).map(|_| ())
+    }
 }
 
 /// Handle setup of the DMA controller. Set `dir` to `true` for qspi -> memory and `false` for
@@ -612,7 +659,7 @@ pub mod tests {
     /// - The test addr is an odd, non page aligned address to stress the `memory_write` function.
     /// - The test length is greater than one subsector to stress the `memory_erase` function.
     pub fn test_mem(dut: &mut QspiDriver) {
-        const ADDR: u32 = 0x7003;
+        const ADDR: u32 = TEST_ADDR + 0x7003;
         const LEN: usize = 4121;
         let mut read_buffer: [u8; LEN] = [0; LEN];
         let mut write_buffer: [u8; LEN] = [0; LEN];
@@ -653,7 +700,7 @@ pub mod tests {
     /// Same idea as `test_mem` but using DMA. Note that transfer size must be 4 byte aligned for
     /// the way the DMA functionality was implemented in software.
     pub fn test_mem_dma(dut: &mut QspiDriver) {
-        const ADDR: u32 = 0x4000;
+        const ADDR: u32 = TEST_ADDR + 0x4000;
         const LEN: usize = 640;
         let read_buffer: [u8; LEN] = [0; LEN];
         let mut write_buffer: [u8; LEN] = [0; LEN];
@@ -0,0 +1,475 @@
+//! SDMMC driver for the microSD card slot (CN3) of the STM32F746G Discovery Board. Like the QSPI
+//! driver, this was written from scratch as the HAL has no SDMMC driver at time of writing. The
+//! card is used in 4 bit mode at 24 MHz, with transfers polled by the CPU and hardware flow
+//! control preventing FIFO overruns and underruns.
+
+use crate::block::{self, BlockStorage, BLOCK_SIZE};
+use crate::nvm::Mem;
+use crate::sd::{self, Cmd, Csd};
+use stm32f7xx_hal::{
+    pac::{GPIOC, GPIOD, RCC, SDMMC1},
+    rcc::Clocks,
+};
+
+/// SDMMC errors.
+#[derive(Debug, Eq, PartialEq)]
+pub enum SdError {
+    /// No card in the slot.
+    NoCard,
+    /// The card does not support 3.3 V or has an unknown CSD version.
+    Unsupported,
+    /// The card did not finish powering up.
+    PowerUpTimeout,
+    /// No response to a command.
+    CommandTimeout,
+    /// Response with a CRC error.
+    CommandCrc,
+    /// Error bits set in the card status.
+    Status(u32),
+    /// The card did not send or accept data in time.
+    DataTimeout,
+    /// Data block with a CRC error.
+    DataCrc,
+    /// The card stayed busy after a transfer.
+    Busy,
+    /// Access beyond the end of the card.
+    OutOfRange,
+}
+
+/// Expected response to a command.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Response {
+    None,
+    /// 48 bit response.
+    Short,
+    /// 48 bit response without a valid CRC (R3).
+    ShortNoCrc,
+    /// 136 bit response (R2).
+    Long,
+}
+
+/// The SDMMC input clock is the 48 MHz clock, 48 / (118 + 2) = 400 kHz during identification.
+const CLKDIV_INIT: u8 = 118;
+
+/// 48 / (0 + 2) = 24 MHz for data transfers.
+const CLKDIV_TRANSFER: u8 = 0;
+
+/// Data timeout in SDMMC clock cycles, 250 ms at 24 MHz as recommended for writes.
+const DATA_TIMEOUT: u32 = 6_000_000;
+
+/// Maximum number of blocks transferred by one command.
+const MAX_TRANSFER_BLOCKS: usize = 128;
+
+/// Number of `SD_SEND_OP_COND` retries, 1 ms apart, before giving up on power up.
+const POWER_UP_RETRIES: u32 = 1000;
+
+/// Number of status register polls until a command without response is sent, far more than the
+/// 120 us it takes at 400 kHz.
+const COMMAND_POLLS: u32 = 1_000_000;
+
+/// Number of `SEND_STATUS` retries after a transfer, about 1 s at 24 MHz, well above the 250 ms a
+/// card may take to program a write.
+const BUSY_RETRIES: u32 = 100_000;
+
+/// The status flags cleared before each command.
+const STATIC_FLAGS: u32 = 0x05FF;
+
+/// The SD card driver interface.
+pub struct SdCard {
+    /// SDMMC1 peripheral registers.
+    sdmmc: SDMMC1,
+    /// Standard capacity cards are addressed in bytes, high capacity cards in blocks.
+    high_capacity: bool,
+    num_blocks: u32,
+    /// Relative card address, as a command argument.
+    rca: u32,
+}
+
+impl SdCard {
+    /// Setup the SDMMC1 peripheral and its pins, and initialize the card in the slot.
+    /// * The SDMMC clock is the 48 MHz clock, so `usb::init` must have been called to set it up.
+    /// * Peripherals are stolen, so this should only be done during init!
+    pub fn new(sdmmc: SDMMC1, clocks: &Clocks) -> Result<Self, SdError> {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        rcc.dckcfgr2.modify(|_, w| w.sdmmc1sel().ck48m());
+        rcc.apb2enr.modify(|_, w| w.sdmmc1en().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.sdmmc1rst().set_bit());
+        rcc.apb2rstr.modify(|_, w| w.sdmmc1rst().clear_bit());
+
+        // Configure PC8-PC11 (D0-D3), PC12 (CK) and PD2 (CMD) as AF12 and PC13 as the card
+        // detect input, the ports are already split by the SDRAM driver
+        rcc.ahb1enr
+            .modify(|_, w| w.gpiocen().set_bit().gpioden().set_bit());
+        let gpioc = unsafe { &(*GPIOC::ptr()) };
+        let gpiod = unsafe { &(*GPIOD::ptr()) };
+        cortex_m::interrupt::free(|_| unsafe {
+            gpioc
+                .moder
+                .modify(|r, w| w.bits(r.bits() & !(0xFFF << 16) | (0x2AA << 16)));
+            gpioc
+                .ospeedr
+                .modify(|r, w| w.bits(r.bits() | (0x3FF << 16)));
+            gpioc
+                .pupdr
+                .modify(|r, w| w.bits(r.bits() & !(0xFFF << 16) | (0x455 << 16)));
+            gpioc
+                .afrh
+                .modify(|r, w| w.bits(r.bits() & !0xF_FFFF | 0xC_CCCC));
+
+            gpiod
+                .moder
+                .modify(|r, w| w.bits(r.bits() & !(0x3 << 4) | (0x2 << 4)));
+            gpiod.ospeedr.modify(|r, w| w.bits(r.bits() | (0x3 << 4)));
+            gpiod
+                .pupdr
+                .modify(|r, w| w.bits(r.bits() & !(0x3 << 4) | (0x1 << 4)));
+            gpiod
+                .afrl
+                .modify(|r, w| w.bits(r.bits() & !(0xF << 8) | (0xC << 8)));
+        });
+
+        // The card detect switch pulls PC13 low when a card is inserted
+        cortex_m::asm::delay(clocks.sysclk().0 / 1000);
+        if gpioc.idr.read().idr13().bit_is_set() {
+            return Err(SdError::NoCard);
+        }
+
+        // Power up with the identification clock, the card needs 74 clock cycles before the
+        // first command
+        unsafe {
+            sdmmc
+                .clkcr
+                .write_with_zero(|w| w.clkdiv().bits(CLKDIV_INIT).clken().set_bit());
+            sdmmc.power.write_with_zero(|w| w.pwrctrl().bits(0b11));
+        }
+        let delay_1ms = clocks.sysclk().0 / 1000;
+        cortex_m::asm::delay(2 * delay_1ms);
+
+        let mut card = SdCard {
+            sdmmc,
+            high_capacity: false,
+            num_blocks: 0,
+            rca: 0,
+        };
+        card.identify(delay_1ms)?;
+
+        // Switch to the 4 bit bus at full speed
+        card.app_command(Cmd::ACMD_SET_BUS_WIDTH, sd::BUS_WIDTH_4)?;
+        unsafe {
+            card.sdmmc.clkcr.write_with_zero(|w| {
+                w.clkdiv()
+                    .bits(CLKDIV_TRANSFER)
+                    .widbus()
+                    .bits(0b01)
+                    .hwfc_en()
+                    .set_bit()
+                    .clken()
+                    .set_bit()
+            });
+        }
+
+        Ok(card)
+    }
+
+    /// Take the card from idle to the transfer state and find its capacity.
+    fn identify(&mut self, delay_1ms: u32) -> Result<(), SdError> {
+        self.command(Cmd::GO_IDLE_STATE, 0, Response::None)?;
+
+        // Only version 2 cards respond to SEND_IF_COND, and only those can be high capacity
+        let v2 = match self.command(Cmd::SEND_IF_COND, sd::IF_COND_ARG, Response::Short) {
+            Ok(response) if sd::if_cond_valid(response) => true,
+            Ok(_) => return Err(SdError::Unsupported),
+            Err(SdError::CommandTimeout) => false,
+            Err(e) => return Err(e),
+        };
+
+        let mut arg = sd::OCR_VOLTAGE_WINDOW;
+        if v2 {
+            arg |= sd::OCR_HIGH_CAPACITY;
+        }
+
+        let mut retries = 0;
+        let ocr = loop {
+            self.command(Cmd::APP_CMD, 0, Response::Short)?;
+            let ocr = self.command(Cmd::ACMD_SD_SEND_OP_COND, arg, Response::ShortNoCrc)?;
+            if ocr & sd::OCR_POWER_UP != 0 {
+                break ocr;
+            } else if ocr & sd::OCR_VOLTAGE_WINDOW == 0 {
+                return Err(SdError::Unsupported);
+            }
+
+            retries += 1;
+            if retries == POWER_UP_RETRIES {
+                return Err(SdError::PowerUpTimeout);
+            }
+            cortex_m::asm::delay(delay_1ms);
+        };
+        self.high_capacity = ocr & sd::OCR_HIGH_CAPACITY != 0;
+
+        self.command(Cmd::ALL_SEND_CID, 0, Response::Long)?;
+        let response = self.command(Cmd::SEND_RELATIVE_ADDR, 0, Response::Short)?;
+        self.rca = sd::rca_arg(response);
+
+        self.command(Cmd::SEND_CSD, self.rca, Response::Long)?;
+        let csd = Csd::from_response(&self.long_response()).ok_or(SdError::Unsupported)?;
+        self.num_blocks = csd.num_blocks;
+
+        self.card_command(Cmd::SELECT_CARD, self.rca)?;
+        if !self.high_capacity {
+            self.card_command(Cmd::SET_BLOCKLEN, BLOCK_SIZE as u32)?;
+        }
+
+        Ok(())
+    }
+
+    /// Send a command with an R1 response, checking the card status for errors.
+    fn card_command(&mut self, index: u8, arg: u32) -> Result<u32, SdError> {
+        let status = self.command(index, arg, Response::Short)?;
+        match sd::status_errors(status) {
+            Some(errors) => Err(SdError::Status(errors)),
+            None => Ok(status),
+        }
+    }
+
+    /// Send an application specific command with an R1 response.
+    fn app_command(&mut self, index: u8, arg: u32) -> Result<u32, SdError> {
+        self.card_command(Cmd::APP_CMD, self.rca)?;
+        self.card_command(index, arg)
+    }
+
+    /// Send a command and wait for its response, which is returned for short responses.
+    fn command(&mut self, index: u8, arg: u32, response: Response) -> Result<u32, SdError> {
+        let waitresp = match response {
+            Response::None => 0b00,
+            Response::Short | Response::ShortNoCrc => 0b01,
+            Response::Long => 0b11,
+        };
+
+        unsafe {
+            self.sdmmc.icr.write(|w| w.bits(STATIC_FLAGS));
+            self.sdmmc.arg.write(|w| w.cmdarg().bits(arg));
+            self.sdmmc.cmd.write_with_zero(|w| {
+                w.cmdindex()
+                    .bits(index)
+                    .waitresp()
+                    .bits(waitresp)
+                    .cpsmen()
+                    .set_bit()
+            });
+        }
+
+        let mut polls = 0;
+        loop {
+            let sta = self.sdmmc.sta.read();
+            if response == Response::None {
+                if sta.cmdsent().bit_is_set() {
+                    break;
+                }
+                polls += 1;
+                if polls == COMMAND_POLLS {
+                    return Err(SdError::CommandTimeout);
+                }
+            } else if sta.ctimeout().bit_is_set() {
+                return Err(SdError::CommandTimeout);
+            } else if sta.ccrcfail().bit_is_set() {
+                // R3 responses have no CRC, so they always fail the check
+                match response {
+                    Response::ShortNoCrc => break,
+                    _ => return Err(SdError::CommandCrc),
+                }
+            } else if sta.cmdrend().bit_is_set() {
+                break;
+            }
+        }
+
+        Ok(self.sdmmc.resp1.read().bits())
+    }
+
+    /// Words of the last long response, most significant first.
+    fn long_response(&self) -> [u32; 4] {
+        [
+            self.sdmmc.resp1.read().bits(),
+            self.sdmmc.resp2.read().bits(),
+            self.sdmmc.resp3.read().bits(),
+            self.sdmmc.resp4.read().bits(),
+        ]
+    }
+
+    /// Prepare the data path for a transfer of `len` bytes, `read` being the direction.
+    fn setup_data(&mut self, len: usize, read: bool) {
+        unsafe {
+            self.sdmmc.dtimer.write(|w| w.datatime().bits(DATA_TIMEOUT));
+            self.sdmmc.dlen.write(|w| w.datalength().bits(len as u32));
+            self.sdmmc.dctrl.write_with_zero(|w| {
+                // 2^9 = 512 byte blocks
+                w.dblocksize().bits(9).dten().set_bit();
+                match read {
+                    true => w.dtdir().set_bit(),
+                    false => w.dtdir().clear_bit(),
+                }
+            });
+        }
+    }
+
+    /// Check the data path for errors, returns `true` once the transfer is done.
+    fn data_status(&self) -> Result<bool, SdError> {
+        let sta = self.sdmmc.sta.read();
+        if sta.dtimeout().bit_is_set() {
+            Err(SdError::DataTimeout)
+        } else if sta.dcrcfail().bit_is_set() {
+            Err(SdError::DataCrc)
+        } else {
+            Ok(sta.dataend().bit_is_set())
+        }
+    }
+
+    /// Stop a multiple block transfer and wait until the card is ready for the next one.
+    fn finish_transfer(&mut self, blocks: usize) -> Result<(), SdError> {
+        if blocks > 1 {
+            self.card_command(Cmd::STOP_TRANSMISSION, 0)?;
+        }
+
+        for _ in 0..BUSY_RETRIES {
+            let status = self.card_command(Cmd::SEND_STATUS, self.rca)?;
+            if sd::current_state(status) == sd::STATE_TRANSFER {
+                return Ok(());
+            }
+        }
+        Err(SdError::Busy)
+    }
+
+    fn check_range(&self, lba: u32, blocks: usize) -> Result<(), SdError> {
+        match lba.checked_add(blocks as u32) {
+            Some(end) if end <= self.num_blocks => Ok(()),
+            _ => Err(SdError::OutOfRange),
+        }
+    }
+
+    /// Read up to `MAX_TRANSFER_BLOCKS` blocks.
+    fn read_transfer(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), SdError> {
+        let blocks = buf.len() / BLOCK_SIZE;
+        let cmd = match blocks {
+            1 => Cmd::READ_SINGLE_BLOCK,
+            _ => Cmd::READ_MULTIPLE_BLOCK,
+        };
+
+        self.setup_data(buf.len(), true);
+        self.card_command(cmd, sd::data_address(lba, self.high_capacity))?;
+
+        // Stop the transfer after errors too, the card keeps sending blocks otherwise
+        let result = self.read_fifo(buf);
+        let finished = self.finish_transfer(blocks);
+        result.and(finished)
+    }
+
+    /// Read the data of a transfer from the FIFO into `buf`.
+    fn read_fifo(&mut self, buf: &mut [u8]) -> Result<(), SdError> {
+        let mut idx = 0;
+        loop {
+            while idx < buf.len() && self.sdmmc.sta.read().rxdavl().bit_is_set() {
+                let word = self.sdmmc.fifo.read().bits();
+                buf[idx..idx + 4].copy_from_slice(&word.to_le_bytes());
+                idx += 4;
+            }
+
+            if self.data_status()? && idx == buf.len() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Write up to `MAX_TRANSFER_BLOCKS` blocks.
+    fn write_transfer(&mut self, lba: u32, buf: &[u8]) -> Result<(), SdError> {
+        let blocks = buf.len() / BLOCK_SIZE;
+        let cmd = match blocks {
+            1 => Cmd::WRITE_BLOCK,
+            _ => Cmd::WRITE_MULTIPLE_BLOCK,
+        };
+
+        self.card_command(cmd, sd::data_address(lba, self.high_capacity))?;
+        self.setup_data(buf.len(), false);
+
+        // Stop the transfer after errors too, the card keeps receiving blocks otherwise
+        let result = self.write_fifo(buf);
+        let finished = self.finish_transfer(blocks);
+        result.and(finished)
+    }
+
+    /// Write the data of a transfer from `buf` to the FIFO.
+    fn write_fifo(&mut self, buf: &[u8]) -> Result<(), SdError> {
+        let mut idx = 0;
+        loop {
+            while idx < buf.len() && self.sdmmc.sta.read().txfifof().bit_is_clear() {
+                let word = u32::from_le_bytes([buf[idx], buf[idx + 1], buf[idx + 2], buf[idx + 3]]);
+                unsafe {
+                    self.sdmmc.fifo.write(|w| w.fifodata().bits(word));
+                }
+                idx += 4;
+            }
+
+            if self.data_status()? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl BlockStorage for SdCard {
+    type Error = SdError;
+
+    fn num_blocks(&self) -> u32 {
+        self.num_blocks
+    }
+
+    fn read_blocks(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), SdError> {
+        self.check_range(lba, buf.len() / BLOCK_SIZE)?;
+        let mut lba = lba;
+        for chunk in buf.chunks_mut(MAX_TRANSFER_BLOCKS * BLOCK_SIZE) {
+            self.read_transfer(lba, chunk)?;
+            lba += (chunk.len() / BLOCK_SIZE) as u32;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u32, buf: &[u8]) -> Result<(), SdError> {
+        self.check_range(lba, buf.len() / BLOCK_SIZE)?;
+        let mut lba = lba;
+        for chunk in buf.chunks(MAX_TRANSFER_BLOCKS * BLOCK_SIZE) {
+            self.write_transfer(lba, chunk)?;
+            lba += (chunk.len() / BLOCK_SIZE) as u32;
+        }
+        Ok(())
+    }
+}
+
+/// Implementation of `Mem` traits for the SD card, so it can replace the QSPI flash. Only the
+/// first 4 GB of the card are addressable.
+impl Mem for SdCard {
+    type Error = SdError;
+
+    /// Blocking read implementation for the SD card.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), SdError> {
+        block::read_bytes(self, addr, buf)
+    }
+
+    /// Blocking write implementation for the SD card.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), SdError> {
+        block::write_bytes(self, addr, buf)
+    }
+
+    /// SD cards handle erasing internally, any block can always be written.
+    fn erase(&mut self) -> Result<(), SdError> {
+        Ok(())
+    }
+
+    /// Fill the blocks of the region with 0xFF, which is what the filesystem expects to read back
+    /// after erasing.
+    fn erase_region(&mut self, addr: u32, len: usize) -> Result<(), SdError> {
+        let erased = [0xFF; BLOCK_SIZE];
+        let start = addr - addr % BLOCK_SIZE as u32;
+        for offset in (start..addr + len as u32).step_by(BLOCK_SIZE) {
+            block::write_bytes(self, offset, &erased)?;
+        }
+        Ok(())
+    }
+}
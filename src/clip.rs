@@ -0,0 +1,445 @@
+//! Self-describing container for saved clips, shared by the firmware and the host tools.
+//!
+//! A clip is stored as follows, with all numbers little endian:
+//!
+//! ```text
+//! +--------+---------+-----+-------------+-------+---------+
+//! | header | frame 0 | ... | frame n - 1 | index | trailer |
+//! +--------+---------+-----+-------------+-------+---------+
+//! ```
+//!
+//! The header has `HEADER_LEN` bytes:
+//!
+//! | Offset | Size | Field                                                  |
+//! |--------|------|--------------------------------------------------------|
+//! | 0      | 4    | Magic number, "DCLP" in ASCII                          |
+//! | 4      | 2    | Version of the format, `VERSION`                       |
+//! | 6      | 1    | Pixel format                                           |
+//! | 7      | 1    | Codec                                                  |
+//! | 8      | 2    | Width in pixels                                        |
+//! | 10     | 2    | Height in pixels                                       |
+//! | 12     | 2    | Frame rate in frames per second                        |
+//! | 14     | 2    | Reserved, 0                                            |
+//! | 16     | 4    | Number of frames                                       |
+//! | 20     | 4    | Time of the first frame, seconds since the Unix epoch  |
+//! | 24     | 4    | Reserved, 0                                            |
+//! | 28     | 4    | CRC-32 of the header bytes before it                   |
+//!
+//! The frames follow the header back to back. The index has an entry of `INDEX_ENTRY_LEN` bytes
+//! for each frame: its offset from the start of the clip, its time in milliseconds after the
+//! first frame and its size in bytes. The trailer has `TRAILER_LEN` bytes: the magic number "DCLE"
+//! in ASCII, the offset of the index, and the CRC-32 of all bytes of the clip before it.
+//!
+//! The header is written first, so the number of frames has to be known when a clip is started.
+//! The index is kept in RAM until the clip is finished.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+use crate::crc::{crc32, Crc32};
+use core::convert::Infallible;
+
+/// Version of the format written, and the only one read.
+pub const VERSION: u16 = 1;
+
+/// Size of the header in bytes.
+pub const HEADER_LEN: usize = 32;
+
+/// Size of an entry of the frame index in bytes.
+pub const INDEX_ENTRY_LEN: usize = 12;
+
+/// Size of the trailer in bytes.
+pub const TRAILER_LEN: usize = 12;
+
+/// Marks the start of a clip ("DCLP" in ASCII).
+const MAGIC: u32 = 0x4443_4C50;
+
+/// Marks the trailer of a clip ("DCLE" in ASCII).
+const TRAILER_MAGIC: u32 = 0x4443_4C45;
+
+/// Number of index entries written at once when finishing a clip.
+const INDEX_CHUNK: usize = 16;
+
+/// Layout of the pixels of a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PixelFormat {
+    /// 16 bit RGB565 pixels, little endian, row by row from the top left.
+    Rgb565 = 1,
+}
+
+impl PixelFormat {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(PixelFormat::Rgb565),
+            _ => None,
+        }
+    }
+}
+
+/// How the frames are compressed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Codec {
+    /// Frames are stored uncompressed, in the pixel format.
+    Raw = 0,
+    /// Each frame is a baseline JPEG (JFIF) image, see `jpeg`.
+    Jpeg = 1,
+    /// Frames are coded losslessly against the previous frame, the first one on its own, see
+    /// `codec`.
+    Delta = 2,
+}
+
+impl Codec {
+    /// The codec with number `value`, as stored in the header.
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Codec::Raw),
+            1 => Some(Codec::Jpeg),
+            2 => Some(Codec::Delta),
+            _ => None,
+        }
+    }
+}
+
+/// Clip errors, with `E` being the error type of the storage.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<E> {
+    /// Error reading or writing the storage.
+    Io(E),
+    /// Not a clip, the magic number doesn't match.
+    NoClip,
+    /// The clip has a newer version of the format.
+    Version,
+    /// The pixel format or codec is unknown.
+    Unsupported,
+    /// The header, index or trailer is damaged, or the clip is cut short.
+    Corrupt,
+    /// The CRC of the clip doesn't match its contents.
+    Crc,
+    /// More or fewer frames were written than given in the header.
+    FrameCount,
+    /// The buffer is too small for the frame.
+    BufferTooSmall,
+}
+
+/// Description of a clip, stored in its header.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub width: u16,
+    pub height: u16,
+    pub pixel_format: PixelFormat,
+    pub codec: Codec,
+    /// Frames per second.
+    pub frame_rate: u16,
+    pub frame_count: u32,
+    /// Time of the first frame, in seconds since the Unix epoch, or 0 if unknown.
+    pub start_time: u32,
+}
+
+impl Header {
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        put_u32(&mut bytes, 0, MAGIC);
+        put_u16(&mut bytes, 4, VERSION);
+        bytes[6] = self.pixel_format as u8;
+        bytes[7] = self.codec as u8;
+        put_u16(&mut bytes, 8, self.width);
+        put_u16(&mut bytes, 10, self.height);
+        put_u16(&mut bytes, 12, self.frame_rate);
+        put_u32(&mut bytes, 16, self.frame_count);
+        put_u32(&mut bytes, 20, self.start_time);
+
+        let crc = crc32(&bytes[..HEADER_LEN - 4]);
+        put_u32(&mut bytes, HEADER_LEN - 4, crc);
+        bytes
+    }
+
+    fn parse<E>(bytes: &[u8; HEADER_LEN]) -> Result<Header, Error<E>> {
+        if u32_at(bytes, 0) != MAGIC {
+            return Err(Error::NoClip);
+        }
+        if u32_at(bytes, HEADER_LEN - 4) != crc32(&bytes[..HEADER_LEN - 4]) {
+            return Err(Error::Corrupt);
+        }
+        if u16_at(bytes, 4) != VERSION {
+            return Err(Error::Version);
+        }
+
+        Ok(Header {
+            width: u16_at(bytes, 8),
+            height: u16_at(bytes, 10),
+            pixel_format: PixelFormat::from_u8(bytes[6]).ok_or(Error::Unsupported)?,
+            codec: Codec::from_u8(bytes[7]).ok_or(Error::Unsupported)?,
+            frame_rate: u16_at(bytes, 12),
+            frame_count: u32_at(bytes, 16),
+            start_time: u32_at(bytes, 20),
+        })
+    }
+}
+
+/// Entry of the frame index.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FrameEntry {
+    /// Offset of the frame from the start of the clip.
+    pub offset: u32,
+    /// Time of the frame in milliseconds after the first frame.
+    pub time_ms: u32,
+    /// Size of the frame in bytes.
+    pub size: u32,
+}
+
+impl FrameEntry {
+    fn to_bytes(self) -> [u8; INDEX_ENTRY_LEN] {
+        let mut bytes = [0; INDEX_ENTRY_LEN];
+        put_u32(&mut bytes, 0, self.offset);
+        put_u32(&mut bytes, 4, self.time_ms);
+        put_u32(&mut bytes, 8, self.size);
+        bytes
+    }
+
+    fn parse(bytes: &[u8; INDEX_ENTRY_LEN]) -> Self {
+        FrameEntry {
+            offset: u32_at(bytes, 0),
+            time_ms: u32_at(bytes, 4),
+            size: u32_at(bytes, 8),
+        }
+    }
+}
+
+/// Storage a clip is written to, from start to end.
+pub trait Sink {
+    type Error;
+
+    /// Append `data` to the clip.
+    fn write(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Storage a clip is read from.
+pub trait Source {
+    type Error;
+
+    /// Read `buf.len()` bytes of the clip starting at byte `offset` to `buf`.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// A clip in memory. Panics if a read is outside of the slice, `ClipReader` only reads inside
+/// the length of the clip.
+impl Source for [u8] {
+    type Error = Infallible;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Infallible> {
+        let start = offset as usize;
+        buf.copy_from_slice(&self[start..start + buf.len()]);
+        Ok(())
+    }
+}
+
+/// Writes a clip to a `Sink`: The header when created, then each frame, and the index and trailer
+/// when finished.
+pub struct ClipWriter<'a> {
+    header: Header,
+    /// Index of the frames written so far.
+    index: &'a mut [FrameEntry],
+    frames: usize,
+    /// Number of bytes written.
+    offset: u32,
+    crc: Crc32,
+}
+
+impl<'a> ClipWriter<'a> {
+    /// Start a clip described by `header`. The index is kept in `index` until the clip is
+    /// finished, it needs room for `header.frame_count` entries.
+    pub fn new<S: Sink + ?Sized>(
+        sink: &mut S,
+        header: Header,
+        index: &'a mut [FrameEntry],
+    ) -> Result<Self, Error<S::Error>> {
+        if index.len() < header.frame_count as usize {
+            return Err(Error::FrameCount);
+        }
+
+        let mut writer = ClipWriter {
+            header,
+            index,
+            frames: 0,
+            offset: 0,
+            crc: Crc32::new(),
+        };
+        writer.put(sink, &header.to_bytes())?;
+        Ok(writer)
+    }
+
+    /// Add a frame taken `time_ms` milliseconds after the first one.
+    pub fn write_frame<S: Sink + ?Sized>(
+        &mut self,
+        sink: &mut S,
+        data: &[u8],
+        time_ms: u32,
+    ) -> Result<(), Error<S::Error>> {
+        if self.frames == self.header.frame_count as usize {
+            return Err(Error::FrameCount);
+        }
+
+        self.index[self.frames] = FrameEntry {
+            offset: self.offset,
+            time_ms,
+            size: data.len() as u32,
+        };
+        self.frames += 1;
+        self.put(sink, data)
+    }
+
+    /// Write the index and trailer, after all frames were added. Returns the size of the clip in
+    /// bytes.
+    pub fn finish<S: Sink + ?Sized>(mut self, sink: &mut S) -> Result<u32, Error<S::Error>> {
+        if self.frames != self.header.frame_count as usize {
+            return Err(Error::FrameCount);
+        }
+
+        let index_offset = self.offset;
+        for n in (0..self.frames).step_by(INDEX_CHUNK) {
+            let count = (self.frames - n).min(INDEX_CHUNK);
+            let mut bytes = [0; INDEX_CHUNK * INDEX_ENTRY_LEN];
+            for (i, chunk) in bytes.chunks_mut(INDEX_ENTRY_LEN).take(count).enumerate() {
+                chunk.copy_from_slice(&self.index[n + i].to_bytes());
+            }
+            self.put(sink, &bytes[..count * INDEX_ENTRY_LEN])?;
+        }
+
+        let mut trailer = [0; TRAILER_LEN];
+        put_u32(&mut trailer, 0, TRAILER_MAGIC);
+        put_u32(&mut trailer, 4, index_offset);
+        self.crc.update(&trailer[..TRAILER_LEN - 4]);
+        put_u32(&mut trailer, TRAILER_LEN - 4, self.crc.finish());
+        sink.write(&trailer).map_err(Error::Io)?;
+        Ok(self.offset + TRAILER_LEN as u32)
+    }
+
+    fn put<S: Sink + ?Sized>(&mut self, sink: &mut S, data: &[u8]) -> Result<(), Error<S::Error>> {
+        sink.write(data).map_err(Error::Io)?;
+        self.crc.update(data);
+        self.offset += data.len() as u32;
+        Ok(())
+    }
+}
+
+/// Reads a clip from a `Source`.
+#[derive(Clone, Copy, Debug)]
+pub struct ClipReader {
+    header: Header,
+    /// Size of the clip in bytes.
+    len: u32,
+    index_offset: u32,
+    /// CRC stored in the trailer.
+    crc: u32,
+}
+
+impl ClipReader {
+    /// Open the clip of `len` bytes in `source`, checking its header and trailer.
+    pub fn open<S: Source + ?Sized>(source: &mut S, len: u32) -> Result<Self, Error<S::Error>> {
+        if len < (HEADER_LEN + TRAILER_LEN) as u32 {
+            return Err(Error::NoClip);
+        }
+
+        let mut bytes = [0; HEADER_LEN];
+        source.read(0, &mut bytes).map_err(Error::Io)?;
+        let header = Header::parse(&bytes)?;
+
+        let mut trailer = [0; TRAILER_LEN];
+        source
+            .read(len - TRAILER_LEN as u32, &mut trailer)
+            .map_err(Error::Io)?;
+        let index_offset = u32_at(&trailer, 4);
+        let index_len = header.frame_count as u64 * INDEX_ENTRY_LEN as u64;
+        if u32_at(&trailer, 0) != TRAILER_MAGIC
+            || index_offset < HEADER_LEN as u32
+            || index_offset as u64 + index_len + TRAILER_LEN as u64 != len as u64
+        {
+            return Err(Error::Corrupt);
+        }
+
+        Ok(ClipReader {
+            header,
+            len,
+            index_offset,
+            crc: u32_at(&trailer, TRAILER_LEN - 4),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Index entry of frame `n`, which must be less than the number of frames.
+    pub fn frame<S: Source + ?Sized>(
+        &self,
+        source: &mut S,
+        n: u32,
+    ) -> Result<FrameEntry, Error<S::Error>> {
+        assert!(n < self.header.frame_count);
+
+        let mut bytes = [0; INDEX_ENTRY_LEN];
+        let offset = self.index_offset + n * INDEX_ENTRY_LEN as u32;
+        source.read(offset, &mut bytes).map_err(Error::Io)?;
+        let entry = FrameEntry::parse(&bytes);
+
+        match entry.offset.checked_add(entry.size) {
+            Some(end) if entry.offset >= HEADER_LEN as u32 && end <= self.index_offset => Ok(entry),
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    /// Read frame `n` to the start of `buf`. Returns the size of the frame.
+    pub fn read_frame<S: Source + ?Sized>(
+        &self,
+        source: &mut S,
+        n: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<S::Error>> {
+        let entry = self.frame(source, n)?;
+        let size = entry.size as usize;
+        if buf.len() < size {
+            return Err(Error::BufferTooSmall);
+        }
+
+        source
+            .read(entry.offset, &mut buf[..size])
+            .map_err(Error::Io)?;
+        Ok(size)
+    }
+
+    /// Check the CRC of the whole clip, which reads all of it.
+    pub fn verify<S: Source + ?Sized>(&self, source: &mut S) -> Result<(), Error<S::Error>> {
+        let mut crc = Crc32::new();
+        let mut buf = [0; 256];
+        let end = self.len - 4;
+        let mut offset = 0;
+        while offset < end {
+            let len = (end - offset).min(buf.len() as u32) as usize;
+            source.read(offset, &mut buf[..len]).map_err(Error::Io)?;
+            crc.update(&buf[..len]);
+            offset += len as u32;
+        }
+
+        match crc.finish() == self.crc {
+            true => Ok(()),
+            false => Err(Error::Crc),
+        }
+    }
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut word = [0; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(word)
+}
+
+fn put_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
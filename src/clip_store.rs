@@ -0,0 +1,259 @@
+//! Saved clips, kept as files of the flash filesystem in the format of the `clip` module.
+//!
+//! Clip `n` is the file `clipn`, for `n` below `MAX_CLIPS`, and a new clip takes the lowest free
+//! number. The flags of a file hold the serial number of its clip, which counts the saved clips,
+//! so their order is known when numbers are reused. One clip at a time is written, it becomes
+//! part of the clips once it is finished.
+//!
+//! When the storage is full or its free blocks are too split up, the oldest clips are reclaimed to
+//! make room for the clip being written, except for locked clips. Once all clips are locked,
+//! writing fails with the error of the filesystem.
+//!
+//! The clips can also be read like one memory, with clip `n` at address `n * CLIP_SPACING`.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+use crate::flashfs::{Error, FileSystem, Mem, Writer};
+
+/// Most clips which can be saved. The filesystem holds a few other files too.
+pub const MAX_CLIPS: usize = 12;
+
+/// Flag of a locked clip, the other bits of the flags of its file are its serial number.
+const LOCKED: u32 = 1 << 31;
+
+/// Distance of the clips in the addresses of `ClipStore::read_at`, more than any clip can take.
+pub const CLIP_SPACING: u32 = 1 << 24;
+
+/// A saved clip.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StoredClip {
+    pub number: usize,
+    /// Size in bytes.
+    pub size: u32,
+    /// Unix time the clip was saved, 0 if not known.
+    pub time: u32,
+    /// Counts the saved clips, newer clips have higher serial numbers.
+    pub serial: u32,
+    /// Locked clips are never reclaimed.
+    pub locked: bool,
+}
+
+/// The saved clips, and the one being written.
+pub struct ClipStore {
+    /// Number and file of the clip being written.
+    writing: Option<(usize, Writer)>,
+    /// Serial number of the next clip.
+    serial: u32,
+}
+
+impl ClipStore {
+    /// Find the clips saved in `fs`.
+    pub fn new(fs: &FileSystem) -> Self {
+        let mut store = ClipStore {
+            writing: None,
+            serial: 0,
+        };
+        store.serial = store
+            .clips(fs)
+            .map(|clip| clip.serial + 1)
+            .max()
+            .unwrap_or(0);
+        store
+    }
+
+    /// The saved clips, by number.
+    pub fn clips<'a>(&self, fs: &'a FileSystem) -> impl Iterator<Item = StoredClip> + 'a {
+        (0..MAX_CLIPS).filter_map(move |number| clip(fs, number))
+    }
+
+    /// Clip `number`, if it is saved.
+    pub fn clip(&self, fs: &FileSystem, number: usize) -> Option<StoredClip> {
+        clip(fs, number)
+    }
+
+    /// Number of the clip being written, if there is one.
+    pub fn writing(&self) -> Option<usize> {
+        self.writing.as_ref().map(|(number, _)| *number)
+    }
+
+    /// Start writing a new clip, saved at Unix time `time`, discarding the clip being written.
+    /// Unlocked clips are reclaimed if there are too many files. Returns the number of the clip.
+    pub fn create<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+        time: u32,
+    ) -> Result<usize, Error<M::Error>> {
+        self.discard(fs);
+        loop {
+            let mut name = [0; NAME_LEN];
+            let result = match (0..MAX_CLIPS).find(|&number| clip(fs, number).is_none()) {
+                Some(number) => fs
+                    .create(file_name(number, &mut name), time, self.serial)
+                    .map(|writer| (number, writer)),
+                None => Err(Error::TooManyFiles),
+            };
+
+            match result {
+                Ok((number, writer)) => {
+                    self.serial += 1;
+                    self.writing = Some((number, writer));
+                    return Ok(number);
+                }
+                Err(Error::TooManyFiles) if self.reclaim(fs, device)? => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Append `data` to the clip being written. Unlocked clips are reclaimed if the storage is
+    /// full or too fragmented for the clip.
+    pub fn write<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+        data: &[u8],
+    ) -> Result<(), Error<M::Error>> {
+        let mut data = data;
+        loop {
+            let (_, writer) = self.writing.as_mut().ok_or(Error::NotFound)?;
+            let size = writer.size();
+            match fs.write(device, writer, data) {
+                Err(e @ Error::Full) | Err(e @ Error::Fragmented) => {
+                    // Continue after the blocks which could be written
+                    data = &data[(writer.size() - size) as usize..];
+                    if !self.reclaim(fs, device)? {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Finish writing the clip, which is saved then. Returns its number, if there was one.
+    pub fn finish<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+    ) -> Result<Option<usize>, Error<M::Error>> {
+        match self.writing.take() {
+            Some((number, writer)) => fs.close(device, writer).map(|_| Some(number)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stop writing the clip without saving it.
+    pub fn discard(&mut self, fs: &mut FileSystem) {
+        if let Some((_, writer)) = self.writing.take() {
+            fs.discard(writer);
+        }
+    }
+
+    /// Remove clip `number`. Its blocks still need to be erased, see `FileSystem::remove`.
+    pub fn remove<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+        number: usize,
+    ) -> Result<(), Error<M::Error>> {
+        let mut name = [0; NAME_LEN];
+        match number < MAX_CLIPS {
+            true => fs.remove(device, file_name(number, &mut name)),
+            false => Err(Error::NotFound),
+        }
+    }
+
+    /// Lock or unlock clip `number`.
+    pub fn lock<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+        number: usize,
+        locked: bool,
+    ) -> Result<(), Error<M::Error>> {
+        let clip = clip(fs, number).ok_or(Error::NotFound)?;
+        let flags = match locked {
+            true => clip.serial | LOCKED,
+            false => clip.serial,
+        };
+
+        let mut name = [0; NAME_LEN];
+        fs.set_flags(device, file_name(number, &mut name), flags)
+    }
+
+    /// Remove the oldest unlocked clip, to make room for the clip being written. Returns `false`
+    /// if all clips are locked.
+    fn reclaim<M: Mem>(
+        &mut self,
+        fs: &mut FileSystem,
+        device: &mut M,
+    ) -> Result<bool, Error<M::Error>> {
+        let oldest = self
+            .clips(fs)
+            .filter(|clip| !clip.locked)
+            .min_by_key(|clip| clip.serial);
+        match oldest {
+            Some(clip) => self.remove(fs, device, clip.number).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Read up to `buf.len()` bytes of the clips starting at address `addr`, without crossing
+    /// the end of a clip. Returns the number of bytes read.
+    pub fn read_at<M: Mem>(
+        &self,
+        fs: &FileSystem,
+        device: &mut M,
+        addr: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<M::Error>> {
+        let number = (addr / CLIP_SPACING) as usize;
+        let mut name = [0; NAME_LEN];
+        match number < MAX_CLIPS {
+            true => fs.read(
+                device,
+                file_name(number, &mut name),
+                addr % CLIP_SPACING,
+                buf,
+            ),
+            false => Err(Error::NotFound),
+        }
+    }
+}
+
+/// Length of the longest file name of a clip.
+const NAME_LEN: usize = 6;
+
+/// Name of the file of clip `number`, in `buf`.
+fn file_name(number: usize, buf: &mut [u8; NAME_LEN]) -> &str {
+    buf[..4].copy_from_slice(b"clip");
+    let len = match number {
+        0..=9 => {
+            buf[4] = b'0' + number as u8;
+            5
+        }
+        _ => {
+            buf[4] = b'0' + (number / 10 % 10) as u8;
+            buf[5] = b'0' + (number % 10) as u8;
+            6
+        }
+    };
+    core::str::from_utf8(&buf[..len]).unwrap_or("")
+}
+
+fn clip(fs: &FileSystem, number: usize) -> Option<StoredClip> {
+    let mut name = [0; NAME_LEN];
+    if number >= MAX_CLIPS {
+        return None;
+    }
+
+    fs.metadata(file_name(number, &mut name))
+        .map(|meta| StoredClip {
+            number,
+            size: meta.size,
+            time: meta.time,
+            serial: meta.flags & !LOCKED,
+            locked: meta.flags & LOCKED != 0,
+        })
+}
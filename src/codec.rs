@@ -0,0 +1,352 @@
+//! Lossless codec for RGB565 frames, much cheaper to run than `jpeg` and keeping the exact pixels.
+//!
+//! A frame is coded in tiles of `TILE` x `TILE` pixels, row by row from the top left. Each tile
+//! starts with its mode byte:
+//!
+//! | Mode       | Tile data                                                              |
+//! |------------|------------------------------------------------------------------------|
+//! | `SKIP`     | None, the pixels are those of the previous frame                       |
+//! | `TEMPORAL` | Packed residuals against the pixels of the previous frame              |
+//! | `SPATIAL`  | Packed residuals against the pixel to the left, or above in column 0   |
+//! | `RAW`      | The pixels, 2 bytes each                                               |
+//!
+//! A residual is the wrapping difference of the 16 bit pixel and its prediction. The residuals of
+//! a tile are packed in runs, each starting with a tag byte:
+//!
+//! | Tag         | Run                                                                |
+//! |-------------|--------------------------------------------------------------------|
+//! | 0x00 - 0x7F | 1 to 128 zero residuals                                            |
+//! | 0x80 - 0xBF | 1 to 64 residuals from -128 to 127, 1 byte each                    |
+//! | 0xC0 - 0xFF | 1 to 64 residuals, 2 bytes each little endian                      |
+//!
+//! Dash cam footage has a lot of static sky and bonnet, which is skipped or packs into a few
+//! bytes per tile. Tiles which don't pack are stored raw, so a frame never takes more than
+//! `max_len` bytes. The first frame of a clip is coded without a previous frame, so frames have to
+//! be decoded in order.
+//!
+//! The codec does not allocate or depend on any hardware, so it can be tested on the host.
+
+/// Width and height of a tile in pixels, tiles at the right and bottom edge may be smaller.
+pub const TILE: usize = 16;
+
+/// Modes of a tile.
+const SKIP: u8 = 0;
+const TEMPORAL: u8 = 1;
+const SPATIAL: u8 = 2;
+const RAW: u8 = 3;
+
+/// First tags of the runs of small and wide residuals, and the longest runs.
+const SMALL: u8 = 0x80;
+const WIDE: u8 = 0xC0;
+const MAX_ZEROS: usize = 128;
+const MAX_LITERALS: usize = 64;
+
+/// Position and number of bits of red, green and blue in a pixel.
+const CHANNELS: [(u32, u32); 3] = [(11, 5), (5, 6), (0, 5)];
+
+/// Number of bits of red, green and blue in a small residual byte.
+const SMALL_BITS: [u32; 3] = [3, 3, 2];
+
+/// Decoding errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The data is cut short, too long or has an invalid mode.
+    Corrupt,
+    /// A tile refers to the previous frame, but there is none.
+    NoPrevious,
+}
+
+/// Most bytes a frame of `width` x `height` pixels can take.
+pub const fn max_len(width: u16, height: u16) -> usize {
+    let tiles = (width as usize).div_ceil(TILE) * (height as usize).div_ceil(TILE);
+    tiles + width as usize * height as usize * 2
+}
+
+/// Encode a frame of RGB565 pixels (little endian) to `out`, against the previous frame `prev`
+/// of the same size if there is one. Returns the size of the coded frame, or `None` if it doesn't
+/// fit in `out`, which never happens if `out` has `max_len` bytes.
+pub fn encode(
+    pixels: &[u8],
+    prev: Option<&[u8]>,
+    width: u16,
+    height: u16,
+    out: &mut [u8],
+) -> Option<usize> {
+    assert_eq!(pixels.len(), width as usize * height as usize * 2);
+    assert!(prev.is_none_or(|prev| prev.len() == pixels.len()));
+    let width = width as usize;
+
+    let mut pos = 0;
+    let mut residuals = [0; TILE * TILE];
+    for tile in tiles(width, height as usize) {
+        let temporal = prev.map(|prev| {
+            tile.fill(&mut residuals, |x, y| {
+                residual(pixel(pixels, width, x, y), pixel(prev, width, x, y))
+            })
+        });
+        let spatial = |x, y| residual(pixel(pixels, width, x, y), predict(pixels, width, x, y));
+        let mode = match temporal {
+            Some(0) => {
+                *out.get_mut(pos)? = SKIP;
+                pos += 1;
+                continue;
+            }
+            Some(nonzero) if nonzero <= tile.count(spatial) => TEMPORAL,
+            _ => {
+                tile.fill(&mut residuals, spatial);
+                SPATIAL
+            }
+        };
+
+        // Tiles which don't pack are stored raw
+        let raw_len = tile.len() * 2;
+        let end = out.len().min(pos + 1 + raw_len);
+        let packed = out
+            .get_mut(pos + 1..end)
+            .and_then(|tile_out| pack(&residuals[..tile.len()], tile_out));
+        match packed {
+            Some(len) => {
+                out[pos] = mode;
+                pos += 1 + len;
+            }
+            None => {
+                let tile_out = out.get_mut(pos..pos + 1 + raw_len)?;
+                tile_out[0] = RAW;
+                for (bytes, (x, y)) in tile_out[1..].chunks_mut(2).zip(tile.pixels()) {
+                    bytes.copy_from_slice(&pixel(pixels, width, x, y).to_le_bytes());
+                }
+                pos += 1 + raw_len;
+            }
+        }
+    }
+    Some(pos)
+}
+
+/// Decode a frame coded by `encode` to `pixels`, given the previous frame `prev` it was coded
+/// against.
+pub fn decode(
+    data: &[u8],
+    prev: Option<&[u8]>,
+    width: u16,
+    height: u16,
+    pixels: &mut [u8],
+) -> Result<(), Error> {
+    assert_eq!(pixels.len(), width as usize * height as usize * 2);
+    assert!(prev.is_none_or(|prev| prev.len() == pixels.len()));
+    let width = width as usize;
+
+    let mut data = data.iter().copied();
+    let mut residuals = [0; TILE * TILE];
+    for tile in tiles(width, height as usize) {
+        let mode = next(&mut data)?;
+        let residuals = &mut residuals[..tile.len()];
+        match mode {
+            SKIP | TEMPORAL => {
+                let prev = prev.ok_or(Error::NoPrevious)?;
+                match mode {
+                    SKIP => residuals.fill(0),
+                    _ => unpack(&mut data, residuals)?,
+                }
+                for ((x, y), &residual) in tile.pixels().zip(residuals.iter()) {
+                    let value = apply(pixel(prev, width, x, y), residual);
+                    set_pixel(pixels, width, x, y, value);
+                }
+            }
+            SPATIAL => {
+                unpack(&mut data, residuals)?;
+                for ((x, y), &residual) in tile.pixels().zip(residuals.iter()) {
+                    let value = apply(predict(pixels, width, x, y), residual);
+                    set_pixel(pixels, width, x, y, value);
+                }
+            }
+            RAW => {
+                for (x, y) in tile.pixels() {
+                    let bytes = [next(&mut data)?, next(&mut data)?];
+                    set_pixel(pixels, width, x, y, u16::from_le_bytes(bytes));
+                }
+            }
+            _ => return Err(Error::Corrupt),
+        }
+    }
+
+    match data.next() {
+        Some(_) => Err(Error::Corrupt),
+        None => Ok(()),
+    }
+}
+
+/// Pack `residuals` in runs to `out`. Returns the packed size, or `None` if it doesn't fit.
+fn pack(residuals: &[u16], out: &mut [u8]) -> Option<usize> {
+    let mut pos = 0;
+    let mut i = 0;
+    while i < residuals.len() {
+        let rest = &residuals[i..];
+        let run = |limit: usize, pred: &dyn Fn(u16) -> bool| {
+            rest.iter().take(limit).take_while(|&&r| pred(r)).count()
+        };
+
+        let zeros = run(MAX_ZEROS, &|r| r == 0);
+        if zeros > 0 {
+            *out.get_mut(pos)? = (zeros - 1) as u8;
+            pos += 1;
+            i += zeros;
+            continue;
+        }
+
+        // Small residuals, or wide ones until the next small or zero one
+        let (tag, count, len) = match run(MAX_LITERALS, &|r| r != 0 && to_small(r).is_some()) {
+            0 => (WIDE, run(MAX_LITERALS, &|r| to_small(r).is_none()), 2),
+            count => (SMALL, count, 1),
+        };
+        let run_out = out.get_mut(pos..pos + 1 + count * len)?;
+        run_out[0] = tag | (count - 1) as u8;
+        for (bytes, &r) in run_out[1..].chunks_mut(len).zip(rest) {
+            match to_small(r) {
+                Some(small) if len == 1 => bytes[0] = small,
+                _ => bytes.copy_from_slice(&r.to_le_bytes()),
+            }
+        }
+        pos += run_out.len();
+        i += count;
+    }
+    Some(pos)
+}
+
+/// Unpack the runs of exactly `residuals.len()` residuals from `data`.
+fn unpack(data: &mut impl Iterator<Item = u8>, residuals: &mut [u16]) -> Result<(), Error> {
+    let mut i = 0;
+    while i < residuals.len() {
+        let tag = next(data)?;
+        let count = match tag {
+            _ if tag >= WIDE => (tag - WIDE) as usize + 1,
+            _ if tag >= SMALL => (tag - SMALL) as usize + 1,
+            _ => tag as usize + 1,
+        };
+        let run = residuals.get_mut(i..i + count).ok_or(Error::Corrupt)?;
+        for r in run {
+            *r = match tag {
+                _ if tag >= WIDE => u16::from_le_bytes([next(data)?, next(data)?]),
+                _ if tag >= SMALL => from_small(next(data)?),
+                _ => 0,
+            };
+        }
+        i += count;
+    }
+    Ok(())
+}
+
+/// Residual of a pixel and its prediction, the wrapping difference of each color.
+fn residual(pixel: u16, predicted: u16) -> u16 {
+    CHANNELS.iter().fold(0, |residual, &(shift, bits)| {
+        let diff = (pixel >> shift).wrapping_sub(predicted >> shift);
+        residual | (diff & ((1 << bits) - 1)) << shift
+    })
+}
+
+/// Pixel given its prediction and residual.
+fn apply(predicted: u16, residual: u16) -> u16 {
+    CHANNELS.iter().fold(0, |pixel, &(shift, bits)| {
+        let sum = (predicted >> shift).wrapping_add(residual >> shift);
+        pixel | (sum & ((1 << bits) - 1)) << shift
+    })
+}
+
+/// A residual as a byte of small differences of each color, if they fit.
+fn to_small(residual: u16) -> Option<u8> {
+    let mut small = 0;
+    for (&(shift, bits), &small_bits) in CHANNELS.iter().zip(&SMALL_BITS) {
+        // Sign extend the difference of the color
+        let diff = ((residual >> shift) << (16 - bits)) as i16 >> (16 - bits);
+        let limit = 1 << (small_bits - 1);
+        if !(-limit..limit).contains(&diff) {
+            return None;
+        }
+        small = small << small_bits | (diff as u16 & ((1 << small_bits) - 1));
+    }
+    Some(small as u8)
+}
+
+/// Residual of a byte of small differences.
+fn from_small(small: u8) -> u16 {
+    let mut small = small as u16;
+    let mut residual = 0;
+    for (&(shift, bits), &small_bits) in CHANNELS.iter().zip(&SMALL_BITS).rev() {
+        let diff = (small << (16 - small_bits)) as i16 >> (16 - small_bits);
+        residual |= (diff as u16 & ((1 << bits) - 1)) << shift;
+        small >>= small_bits;
+    }
+    residual
+}
+
+fn next(data: &mut impl Iterator<Item = u8>) -> Result<u8, Error> {
+    data.next().ok_or(Error::Corrupt)
+}
+
+/// Pixel at `x`, `y` of a frame `width` pixels wide.
+fn pixel(pixels: &[u8], width: usize, x: usize, y: usize) -> u16 {
+    let i = (y * width + x) * 2;
+    u16::from_le_bytes([pixels[i], pixels[i + 1]])
+}
+
+fn set_pixel(pixels: &mut [u8], width: usize, x: usize, y: usize, value: u16) {
+    let i = (y * width + x) * 2;
+    pixels[i..i + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Spatial prediction of a pixel, the pixel to its left or in column 0 the one above.
+fn predict(pixels: &[u8], width: usize, x: usize, y: usize) -> u16 {
+    match (x, y) {
+        (0, 0) => 0,
+        (0, _) => pixel(pixels, width, 0, y - 1),
+        _ => pixel(pixels, width, x - 1, y),
+    }
+}
+
+/// The tiles of a frame, in coding order.
+fn tiles(width: usize, height: usize) -> impl Iterator<Item = Tile> {
+    (0..height).step_by(TILE).flat_map(move |y| {
+        (0..width).step_by(TILE).map(move |x| Tile {
+            x,
+            y,
+            width: TILE.min(width - x),
+            height: TILE.min(height - y),
+        })
+    })
+}
+
+/// Position and size of a tile in pixels.
+#[derive(Clone, Copy)]
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Tile {
+    fn len(self) -> usize {
+        self.width * self.height
+    }
+
+    /// Coordinates of the pixels of the tile, row by row.
+    fn pixels(self) -> impl Iterator<Item = (usize, usize)> {
+        (self.y..self.y + self.height)
+            .flat_map(move |y| (self.x..self.x + self.width).map(move |x| (x, y)))
+    }
+
+    /// Number of pixels with a nonzero `residual`.
+    fn count(self, residual: impl Fn(usize, usize) -> u16) -> usize {
+        self.pixels().filter(|&(x, y)| residual(x, y) != 0).count()
+    }
+
+    /// Fill the first residuals with `residual` of each pixel. Returns the number of nonzero
+    /// residuals.
+    fn fill(self, residuals: &mut [u16], residual: impl Fn(usize, usize) -> u16) -> usize {
+        let mut nonzero = 0;
+        for ((x, y), r) in self.pixels().zip(residuals.iter_mut()) {
+            *r = residual(x, y);
+            nonzero += (*r != 0) as usize;
+        }
+        nonzero
+    }
+}
@@ -0,0 +1,196 @@
+//! Parser for the text commands received over the RTT down channel. One command per line, words
+//! separated by whitespace, numbers in decimal or hexadecimal with a `0x` prefix:
+//!
+//! ```text
+//! status                  Print the dashcam state
+//! list                    List the saved clips
+//! save                    Save the buffered video now
+//! replay <clip>           Replay a saved clip on the display
+//! erase <clip>            Erase a saved clip
+//! lock <clip>             Protect a saved clip from being overwritten
+//! unlock <clip>           Allow a saved clip to be overwritten again
+//! set time <unix time>    Set the wall clock time in seconds
+//! set <setting> <value>   Change a setting, see `Setting`
+//! ```
+//!
+//! The parser does not depend on any hardware, so it can be tested on the host.
+
+/// Maximum length of a command line, longer lines are rejected.
+pub const MAX_LINE_LEN: usize = 64;
+
+/// A command received from the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    Status,
+    ListClips,
+    SaveNow,
+    ReplayClip(u32),
+    EraseClip(u32),
+    LockClip(u32),
+    UnlockClip(u32),
+    SetTime(u32),
+    SetConfig(Setting, u32),
+}
+
+/// A setting which can be changed with `set`, named like the config fields.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Setting {
+    /// `orientation`: Camera orientation, see `Orientation`.
+    Orientation,
+    /// `fps`: Frame rate in frames per second.
+    FrameRate,
+    /// `parking`: Parking mode interval in milliseconds, 0 to disable.
+    ParkingInterval,
+    /// `motion_threshold`: Motion detection luma threshold, 0 to disable.
+    MotionThreshold,
+    /// `motion_area`: Motion detection area in percent.
+    MotionArea,
+    /// `quality`: JPEG quality of saved clips from 1 to 100, 0 for uncompressed frames.
+    Quality,
+    /// `pre_event`: Recording before a save event in seconds.
+    PreEvent,
+    /// `post_event`: Recording after a save event in seconds.
+    PostEvent,
+    /// `pre_frames`: Recording before a save event in frames.
+    PreEventFrames,
+    /// `post_frames`: Recording after a save event in frames.
+    PostEventFrames,
+}
+
+/// Reasons a command line can't be parsed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ParseError {
+    Empty,
+    TooLong,
+    UnknownCommand,
+    UnknownSetting,
+    MissingArgument,
+    InvalidNumber,
+    TooManyArguments,
+}
+
+/// Assembles received bytes into lines and parses them.
+pub struct LineReader {
+    buf: [u8; MAX_LINE_LEN],
+    len: usize,
+    /// The current line is too long and is skipped until its end.
+    overflow: bool,
+}
+
+impl Default for LineReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LineReader {
+    pub fn new() -> Self {
+        LineReader {
+            buf: [0; MAX_LINE_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the parsed command when a line is complete. Empty lines are
+    /// ignored.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Command, ParseError>> {
+        match byte {
+            b'\r' | b'\n' => {
+                let line = &self.buf[..self.len];
+                let result = match self.overflow {
+                    true => Some(Err(ParseError::TooLong)),
+                    false => match core::str::from_utf8(line).map(parse) {
+                        Ok(Err(ParseError::Empty)) => None,
+                        Ok(result) => Some(result),
+                        Err(_) => Some(Err(ParseError::UnknownCommand)),
+                    },
+                };
+
+                self.len = 0;
+                self.overflow = false;
+                result
+            }
+            _ if self.len == MAX_LINE_LEN => {
+                self.overflow = true;
+                None
+            }
+            _ => {
+                self.buf[self.len] = byte;
+                self.len += 1;
+                None
+            }
+        }
+    }
+}
+
+/// Names of the settings.
+const SETTINGS: [(&str, Setting); 10] = [
+    ("orientation", Setting::Orientation),
+    ("fps", Setting::FrameRate),
+    ("parking", Setting::ParkingInterval),
+    ("motion_threshold", Setting::MotionThreshold),
+    ("motion_area", Setting::MotionArea),
+    ("quality", Setting::Quality),
+    ("pre_event", Setting::PreEvent),
+    ("post_event", Setting::PostEvent),
+    ("pre_frames", Setting::PreEventFrames),
+    ("post_frames", Setting::PostEventFrames),
+];
+
+/// Parse a single command line.
+pub fn parse(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+
+    let name = words.next().ok_or(ParseError::Empty)?;
+    let is = |command: &str| name.eq_ignore_ascii_case(command);
+
+    let command = if is("status") {
+        Command::Status
+    } else if is("list") {
+        Command::ListClips
+    } else if is("save") {
+        Command::SaveNow
+    } else if is("replay") {
+        Command::ReplayClip(number(words.next())?)
+    } else if is("erase") {
+        Command::EraseClip(number(words.next())?)
+    } else if is("lock") {
+        Command::LockClip(number(words.next())?)
+    } else if is("unlock") {
+        Command::UnlockClip(number(words.next())?)
+    } else if is("set") {
+        let setting = words.next().ok_or(ParseError::MissingArgument)?;
+        let value = number(words.next())?;
+        match setting.eq_ignore_ascii_case("time") {
+            true => Command::SetTime(value),
+            false => Command::SetConfig(find_setting(setting)?, value),
+        }
+    } else {
+        return Err(ParseError::UnknownCommand);
+    };
+
+    match words.next() {
+        Some(_) => Err(ParseError::TooManyArguments),
+        None => Ok(command),
+    }
+}
+
+fn find_setting(name: &str) -> Result<Setting, ParseError> {
+    SETTINGS
+        .iter()
+        .find(|(setting, _)| setting.eq_ignore_ascii_case(name))
+        .map(|(_, setting)| *setting)
+        .ok_or(ParseError::UnknownSetting)
+}
+
+/// Parse a decimal or hexadecimal number argument.
+fn number(word: Option<&str>) -> Result<u32, ParseError> {
+    let word = word.ok_or(ParseError::MissingArgument)?;
+    let result = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+
+    result.map_err(|_| ParseError::InvalidNumber)
+}
@@ -0,0 +1,149 @@
+//! Persistent dashcam settings, stored as a small checksummed record in non-volatile memory.
+
+use crate::{
+    motion::MotionSettings,
+    ov9655::{FrameRate, Orientation},
+};
+
+/// Number of 32-bit words in a serialized `Config` record. Keeps the record DMA friendly.
+pub const CONFIG_WORDS: usize = 11;
+
+/// Marks the start of a valid config record ("DCFG" in ASCII).
+const CONFIG_MAGIC: u32 = 0x4443_4647;
+
+/// Record layout version. Records with a different version are ignored and defaults are used.
+/// Bump it whenever the meaning of a word changes, even if the record size stays the same.
+const CONFIG_VERSION: u32 = 4;
+
+/// User settings that survive a power cycle.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Config {
+    /// How the camera is mounted in the vehicle.
+    pub orientation: Orientation,
+    /// Target frame rate for recording.
+    pub frame_rate: FrameRate,
+    /// Parking mode: Time between single frame captures in milliseconds, 0 to disable.
+    pub parking_interval_ms: u32,
+    /// Motion detection settings, triggers a save when motion is detected.
+    pub motion: MotionSettings,
+    /// JPEG quality of saved clips from 1 to 100, 0 to save uncompressed frames.
+    pub quality: u32,
+    /// Recording before a save event which goes in the clip.
+    pub pre_event: Window,
+    /// Recording after a save event which goes in the clip, the clip is saved once it is
+    /// recorded.
+    pub post_event: Window,
+}
+
+/// Length of the recording before or after an event.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Window {
+    Seconds(u32),
+    Frames(u32),
+}
+
+/// Marks a `Window` in frames in its serialized form.
+const WINDOW_FRAMES: u32 = 1 << 31;
+
+impl Window {
+    fn to_u32(self) -> u32 {
+        match self {
+            Window::Seconds(seconds) => seconds & !WINDOW_FRAMES,
+            Window::Frames(frames) => frames | WINDOW_FRAMES,
+        }
+    }
+
+    fn from_u32(value: u32) -> Self {
+        match value & WINDOW_FRAMES {
+            0 => Window::Seconds(value),
+            _ => Window::Frames(value & !WINDOW_FRAMES),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            orientation: Orientation::Normal,
+            frame_rate: FrameRate::Fps30,
+            parking_interval_ms: 0,
+            motion: MotionSettings {
+                threshold: 0,
+                area_pct: 10,
+            },
+            quality: 75,
+            pre_event: Window::Seconds(30),
+            post_event: Window::Seconds(10),
+        }
+    }
+}
+
+impl Config {
+    /// Time between recorded frames in milliseconds: The frame period, or the parking interval in
+    /// parking mode.
+    pub fn period_ms(&self) -> u32 {
+        match self.parking_interval_ms {
+            0 => self.frame_rate.period_ms(),
+            interval => interval,
+        }
+    }
+
+    /// Number of frames recorded in `window`, at the frame rate or one every parking interval
+    /// in parking mode.
+    pub fn frames(&self, window: Window) -> u32 {
+        match (window, self.parking_interval_ms) {
+            (Window::Frames(frames), _) => frames,
+            (Window::Seconds(seconds), 0) => seconds.saturating_mul(self.frame_rate as u32),
+            (Window::Seconds(seconds), interval) => {
+                (seconds as u64 * 1000 / interval as u64).min(u32::MAX as u64) as u32
+            }
+        }
+    }
+
+    /// Serialize the settings as `[magic, version, fields..., checksum]`.
+    pub fn to_words(&self) -> [u32; CONFIG_WORDS] {
+        let mut words = [0; CONFIG_WORDS];
+        words[0] = CONFIG_MAGIC;
+        words[1] = CONFIG_VERSION;
+        words[2] = self.orientation as u32;
+        words[3] = self.frame_rate as u32;
+        words[4] = self.parking_interval_ms;
+        words[5] = self.motion.threshold;
+        words[6] = self.motion.area_pct;
+        words[7] = self.quality;
+        words[8] = self.pre_event.to_u32();
+        words[9] = self.post_event.to_u32();
+        words[CONFIG_WORDS - 1] = checksum(&words[..CONFIG_WORDS - 1]);
+        words
+    }
+
+    /// Deserialize the settings. Returns `None` for erased, corrupt or outdated records.
+    pub fn from_words(words: &[u32; CONFIG_WORDS]) -> Option<Self> {
+        if words[0] != CONFIG_MAGIC
+            || words[1] != CONFIG_VERSION
+            || words[CONFIG_WORDS - 1] != checksum(&words[..CONFIG_WORDS - 1])
+        {
+            return None;
+        }
+
+        Some(Config {
+            orientation: Orientation::from_u32(words[2])?,
+            frame_rate: FrameRate::from_u32(words[3])?,
+            parking_interval_ms: words[4],
+            motion: MotionSettings {
+                threshold: words[5],
+                area_pct: words[6],
+            },
+            quality: words[7],
+            pre_event: Window::from_u32(words[8]),
+            post_event: Window::from_u32(words[9]),
+        })
+    }
+}
+
+/// Simple rotate-and-xor checksum, good enough to catch erased or half-written records.
+fn checksum(words: &[u32]) -> u32 {
+    words
+        .iter()
+        .fold(0xFFFF_FFFF, |acc, word| acc.rotate_left(5) ^ word)
+}
@@ -0,0 +1,303 @@
+//! A persistent crash log in the STM32F7 backup SRAM. The panic and HardFault handlers write a
+//! compact crash record, which survives the following reset and is reported on the next boot.
+//! The log is a ring of the last `NUM_RECORDS` crashes.
+
+use crate::{timer, util::ByteWriter};
+use core::{fmt::Write, mem::size_of, panic::PanicInfo, ptr};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use stm32f7xx_hal::pac::{PWR, RCC};
+
+/// Backup SRAM base address, 4 KB in size.
+const BKPSRAM_ADDR: u32 = 0x4002_4000;
+
+/// Number of crash records kept in the ring.
+pub const NUM_RECORDS: usize = 8;
+
+/// Maximum length of the source file name, longer names keep their end.
+const FILE_LEN: usize = 48;
+
+/// Maximum length of the panic message, longer messages are truncated.
+const MESSAGE_LEN: usize = 80;
+
+/// Magic number and layout version of the crash log.
+const LOG_MAGIC: u32 = 0x4352_5348;
+const LOG_VERSION: u32 = 1;
+
+/// Magic number marking a written crash record.
+const RECORD_MAGIC: u32 = 0x5245_4344;
+
+/// What caused the crash.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CrashKind {
+    Panic = 1,
+    HardFault = 2,
+}
+
+/// A single crash record.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    /// Time since boot in milliseconds.
+    pub uptime_ms: u32,
+    /// Program counter and link register at the time of the crash.
+    pub pc: u32,
+    pub lr: u32,
+    /// Configurable, HardFault, MemManage address and BusFault address fault status registers.
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    /// Source line of the panic, zero for faults.
+    pub line: u32,
+    file: [u8; FILE_LEN],
+    message: [u8; MESSAGE_LEN],
+    checksum: u32,
+}
+
+/// Layout of the crash log in backup SRAM.
+#[repr(C)]
+struct CrashLog {
+    magic: u32,
+    version: u32,
+    /// Total number of crashes logged, the next record is written at `count % NUM_RECORDS`.
+    count: u32,
+    /// Value of `count` when the log was last reported.
+    reported: u32,
+    records: [CrashRecord; NUM_RECORDS],
+}
+
+impl CrashRecord {
+    /// Create a record with all fault status registers captured.
+    fn new(kind: CrashKind, pc: u32, lr: u32) -> Self {
+        let scb_regs = unsafe { &(*SCB::ptr()) };
+
+        let mut record = CrashRecord {
+            magic: RECORD_MAGIC,
+            kind: kind as u32,
+            uptime_ms: timer::uptime_ms(),
+            pc,
+            lr,
+            cfsr: scb_regs.cfsr.read(),
+            hfsr: scb_regs.hfsr.read(),
+            mmfar: scb_regs.mmfar.read(),
+            bfar: scb_regs.bfar.read(),
+            line: 0,
+            file: [0; FILE_LEN],
+            message: [0; MESSAGE_LEN],
+            checksum: 0,
+        };
+
+        record.checksum = record.compute_checksum();
+        record
+    }
+
+    /// Get the kind of crash.
+    pub fn kind(&self) -> Option<CrashKind> {
+        match self.kind {
+            1 => Some(CrashKind::Panic),
+            2 => Some(CrashKind::HardFault),
+            _ => None,
+        }
+    }
+
+    /// Get the source file of the panic.
+    pub fn file(&self) -> &str {
+        as_str(&self.file)
+    }
+
+    /// Get the panic message.
+    pub fn message(&self) -> &str {
+        as_str(&self.message)
+    }
+
+    /// Check that the record was completely written.
+    fn is_valid(&self) -> bool {
+        self.magic == RECORD_MAGIC && self.checksum == self.compute_checksum()
+    }
+
+    /// Compute the checksum over all fields but the checksum itself.
+    fn compute_checksum(&self) -> u32 {
+        let words = unsafe {
+            core::slice::from_raw_parts(
+                self as *const Self as *const u32,
+                size_of::<Self>() / 4 - 1,
+            )
+        };
+
+        words
+            .iter()
+            .fold(0xFFFF_FFFF, |acc, word| acc.rotate_left(5) ^ word)
+    }
+}
+
+/// Enable the backup SRAM and clear the crash log if it holds no valid log, e.g. after power on.
+pub fn init() {
+    let pwr_regs = unsafe { &(*PWR::ptr()) };
+    let log = get_log();
+
+    // Enable the backup regulator, to retain the log in VBAT mode
+    pwr_regs.csr1.modify(|_, w| w.bre().set_bit());
+    while pwr_regs.csr1.read().brr().bit_is_clear() {}
+
+    let magic = unsafe { ptr::read_volatile(&log.magic) };
+    let version = unsafe { ptr::read_volatile(&log.version) };
+    if magic != LOG_MAGIC || version != LOG_VERSION {
+        unsafe {
+            ptr::write_volatile(&mut log.count, 0);
+            ptr::write_volatile(&mut log.reported, 0);
+            for record in log.records.iter_mut() {
+                ptr::write_volatile(&mut record.magic, 0);
+            }
+            ptr::write_volatile(&mut log.version, LOG_VERSION);
+            ptr::write_volatile(&mut log.magic, LOG_MAGIC);
+        }
+    }
+}
+
+/// Log the crashes logged since the last report. Returns the number of new crashes.
+pub fn report() -> u32 {
+    let log = get_log();
+
+    let count = unsafe { ptr::read_volatile(&log.count) };
+    let reported = unsafe { ptr::read_volatile(&log.reported) };
+    let new = count.wrapping_sub(reported);
+
+    // Older crashes were overwritten by the ring
+    for i in (0..new.min(NUM_RECORDS as u32)).rev() {
+        let number = count - i;
+        let record = match get(i as usize) {
+            Some(record) => record,
+            None => {
+                warn!("Crash #{}: Record is corrupted", number);
+                continue;
+            }
+        };
+
+        match record.kind() {
+            Some(CrashKind::Panic) => warn!(
+                "Crash #{}: Panic after {} ms at {}:{}: {}",
+                number,
+                record.uptime_ms,
+                record.file(),
+                record.line,
+                record.message()
+            ),
+            _ => warn!("Crash #{}: HardFault after {} ms", number, record.uptime_ms),
+        };
+        warn!(
+            "Crash #{}: PC {:#010X}, LR {:#010X}, CFSR {:#010X}, HFSR {:#010X}, MMFAR {:#010X}, BFAR {:#010X}",
+            number,
+            record.pc,
+            record.lr,
+            record.cfsr,
+            record.hfsr,
+            record.mmfar,
+            record.bfar
+        );
+    }
+
+    unsafe { ptr::write_volatile(&mut log.reported, count) };
+
+    new
+}
+
+/// Get the number of crashes logged since the log was created.
+pub fn count() -> u32 {
+    unsafe { ptr::read_volatile(&get_log().count) }
+}
+
+/// Get a crash record, where `index` zero is the most recent crash.
+pub fn get(index: usize) -> Option<CrashRecord> {
+    let log = get_log();
+
+    let count = unsafe { ptr::read_volatile(&log.count) } as usize;
+    if index >= count.min(NUM_RECORDS) {
+        return None;
+    }
+
+    let slot = (count - 1 - index) % NUM_RECORDS;
+    let record = unsafe { ptr::read_volatile(&log.records[slot]) };
+
+    match record.is_valid() {
+        true => Some(record),
+        false => None,
+    }
+}
+
+/// Log a panic. Only call from the panic handler.
+pub fn log_panic(info: &PanicInfo) {
+    let pc = cortex_m::register::pc::read();
+    let lr = cortex_m::register::lr::read();
+
+    let mut record = CrashRecord::new(CrashKind::Panic, pc, lr);
+
+    if let Some(location) = info.location() {
+        // Keep the end of long paths, it is the interesting part
+        let file = location.file();
+        let start = file
+            .char_indices()
+            .map(|(i, _)| i)
+            .find(|i| file.len() - i < FILE_LEN)
+            .unwrap_or(file.len());
+
+        ByteWriter::new(&mut record.file)
+            .write_str(&file[start..])
+            .ok();
+        record.line = location.line();
+    }
+
+    write!(ByteWriter::new(&mut record.message), "{}", info).ok();
+
+    record.checksum = record.compute_checksum();
+    store(&record);
+}
+
+/// Log a HardFault and reset the device.
+#[exception]
+fn HardFault(ef: &ExceptionFrame) -> ! {
+    store(&CrashRecord::new(CrashKind::HardFault, ef.pc, ef.lr));
+
+    error!("HardFault at PC {:#010X}, LR {:#010X}!", ef.pc, ef.lr);
+
+    SCB::sys_reset();
+}
+
+/// Write a crash record to the next slot of the ring.
+fn store(record: &CrashRecord) {
+    // Make sure a log exists, the crash may have happened before `init`
+    if unsafe { ptr::read_volatile(&get_log().magic) } != LOG_MAGIC {
+        init();
+    }
+
+    let log = get_log();
+
+    unsafe {
+        let count = ptr::read_volatile(&log.count);
+        ptr::write_volatile(&mut log.records[count as usize % NUM_RECORDS], *record);
+        ptr::write_volatile(&mut log.count, count.wrapping_add(1));
+    }
+}
+
+/// Enable access to the backup SRAM and return the crash log located in it.
+fn get_log() -> &'static mut CrashLog {
+    let rcc_regs = unsafe { &(*RCC::ptr()) };
+    let pwr_regs = unsafe { &(*PWR::ptr()) };
+
+    // Enable the power interface clock and disable backup domain write protection
+    rcc_regs.apb1enr.modify(|_, w| w.pwren().set_bit());
+    pwr_regs.cr1.modify(|_, w| w.dbp().set_bit());
+
+    // Enable the backup SRAM clock
+    rcc_regs.ahb1enr.modify(|_, w| w.bkpsramen().set_bit());
+
+    unsafe { &mut *(BKPSRAM_ADDR as *mut CrashLog) }
+}
+
+/// Convert a zero terminated byte buffer to a string.
+fn as_str(buf: &[u8]) -> &str {
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    core::str::from_utf8(&buf[..len]).unwrap_or("?")
+}
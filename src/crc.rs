@@ -0,0 +1,63 @@
+//! CRC-32 as used by Ethernet, zlib and PNG (reflected polynomial 0xEDB88320), with a small table
+//! processing 4 bits at a time.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+const TABLE: [u32; 16] = [
+    0x0000_0000,
+    0x1DB7_1064,
+    0x3B6E_20C8,
+    0x26D9_30AC,
+    0x76DC_4190,
+    0x6B6B_51F4,
+    0x4DB2_6158,
+    0x5005_713C,
+    0xEDB8_8320,
+    0xF00F_9344,
+    0xD6D6_A3E8,
+    0xCB61_B38C,
+    0x9B64_C2B0,
+    0x86D3_D2D4,
+    0xA00A_E278,
+    0xBDBD_F21C,
+];
+
+/// Incremental CRC-32 calculation.
+#[derive(Clone, Copy, Debug)]
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Crc32 { state: !0 }
+    }
+
+    /// Add `bytes` to the calculation.
+    pub fn update(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let mut crc = self.state ^ byte as u32;
+            crc = (crc >> 4) ^ TABLE[(crc & 0xF) as usize];
+            crc = (crc >> 4) ^ TABLE[(crc & 0xF) as usize];
+            self.state = crc;
+        }
+    }
+
+    /// CRC of the bytes added so far.
+    pub fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// CRC-32 of `bytes`.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
@@ -0,0 +1,46 @@
+//! Conversions of Unix time to calendar dates, as used in FAT directory entries and names.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+/// Date 1980-01-01 in the FAT date format, used when the time is not known or out of range.
+pub const DEFAULT_FAT_DATE: u16 = (1 << 5) | 1;
+
+/// Calendar date in UTC.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Date {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    /// Date of Unix time `unix_time`.
+    pub fn from_unix(unix_time: u32) -> Date {
+        // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+        let z = unix_time / 86400 + 719_468;
+        let era = z / 146_097;
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u32;
+
+        Date { year, month, day }
+    }
+}
+
+/// Convert Unix time to the FAT date and time format, with a resolution of 2 seconds.
+pub fn fat_date_time(unix_time: u32) -> (u16, u16) {
+    let Date { year, month, day } = Date::from_unix(unix_time);
+    let seconds = unix_time % 86400;
+
+    match year {
+        1980..=2107 => (
+            ((year - 1980) << 9 | month << 5 | day) as u16,
+            ((seconds / 3600) << 11 | (seconds / 60 % 60) << 5 | (seconds % 60 / 2)) as u16,
+        ),
+        _ => (DEFAULT_FAT_DATE, 0),
+    }
+}
@@ -0,0 +1,108 @@
+//! Queue of the clips to save for save events, as windows of frame sequence numbers. A clip is
+//! saved once the last frame of its window is recorded, while recording goes on, so several
+//! events can wait for their clips to be saved.
+//!
+//! Events close to each other would save the same frames twice. A window overlapping the newest
+//! one is merged with it instead, or continues after it if that one is being saved already.
+//!
+//! The queue does not depend on any hardware, so it can be tested on the host.
+
+use core::ops::RangeInclusive;
+
+/// Most clips which can wait to be saved.
+pub const MAX_EVENTS: usize = 8;
+
+/// Windows of the clips to save, oldest first.
+pub struct EventQueue {
+    /// First and last sequence number of each window.
+    windows: [(u32, u32); MAX_EVENTS],
+    len: usize,
+    /// The oldest window is being saved, so it can't change anymore.
+    saving: bool,
+}
+
+impl Default for EventQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventQueue {
+    pub fn new() -> Self {
+        EventQueue {
+            windows: [(0, 0); MAX_EVENTS],
+            len: 0,
+            saving: false,
+        }
+    }
+
+    /// Number of clips waiting to be saved, including the one being saved.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The windows, oldest first.
+    pub fn windows(&self) -> impl Iterator<Item = RangeInclusive<u32>> + '_ {
+        self.windows[..self.len]
+            .iter()
+            .map(|&(first, last)| first..=last)
+    }
+
+    /// Add the window `seqs` of a save event, merging it with the newest window if they overlap
+    /// or are next to each other. Returns `false` if the queue is full.
+    pub fn push(&mut self, seqs: RangeInclusive<u32>) -> bool {
+        let (mut first, last) = (*seqs.start(), *seqs.end());
+        if let Some(newest) = self.len.checked_sub(1) {
+            let (start, end) = self.windows[newest];
+            if first <= end.saturating_add(1) {
+                if newest > 0 || !self.saving {
+                    self.windows[newest] = (start.min(first), end.max(last));
+                    return true;
+                }
+
+                // The newest window is being saved, the rest of this one becomes the next clip
+                first = end.saturating_add(1);
+                if first > last || end == u32::MAX {
+                    return true;
+                }
+            }
+        }
+
+        if self.len == MAX_EVENTS {
+            return false;
+        }
+        self.windows[self.len] = (first, last);
+        self.len += 1;
+        true
+    }
+
+    /// The window of the oldest clip, if all of its frames are recorded once frame `newest` is.
+    /// It is being saved from then on, until it is removed with `pop`.
+    pub fn start(&mut self, newest: u32) -> Option<RangeInclusive<u32>> {
+        if self.len == 0 || self.saving || newest < self.windows[0].1 {
+            return None;
+        }
+
+        self.saving = true;
+        let (first, last) = self.windows[0];
+        Some(first..=last)
+    }
+
+    /// Whether the oldest window is being saved.
+    pub fn is_saving(&self) -> bool {
+        self.saving
+    }
+
+    /// Remove the oldest window, when its clip was saved.
+    pub fn pop(&mut self) {
+        if self.len > 0 {
+            self.windows.copy_within(1..self.len, 0);
+            self.len -= 1;
+        }
+        self.saving = false;
+    }
+}
@@ -0,0 +1,691 @@
+//! FAT32 filesystem on a block device, for saving clips as files on an SD card formatted by a
+//! computer. Each clip is written to a new file `CLIPnnnn.CLP` in a directory named after the date
+//! it was saved, for example `20201231/CLIP0001.CLP`.
+//!
+//! Only what is needed for that is supported: 8.3 names, directories in the root directory and
+//! appending to new files. Entries of long file names written by other systems are skipped.
+//! Clusters are allocated one at a time as a file grows, and its directory entry is updated when
+//! it is closed, so a clip interrupted by a power loss ends up as an empty file.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+use crate::block::{BlockStorage, BLOCK_SIZE};
+use crate::date::fat_date_time;
+
+/// Largest clip number in a directory.
+pub const MAX_CLIP_NUMBER: u32 = 9999;
+
+const DIR_ENTRY_LEN: usize = 32;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+
+/// FAT entries are 28 bits, the upper 4 bits are reserved.
+const FAT_MASK: u32 = 0x0FFF_FFFF;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+
+/// FAT entries from this value on mark the end of a chain.
+const MIN_END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+
+/// Cluster numbers start at 2, the first two FAT entries are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+/// The FAT type is decided by the number of clusters alone, FAT32 has at least this many.
+const MIN_CLUSTERS: u32 = 65525;
+
+/// MBR partition types of FAT32, with CHS and LBA addressing.
+const PARTITION_TYPES: [u8; 2] = [0x0B, 0x0C];
+
+const FS_INFO_LEAD_SIG: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIG: u32 = 0x6141_7272;
+
+/// Filesystem errors, with `E` being the error type of the block device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<E> {
+    /// Error of the block device.
+    Device(E),
+    /// No FAT32 filesystem found.
+    NoFilesystem,
+    /// The filesystem is damaged, like a cluster chain leading outside of the volume.
+    Corrupt,
+    /// No free clusters left.
+    Full,
+    /// The directory holds `MAX_CLIP_NUMBER` clips already.
+    TooManyClips,
+}
+
+/// Copy of a directory entry.
+type Entry = [u8; DIR_ENTRY_LEN];
+
+/// Location of a directory entry.
+#[derive(Clone, Copy, Debug)]
+struct EntryPos {
+    lba: u32,
+    offset: usize,
+}
+
+/// A clip file being written. Must be passed to `Volume::close` when done.
+pub struct ClipFile {
+    /// Clip number in its directory.
+    pub number: u32,
+    entry: EntryPos,
+    first_cluster: u32,
+    /// Cluster holding the end of the file.
+    cluster: u32,
+    len: u32,
+    /// Partial block at the end of the file.
+    block: [u8; BLOCK_SIZE],
+}
+
+impl ClipFile {
+    /// Number of bytes written so far.
+    pub fn size(&self) -> u32 {
+        self.len
+    }
+}
+
+/// A mounted FAT32 volume.
+pub struct Volume {
+    blocks_per_cluster: u32,
+    /// First block and size in blocks of the first FAT, the others follow it.
+    fat_start: u32,
+    fat_blocks: u32,
+    num_fats: u32,
+    /// Block of cluster 2.
+    data_start: u32,
+    /// Number of the cluster after the last one.
+    end_cluster: u32,
+    root_cluster: u32,
+    /// Block of the FS information sector, if any.
+    fs_info: Option<u32>,
+    /// Cluster where the search for free clusters starts.
+    next_free: u32,
+    /// Cached FAT block, written back to all FATs when another one is needed.
+    fat: [u8; BLOCK_SIZE],
+    fat_index: Option<u32>,
+    fat_dirty: bool,
+}
+
+impl Volume {
+    /// Find the FAT32 filesystem on `device`, either at its start or in the first FAT32
+    /// partition of the MBR.
+    pub fn mount<D: BlockStorage>(device: &mut D) -> Result<Volume, Error<D::Error>> {
+        let mut buf = [0; BLOCK_SIZE];
+        read(device, 0, &mut buf)?;
+
+        let start = if is_fat32_boot_sector(&buf) {
+            0
+        } else if buf[510..] == [0x55, 0xAA] {
+            let partition = (0..4)
+                .map(|n| &buf[446 + n * 16..462 + n * 16])
+                .find(|entry| PARTITION_TYPES.contains(&entry[4]))
+                .ok_or(Error::NoFilesystem)?;
+            let start = u32_at(partition, 8);
+            read(device, start, &mut buf)?;
+            start
+        } else {
+            return Err(Error::NoFilesystem);
+        };
+
+        if !is_fat32_boot_sector(&buf) {
+            return Err(Error::NoFilesystem);
+        }
+
+        let blocks_per_cluster = buf[13] as u32;
+        let reserved = u16_at(&buf, 14) as u32;
+        let num_fats = buf[16] as u32;
+        let total_blocks = match u16_at(&buf, 19) {
+            0 => u32_at(&buf, 32),
+            blocks => blocks as u32,
+        };
+        let fat_blocks = u32_at(&buf, 36);
+        let root_cluster = u32_at(&buf, 44);
+        let fs_info = match u16_at(&buf, 48) {
+            0 | 0xFFFF => None,
+            block => Some(start + block as u32),
+        };
+
+        let data_start = reserved + num_fats * fat_blocks;
+        let num_clusters = total_blocks.saturating_sub(data_start) / blocks_per_cluster;
+        if num_clusters < MIN_CLUSTERS || start + total_blocks > device.num_blocks() {
+            return Err(Error::NoFilesystem);
+        }
+
+        // Clusters without a FAT entry can't be used
+        let end_cluster = (num_clusters + FIRST_CLUSTER).min(fat_blocks * (BLOCK_SIZE as u32 / 4));
+        if root_cluster < FIRST_CLUSTER || root_cluster >= end_cluster {
+            return Err(Error::Corrupt);
+        }
+
+        let mut volume = Volume {
+            blocks_per_cluster,
+            fat_start: start + reserved,
+            fat_blocks,
+            num_fats,
+            data_start: start + data_start,
+            end_cluster,
+            root_cluster,
+            fs_info,
+            next_free: FIRST_CLUSTER,
+            fat: [0; BLOCK_SIZE],
+            fat_index: None,
+            fat_dirty: false,
+        };
+
+        // Continue the search for free clusters where the last one left off
+        if volume.read_fs_info(device, &mut buf)?.is_some() {
+            let hint = u32_at(&buf, 492);
+            if (FIRST_CLUSTER..end_cluster).contains(&hint) {
+                volume.next_free = hint;
+            }
+        }
+
+        Ok(volume)
+    }
+
+    /// Create a new, empty clip file for a clip saved at Unix time `time`. The file is put in the
+    /// directory of that date, which is created if needed, and gets the next free clip number.
+    pub fn create_clip<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        time: u32,
+    ) -> Result<ClipFile, Error<D::Error>> {
+        let name = dir_name(time);
+        let found = self.find_entry(device, self.root_cluster, |entry| {
+            entry[..11] == name && entry[11] & ATTR_DIRECTORY != 0
+        })?;
+        let dir = match found {
+            Some((_, cluster)) => cluster,
+            None => self.create_dir(device, &name, time)?,
+        };
+
+        // The number follows the highest one in the directory
+        let mut number = 0;
+        self.find_entry(device, dir, |entry| {
+            if let Some(n) = clip_number(entry) {
+                number = number.max(n);
+            }
+            false
+        })?;
+        number += 1;
+        if number > MAX_CLIP_NUMBER {
+            return Err(Error::TooManyClips);
+        }
+
+        let entry = self.free_entry(device, dir)?;
+        let dir_entry = dir_entry(&clip_name(number), ATTR_ARCHIVE, time, 0, 0);
+        self.write_entry(device, entry, &dir_entry)?;
+        self.flush_fat(device)?;
+
+        Ok(ClipFile {
+            number,
+            entry,
+            first_cluster: 0,
+            cluster: 0,
+            len: 0,
+            block: [0; BLOCK_SIZE],
+        })
+    }
+
+    /// Append `data` to `file`. Whole blocks are written straight from `data`.
+    pub fn write<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        file: &mut ClipFile,
+        data: &[u8],
+    ) -> Result<(), Error<D::Error>> {
+        let mut data = data;
+        while !data.is_empty() {
+            let in_block = file.len as usize % BLOCK_SIZE;
+            if in_block == 0 {
+                self.start_block(device, file)?;
+            }
+
+            if in_block == 0 && data.len() >= BLOCK_SIZE {
+                // Whole blocks, up to the end of the cluster
+                let blocks = self.blocks_per_cluster
+                    - file.len / BLOCK_SIZE as u32 % self.blocks_per_cluster;
+                let len = (blocks as usize).min(data.len() / BLOCK_SIZE) * BLOCK_SIZE;
+                write(device, self.file_block(file, file.len), &data[..len])?;
+                file.len += len as u32;
+                data = &data[len..];
+            } else {
+                let len = (BLOCK_SIZE - in_block).min(data.len());
+                file.block[in_block..in_block + len].copy_from_slice(&data[..len]);
+                file.len += len as u32;
+                data = &data[len..];
+
+                if (file.len as usize).is_multiple_of(BLOCK_SIZE) {
+                    let lba = self.file_block(file, file.len - BLOCK_SIZE as u32);
+                    write(device, lba, &file.block)?;
+                    file.block = [0; BLOCK_SIZE];
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finish writing `file`: Write its last partial block, the FAT and its directory entry.
+    pub fn close<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        file: ClipFile,
+    ) -> Result<(), Error<D::Error>> {
+        let in_block = file.len % BLOCK_SIZE as u32;
+        if in_block > 0 {
+            let lba = self.file_block(&file, file.len - in_block);
+            write(device, lba, &file.block)?;
+        }
+        self.flush_fat(device)?;
+
+        let mut buf = [0; BLOCK_SIZE];
+        read(device, file.entry.lba, &mut buf)?;
+        let entry = &mut buf[file.entry.offset..file.entry.offset + DIR_ENTRY_LEN];
+        put_u16(entry, 20, (file.first_cluster >> 16) as u16);
+        put_u16(entry, 26, file.first_cluster as u16);
+        put_u32(entry, 28, file.len);
+        write(device, file.entry.lba, &buf)?;
+
+        // The free cluster count is not tracked, mark it unknown
+        if let Some(block) = self.read_fs_info(device, &mut buf)? {
+            put_u32(&mut buf, 488, 0xFFFF_FFFF);
+            put_u32(&mut buf, 492, self.next_free);
+            write(device, block, &buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the FS information sector to `buf`, returns its block if it is valid.
+    fn read_fs_info<D: BlockStorage>(
+        &self,
+        device: &mut D,
+        buf: &mut [u8; BLOCK_SIZE],
+    ) -> Result<Option<u32>, Error<D::Error>> {
+        let block = match self.fs_info {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        read(device, block, buf)?;
+        match u32_at(buf, 0) == FS_INFO_LEAD_SIG && u32_at(buf, 484) == FS_INFO_STRUCT_SIG {
+            true => Ok(Some(block)),
+            false => Ok(None),
+        }
+    }
+
+    /// Create a directory named `name` in the root directory, returns its cluster.
+    fn create_dir<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        name: &[u8; 11],
+        time: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        let cluster = self.allocate(device, None)?;
+
+        // Empty apart from the entries of itself and the parent, which is 0 for the root
+        let mut buf = [0; BLOCK_SIZE];
+        let lba = self.cluster_block(cluster);
+        for i in 1..self.blocks_per_cluster {
+            write(device, lba + i, &buf)?;
+        }
+        buf[..DIR_ENTRY_LEN].copy_from_slice(&dir_entry(
+            b".          ",
+            ATTR_DIRECTORY,
+            time,
+            cluster,
+            0,
+        ));
+        buf[DIR_ENTRY_LEN..2 * DIR_ENTRY_LEN].copy_from_slice(&dir_entry(
+            b"..         ",
+            ATTR_DIRECTORY,
+            time,
+            0,
+            0,
+        ));
+        write(device, lba, &buf)?;
+        self.flush_fat(device)?;
+
+        let entry = self.free_entry(device, self.root_cluster)?;
+        self.write_entry(
+            device,
+            entry,
+            &dir_entry(name, ATTR_DIRECTORY, time, cluster, 0),
+        )?;
+        Ok(cluster)
+    }
+
+    /// Find the first entry of directory `dir` for which `matches` returns `true`, returns where
+    /// it is and its first cluster. Free entries and those of long names and volume labels are
+    /// skipped.
+    fn find_entry<D, F>(
+        &mut self,
+        device: &mut D,
+        dir: u32,
+        mut matches: F,
+    ) -> Result<Option<(EntryPos, u32)>, Error<D::Error>>
+    where
+        D: BlockStorage,
+        F: FnMut(&[u8]) -> bool,
+    {
+        let found = self.scan_dir(device, dir, |entry| {
+            entry[0] != ENTRY_END
+                && entry[0] != ENTRY_DELETED
+                && entry[11] & ATTR_LONG_NAME != ATTR_LONG_NAME
+                && entry[11] & ATTR_VOLUME_ID == 0
+                && matches(entry)
+        })?;
+
+        Ok(found.map(|(pos, entry)| {
+            let cluster = (u16_at(&entry, 20) as u32) << 16 | u16_at(&entry, 26) as u32;
+            (pos, cluster)
+        }))
+    }
+
+    /// Find a free entry in directory `dir`, adding a cluster to it if it is full.
+    fn free_entry<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        dir: u32,
+    ) -> Result<EntryPos, Error<D::Error>> {
+        let found = self.scan_dir(device, dir, |entry| {
+            entry[0] == ENTRY_END || entry[0] == ENTRY_DELETED
+        })?;
+        if let Some((pos, _)) = found {
+            return Ok(pos);
+        }
+
+        let mut last = dir;
+        while let Some(next) = self.next_cluster(device, last)? {
+            last = next;
+        }
+
+        let cluster = self.allocate(device, Some(last))?;
+        let buf = [0; BLOCK_SIZE];
+        let lba = self.cluster_block(cluster);
+        for i in 0..self.blocks_per_cluster {
+            write(device, lba + i, &buf)?;
+        }
+        self.flush_fat(device)?;
+
+        Ok(EntryPos { lba, offset: 0 })
+    }
+
+    /// Call `visit` for the entries of directory `dir` until it returns `true`, returns where
+    /// that entry is and a copy of it. The scan ends at the entry marking the end of the
+    /// directory, after passing it to `visit`.
+    fn scan_dir<D, F>(
+        &mut self,
+        device: &mut D,
+        dir: u32,
+        mut visit: F,
+    ) -> Result<Option<(EntryPos, Entry)>, Error<D::Error>>
+    where
+        D: BlockStorage,
+        F: FnMut(&[u8]) -> bool,
+    {
+        let mut buf = [0; BLOCK_SIZE];
+        let mut cluster = dir;
+        for _ in FIRST_CLUSTER..self.end_cluster {
+            let start = self.cluster_block(cluster);
+            for lba in start..start + self.blocks_per_cluster {
+                read(device, lba, &mut buf)?;
+                for offset in (0..BLOCK_SIZE).step_by(DIR_ENTRY_LEN) {
+                    let entry = &buf[offset..offset + DIR_ENTRY_LEN];
+                    if visit(entry) {
+                        let mut copy = [0; DIR_ENTRY_LEN];
+                        copy.copy_from_slice(entry);
+                        return Ok(Some((EntryPos { lba, offset }, copy)));
+                    } else if entry[0] == ENTRY_END {
+                        return Ok(None);
+                    }
+                }
+            }
+
+            cluster = match self.next_cluster(device, cluster)? {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+        }
+
+        // The chain is longer than the volume, so it must be a loop
+        Err(Error::Corrupt)
+    }
+
+    fn write_entry<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        pos: EntryPos,
+        entry: &Entry,
+    ) -> Result<(), Error<D::Error>> {
+        let mut buf = [0; BLOCK_SIZE];
+        read(device, pos.lba, &mut buf)?;
+        buf[pos.offset..pos.offset + DIR_ENTRY_LEN].copy_from_slice(entry);
+        write(device, pos.lba, &buf)
+    }
+
+    /// Make sure the block starting at the end of `file` has a cluster.
+    fn start_block<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        file: &mut ClipFile,
+    ) -> Result<(), Error<D::Error>> {
+        if file
+            .len
+            .is_multiple_of(self.blocks_per_cluster * BLOCK_SIZE as u32)
+        {
+            let prev = match file.first_cluster {
+                0 => None,
+                _ => Some(file.cluster),
+            };
+            file.cluster = self.allocate(device, prev)?;
+            if file.first_cluster == 0 {
+                file.first_cluster = file.cluster;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Block holding byte `pos` of `file`, which must be in the last cluster of the file.
+    fn file_block(&self, file: &ClipFile, pos: u32) -> u32 {
+        self.cluster_block(file.cluster) + pos / BLOCK_SIZE as u32 % self.blocks_per_cluster
+    }
+
+    fn cluster_block(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - FIRST_CLUSTER) * self.blocks_per_cluster
+    }
+
+    /// Allocate a free cluster, appending it to the chain ending with `prev` if given.
+    fn allocate<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        prev: Option<u32>,
+    ) -> Result<u32, Error<D::Error>> {
+        let count = self.end_cluster - FIRST_CLUSTER;
+        for i in 0..count {
+            let cluster = FIRST_CLUSTER + (self.next_free - FIRST_CLUSTER + i) % count;
+            if self.fat_entry(device, cluster)? == 0 {
+                self.set_fat_entry(device, cluster, END_OF_CHAIN)?;
+                if let Some(prev) = prev {
+                    self.set_fat_entry(device, prev, cluster)?;
+                }
+                self.next_free = cluster + 1;
+                if self.next_free == self.end_cluster {
+                    self.next_free = FIRST_CLUSTER;
+                }
+                return Ok(cluster);
+            }
+        }
+
+        Err(Error::Full)
+    }
+
+    /// Cluster following `cluster` in its chain, if any.
+    fn next_cluster<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        cluster: u32,
+    ) -> Result<Option<u32>, Error<D::Error>> {
+        match self.fat_entry(device, cluster)? {
+            next if next >= MIN_END_OF_CHAIN => Ok(None),
+            next if (FIRST_CLUSTER..self.end_cluster).contains(&next) => Ok(Some(next)),
+            _ => Err(Error::Corrupt),
+        }
+    }
+
+    fn fat_entry<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        cluster: u32,
+    ) -> Result<u32, Error<D::Error>> {
+        let offset = self.load_fat(device, cluster)?;
+        Ok(u32_at(&self.fat, offset) & FAT_MASK)
+    }
+
+    fn set_fat_entry<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        cluster: u32,
+        value: u32,
+    ) -> Result<(), Error<D::Error>> {
+        let offset = self.load_fat(device, cluster)?;
+        let reserved = u32_at(&self.fat, offset) & !FAT_MASK;
+        put_u32(&mut self.fat, offset, reserved | value);
+        self.fat_dirty = true;
+        Ok(())
+    }
+
+    /// Load the FAT block holding the entry of `cluster`, returns the offset of the entry.
+    fn load_fat<D: BlockStorage>(
+        &mut self,
+        device: &mut D,
+        cluster: u32,
+    ) -> Result<usize, Error<D::Error>> {
+        let index = cluster / (BLOCK_SIZE as u32 / 4);
+        if self.fat_index != Some(index) {
+            self.flush_fat(device)?;
+            read(device, self.fat_start + index, &mut self.fat)?;
+            self.fat_index = Some(index);
+        }
+
+        Ok(cluster as usize * 4 % BLOCK_SIZE)
+    }
+
+    /// Write the cached FAT block to all FATs if it was changed.
+    fn flush_fat<D: BlockStorage>(&mut self, device: &mut D) -> Result<(), Error<D::Error>> {
+        if let (true, Some(index)) = (self.fat_dirty, self.fat_index) {
+            for n in 0..self.num_fats {
+                write(
+                    device,
+                    self.fat_start + n * self.fat_blocks + index,
+                    &self.fat,
+                )?;
+            }
+            self.fat_dirty = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Is `buf` the boot sector of a FAT32 filesystem with 512 byte sectors?
+fn is_fat32_boot_sector(buf: &[u8; BLOCK_SIZE]) -> bool {
+    buf[510..] == [0x55, 0xAA]
+        && u16_at(buf, 11) == BLOCK_SIZE as u16
+        && buf[13].is_power_of_two()
+        && buf[16] > 0
+        && u16_at(buf, 17) == 0
+        && u16_at(buf, 22) == 0
+        && u32_at(buf, 36) > 0
+}
+
+/// 8.3 name of the directory for clips saved at Unix time `time`, like `20201231`.
+fn dir_name(time: u32) -> [u8; 11] {
+    let (date, _) = fat_date_time(time);
+    let mut name = *b"00000000   ";
+    let year = 1980 + (date >> 9) as u32;
+    let month = (date >> 5 & 0xF) as u32;
+    let day = (date & 0x1F) as u32;
+    put_digits(&mut name[0..4], year);
+    put_digits(&mut name[4..6], month);
+    put_digits(&mut name[6..8], day);
+    name
+}
+
+/// 8.3 file name of clip `n`, without the dot.
+fn clip_name(n: u32) -> [u8; 11] {
+    let mut name = *b"CLIP0000CLP";
+    put_digits(&mut name[4..8], n);
+    name
+}
+
+/// Number of the clip with directory entry `entry`, if it is one.
+fn clip_number(entry: &[u8]) -> Option<u32> {
+    if entry[..4] != *b"CLIP" || entry[8..11] != *b"CLP" || entry[11] & ATTR_DIRECTORY != 0 {
+        return None;
+    }
+
+    entry[4..8].iter().try_fold(0, |n, &digit| match digit {
+        b'0'..=b'9' => Some(n * 10 + (digit - b'0') as u32),
+        _ => None,
+    })
+}
+
+/// Write `value` as decimal digits filling `buf`.
+fn put_digits(buf: &mut [u8], value: u32) {
+    let mut value = value;
+    for digit in buf.iter_mut().rev() {
+        *digit = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
+
+fn dir_entry(name: &[u8; 11], attributes: u8, time: u32, cluster: u32, len: u32) -> Entry {
+    let mut entry = [0; DIR_ENTRY_LEN];
+    let (date, time) = fat_date_time(time);
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    put_u16(&mut entry, 14, time);
+    put_u16(&mut entry, 16, date);
+    put_u16(&mut entry, 18, date);
+    put_u16(&mut entry, 20, (cluster >> 16) as u16);
+    put_u16(&mut entry, 22, time);
+    put_u16(&mut entry, 24, date);
+    put_u16(&mut entry, 26, cluster as u16);
+    put_u32(&mut entry, 28, len);
+    entry
+}
+
+fn read<D: BlockStorage>(device: &mut D, lba: u32, buf: &mut [u8]) -> Result<(), Error<D::Error>> {
+    device.read_blocks(lba, buf).map_err(Error::Device)
+}
+
+fn write<D: BlockStorage>(device: &mut D, lba: u32, buf: &[u8]) -> Result<(), Error<D::Error>> {
+    device.write_blocks(lba, buf).map_err(Error::Device)
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
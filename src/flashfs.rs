@@ -0,0 +1,840 @@
+//! Power-safe filesystem for NOR flash like the QSPI flash, keeping clips, the config and logs as
+//! named files.
+//!
+//! The flash is divided into blocks of `BLOCK_SIZE` bytes, the smallest erasable unit. The first
+//! `META_BLOCKS` blocks hold the metadata, the others the file data.
+//!
+//! Like the metadata pairs of littlefs, the metadata is a log of changes in a block: A file was
+//! written, replacing any file of the same name, or a file was removed. Each change is a single
+//! record with a CRC, so a change interrupted by a power loss is ignored when mounting. When the
+//! block is full, the files are written compactly to the next metadata block, with its header and
+//! a higher revision written last. Mounting uses the valid block with the highest revision. The
+//! metadata blocks are used in turn, which spreads their wear.
+//!
+//! File data is written to free blocks only, and becomes part of the file when it is closed, so a
+//! file keeps its previous contents until then. Free blocks are allocated round robin, continuing
+//! after the last allocated block across reboots, so the data blocks wear evenly. A file is a list
+//! of extents, which are runs of consecutive blocks.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+use crate::crc::crc32;
+
+/// Size of a block in bytes, the subsector size of the QSPI flash.
+pub const BLOCK_SIZE: u32 = 4096;
+
+/// Largest supported number of blocks, the 16 MB of the QSPI flash.
+pub const MAX_BLOCKS: u32 = 4096;
+
+/// Number of blocks at the start of the flash holding the metadata.
+pub const META_BLOCKS: u32 = 8;
+
+/// Maximum length of a file name in bytes.
+pub const NAME_LEN: usize = 16;
+
+/// Maximum number of files.
+pub const MAX_FILES: usize = 16;
+
+/// Maximum number of extents of a file.
+pub const MAX_EXTENTS: usize = 21;
+
+/// Size of a metadata record in bytes. The first record of a metadata block is its header.
+const RECORD_SIZE: usize = 128;
+const RECORDS_PER_BLOCK: usize = BLOCK_SIZE as usize / RECORD_SIZE;
+
+/// Offset of the CRC in a record, it covers all bytes before it.
+const CRC_OFFSET: usize = RECORD_SIZE - 4;
+
+/// Marks the header of a metadata block ("DCFS" in ASCII).
+const MAGIC: u32 = 0x4443_4653;
+
+/// Layout version of the metadata.
+const VERSION: u32 = 1;
+
+/// Kinds of records.
+const RECORD_FILE: u32 = 1;
+const RECORD_REMOVE: u32 = 2;
+
+/// Memory device API used by the filesystem and the `NonVolatileMemory` driver.
+pub trait Mem {
+    type Error;
+
+    /// Read `buf.len()` bytes at address `addr` to `buf`.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Write `buf` to address `addr`, which must have been erased.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erase the whole device, such that any section of it can be written.
+    fn erase(&mut self) -> Result<(), Self::Error>;
+
+    /// Erase at least `len` bytes starting at address `addr`, such that they read as 0xFF and can
+    /// be written.
+    fn erase_region(&mut self, addr: u32, len: usize) -> Result<(), Self::Error>;
+}
+
+/// Filesystem errors, with `E` being the error type of the memory device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error<E> {
+    /// Error of the memory device.
+    Device(E),
+    /// No filesystem found.
+    NoFilesystem,
+    /// The metadata is inconsistent, like a block used by two files.
+    Corrupt,
+    /// No free blocks left.
+    Full,
+    /// There are `MAX_FILES` files already.
+    TooManyFiles,
+    /// The free blocks are split up so much that the file needs more than `MAX_EXTENTS` extents.
+    Fragmented,
+    /// There is no file with the name.
+    NotFound,
+    /// The name is empty or longer than `NAME_LEN` bytes.
+    BadName,
+}
+
+/// Information about a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    name: [u8; NAME_LEN],
+    /// Size in bytes.
+    pub size: u32,
+    /// Unix time the file was created, 0 if not known.
+    pub time: u32,
+    /// Flags for the use of the application.
+    pub flags: u32,
+}
+
+impl Metadata {
+    /// Name of the file.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// A run of consecutive blocks.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Extent {
+    start: u16,
+    count: u16,
+}
+
+impl Extent {
+    fn blocks(&self) -> core::ops::Range<u32> {
+        self.start as u32..self.start as u32 + self.count as u32
+    }
+}
+
+/// A file as recorded in the metadata.
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    meta: Metadata,
+    extents: [Extent; MAX_EXTENTS],
+    num_extents: usize,
+}
+
+impl Entry {
+    fn extents(&self) -> &[Extent] {
+        &self.extents[..self.num_extents]
+    }
+
+    fn blocks(&self) -> impl Iterator<Item = u32> + '_ {
+        self.extents().iter().flat_map(|extent| extent.blocks())
+    }
+
+    /// Address of byte `pos` of the file, and the number of bytes from there to the end of its
+    /// extent.
+    fn locate(&self, pos: u32) -> (u32, u32) {
+        let mut index = pos / BLOCK_SIZE;
+        for extent in self.extents() {
+            if index < extent.count as u32 {
+                let block = extent.start as u32 + index;
+                let offset = pos % BLOCK_SIZE;
+                let len = (extent.count as u32 - index) * BLOCK_SIZE - offset;
+                return (block * BLOCK_SIZE + offset, len);
+            }
+            index -= extent.count as u32;
+        }
+
+        (0, 0)
+    }
+
+    /// Block holding the end of the file, which must have at least one block.
+    fn last_block(&self) -> u32 {
+        let last = self.extents[self.num_extents - 1];
+        last.start as u32 + last.count as u32 - 1
+    }
+}
+
+/// A file being written. Must be passed to `FileSystem::close` to make the data part of the
+/// file.
+pub struct Writer {
+    entry: Entry,
+}
+
+impl Writer {
+    /// Size of the file in bytes, including the data written so far.
+    pub fn size(&self) -> u32 {
+        self.entry.meta.size
+    }
+}
+
+/// A mounted filesystem. The memory device is passed to each call, so it can be shared.
+pub struct FileSystem {
+    num_blocks: u32,
+    files: [Option<Entry>; MAX_FILES],
+    /// Metadata block in use, its revision and the next record to write in it.
+    meta_block: u32,
+    revision: u32,
+    record: usize,
+    /// Block where the search for a free block starts.
+    next_block: u32,
+    /// Blocks in use by files, including those being written.
+    used: [u32; MAX_BLOCKS as usize / 32],
+    /// Free blocks which were used since mounting, so they are known to need erasing.
+    dirty: [u32; MAX_BLOCKS as usize / 32],
+}
+
+impl FileSystem {
+    /// Mount the filesystem on the first `size` bytes of `device`. Fails with `NoFilesystem` if
+    /// it was never formatted.
+    pub fn mount<M: Mem>(device: &mut M, size: u32) -> Result<FileSystem, Error<M::Error>> {
+        let mut fs = FileSystem::new(size);
+        let mut buf = [0; RECORD_SIZE];
+
+        // Newest metadata block
+        let mut found = None;
+        for block in 0..META_BLOCKS {
+            read(device, block * BLOCK_SIZE, &mut buf)?;
+            if !record_valid(&buf) || u32_at(&buf, 0) != MAGIC || u32_at(&buf, 4) != VERSION {
+                continue;
+            }
+            let revision = u32_at(&buf, 8);
+            if u32_at(&buf, 12) == fs.num_blocks && found.is_none_or(|(_, r, _)| revision > r) {
+                found = Some((block, revision, u32_at(&buf, 16)));
+            }
+        }
+        let (meta_block, revision, next_block) = found.ok_or(Error::NoFilesystem)?;
+        fs.meta_block = meta_block;
+        fs.revision = revision;
+        fs.next_block = next_block;
+
+        // Apply the changes logged after the header, up to the first free or damaged record
+        let mut damaged = false;
+        fs.record = 1;
+        while fs.record < RECORDS_PER_BLOCK {
+            let addr = meta_block * BLOCK_SIZE + (fs.record * RECORD_SIZE) as u32;
+            read(device, addr, &mut buf)?;
+            if buf.iter().all(|&b| b == 0xFF) {
+                break;
+            } else if !record_valid(&buf) {
+                damaged = true;
+                break;
+            }
+
+            fs.apply(&buf)?;
+            fs.record += 1;
+        }
+
+        // Mark the blocks of the files as used, checking that no two files share one
+        for entry in fs.files.iter().flatten() {
+            for block in entry.blocks() {
+                if block < META_BLOCKS || block >= fs.num_blocks || get_bit(&fs.used, block) {
+                    return Err(Error::Corrupt);
+                }
+                set_bit(&mut fs.used, block, true);
+            }
+        }
+        if fs.next_block < META_BLOCKS || fs.next_block >= fs.num_blocks {
+            fs.next_block = META_BLOCKS;
+        }
+
+        // Records can't be written after a damaged one, start a new metadata block
+        if damaged {
+            fs.compact(device)?;
+        }
+
+        Ok(fs)
+    }
+
+    /// Create an empty filesystem on the first `size` bytes of `device`. Erases the whole
+    /// device, so writing files doesn't need to wait for erases until all blocks were used.
+    pub fn format<M: Mem>(device: &mut M, size: u32) -> Result<FileSystem, Error<M::Error>> {
+        device.erase().map_err(Error::Device)?;
+        for block in 0..META_BLOCKS {
+            if !is_erased(device, block * BLOCK_SIZE, BLOCK_SIZE)? {
+                erase(device, block)?;
+            }
+        }
+
+        // The first metadata block is the one after the last
+        let mut fs = FileSystem::new(size);
+        fs.meta_block = META_BLOCKS - 1;
+        fs.compact(device)?;
+        Ok(fs)
+    }
+
+    fn new(size: u32) -> FileSystem {
+        let num_blocks = size / BLOCK_SIZE;
+        assert!(num_blocks > META_BLOCKS && num_blocks <= MAX_BLOCKS);
+
+        FileSystem {
+            num_blocks,
+            files: [None; MAX_FILES],
+            meta_block: 0,
+            revision: 0,
+            record: RECORDS_PER_BLOCK,
+            next_block: META_BLOCKS,
+            used: [0; MAX_BLOCKS as usize / 32],
+            dirty: [0; MAX_BLOCKS as usize / 32],
+        }
+    }
+
+    /// The files, in no particular order.
+    pub fn files(&self) -> impl Iterator<Item = &Metadata> {
+        self.files.iter().flatten().map(|entry| &entry.meta)
+    }
+
+    /// Information about the file `name`, if there is one.
+    pub fn metadata(&self, name: &str) -> Option<&Metadata> {
+        self.find(name)
+            .map(|index| &self.files[index].as_ref().unwrap().meta)
+    }
+
+    /// Number of bytes which can be written to new files.
+    pub fn free_space(&self) -> u32 {
+        let used: u32 = self.used.iter().map(|word| word.count_ones()).sum();
+        (self.num_blocks - META_BLOCKS - used) * BLOCK_SIZE
+    }
+
+    /// Read up to `buf.len()` bytes of file `name`, starting at byte `offset`. Returns the number
+    /// of bytes read, which is less than requested at the end of the file.
+    pub fn read<M: Mem>(
+        &self,
+        device: &mut M,
+        name: &str,
+        offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, Error<M::Error>> {
+        let index = self.find(name).ok_or(Error::NotFound)?;
+        let entry = self.files[index].as_ref().unwrap();
+        let len = buf
+            .len()
+            .min(entry.meta.size.saturating_sub(offset) as usize);
+
+        let mut done = 0;
+        while done < len {
+            let (addr, run) = entry.locate(offset + done as u32);
+            let part = (run as usize).min(len - done);
+            read(device, addr, &mut buf[done..done + part])?;
+            done += part;
+        }
+
+        Ok(len)
+    }
+
+    /// Start writing a new file `name`. An existing file of that name is replaced when the new
+    /// one is closed.
+    pub fn create<E>(&self, name: &str, time: u32, flags: u32) -> Result<Writer, Error<E>> {
+        let name = encode_name(name).ok_or(Error::BadName)?;
+        if self.find_encoded(&name).is_none() && self.files().count() == MAX_FILES {
+            return Err(Error::TooManyFiles);
+        }
+
+        Ok(Writer {
+            entry: Entry {
+                meta: Metadata {
+                    name,
+                    size: 0,
+                    time,
+                    flags,
+                },
+                extents: [Extent::default(); MAX_EXTENTS],
+                num_extents: 0,
+            },
+        })
+    }
+
+    /// Continue writing the existing file `name` at its end.
+    pub fn append<M: Mem>(
+        &mut self,
+        device: &mut M,
+        name: &str,
+    ) -> Result<Writer, Error<M::Error>> {
+        let index = self.find(name).ok_or(Error::NotFound)?;
+        let mut entry = self.files[index].unwrap();
+
+        // The rest of the last block can be written if a previous append was not closed, for
+        // example due to a power loss. If not, continue with a copy of the block.
+        let offset = entry.meta.size % BLOCK_SIZE;
+        if offset > 0 {
+            let end = entry.last_block() * BLOCK_SIZE + BLOCK_SIZE;
+            let tail = end - (BLOCK_SIZE - offset);
+            if !is_erased(device, tail, BLOCK_SIZE - offset)? {
+                self.copy_last_block(device, &mut entry)?;
+            }
+        }
+
+        Ok(Writer { entry })
+    }
+
+    /// Append `data` to the file of `writer`.
+    pub fn write<M: Mem>(
+        &mut self,
+        device: &mut M,
+        writer: &mut Writer,
+        data: &[u8],
+    ) -> Result<(), Error<M::Error>> {
+        let entry = &mut writer.entry;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = entry.meta.size % BLOCK_SIZE;
+            if offset == 0 {
+                let prev = match entry.num_extents {
+                    0 => None,
+                    _ => Some(entry.last_block()),
+                };
+                let block = self.allocate(device, prev)?;
+                if let Err(e) = add_block(entry, block) {
+                    self.release(block);
+                    return Err(e);
+                }
+            }
+
+            let len = ((BLOCK_SIZE - offset) as usize).min(data.len());
+            write(
+                device,
+                entry.last_block() * BLOCK_SIZE + offset,
+                &data[..len],
+            )?;
+            entry.meta.size += len as u32;
+            data = &data[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Finish writing the file of `writer`, replacing the previous version of the file.
+    pub fn close<M: Mem>(&mut self, device: &mut M, writer: Writer) -> Result<(), Error<M::Error>> {
+        let entry = writer.entry;
+        let index = match self.find_encoded(&entry.meta.name) {
+            Some(index) => index,
+            None => self
+                .files
+                .iter()
+                .position(|file| file.is_none())
+                .ok_or(Error::TooManyFiles)?,
+        };
+
+        let old = self.files[index].replace(entry);
+        let record = self.file_record(&entry);
+        if let Err(e) = self.commit(device, &record) {
+            self.files[index] = old;
+            return Err(e);
+        }
+
+        // Blocks of the previous version which were not kept are free now
+        if let Some(old) = old {
+            for block in old.blocks() {
+                if !entry.extents().iter().any(|e| e.blocks().contains(&block)) {
+                    self.release(block);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stop writing the file of `writer` without closing it. The blocks written are free again,
+    /// the file keeps its previous contents.
+    pub fn discard(&mut self, writer: Writer) {
+        let old = self
+            .find_encoded(&writer.entry.meta.name)
+            .and_then(|index| self.files[index]);
+        for block in writer.entry.blocks() {
+            if !old.is_some_and(|old| old.blocks().any(|b| b == block)) {
+                self.release(block);
+            }
+        }
+    }
+
+    /// Create the file `name` with `data`, replacing an existing file of that name.
+    pub fn write_file<M: Mem>(
+        &mut self,
+        device: &mut M,
+        name: &str,
+        time: u32,
+        data: &[u8],
+    ) -> Result<(), Error<M::Error>> {
+        let mut writer = self.create(name, time, 0)?;
+        self.write(device, &mut writer, data)?;
+        self.close(device, writer)
+    }
+
+    /// Change the flags of the file `name`, keeping its contents.
+    pub fn set_flags<M: Mem>(
+        &mut self,
+        device: &mut M,
+        name: &str,
+        flags: u32,
+    ) -> Result<(), Error<M::Error>> {
+        let index = self.find(name).ok_or(Error::NotFound)?;
+        let old = self.files[index];
+        let mut entry = old.unwrap();
+        entry.meta.flags = flags;
+
+        self.files[index] = Some(entry);
+        let record = self.file_record(&entry);
+        if let Err(e) = self.commit(device, &record) {
+            self.files[index] = old;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Remove the file `name`. Its blocks still need to be erased before they can be written,
+    /// which is done when they are allocated or by `erase_free`.
+    pub fn remove<M: Mem>(&mut self, device: &mut M, name: &str) -> Result<(), Error<M::Error>> {
+        let index = self.find(name).ok_or(Error::NotFound)?;
+        let old = self.files[index].take().unwrap();
+
+        let mut record = [0xFF; RECORD_SIZE];
+        put_u32(&mut record, 0, RECORD_REMOVE);
+        record[4..4 + NAME_LEN].copy_from_slice(&old.meta.name);
+        put_u32(&mut record, 32, self.next_block);
+        if let Err(e) = self.commit(device, &record) {
+            self.files[index] = Some(old);
+            return Err(e);
+        }
+
+        old.blocks().for_each(|block| self.release(block));
+        Ok(())
+    }
+
+    /// Erase the free blocks which were used since mounting, calling `progress` after each
+    /// block. Writing to them later doesn't need to wait for the erase then.
+    pub fn erase_free<M, F>(
+        &mut self,
+        device: &mut M,
+        mut progress: F,
+    ) -> Result<(), Error<M::Error>>
+    where
+        M: Mem,
+        F: FnMut(),
+    {
+        for block in META_BLOCKS..self.num_blocks {
+            if get_bit(&self.dirty, block) && !get_bit(&self.used, block) {
+                erase(device, block)?;
+                set_bit(&mut self.dirty, block, false);
+                progress();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply a logged change while mounting.
+    fn apply<E>(&mut self, record: &[u8; RECORD_SIZE]) -> Result<(), Error<E>> {
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&record[4..4 + NAME_LEN]);
+        let index = self.find_encoded(&name);
+        self.next_block = u32_at(record, 32);
+
+        match u32_at(record, 0) {
+            RECORD_FILE => {
+                let entry = decode_entry(record).ok_or(Error::Corrupt)?;
+                let index = index
+                    .or_else(|| self.files.iter().position(|file| file.is_none()))
+                    .ok_or(Error::Corrupt)?;
+                self.files[index] = Some(entry);
+            }
+            RECORD_REMOVE => {
+                let index = index.ok_or(Error::Corrupt)?;
+                self.files[index] = None;
+            }
+            _ => return Err(Error::Corrupt),
+        }
+
+        Ok(())
+    }
+
+    /// Log a change in the metadata block. If it is full, the files are written to the next
+    /// metadata block instead, which includes the change already.
+    fn commit<M: Mem>(
+        &mut self,
+        device: &mut M,
+        record: &[u8; RECORD_SIZE],
+    ) -> Result<(), Error<M::Error>> {
+        if self.record == RECORDS_PER_BLOCK {
+            return self.compact(device);
+        }
+
+        let mut record = *record;
+        seal(&mut record);
+        let addr = self.meta_block * BLOCK_SIZE + (self.record * RECORD_SIZE) as u32;
+        write(device, addr, &record)?;
+        self.record += 1;
+        Ok(())
+    }
+
+    /// Write all files to the next metadata block, followed by its header with a higher
+    /// revision. Until the header is written, the current block stays the valid one.
+    fn compact<M: Mem>(&mut self, device: &mut M) -> Result<(), Error<M::Error>> {
+        let block = (self.meta_block + 1) % META_BLOCKS;
+        erase(device, block)?;
+
+        let mut record = 1;
+        for entry in self.files.iter().flatten() {
+            let mut buf = self.file_record(entry);
+            seal(&mut buf);
+            write(
+                device,
+                block * BLOCK_SIZE + (record * RECORD_SIZE) as u32,
+                &buf,
+            )?;
+            record += 1;
+        }
+
+        let mut header = [0xFF; RECORD_SIZE];
+        put_u32(&mut header, 0, MAGIC);
+        put_u32(&mut header, 4, VERSION);
+        put_u32(&mut header, 8, self.revision.wrapping_add(1));
+        put_u32(&mut header, 12, self.num_blocks);
+        put_u32(&mut header, 16, self.next_block);
+        seal(&mut header);
+        write(device, block * BLOCK_SIZE, &header)?;
+
+        self.meta_block = block;
+        self.revision = self.revision.wrapping_add(1);
+        self.record = record;
+        Ok(())
+    }
+
+    fn file_record(&self, entry: &Entry) -> [u8; RECORD_SIZE] {
+        let mut record = [0xFF; RECORD_SIZE];
+        put_u32(&mut record, 0, RECORD_FILE);
+        record[4..4 + NAME_LEN].copy_from_slice(&entry.meta.name);
+        put_u32(&mut record, 20, entry.meta.size);
+        put_u32(&mut record, 24, entry.meta.time);
+        put_u32(&mut record, 28, entry.meta.flags);
+        put_u32(&mut record, 32, self.next_block);
+        put_u32(&mut record, 36, entry.num_extents as u32);
+        for (i, extent) in entry.extents().iter().enumerate() {
+            put_u16(&mut record, 40 + i * 4, extent.start);
+            put_u16(&mut record, 42 + i * 4, extent.count);
+        }
+        record
+    }
+
+    /// Allocate a free block, erasing it if needed. The block after `prev` is taken if it is
+    /// free, so a file needs fewer extents, otherwise free blocks are taken round robin.
+    fn allocate<M: Mem>(
+        &mut self,
+        device: &mut M,
+        prev: Option<u32>,
+    ) -> Result<u32, Error<M::Error>> {
+        let count = self.num_blocks - META_BLOCKS;
+        let start = match prev.map(|prev| prev + 1) {
+            Some(next) if next < self.num_blocks && !get_bit(&self.used, next) => next,
+            _ => self.next_block,
+        };
+        let block = (0..count)
+            .map(|i| META_BLOCKS + (start - META_BLOCKS + i) % count)
+            .find(|&block| !get_bit(&self.used, block))
+            .ok_or(Error::Full)?;
+
+        // Blocks which were not used since mounting may still be erased
+        if get_bit(&self.dirty, block) || !is_erased(device, block * BLOCK_SIZE, BLOCK_SIZE)? {
+            erase(device, block)?;
+            set_bit(&mut self.dirty, block, false);
+        }
+
+        set_bit(&mut self.used, block, true);
+        self.next_block = match block + 1 {
+            next if next == self.num_blocks => META_BLOCKS,
+            next => next,
+        };
+        Ok(block)
+    }
+
+    fn release(&mut self, block: u32) {
+        set_bit(&mut self.used, block, false);
+        set_bit(&mut self.dirty, block, true);
+    }
+
+    /// Replace the last block of `entry` with a copy in a new block, up to the end of the file.
+    fn copy_last_block<M: Mem>(
+        &mut self,
+        device: &mut M,
+        entry: &mut Entry,
+    ) -> Result<(), Error<M::Error>> {
+        let old = entry.last_block();
+        let block = self.allocate(device, None)?;
+
+        let mut buf = [0; RECORD_SIZE];
+        let len = entry.meta.size % BLOCK_SIZE;
+        let mut offset = 0;
+        while offset < len {
+            let part = (len - offset).min(RECORD_SIZE as u32);
+            let chunk = &mut buf[..part as usize];
+            read(device, old * BLOCK_SIZE + offset, chunk)?;
+            write(device, block * BLOCK_SIZE + offset, chunk)?;
+            offset += part;
+        }
+
+        // The old block is freed when the file is closed
+        let last = &mut entry.extents[entry.num_extents - 1];
+        last.count -= 1;
+        if last.count == 0 {
+            entry.num_extents -= 1;
+        }
+        if let Err(e) = add_block(entry, block) {
+            self.release(block);
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.find_encoded(&encode_name(name)?)
+    }
+
+    fn find_encoded(&self, name: &[u8; NAME_LEN]) -> Option<usize> {
+        self.files
+            .iter()
+            .position(|file| file.is_some_and(|entry| entry.meta.name == *name))
+    }
+}
+
+/// Add `block` to the end of the file of `entry`.
+fn add_block<E>(entry: &mut Entry, block: u32) -> Result<(), Error<E>> {
+    match entry.extents[..entry.num_extents].last_mut() {
+        Some(last) if last.blocks().end == block => last.count += 1,
+        _ if entry.num_extents < MAX_EXTENTS => {
+            entry.extents[entry.num_extents] = Extent {
+                start: block as u16,
+                count: 1,
+            };
+            entry.num_extents += 1;
+        }
+        _ => return Err(Error::Fragmented),
+    }
+
+    Ok(())
+}
+
+fn decode_entry(record: &[u8; RECORD_SIZE]) -> Option<Entry> {
+    let mut entry = Entry {
+        meta: Metadata {
+            name: [0; NAME_LEN],
+            size: u32_at(record, 20),
+            time: u32_at(record, 24),
+            flags: u32_at(record, 28),
+        },
+        extents: [Extent::default(); MAX_EXTENTS],
+        num_extents: u32_at(record, 36) as usize,
+    };
+    entry.meta.name.copy_from_slice(&record[4..4 + NAME_LEN]);
+    if entry.num_extents > MAX_EXTENTS {
+        return None;
+    }
+
+    for (i, extent) in entry.extents[..entry.num_extents].iter_mut().enumerate() {
+        extent.start = u16_at(record, 40 + i * 4);
+        extent.count = u16_at(record, 42 + i * 4);
+    }
+
+    // The extents must hold the data
+    let blocks: u32 = entry.extents().iter().map(|e| e.count as u32).sum();
+    match blocks == entry.meta.size.div_ceil(BLOCK_SIZE) {
+        true => Some(entry),
+        false => None,
+    }
+}
+
+/// Name padded with zeros, if it is valid.
+fn encode_name(name: &str) -> Option<[u8; NAME_LEN]> {
+    let bytes = name.as_bytes();
+    if bytes.is_empty() || bytes.len() > NAME_LEN || bytes.contains(&0) {
+        return None;
+    }
+
+    let mut encoded = [0; NAME_LEN];
+    encoded[..bytes.len()].copy_from_slice(bytes);
+    Some(encoded)
+}
+
+/// Set the CRC of `record`.
+fn seal(record: &mut [u8; RECORD_SIZE]) {
+    let crc = crc32(&record[..CRC_OFFSET]);
+    put_u32(record, CRC_OFFSET, crc);
+}
+
+fn record_valid(record: &[u8; RECORD_SIZE]) -> bool {
+    crc32(&record[..CRC_OFFSET]) == u32_at(record, CRC_OFFSET)
+}
+
+/// Are the `len` bytes at address `addr` erased?
+fn is_erased<M: Mem>(device: &mut M, addr: u32, len: u32) -> Result<bool, Error<M::Error>> {
+    let mut buf = [0; RECORD_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let part = (len - offset).min(RECORD_SIZE as u32) as usize;
+        read(device, addr + offset, &mut buf[..part])?;
+        if buf[..part].iter().any(|&b| b != 0xFF) {
+            return Ok(false);
+        }
+        offset += part as u32;
+    }
+
+    Ok(true)
+}
+
+fn read<M: Mem>(device: &mut M, addr: u32, buf: &mut [u8]) -> Result<(), Error<M::Error>> {
+    device.read(addr, buf).map_err(Error::Device)
+}
+
+fn write<M: Mem>(device: &mut M, addr: u32, buf: &[u8]) -> Result<(), Error<M::Error>> {
+    device.write(addr, buf).map_err(Error::Device)
+}
+
+fn erase<M: Mem>(device: &mut M, block: u32) -> Result<(), Error<M::Error>> {
+    device
+        .erase_region(block * BLOCK_SIZE, BLOCK_SIZE as usize)
+        .map_err(Error::Device)
+}
+
+fn get_bit(bits: &[u32], n: u32) -> bool {
+    bits[n as usize / 32] & (1 << (n % 32)) != 0
+}
+
+fn set_bit(bits: &mut [u32], n: u32, value: bool) {
+    match value {
+        true => bits[n as usize / 32] |= 1 << (n % 32),
+        false => bits[n as usize / 32] &= !(1 << (n % 32)),
+    }
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -3,6 +3,10 @@
 /// `FrameBuffer` is intialized with a base address, frame size (bytes), and the number frames
 /// that can be stored. The `FrameBuffer` counts the number of frames written and stores them in
 /// SDRAM via the OV9655 DMA address registers, in a circular buffer fashion.
+///
+/// To reduce the frame rate, only one out of every `decimation` frames delivered by the DMA is
+/// kept. Dropped frames are written to the slot of the next frame that will be kept, so they are
+/// simply overwritten and never show up in the frame buffer.
 #[derive(Clone, Debug)]
 pub struct FrameBuffer {
     /// Base address for the frame buffer. This field does not change after calling `new`.
@@ -14,6 +18,15 @@ pub struct FrameBuffer {
     /// Number of frames in the frame buffer. This field does not change after calling `new`.
     num_frames: u32,
 
+    /// Keep one out of this many DMA frames. This field does not change after calling `new`.
+    decimation: u32,
+
+    /// Number of frames delivered by the DMA (calls to `update`) since the last (re)start.
+    num_dma: u32,
+
+    /// Value of `num_caps` at the last (re)start, the index DMA frame 0 is stored at.
+    start_caps: u32,
+
     /// Number of frames currently captured (calls to `update`). Increases forever.
     num_caps: u32,
 
@@ -22,38 +35,97 @@ pub struct FrameBuffer {
 }
 
 impl FrameBuffer {
-    /// Creates a new FrameBuffer object.
-    pub fn new(base: u32, size: u32, fsize: u32) -> Self {
-        crate::ov9655::update_addr0(base);
-        crate::ov9655::update_addr1(base + fsize);
+    /// Creates a new FrameBuffer object. Use a `decimation` of 1 to keep every frame.
+    pub fn new(base: u32, size: u32, fsize: u32, decimation: u32) -> Self {
+        assert!(decimation > 0);
 
-        FrameBuffer {
+        let mut fb = FrameBuffer {
             mem_base: base,
             frame_size: fsize,
             num_frames: size / fsize,
+            decimation,
+            num_dma: 0,
+            start_caps: 0,
             num_caps: 0,
             iter_cnt: 0,
-        }
+        };
+
+        fb.restart();
+        fb
+    }
+
+    /// Restart DMA frame counting and setup both ping-pong DMA addresses. Needed after the
+    /// capture pipeline is reset, since the DMA starts over with address 0. Frames already
+    /// captured are kept.
+    pub fn restart(&mut self) {
+        self.num_dma = 0;
+        self.start_caps = self.num_caps;
+
+        crate::ov9655::update_addr0(self.get_addr(self.get_index(0)));
+        crate::ov9655::update_addr1(self.get_addr(self.get_index(1)));
     }
 
-    /// Update frame buffer, `self.num_caps` completed and `self.num_caps + 1` is now underway.
-    /// Return the current address. Set `reg_update` to false to skip DMA register updates.
-    pub fn update(&mut self, reg_update: bool) -> u32 {
-        // Get current and next addresses
-        let curr_addr = self.get_addr(self.num_caps);
-        let next_addr = self.get_addr(self.num_caps + 2);
+    /// Remove all frames, for a frame buffer which is filled with `update(false)`. The DMA address
+    /// registers are left alone.
+    pub fn clear(&mut self) {
+        self.num_dma = 0;
+        self.start_caps = 0;
+        self.num_caps = 0;
+        self.iter_cnt = 0;
+    }
 
-        // Replace the address in `self.num_caps` with `self.num_caps + 2`
+    /// Update frame buffer, DMA frame `self.num_dma` completed and `self.num_dma + 1` is now
+    /// underway. Return the address of the completed frame, or `None` if it was dropped due to
+    /// decimation. Set `reg_update` to false to skip DMA register updates.
+    pub fn update(&mut self, reg_update: bool) -> Option<u32> {
+        // Replace the address for DMA frame `self.num_dma` with DMA frame `self.num_dma + 2`
         if reg_update {
-            match self.num_caps % 2 {
+            let next_addr = self.get_addr(self.get_index(self.num_dma + 2));
+            match self.num_dma % 2 {
                 0 => crate::ov9655::update_addr0(next_addr),
                 _ => crate::ov9655::update_addr1(next_addr),
             };
         }
 
+        // Only every `self.decimation` DMA frame is kept
+        let keep = self.num_dma % self.decimation == 0;
+        self.num_dma += 1;
+
         // Update and return current address
-        self.num_caps += 1;
-        curr_addr
+        if keep {
+            let curr_addr = self.get_addr(self.num_caps);
+            self.num_caps += 1;
+            Some(curr_addr)
+        } else {
+            None
+        }
+    }
+
+    /// Number of frames in the frame buffer, as returned by iterating over it.
+    pub fn num_buffered(&self) -> u32 {
+        // Usually the buffer will be full, but handle edge case where it is not
+        match self.num_caps < self.num_frames {
+            true => self.num_caps,
+            false => self.num_frames,
+        }
+    }
+
+    /// Number of frames captured since the frame buffer was created, the last address returned by
+    /// `update` is that of frame `num_captured() - 1`.
+    pub fn num_captured(&self) -> u32 {
+        self.num_caps
+    }
+
+    /// Start iterating from the oldest frame again.
+    pub fn rewind(&mut self) -> &mut Self {
+        self.iter_cnt = 0;
+        self
+    }
+
+    /// Convert a DMA frame count to an index in the circular buffer. Frames that will be dropped
+    /// map to the index of the next frame that will be kept.
+    fn get_index(&self, dma_cnt: u32) -> u32 {
+        self.start_caps + (dma_cnt + self.decimation - 1) / self.decimation
     }
 
     /// Convert an index in the circular buffer to an address.
@@ -67,11 +139,7 @@ impl Iterator for FrameBuffer {
     type Item = u32;
 
     fn next(&mut self) -> Option<u32> {
-        // Usually the buffer will be full, but handle edge case where it is not
-        let limit = match self.num_caps < self.num_frames {
-            true => self.num_caps,
-            false => self.num_frames,
-        };
+        let limit = self.num_buffered();
 
         // We will return the next address until the limit, then return None
         if self.iter_cnt < limit {
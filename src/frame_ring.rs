@@ -0,0 +1,263 @@
+//! Ring of variable-size frames, such as JPEG images, which keeps as many of the newest frames as
+//! fit in its memory. Frames of different sizes, resolutions and codecs can share the ring.
+//!
+//! Each frame is stored as a record: A header of `RECORD_HEADER_LEN` bytes with the size of the
+//! frame and its `Meta`, then the frame data padded to a multiple of 4 bytes. Records are written
+//! one after the other. When a record doesn't fit before the end of the memory, a `WRAP` marker
+//! is written instead (if there is room for it) and the record goes to the start. The oldest
+//! records are evicted to make room for new ones, unless they are protected.
+//!
+//! The ring does not depend on any hardware, so it can be tested on the host.
+
+use crate::clip::Codec;
+use core::ops::RangeInclusive;
+
+/// Bytes in front of the data of each record.
+pub const RECORD_HEADER_LEN: usize = 16;
+
+/// Marks the end of the records before the memory end, the next record is at the start.
+const WRAP: u32 = u32::MAX;
+
+/// Description of a frame in the ring.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Meta {
+    /// Sequence number of the frame, which counts the captured frames.
+    pub seq: u32,
+    pub width: u16,
+    pub height: u16,
+    /// How the frame is compressed, the pixel format is always RGB565.
+    pub codec: Codec,
+}
+
+impl Meta {
+    /// Whether frames of `self` and `other` can go in the same clip.
+    pub fn same_format(&self, other: &Meta) -> bool {
+        (self.width, self.height, self.codec) == (other.width, other.height, other.codec)
+    }
+}
+
+/// Ring of frames in `mem`, oldest first.
+pub struct FrameRing<'a> {
+    mem: &'a mut [u8],
+    /// Offset of the oldest record.
+    tail: usize,
+    /// Offset the next record is written to.
+    head: usize,
+    /// Number of records, distinguishes a full ring from an empty one.
+    count: u32,
+    /// Description of the newest frame.
+    newest: Option<Meta>,
+    /// Frames from this sequence number on are not evicted.
+    protect: Option<u32>,
+}
+
+impl<'a> FrameRing<'a> {
+    /// Create an empty ring in `mem`, whose length is rounded down to a multiple of 4 bytes.
+    pub fn new(mem: &'a mut [u8]) -> Self {
+        let len = mem.len() / 4 * 4;
+        FrameRing {
+            mem: &mut mem[..len],
+            tail: 0,
+            head: 0,
+            count: 0,
+            newest: None,
+            protect: None,
+        }
+    }
+
+    /// Number of frames in the ring.
+    pub fn num_frames(&self) -> u32 {
+        self.count
+    }
+
+    /// Largest frame the ring can hold, it is then the only one.
+    pub fn max_frame_len(&self) -> usize {
+        self.mem.len().saturating_sub(RECORD_HEADER_LEN)
+    }
+
+    /// Description of the newest frame, if there is one.
+    pub fn newest(&self) -> Option<Meta> {
+        self.newest
+    }
+
+    /// Keep the frames from sequence number `seq` on, or none with `None`, until they are
+    /// released. New frames are not recorded if they would evict them.
+    pub fn protect(&mut self, seq: Option<u32>) {
+        self.protect = seq;
+    }
+
+    /// Remove all frames.
+    pub fn clear(&mut self) {
+        self.tail = 0;
+        self.head = 0;
+        self.count = 0;
+        self.newest = None;
+    }
+
+    /// Add a frame described by `meta`, evicting the oldest frames as needed. Returns `false` if
+    /// the frame is larger than `max_frame_len`, or if it would evict protected frames.
+    pub fn push(&mut self, meta: Meta, data: &[u8]) -> bool {
+        let len = record_len(data.len());
+        if len > self.mem.len() {
+            return false;
+        }
+
+        let start = loop {
+            if self.count == 0 {
+                self.clear();
+                break 0;
+            }
+
+            if self.head > self.tail {
+                // Free space at the end, and at the start before the oldest record
+                if len <= self.mem.len() - self.head {
+                    break self.head;
+                }
+                if len <= self.tail {
+                    if self.head < self.mem.len() {
+                        self.write_u32(self.head, WRAP);
+                    }
+                    break 0;
+                }
+            } else if self.head < self.tail && len <= self.tail - self.head {
+                // Free space between the newest and the oldest record
+                break self.head;
+            }
+
+            let oldest = read_u32(self.mem, record_start(self.mem, self.tail) + 4);
+            if self.protect.is_some_and(|seq| oldest >= seq) {
+                return false;
+            }
+            self.evict();
+        };
+
+        let header = &mut self.mem[start..start + RECORD_HEADER_LEN];
+        header[..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&meta.seq.to_le_bytes());
+        header[8..10].copy_from_slice(&meta.width.to_le_bytes());
+        header[10..12].copy_from_slice(&meta.height.to_le_bytes());
+        header[12..].copy_from_slice(&[meta.codec as u8, 0, 0, 0]);
+        self.mem[start + RECORD_HEADER_LEN..][..data.len()].copy_from_slice(data);
+        self.head = start + len;
+        self.count += 1;
+        self.newest = Some(meta);
+        true
+    }
+
+    /// Iterate over the frames from the oldest, as the address and size of their data and their
+    /// description.
+    pub fn iter(&self) -> Records<'_> {
+        Records {
+            mem: self.mem,
+            pos: self.tail,
+            remaining: self.count,
+        }
+    }
+
+    /// Iterate over the frames with sequence numbers in `seqs` from the oldest, like `iter`.
+    /// Frames of the window which were not recorded or were evicted already are left out.
+    pub fn window(&self, seqs: RangeInclusive<u32>) -> Records<'_> {
+        let mut records = self.iter();
+        while let Some((_, _, meta)) = records.clone().next() {
+            if meta.seq >= *seqs.start() {
+                break;
+            }
+            records.next();
+        }
+
+        let count = records
+            .clone()
+            .take_while(|(_, _, meta)| meta.seq <= *seqs.end())
+            .count();
+        Records {
+            remaining: count as u32,
+            ..records
+        }
+    }
+
+    /// Iterate over the frames from the oldest, as their description and data.
+    pub fn frames(&self) -> impl ExactSizeIterator<Item = (Meta, &[u8])> {
+        self.iter().with_data()
+    }
+
+    /// Remove the oldest record.
+    fn evict(&mut self) {
+        self.tail = record_start(self.mem, self.tail);
+        let len = read_u32(self.mem, self.tail) as usize;
+        self.tail += record_len(len);
+        self.count -= 1;
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.mem[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Iterator over the frames of a `FrameRing`.
+#[derive(Clone)]
+pub struct Records<'a> {
+    mem: &'a [u8],
+    pos: usize,
+    remaining: u32,
+}
+
+impl<'a> Records<'a> {
+    /// Iterate over the same frames, as their description and data.
+    pub fn with_data(self) -> impl ExactSizeIterator<Item = (Meta, &'a [u8])> {
+        let mem = self.mem;
+        let base = mem.as_ptr() as usize;
+        self.map(move |(address, len, meta)| (meta, &mem[address - base..][..len]))
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = (usize, usize, Meta);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let start = record_start(self.mem, self.pos);
+        let header = &self.mem[start..start + RECORD_HEADER_LEN];
+        let len = read_u32(header, 0) as usize;
+        let meta = Meta {
+            seq: read_u32(header, 4),
+            width: u16::from_le_bytes([header[8], header[9]]),
+            height: u16::from_le_bytes([header[10], header[11]]),
+            codec: Codec::from_u8(header[12]).unwrap_or(Codec::Raw),
+        };
+        let address = self.mem.as_ptr() as usize + start + RECORD_HEADER_LEN;
+        self.pos = start + record_len(len);
+        self.remaining -= 1;
+        Some((address, len, meta))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining as usize, Some(self.remaining as usize))
+    }
+}
+
+impl ExactSizeIterator for Records<'_> {}
+
+/// Size of the record of a frame of `len` bytes.
+fn record_len(len: usize) -> usize {
+    RECORD_HEADER_LEN + len.div_ceil(4) * 4
+}
+
+/// Offset of the record at `offset`, which is at the start after the end or a `WRAP` marker.
+fn record_start(mem: &[u8], offset: usize) -> usize {
+    match offset == mem.len() || read_u32(mem, offset) == WRAP {
+        true => 0,
+        false => offset,
+    }
+}
+
+fn read_u32(mem: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        mem[offset],
+        mem[offset + 1],
+        mem[offset + 2],
+        mem[offset + 3],
+    ])
+}
@@ -0,0 +1,114 @@
+//! Capture health monitoring. Checks that frames keep arriving at the expected rate, counts
+//! missed frames, and decides when the capture pipeline has stalled or failed for good.
+
+/// Shortest time window over which frame arrivals are evaluated, in milliseconds.
+const MIN_WINDOW_MS: u32 = 1000;
+
+/// Number of consecutive stalled windows (each followed by a recovery attempt) before the capture
+/// pipeline is considered failed.
+const MAX_STALLS: u32 = 3;
+
+/// Outcome of a health check.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Health {
+    /// Frames are arriving, or the current window is not over yet.
+    Ok,
+    /// No frames arrived during the last window, the pipeline should be reset.
+    Stalled,
+    /// The pipeline stayed stalled despite resets, only a device reset can help.
+    Failed,
+}
+
+/// Frame drop statistics.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    /// Frames the sensor should have delivered but did not (approximate, see `check`).
+    pub missed: u32,
+    /// Frames captured but not displayed, because the previous one was still being drawn.
+    pub display_drops: u32,
+    /// Frames captured but not recorded, because the previous one was still being compressed.
+    pub record_drops: u32,
+    /// Number of windows without any frames.
+    pub stalls: u32,
+}
+
+/// Capture health monitor, `check` is called periodically from a timer interrupt.
+pub struct CaptureMonitor {
+    /// Expected time between frames in milliseconds.
+    period_ms: u32,
+    /// Length of an evaluation window in milliseconds.
+    window_ms: u32,
+    /// Time elapsed in the current window.
+    elapsed_ms: u32,
+    /// Frame count at the start of the current window.
+    start_frames: u32,
+    /// Number of consecutive stalled windows.
+    num_stalled: u32,
+    /// Statistics, see `get_stats`.
+    stats: FrameStats,
+}
+
+impl CaptureMonitor {
+    /// Create a monitor for frames arriving every `period_ms` milliseconds.
+    pub fn new(period_ms: u32) -> Self {
+        CaptureMonitor {
+            period_ms,
+            window_ms: MIN_WINDOW_MS.max(2 * period_ms),
+            elapsed_ms: 0,
+            start_frames: 0,
+            num_stalled: 0,
+            stats: FrameStats::default(),
+        }
+    }
+
+    /// Count a frame that could not be displayed.
+    pub fn display_drop(&mut self) {
+        self.stats.display_drops += 1;
+    }
+
+    /// Count a frame that could not be recorded.
+    pub fn record_drop(&mut self) {
+        self.stats.record_drops += 1;
+    }
+
+    /// Get the frame drop statistics.
+    pub fn get_stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Start a new window, used when frames are not expected (e.g. capture was stopped).
+    pub fn pause(&mut self, frames: u32) {
+        self.elapsed_ms = 0;
+        self.start_frames = frames;
+        self.num_stalled = 0;
+    }
+
+    /// Call every `tick_ms` milliseconds with the total number of frames delivered so far. When a
+    /// window is over, frames arriving up to one period late are not counted as missed, since the
+    /// sensor and timer clocks are not synchronized.
+    pub fn check(&mut self, tick_ms: u32, frames: u32) -> Health {
+        self.elapsed_ms += tick_ms;
+        if self.elapsed_ms < self.window_ms {
+            return Health::Ok;
+        }
+
+        let received = frames.wrapping_sub(self.start_frames);
+        let expected = self.elapsed_ms / self.period_ms;
+        self.stats.missed += expected.saturating_sub(received + 1);
+        self.elapsed_ms = 0;
+        self.start_frames = frames;
+
+        if received > 0 {
+            self.num_stalled = 0;
+            Health::Ok
+        } else {
+            self.num_stalled += 1;
+            self.stats.stalls += 1;
+            if self.num_stalled > MAX_STALLS {
+                Health::Failed
+            } else {
+                Health::Stalled
+            }
+        }
+    }
+}
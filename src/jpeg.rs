@@ -0,0 +1,824 @@
+//! Baseline JPEG encoder for RGB565 frames, so saved clips take a fraction of the flash.
+//!
+//! Frames are converted to YCbCr with the chroma subsampled 2:1 in both directions (4:2:0) and
+//! coded with the standard Huffman tables of the JPEG specification (Annex K). The output is a
+//! JFIF image any decoder can read. The quality scales the standard quantization tables like
+//! libjpeg does, from 1 (smallest) to 100 (best).
+//!
+//! `decode` reads the images of the encoder back for replay. It only supports what the encoder
+//! writes: Baseline images with three components in 4:2:0 and no restart intervals.
+//!
+//! The encoder and decoder do not allocate or depend on any hardware, so they can be tested on
+//! the host.
+
+/// Zigzag order of the coefficients of a block, as indices into the block in row order.
+const ZIGZAG: [u8; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20,
+    13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22, 15, 23, 30, 37, 44, 51, 58, 59,
+    52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Quantization tables for quality 50, in row order.
+const LUMA_QUANT: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113,
+    92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+const CHROMA_QUANT: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Huffman tables as the number of codes of each length from 1 to 16, followed by the symbols.
+const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+const DC_VALUES: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7D];
+const AC_LUMA_VALUES: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xA1, 0x08, 0x23, 0x42, 0xB1, 0xC1, 0x15, 0x52, 0xD1, 0xF0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0A, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2A, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7,
+    0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3, 0xC4, 0xC5,
+    0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA, 0xE1, 0xE2,
+    0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF1, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+const AC_CHROMA_VALUES: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xA1, 0xB1, 0xC1, 0x09, 0x23, 0x33, 0x52, 0xF0,
+    0x15, 0x62, 0x72, 0xD1, 0x0A, 0x16, 0x24, 0x34, 0xE1, 0x25, 0xF1, 0x17, 0x18, 0x19, 0x1A, 0x26,
+    0x27, 0x28, 0x29, 0x2A, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3A, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4A, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5A, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6A, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7A, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8A, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9A, 0xA2, 0xA3, 0xA4, 0xA5,
+    0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xB2, 0xB3, 0xB4, 0xB5, 0xB6, 0xB7, 0xB8, 0xB9, 0xBA, 0xC2, 0xC3,
+    0xC4, 0xC5, 0xC6, 0xC7, 0xC8, 0xC9, 0xCA, 0xD2, 0xD3, 0xD4, 0xD5, 0xD6, 0xD7, 0xD8, 0xD9, 0xDA,
+    0xE2, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xF2, 0xF3, 0xF4, 0xF5, 0xF6, 0xF7, 0xF8,
+    0xF9, 0xFA,
+];
+
+/// Scale factors of the AAN DCT for each row and column.
+const AAN_SCALE: [f32; 8] = [
+    1.0,
+    1.387_039_8,
+    1.306_563,
+    1.175_875_6,
+    1.0,
+    0.785_694_96,
+    0.541_196_1,
+    0.275_899_38,
+];
+
+/// cos(n * pi / 16) for n from 0 to 7, for the inverse DCT.
+const COS16: [f32; 8] = [
+    1.0,
+    0.980_785_25,
+    0.923_879_5,
+    0.831_469_6,
+    0.707_106_77,
+    0.555_570_24,
+    0.382_683_43,
+    0.195_090_32,
+];
+
+/// Decoding errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// The image is cut short, or its markers or coded data are invalid.
+    Corrupt,
+    /// The image has a different size, or uses features the encoder doesn't write.
+    Unsupported,
+}
+
+/// A Huffman table for encoding, the code and its length for each symbol.
+struct HuffTable {
+    code: [u16; 256],
+    len: [u8; 256],
+}
+
+impl HuffTable {
+    /// Assign the canonical codes given the number of codes of each length.
+    fn new(bits: &[u8; 16], values: &[u8]) -> Self {
+        let mut table = HuffTable {
+            code: [0; 256],
+            len: [0; 256],
+        };
+        let mut code = 0_u32;
+        let mut values = values.iter();
+        for (len, &count) in (1..=16).zip(bits) {
+            for _ in 0..count {
+                let symbol = *values.next().unwrap() as usize;
+                table.code[symbol] = code as u16;
+                table.len[symbol] = len;
+                code += 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+}
+
+/// A Huffman table for decoding, with the codes of each length like in Annex F.2.2.3 of the JPEG
+/// specification.
+struct DecodeTable {
+    /// Largest code of each length from 1 to 16, -1 if there is none.
+    max_code: [i32; 17],
+    /// Index of the symbols of each length in `values`, minus the first code of that length.
+    offset: [i32; 17],
+    values: [u8; 256],
+}
+
+impl DecodeTable {
+    /// Assign the canonical codes given the number of codes of each length. There must be at most
+    /// 256 `values`, as many as codes.
+    fn new(bits: &[u8], values: &[u8]) -> Self {
+        let mut table = DecodeTable {
+            max_code: [-1; 17],
+            offset: [0; 17],
+            values: [0; 256],
+        };
+        table.values[..values.len()].copy_from_slice(values);
+        let mut code = 0_i32;
+        let mut index = 0_i32;
+        for (len, &count) in (1..=16).zip(bits) {
+            if count > 0 {
+                table.offset[len] = index - code;
+                code += count as i32;
+                index += count as i32;
+                table.max_code[len] = code - 1;
+            }
+            code <<= 1;
+        }
+        table
+    }
+}
+
+/// Quantization and Huffman tables of one component type, luma or chroma.
+struct Tables {
+    /// Quantization table in zigzag order, as written to the image.
+    quant: [u8; 64],
+    /// Factors turning the scaled DCT output into quantized coefficients, in row order.
+    factors: [f32; 64],
+    dc: HuffTable,
+    ac: HuffTable,
+}
+
+impl Tables {
+    fn new(quant: &[u8; 64], scale: u32, dc: HuffTable, ac: HuffTable) -> Self {
+        let mut tables = Tables {
+            quant: [0; 64],
+            factors: [0.0; 64],
+            dc,
+            ac,
+        };
+        for (n, &i) in ZIGZAG.iter().enumerate() {
+            let i = i as usize;
+            let q = ((quant[i] as u32 * scale + 50) / 100).clamp(1, 255);
+            tables.quant[n] = q as u8;
+            tables.factors[i] = 1.0 / (q as f32 * AAN_SCALE[i / 8] * AAN_SCALE[i % 8] * 8.0);
+        }
+        tables
+    }
+}
+
+/// JPEG encoder with the tables for one quality setting.
+pub struct Encoder {
+    quality: u8,
+    luma: Tables,
+    chroma: Tables,
+}
+
+impl Encoder {
+    /// Create an encoder for `quality` from 1 to 100, values outside are clamped.
+    pub fn new(quality: u8) -> Self {
+        let quality = quality.clamp(1, 100);
+        let scale = match quality {
+            q if q < 50 => 5000 / q as u32,
+            q => 200 - 2 * q as u32,
+        };
+
+        Encoder {
+            quality,
+            luma: Tables::new(
+                &LUMA_QUANT,
+                scale,
+                HuffTable::new(&DC_LUMA_BITS, &DC_VALUES),
+                HuffTable::new(&AC_LUMA_BITS, &AC_LUMA_VALUES),
+            ),
+            chroma: Tables::new(
+                &CHROMA_QUANT,
+                scale,
+                HuffTable::new(&DC_CHROMA_BITS, &DC_VALUES),
+                HuffTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+            ),
+        }
+    }
+
+    pub fn quality(&self) -> u8 {
+        self.quality
+    }
+
+    /// Encode a frame of `width` x `height` RGB565 pixels, little endian and row by row from the
+    /// top left. Returns the size of the image written to `out`, or `None` if it doesn't fit.
+    pub fn encode(&self, pixels: &[u8], width: u16, height: u16, out: &mut [u8]) -> Option<usize> {
+        assert!(width > 0 && height > 0);
+        assert_eq!(pixels.len(), width as usize * height as usize * 2);
+
+        let mut writer = BitWriter::new(out);
+        self.write_headers(&mut writer, width, height)?;
+
+        let frame = Frame {
+            pixels,
+            width: width as usize,
+            height: height as usize,
+        };
+        let mut dc = [0; 3];
+        for mcu_y in (0..frame.height).step_by(16) {
+            for mcu_x in (0..frame.width).step_by(16) {
+                let mut y = [[0.0; 64]; 4];
+                let mut cb = [0.0; 64];
+                let mut cr = [0.0; 64];
+                frame.mcu(mcu_x, mcu_y, &mut y, &mut cb, &mut cr);
+
+                for block in y.iter_mut() {
+                    encode_block(&mut writer, block, &self.luma, &mut dc[0])?;
+                }
+                encode_block(&mut writer, &mut cb, &self.chroma, &mut dc[1])?;
+                encode_block(&mut writer, &mut cr, &self.chroma, &mut dc[2])?;
+            }
+        }
+
+        writer.flush()?;
+        writer.put(&[0xFF, 0xD9])?;
+        Some(writer.len)
+    }
+
+    /// Write the markers up to the start of the entropy coded data.
+    fn write_headers(&self, writer: &mut BitWriter, width: u16, height: u16) -> Option<()> {
+        // Start of image and JFIF marker without thumbnail
+        writer.put(&[0xFF, 0xD8])?;
+        writer.put(&[0xFF, 0xE0, 0, 16])?;
+        writer.put(b"JFIF\0")?;
+        writer.put(&[1, 1, 0, 0, 1, 0, 1, 0, 0])?;
+
+        // Quantization tables
+        writer.put(&[0xFF, 0xDB, 0, 2 + 2 * 65])?;
+        writer.put(&[0])?;
+        writer.put(&self.luma.quant)?;
+        writer.put(&[1])?;
+        writer.put(&self.chroma.quant)?;
+
+        // Baseline frame with Y at twice the resolution of Cb and Cr
+        let [h1, h0] = height.to_be_bytes();
+        let [w1, w0] = width.to_be_bytes();
+        writer.put(&[0xFF, 0xC0, 0, 17, 8, h1, h0, w1, w0, 3])?;
+        writer.put(&[1, 0x22, 0, 2, 0x11, 1, 3, 0x11, 1])?;
+
+        // Huffman tables
+        let len = 2 + 4 * 17 + 2 * DC_VALUES.len() + 2 * AC_LUMA_VALUES.len();
+        writer.put(&(0xFFC4_0000 | len as u32).to_be_bytes())?;
+        let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+            (0x00, &DC_LUMA_BITS, &DC_VALUES),
+            (0x10, &AC_LUMA_BITS, &AC_LUMA_VALUES),
+            (0x01, &DC_CHROMA_BITS, &DC_VALUES),
+            (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALUES),
+        ];
+        for (id, bits, values) in tables.iter() {
+            writer.put(&[*id])?;
+            writer.put(*bits)?;
+            writer.put(values)?;
+        }
+
+        // Start of scan with all components
+        writer.put(&[0xFF, 0xDA, 0, 12, 3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0])
+    }
+}
+
+/// The pixels of a frame.
+struct Frame<'a> {
+    pixels: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Frame<'_> {
+    /// Pixel as YCbCr, repeating the last row and column beyond the edges.
+    fn ycbcr(&self, x: usize, y: usize) -> [i32; 3] {
+        let i = (y.min(self.height - 1) * self.width + x.min(self.width - 1)) * 2;
+        let pixel = u16::from_le_bytes([self.pixels[i], self.pixels[i + 1]]) as i32;
+        let r = (pixel >> 11 & 0x1F) << 3 | (pixel >> 13);
+        let g = (pixel >> 5 & 0x3F) << 2 | (pixel >> 9 & 0x03);
+        let b = (pixel & 0x1F) << 3 | (pixel >> 2 & 0x07);
+
+        // JFIF conversion with 16 fractional bits
+        [
+            (19595 * r + 38470 * g + 7471 * b + 32768) >> 16,
+            (-11059 * r - 21709 * g + 32768 * b + (128 << 16) + 32767) >> 16,
+            (32768 * r - 27439 * g - 5329 * b + (128 << 16) + 32767) >> 16,
+        ]
+    }
+
+    /// The level shifted samples of the 16 x 16 pixels at (`x`, `y`): Four luma blocks and one
+    /// block each of the averaged chroma.
+    fn mcu(
+        &self,
+        x: usize,
+        y: usize,
+        luma: &mut [[f32; 64]; 4],
+        cb: &mut [f32; 64],
+        cr: &mut [f32; 64],
+    ) {
+        for row in 0..8 {
+            for col in 0..8 {
+                let mut sum = [0; 2];
+                for (dy, dx) in [(0, 0), (0, 1), (1, 0), (1, 1)].iter() {
+                    let (py, px) = (row * 2 + dy, col * 2 + dx);
+                    let [l, u, v] = self.ycbcr(x + px, y + py);
+                    let block = (py / 8) * 2 + px / 8;
+                    luma[block][(py % 8) * 8 + px % 8] = (l - 128) as f32;
+                    sum[0] += u;
+                    sum[1] += v;
+                }
+                cb[row * 8 + col] = ((sum[0] + 2) / 4 - 128) as f32;
+                cr[row * 8 + col] = ((sum[1] + 2) / 4 - 128) as f32;
+            }
+        }
+    }
+}
+
+/// Decode an image written by `Encoder::encode` to `width` x `height` RGB565 pixels, little endian
+/// and row by row from the top left.
+pub fn decode(data: &[u8], width: u16, height: u16, pixels: &mut [u8]) -> Result<(), Error> {
+    assert_eq!(pixels.len(), width as usize * height as usize * 2);
+    if data.get(..2) != Some(&[0xFF, 0xD8]) {
+        return Err(Error::Corrupt);
+    }
+
+    // Tables are numbered as in the image, Huffman tables are DC 0, DC 1, AC 0 and AC 1
+    let mut quant = [[0_u8; 64]; 4];
+    let mut huff = [
+        DecodeTable::new(&[0; 16], &[]),
+        DecodeTable::new(&[0; 16], &[]),
+        DecodeTable::new(&[0; 16], &[]),
+        DecodeTable::new(&[0; 16], &[]),
+    ];
+    let mut components = None;
+
+    let mut pos = 2;
+    loop {
+        let (marker, len) = match data.get(pos..pos + 4) {
+            Some(&[0xFF, marker, len1, len0]) => {
+                (marker, u16::from_be_bytes([len1, len0]) as usize)
+            }
+            _ => return Err(Error::Corrupt),
+        };
+        let segment = data.get(pos + 4..pos + 2 + len).ok_or(Error::Corrupt)?;
+        pos += 2 + len;
+
+        match marker {
+            // Quantization tables
+            0xDB => {
+                for table in segment.chunks(65) {
+                    match table {
+                        &[id, ..] if id < 4 && table.len() == 65 => {
+                            quant[id as usize].copy_from_slice(&table[1..])
+                        }
+                        _ => return Err(Error::Unsupported),
+                    }
+                }
+            }
+
+            // Huffman tables
+            0xC4 => {
+                let mut rest = segment;
+                while let Some(&class_id) = rest.first() {
+                    let bits = rest.get(1..17).ok_or(Error::Corrupt)?;
+                    let count = bits.iter().map(|&count| count as usize).sum::<usize>();
+                    let values = rest.get(17..17 + count).ok_or(Error::Corrupt)?;
+                    let index = match class_id {
+                        0x00 | 0x01 => class_id as usize,
+                        0x10 | 0x11 => 2 + (class_id & 1) as usize,
+                        _ => return Err(Error::Unsupported),
+                    };
+                    if count > 256 {
+                        return Err(Error::Corrupt);
+                    }
+                    huff[index] = DecodeTable::new(bits, values);
+                    rest = &rest[17 + count..];
+                }
+            }
+
+            // Baseline frame of the size and layout of the encoder, with the quantization table
+            // of each component
+            0xC0 => {
+                let [h1, h0] = height.to_be_bytes();
+                let [w1, w0] = width.to_be_bytes();
+                components = match segment {
+                    &[8, a, b, c, d, 3, 1, 0x22, y, 2, 0x11, cb, 3, 0x11, cr]
+                        if [a, b, c, d] == [h1, h0, w1, w0] && y < 4 && cb < 4 && cr < 4 =>
+                    {
+                        Some([y, cb, cr])
+                    }
+                    _ => return Err(Error::Unsupported),
+                };
+            }
+
+            // Scan of all components, with the Huffman tables of each
+            0xDA => {
+                let (quant_ids, huff_ids) = match (components, segment) {
+                    (Some(quant_ids), &[3, 1, y, 2, cb, 3, cr, 0, 63, 0])
+                        if [y, cb, cr].iter().all(|&id| id & 0xEE == 0) =>
+                    {
+                        (quant_ids, [y, cb, cr])
+                    }
+                    _ => return Err(Error::Unsupported),
+                };
+                let component = |c: usize| {
+                    (
+                        &quant[quant_ids[c] as usize],
+                        &huff[(huff_ids[c] >> 4) as usize],
+                        &huff[2 + (huff_ids[c] & 0x0F) as usize],
+                    )
+                };
+                let tables = [component(0), component(1), component(2)];
+                let mut reader = BitReader::new(&data[pos..]);
+                return decode_scan(
+                    &mut reader,
+                    &tables,
+                    width as usize,
+                    height as usize,
+                    pixels,
+                );
+            }
+
+            // Application data and comments
+            0xE0..=0xEF | 0xFE => (),
+            _ => return Err(Error::Unsupported),
+        }
+    }
+}
+
+/// Quantization table, DC and AC Huffman table of a component.
+type ComponentTables<'a> = (&'a [u8; 64], &'a DecodeTable, &'a DecodeTable);
+
+/// Decode the coded data of the image, in blocks of 16 x 16 pixels like `Encoder::encode`.
+fn decode_scan(
+    reader: &mut BitReader,
+    tables: &[ComponentTables; 3],
+    width: usize,
+    height: usize,
+    pixels: &mut [u8],
+) -> Result<(), Error> {
+    let mut cos = [[0.0; 8]; 8];
+    for (x, row) in cos.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            let scale = if u == 0 { COS16[4] } else { 1.0 };
+            *value = scale * cos16((2 * x + 1) * u) / 2.0;
+        }
+    }
+
+    // Four luma blocks and one block each of the averaged chroma
+    let mut blocks = [[0_u8; 64]; 6];
+    let mut dc = [0; 3];
+    for mcu_y in (0..height).step_by(16) {
+        for mcu_x in (0..width).step_by(16) {
+            for (n, block) in blocks.iter_mut().enumerate() {
+                let c = n.saturating_sub(3);
+                decode_block(reader, &tables[c], &mut dc[c], &cos, block)?;
+            }
+
+            for y in mcu_y..(mcu_y + 16).min(height) {
+                for x in mcu_x..(mcu_x + 16).min(width) {
+                    let (py, px) = (y - mcu_y, x - mcu_x);
+                    let l = blocks[(py / 8) * 2 + px / 8][(py % 8) * 8 + px % 8] as i32;
+                    let chroma = (py / 2) * 8 + px / 2;
+                    let cb = blocks[4][chroma] as i32 - 128;
+                    let cr = blocks[5][chroma] as i32 - 128;
+
+                    // JFIF conversion with 16 fractional bits
+                    let r = l + ((91881 * cr + 32768) >> 16);
+                    let g = l - ((22554 * cb + 46802 * cr + 32768) >> 16);
+                    let b = l + ((116130 * cb + 32768) >> 16);
+                    let pixel = (scale(r, 31) << 11) | (scale(g, 63) << 5) | scale(b, 31);
+                    let i = (y * width + x) * 2;
+                    pixels[i..i + 2].copy_from_slice(&pixel.to_le_bytes());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decode, dequantize and transform a block of samples.
+fn decode_block(
+    reader: &mut BitReader,
+    (quant, dc, ac): &ComponentTables,
+    prev_dc: &mut i32,
+    cos: &[[f32; 8]; 8],
+    samples: &mut [u8; 64],
+) -> Result<(), Error> {
+    let mut block = [0.0; 64];
+
+    // The DC coefficient is coded as the difference to the previous block
+    let len = reader.symbol(dc)?;
+    if len > 11 {
+        return Err(Error::Corrupt);
+    }
+    *prev_dc += extend(reader.bits(len), len);
+    block[0] = (*prev_dc * quant[0] as i32) as f32;
+
+    // AC coefficients as runs of zeros followed by a value, up to the end of the block
+    let mut n = 1;
+    while n < 64 {
+        let symbol = reader.symbol(ac)?;
+        let (run, len) = ((symbol >> 4) as usize, symbol & 0x0F);
+        if len == 0 && run != 15 {
+            break;
+        }
+        n += run;
+        if n > 63 {
+            return Err(Error::Corrupt);
+        }
+        let value = extend(reader.bits(len), len) * quant[n] as i32;
+        block[ZIGZAG[n] as usize] = value as f32;
+        n += 1;
+    }
+
+    // Inverse DCT of the rows, then of the columns
+    let mut rows = [0.0; 64];
+    for y in 0..8 {
+        for x in 0..8 {
+            rows[y * 8 + x] = (0..8).map(|u| cos[x][u] * block[y * 8 + u]).sum();
+        }
+    }
+    for y in 0..8 {
+        for x in 0..8 {
+            let value: f32 = (0..8).map(|v| cos[y][v] * rows[v * 8 + x]).sum();
+            let rounded = match value < 0.0 {
+                true => value - 0.5,
+                false => value + 0.5,
+            };
+            samples[y * 8 + x] = (rounded as i32 + 128).clamp(0, 255) as u8;
+        }
+    }
+    Ok(())
+}
+
+/// Clamp a sample to 0 to 255 and scale it to 0 to `max`, rounding to the nearest value.
+fn scale(sample: i32, max: u16) -> u16 {
+    (sample.clamp(0, 255) as u16 * max + 127) / 255
+}
+
+/// cos(n * pi / 16).
+fn cos16(n: usize) -> f32 {
+    let n = n % 32;
+    let n = if n > 16 { 32 - n } else { n };
+    match n {
+        0..=7 => COS16[n],
+        8 => 0.0,
+        _ => -COS16[16 - n],
+    }
+}
+
+/// The value of `len` bits as coded by `magnitude`.
+fn extend(bits: u32, len: u8) -> i32 {
+    match len {
+        0 => 0,
+        _ if bits < 1 << (len - 1) => bits as i32 - (1 << len) + 1,
+        _ => bits as i32,
+    }
+}
+
+/// Transform, quantize and code a block of samples.
+fn encode_block(
+    writer: &mut BitWriter,
+    block: &mut [f32; 64],
+    tables: &Tables,
+    prev_dc: &mut i32,
+) -> Option<()> {
+    fdct(block);
+
+    let mut coefs = [0_i32; 64];
+    for (coef, &i) in coefs.iter_mut().zip(ZIGZAG.iter()) {
+        let value = block[i as usize] * tables.factors[i as usize];
+        let rounded = match value < 0.0 {
+            true => value - 0.5,
+            false => value + 0.5,
+        };
+        *coef = (rounded as i32).clamp(-1023, 1023);
+    }
+
+    // The DC coefficient is coded as the difference to the previous block
+    let diff = coefs[0] - *prev_dc;
+    *prev_dc = coefs[0];
+    let (len, bits) = magnitude(diff);
+    writer.write_symbol(&tables.dc, len)?;
+    writer.write_bits(bits, len)?;
+
+    // AC coefficients as runs of zeros followed by a value, 0xF0 for 16 zeros
+    let mut run = 0;
+    for &coef in &coefs[1..] {
+        if coef == 0 {
+            run += 1;
+            continue;
+        }
+        while run >= 16 {
+            writer.write_symbol(&tables.ac, 0xF0)?;
+            run -= 16;
+        }
+        let (len, bits) = magnitude(coef);
+        writer.write_symbol(&tables.ac, run << 4 | len)?;
+        writer.write_bits(bits, len)?;
+        run = 0;
+    }
+
+    // End of block
+    if run > 0 {
+        writer.write_symbol(&tables.ac, 0x00)?;
+    }
+    Some(())
+}
+
+/// Number of bits of `value` and its bits as coded, negative values are decremented by one.
+fn magnitude(value: i32) -> (u8, u32) {
+    let len = 32 - value.unsigned_abs().leading_zeros();
+    let bits = match value < 0 {
+        true => (value - 1) as u32 & ((1 << len) - 1),
+        false => value as u32,
+    };
+    (len as u8, bits)
+}
+
+/// Forward DCT of a block in place, with the AAN algorithm like `jfdctflt.c` of libjpeg. The
+/// output is scaled by the `AAN_SCALE` factors of its row and column and by 8.
+fn fdct(block: &mut [f32; 64]) {
+    for i in 0..8 {
+        fdct_1d(block, i * 8, 1);
+    }
+    for i in 0..8 {
+        fdct_1d(block, i, 8);
+    }
+}
+
+/// One dimensional DCT of the 8 values starting at `start`, `step` apart.
+fn fdct_1d(d: &mut [f32; 64], start: usize, step: usize) {
+    let at = |k: usize| start + k * step;
+    let tmp0 = d[at(0)] + d[at(7)];
+    let tmp7 = d[at(0)] - d[at(7)];
+    let tmp1 = d[at(1)] + d[at(6)];
+    let tmp6 = d[at(1)] - d[at(6)];
+    let tmp2 = d[at(2)] + d[at(5)];
+    let tmp5 = d[at(2)] - d[at(5)];
+    let tmp3 = d[at(3)] + d[at(4)];
+    let tmp4 = d[at(3)] - d[at(4)];
+
+    // Even part
+    let tmp10 = tmp0 + tmp3;
+    let tmp13 = tmp0 - tmp3;
+    let tmp11 = tmp1 + tmp2;
+    let tmp12 = tmp1 - tmp2;
+    d[at(0)] = tmp10 + tmp11;
+    d[at(4)] = tmp10 - tmp11;
+    let z1 = (tmp12 + tmp13) * 0.707_106_77;
+    d[at(2)] = tmp13 + z1;
+    d[at(6)] = tmp13 - z1;
+
+    // Odd part
+    let tmp10 = tmp4 + tmp5;
+    let tmp11 = tmp5 + tmp6;
+    let tmp12 = tmp6 + tmp7;
+    let z5 = (tmp10 - tmp12) * 0.382_683_43;
+    let z2 = 0.541_196_1 * tmp10 + z5;
+    let z4 = 1.306_563 * tmp12 + z5;
+    let z3 = tmp11 * 0.707_106_77;
+    let z11 = tmp7 + z3;
+    let z13 = tmp7 - z3;
+    d[at(5)] = z13 + z2;
+    d[at(3)] = z13 - z2;
+    d[at(1)] = z11 + z4;
+    d[at(7)] = z11 - z4;
+}
+
+/// Reads the bits of the entropy coded data, skipping the 0 bytes which follow 0xFF bytes. At a
+/// marker or the end of the data, it reads 1 bits like the padding of the last byte.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Byte being read, the lowest `count` bits are left.
+    byte: u8,
+    count: u8,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            byte: 0,
+            count: 0,
+        }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.count == 0 {
+            self.byte = match self.data.get(self.pos..self.pos + 2) {
+                Some(&[0xFF, 0]) => {
+                    self.pos += 2;
+                    0xFF
+                }
+                Some(&[0xFF, _]) | None => 0xFF,
+                Some(&[byte, _]) => {
+                    self.pos += 1;
+                    byte
+                }
+                Some(_) => unreachable!(),
+            };
+            self.count = 8;
+        }
+        self.count -= 1;
+        (self.byte >> self.count) as u32 & 1
+    }
+
+    /// Read `len` bits, at most 16.
+    fn bits(&mut self, len: u8) -> u32 {
+        (0..len).fold(0, |bits, _| bits << 1 | self.bit())
+    }
+
+    /// Read a Huffman coded symbol.
+    fn symbol(&mut self, table: &DecodeTable) -> Result<u8, Error> {
+        let mut code = 0;
+        for len in 1..=16 {
+            code = code << 1 | self.bit() as i32;
+            if code <= table.max_code[len] {
+                return Ok(table.values[(table.offset[len] + code) as usize]);
+            }
+        }
+        Err(Error::Corrupt)
+    }
+}
+
+/// Writes bytes and the bits of the entropy coded data to a buffer.
+struct BitWriter<'a> {
+    out: &'a mut [u8],
+    len: usize,
+    /// Bits not written yet, the lowest `count` bits are used.
+    bits: u32,
+    count: u8,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut [u8]) -> Self {
+        BitWriter {
+            out,
+            len: 0,
+            bits: 0,
+            count: 0,
+        }
+    }
+
+    fn put(&mut self, data: &[u8]) -> Option<()> {
+        let end = self.len + data.len();
+        self.out.get_mut(self.len..end)?.copy_from_slice(data);
+        self.len = end;
+        Some(())
+    }
+
+    fn write_symbol(&mut self, table: &HuffTable, symbol: u8) -> Option<()> {
+        let len = table.len[symbol as usize];
+        debug_assert!(len > 0);
+        self.write_bits(table.code[symbol as usize] as u32, len)
+    }
+
+    /// Write the lowest `len` bits of `bits`, at most 16. A 0xFF byte is followed by a 0 byte, so
+    /// it isn't taken for a marker.
+    fn write_bits(&mut self, bits: u32, len: u8) -> Option<()> {
+        self.bits = self.bits << len | bits;
+        self.count += len;
+        while self.count >= 8 {
+            self.count -= 8;
+            let byte = (self.bits >> self.count) as u8;
+            self.put(&[byte])?;
+            if byte == 0xFF {
+                self.put(&[0])?;
+            }
+        }
+        self.bits &= (1 << self.count) - 1;
+        Some(())
+    }
+
+    /// Pad the last byte with 1 bits.
+    fn flush(&mut self) -> Option<()> {
+        match self.count {
+            0 => Some(()),
+            count => self.write_bits((1 << (8 - count)) - 1, 8 - count),
+        }
+    }
+}
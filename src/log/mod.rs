@@ -0,0 +1,274 @@
+//! A logging facade with levels and module targets. Messages are encoded in a compact binary
+//! format (see `wire`) and written to a non-blocking RTT channel, so logging never stalls an
+//! interrupt. Messages which don't fit in the channel are dropped and counted. The host decoder
+//! in `tools/logdec` turns the stream back into text.
+//!
+//! Arguments must implement `Encode`, which is the case for integers, floats, `bool` and `str`.
+//! Anything implementing `Debug` or `Display` can be logged by wrapping it with `dbg` or
+//! `display`, which formats it on the target instead.
+
+// Decoding is only used by the host decoder
+#[allow(dead_code)]
+pub mod wire;
+
+use crate::{timer, util::ByteWriter};
+use core::{
+    cell::RefCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+use cortex_m::interrupt::{self, Mutex};
+use rtt_target::UpChannel;
+use wire::{Arg, Header, Writer, MAX_PAYLOAD_LEN};
+
+pub use wire::Level;
+
+/// Maximum length of an argument formatted on the target.
+const MAX_FORMATTED_LEN: usize = 128;
+
+/// Level used until `set_level` is called.
+#[cfg(debug_assertions)]
+const DEFAULT_LEVEL: Level = Level::Debug;
+#[cfg(not(debug_assertions))]
+const DEFAULT_LEVEL: Level = Level::Info;
+
+/// RTT channel the log is written to, set by `init`.
+static CHANNEL: Mutex<RefCell<Option<UpChannel>>> = Mutex::new(RefCell::new(None));
+
+/// Least severe level which is logged.
+static LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LEVEL as u8);
+
+/// Number of messages dropped because the channel was full.
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+/// Log a message at `$level`. The format string is checked at compile time, but only its address
+/// is sent.
+macro_rules! log_at {
+    ($level:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        if false {
+            $crate::log::check_format(format_args!($fmt $(, $arg)*));
+        }
+        if $crate::log::enabled($level) {
+            #[allow(unused_mut)]
+            let mut frame =
+                $crate::log::Frame::new($level, concat!(module_path!(), "\0", $fmt));
+            $(frame.arg(&$arg);)*
+            frame.send();
+        }
+    }};
+}
+
+/// Log a message at the error level.
+macro_rules! error {
+    ($($arg:tt)*) => { log_at!($crate::log::Level::Error, $($arg)*) };
+}
+
+/// Log a message at the warning level.
+macro_rules! warn {
+    ($($arg:tt)*) => { log_at!($crate::log::Level::Warn, $($arg)*) };
+}
+
+/// Log a message at the info level.
+macro_rules! info {
+    ($($arg:tt)*) => { log_at!($crate::log::Level::Info, $($arg)*) };
+}
+
+/// Log a message at the debug level.
+#[allow(unused_macros)]
+macro_rules! debug {
+    ($($arg:tt)*) => { log_at!($crate::log::Level::Debug, $($arg)*) };
+}
+
+/// Log a message at the trace level.
+#[allow(unused_macros)]
+macro_rules! trace {
+    ($($arg:tt)*) => { log_at!($crate::log::Level::Trace, $($arg)*) };
+}
+
+/// A type which can be sent as a log argument.
+pub trait Encode {
+    /// Write the argument, returns `false` if it doesn't fit.
+    fn encode(&self, writer: &mut Writer) -> bool;
+}
+
+/// A log message being encoded.
+pub struct Frame {
+    buf: [u8; 1 + MAX_PAYLOAD_LEN],
+    len: usize,
+}
+
+/// Wrapper to log a value using its `Debug` implementation.
+pub struct Dbg<'a, T: ?Sized>(&'a T);
+
+/// Wrapper to log a value using its `Display` implementation.
+pub struct Disp<'a, T: ?Sized>(&'a T);
+
+impl Frame {
+    /// Start a message with the given level and format string.
+    pub fn new(level: Level, format: &'static str) -> Self {
+        let mut frame = Frame {
+            buf: [0; 1 + MAX_PAYLOAD_LEN],
+            len: 0,
+        };
+
+        let header = Header {
+            level,
+            timestamp_ms: timer::uptime_ms(),
+            format_addr: format.as_ptr() as u32,
+            format_len: format.len() as u32,
+        };
+
+        let mut writer = Writer::new(&mut frame.buf[1..]);
+        writer.header(&header);
+        frame.len = writer.written();
+
+        frame
+    }
+
+    /// Add an argument. Arguments which don't fit are left out, the decoder shows them as missing.
+    pub fn arg<T: Encode + ?Sized>(&mut self, val: &T) {
+        let mut writer = Writer::new(&mut self.buf[1 + self.len..]);
+        val.encode(&mut writer);
+        self.len += writer.written();
+    }
+
+    /// Write the message to the channel. Never blocks, if the channel is full the message is
+    /// dropped.
+    pub fn send(mut self) {
+        self.buf[0] = self.len as u8;
+        let bytes = &self.buf[..1 + self.len];
+
+        let sent = interrupt::free(|cs| {
+            // May already be borrowed if a fault interrupted logging
+            match CHANNEL.borrow(cs).try_borrow_mut() {
+                Ok(mut channel) => match channel.as_mut() {
+                    Some(channel) => channel.write(bytes) == bytes.len(),
+                    None => false,
+                },
+                Err(_) => false,
+            }
+        });
+
+        if !sent {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        (**self).encode(writer)
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        writer.arg(&Arg::Str(self))
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        writer.arg(&Arg::Bool(*self))
+    }
+}
+
+impl Encode for f32 {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        writer.arg(&Arg::F32(*self))
+    }
+}
+
+/// Helper macro for implementing `Encode` for integers.
+macro_rules! encode_int {
+    ($arg:ident, $conv:ty, $($int:ty),*) => {
+        $(
+            impl Encode for $int {
+                fn encode(&self, writer: &mut Writer) -> bool {
+                    writer.arg(&Arg::$arg(*self as $conv))
+                }
+            }
+        )*
+    };
+}
+
+encode_int!(Unsigned, u64, u8, u16, u32, u64, usize);
+encode_int!(Signed, i64, i8, i16, i32, i64, isize);
+
+impl<T: fmt::Debug + ?Sized> Encode for Dbg<'_, T> {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        let mut buf = [0; MAX_FORMATTED_LEN];
+        let mut formatted = ByteWriter::new(&mut buf);
+        write!(formatted, "{:?}", self.0).ok();
+        writer.arg(&Arg::Str(formatted.as_str()))
+    }
+}
+
+impl<T: fmt::Display + ?Sized> Encode for Disp<'_, T> {
+    fn encode(&self, writer: &mut Writer) -> bool {
+        let mut buf = [0; MAX_FORMATTED_LEN];
+        let mut formatted = ByteWriter::new(&mut buf);
+        write!(formatted, "{}", self.0).ok();
+        writer.arg(&Arg::Str(formatted.as_str()))
+    }
+}
+
+// Both wrappers format like the wrapped value, so the format string check accepts them
+impl<T: fmt::Debug + ?Sized> fmt::Debug for Dbg<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+}
+
+impl<T: fmt::Debug + ?Sized> fmt::Display for Dbg<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.0, f)
+    }
+}
+
+impl<T: fmt::Display + ?Sized> fmt::Debug for Disp<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+impl<T: fmt::Display + ?Sized> fmt::Display for Disp<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.0, f)
+    }
+}
+
+/// Log `val` using its `Debug` implementation.
+pub fn dbg<T: fmt::Debug + ?Sized>(val: &T) -> Dbg<T> {
+    Dbg(val)
+}
+
+/// Log `val` using its `Display` implementation.
+pub fn display<T: fmt::Display + ?Sized>(val: &T) -> Disp<T> {
+    Disp(val)
+}
+
+/// Start logging to `channel`, which should be in a non-blocking mode.
+pub fn init(channel: UpChannel) {
+    interrupt::free(|cs| CHANNEL.borrow(cs).replace(Some(channel)));
+}
+
+/// Only log messages at `level` or more severe.
+#[allow(dead_code)]
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns `true` if messages at `level` are logged.
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Get the number of messages dropped because the channel was full.
+#[allow(dead_code)]
+pub fn dropped() -> u32 {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Used by the log macros to check the format string against the arguments.
+pub fn check_format(_: fmt::Arguments) {}
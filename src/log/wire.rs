@@ -0,0 +1,228 @@
+//! Binary wire format of the log stream, shared by the firmware and the host decoder.
+//!
+//! Each log message is sent as one frame: A length byte followed by the payload. The payload is
+//! a header and the arguments. Format strings are not sent, only their address and length in
+//! flash, so the decoder looks them up in the ELF file. A format string is the module path of
+//! the call site and the format, separated by a zero byte.
+//!
+//! Integers are encoded as LEB128 varints, signed integers zigzag encoded first. Each argument
+//! is prefixed with a tag byte, so the stream can be decoded without parsing format strings.
+
+/// Maximum payload length of a frame.
+pub const MAX_PAYLOAD_LEN: usize = 255;
+
+/// Argument tags.
+pub const TAG_UNSIGNED: u8 = 0;
+pub const TAG_SIGNED: u8 = 1;
+pub const TAG_BOOL: u8 = 2;
+pub const TAG_STR: u8 = 3;
+pub const TAG_F32: u8 = 4;
+
+/// Log levels, from most to least severe.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+/// Header of a frame.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub level: Level,
+    /// Time since boot in milliseconds.
+    pub timestamp_ms: u32,
+    /// Address and length of the format string in flash.
+    pub format_addr: u32,
+    pub format_len: u32,
+}
+
+/// A decoded argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg<'a> {
+    Unsigned(u64),
+    Signed(i64),
+    Bool(bool),
+    Str(&'a str),
+    F32(f32),
+}
+
+/// Encodes into a byte buffer, without ever writing past its end.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+/// Decodes from a byte buffer.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Level {
+    pub fn from_u8(val: u8) -> Option<Self> {
+        match val {
+            0 => Some(Level::Error),
+            1 => Some(Level::Warn),
+            2 => Some(Level::Info),
+            3 => Some(Level::Debug),
+            4 => Some(Level::Trace),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Writer { buf, len: 0 }
+    }
+
+    /// Number of bytes written.
+    pub fn written(&self) -> usize {
+        self.len
+    }
+
+    /// Write a frame header. Returns `false` if it doesn't fit.
+    pub fn header(&mut self, header: &Header) -> bool {
+        self.u8(header.level as u8)
+            && self.varint(header.timestamp_ms as u64)
+            && self.bytes(&header.format_addr.to_le_bytes())
+            && self.varint(header.format_len as u64)
+    }
+
+    /// Write an argument. Returns `false` and writes nothing if it doesn't fit.
+    pub fn arg(&mut self, arg: &Arg) -> bool {
+        let start = self.len;
+        let fits = match *arg {
+            Arg::Unsigned(val) => self.u8(TAG_UNSIGNED) && self.varint(val),
+            Arg::Signed(val) => self.u8(TAG_SIGNED) && self.varint(zigzag(val)),
+            Arg::Bool(val) => self.u8(TAG_BOOL) && self.u8(val as u8),
+            Arg::Str(val) => {
+                self.u8(TAG_STR) && self.varint(val.len() as u64) && self.bytes(val.as_bytes())
+            }
+            Arg::F32(val) => self.u8(TAG_F32) && self.bytes(&val.to_bits().to_le_bytes()),
+        };
+
+        if !fits {
+            self.len = start;
+        }
+
+        fits
+    }
+
+    fn u8(&mut self, val: u8) -> bool {
+        self.bytes(&[val])
+    }
+
+    fn varint(&mut self, mut val: u64) -> bool {
+        loop {
+            let byte = (val & 0x7F) as u8;
+            val >>= 7;
+            if val == 0 {
+                return self.u8(byte);
+            }
+            if !self.u8(byte | 0x80) {
+                return false;
+            }
+        }
+    }
+
+    fn bytes(&mut self, val: &[u8]) -> bool {
+        if self.len + val.len() > self.buf.len() {
+            return false;
+        }
+
+        self.buf[self.len..self.len + val.len()].copy_from_slice(val);
+        self.len += val.len();
+        true
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    /// Read a frame header.
+    pub fn header(&mut self) -> Option<Header> {
+        let level = Level::from_u8(self.u8()?)?;
+        let timestamp_ms = self.varint()? as u32;
+        let mut addr = [0; 4];
+        addr.copy_from_slice(self.bytes(4)?);
+        let format_len = self.varint()? as u32;
+
+        Some(Header {
+            level,
+            timestamp_ms,
+            format_addr: u32::from_le_bytes(addr),
+            format_len,
+        })
+    }
+
+    /// Read the next argument, `None` at the end of the frame or if it is malformed.
+    pub fn arg(&mut self) -> Option<Arg<'a>> {
+        match self.u8()? {
+            TAG_UNSIGNED => Some(Arg::Unsigned(self.varint()?)),
+            TAG_SIGNED => Some(Arg::Signed(unzigzag(self.varint()?))),
+            TAG_BOOL => Some(Arg::Bool(self.u8()? != 0)),
+            TAG_STR => {
+                let len = self.varint()? as usize;
+                core::str::from_utf8(self.bytes(len)?).ok().map(Arg::Str)
+            }
+            TAG_F32 => {
+                let mut bits = [0; 4];
+                bits.copy_from_slice(self.bytes(4)?);
+                Some(Arg::F32(f32::from_bits(u32::from_le_bytes(bits))))
+            }
+            _ => None,
+        }
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut val = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            val |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(val);
+            }
+        }
+
+        None
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.pos + len > self.buf.len() {
+            return None;
+        }
+
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Some(bytes)
+    }
+}
+
+/// Map signed integers to unsigned ones, so small magnitudes encode to short varints.
+fn zigzag(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+fn unzigzag(val: u64) -> i64 {
+    ((val >> 1) as i64) ^ -((val & 1) as i64)
+}
@@ -3,60 +3,178 @@
 #![no_std]
 #![no_main]
 
+// Must come first, so the log macros are available in all other modules
+#[macro_use]
+mod log;
+
+#[cfg(feature = "sdcard")]
+mod block;
 mod board;
+mod clip;
+mod clip_store;
+mod codec;
+mod command;
+mod config;
+mod crash;
+mod crc;
+mod date;
+mod event;
+#[cfg(feature = "sdcard")]
+mod fat32;
+mod flashfs;
 mod frame_buf;
+mod frame_ring;
+mod health;
+mod jpeg;
+mod motion;
 mod nvm;
 mod ov9655;
+#[cfg(feature = "sdcard")]
+mod sd;
+mod timer;
+mod usb;
 mod util;
+mod watchdog;
 
+#[cfg(feature = "sdcard")]
+use board::sdmmc::{SdCard, SdError};
 use board::{
     display, get_xtal,
     qspi::{self, QspiDriver},
     sdram, setup_button, ButtonPin,
 };
+use clip::{ClipReader, ClipWriter, Codec, FrameEntry, PixelFormat};
+use clip_store::{StoredClip, CLIP_SPACING};
+use command::{Command, LineReader, Setting};
+use config::{Config, Window};
+use core::{fmt::Write, ops::RangeInclusive};
+use event::EventQueue;
+#[cfg(feature = "sdcard")]
+use fat32::Volume;
 use frame_buf::FrameBuffer;
+use frame_ring::{FrameRing, Meta};
+use health::{CaptureMonitor, Health};
+use jpeg::Encoder;
+use motion::MotionDetector;
 use nvm::NonVolatileMemory;
-use ov9655::{FRAME_HEIGHT, FRAME_RATE, FRAME_SIZE, FRAME_WIDTH};
-use rtt_target::{rprintln, rtt_init, set_print_channel};
+use ov9655::{FrameRate, Orientation, Ov9655, FRAME_HEIGHT, FRAME_SIZE, FRAME_WIDTH};
+use rtic::Mutex;
+use rtt_target::{rtt_init, DownChannel};
 use stm32f7xx_hal::{
     delay::Delay,
     gpio::ExtiPin,
     pac,
     prelude::_embedded_hal_blocking_delay_DelayMs,
-    rcc::{HSEClock, HSEClockMode, RccExt},
+    rcc::{Clocks, HSEClock, HSEClockMode, RccExt},
     time::U32Ext,
 };
+use usb::{
+    fat::{Clip, FatDisk, FatVolume},
+    link::Link,
+    msc::MscClass,
+    proto::{DeviceStatus, State},
+    UsbBus,
+};
+use usb_device::{
+    bus::UsbBusAllocator,
+    device::{UsbDevice, UsbDeviceBuilder, UsbVidPid},
+};
+use usbd_serial::SerialPort;
+use util::ByteWriter;
+use watchdog::{ResetCause, Task};
 
 /// Alias for NVM driver that uses QSPI flash.
 type NvmDriver = NonVolatileMemory<QspiDriver>;
 
+/// Errors reading or writing a clip in the NVM.
+type ClipError = clip::Error<flashfs::Error<qspi::QspiError>>;
+
+/// Most frames a clip can have. Compressed frames are a fraction of their raw size, so the frame
+/// ring holds many more frames than would fit in the 8 MB of SDRAM uncompressed.
+const MAX_CLIP_FRAMES: usize = 2048;
+
+/// Number of frames the camera captures into in turn, at the start of the SDRAM. A frame has to
+/// be recorded within `CAPTURE_SLOTS - 1` frame periods, before the camera reuses its memory.
+const CAPTURE_SLOTS: u32 = 4;
+
+/// Size of the buffer a frame is compressed into, at the end of the SDRAM after the frame ring.
+/// Camera frames compress to a fraction of their raw size even at the highest quality.
+const JPEG_BUF_SIZE: u32 = FRAME_SIZE;
+
+/// Size of the buffer a frame is delta coded into when saving or decoded from when replaying, in
+/// the SDRAM between the frame ring and the JPEG buffer.
+const DELTA_BUF_SIZE: u32 = codec::max_len(FRAME_WIDTH, FRAME_HEIGHT) as u32;
+
+/// Time between capture health checks in milliseconds.
+const HEALTH_TICK_MS: u32 = 250;
+
+/// Watchdog timeout in milliseconds, the watchdog is serviced by the capture health checks.
+const WATCHDOG_TIMEOUT_MS: u32 = 2000;
+
 #[rtic::app(device = stm32f7xx_hal::pac, peripherals = true)]
 const APP: () = {
     // Static resources.
     struct Resources {
         nvm: NvmDriver,
+        cam: Ov9655,
+        park: bool,
+        mot: MotionDetector,
+        mon: CaptureMonitor,
         fb1: FrameBuffer,
         fb2: FrameBuffer,
+        raw: Capture,
+        ring: FrameRing<'static>,
+        events: EventQueue,
+        enc: Option<Encoder>,
+        jpg: &'static mut [u8],
+        delta: &'static mut [u8],
         but: ButtonPin,
-        dly: Delay,
-        pbn: u32,
+        clk: Clocks,
+        rst: ResetCause,
+        cmd: DownChannel,
+        lines: LineReader,
+        usb_dev: UsbDevice<'static, UsbBus>,
+        msc: MscClass<'static, UsbBus>,
+        serial: SerialPort<'static, UsbBus>,
+        link: Link,
+        vol: FatVolume,
+        #[cfg(feature = "sdcard")]
+        card: Option<(SdCard, Volume)>,
+        replaying: bool,
     }
 
     // Program entry point.
     #[init]
     fn init(cx: init::Context) -> init::LateResources {
-        // Setup RTT for logging
+        static mut USB_EP_MEMORY: [u32; 1024] = [0; 1024];
+        static mut USB_BUS: Option<UsbBusAllocator<UsbBus>> = None;
+
+        // Setup RTT for logging, dropping messages rather than blocking when the host is slow
         let channels = rtt_init! {
             up: {
                 0: {
                     size: 4096
-                    mode: BlockIfFull
-                    name: "Terminal"
+                    mode: NoBlockSkip
+                    name: "Log"
+                }
+            }
+            down: {
+                0: {
+                    size: 64
+                    name: "Command"
                 }
             }
         };
 
-        set_print_channel(channels.up.0);
+        log::init(channels.up.0);
+
+        // Find out why we were reset, before anything else can reset
+        let rst = watchdog::reset_cause();
+        info!("Reset cause: {:?}", log::dbg(&rst));
+
+        // Report crashes which happened since the last boot
+        crash::init();
+        crash::report();
 
         // Get peripherals from RTIC
         let pac_periph: pac::Peripherals = cx.device;
@@ -84,13 +202,17 @@ const APP: () = {
         let mut rcc = rcc.constrain();
         let hse_cfg = HSEClock::new(get_xtal(), HSEClockMode::Oscillator);
         let clocks = rcc.cfgr.hse(hse_cfg).sysclk(216.mhz()).freeze();
+        let otg_fs = usb::init(&clocks);
         let mut dly = Delay::new(cm_periph.SYST, clocks);
 
+        // Erases run in the idle loop, or in a task which keeps it from running
+        qspi.set_erase_progress(|| watchdog::check_in(Task::Idle));
+
         // Test QSPI
         qspi.check_id().unwrap();
         qspi::tests::test_mem(&mut qspi);
         qspi::tests::test_mem_dma(&mut qspi);
-        rprintln!("QSPI driver successfully initialized!");
+        info!("QSPI driver successfully initialized!");
 
         // LCD screen
         let mut display = display::config();
@@ -100,41 +222,241 @@ const APP: () = {
         let (sdram_ptr, sdram_size) = sdram::init(&clocks, &mut dly);
 
         // NVM
-        let nvm = NvmDriver::new(qspi, 0);
-        rprintln!("NVM driver successfully initialized and erased!");
+        let mut nvm = NvmDriver::new(qspi, qspi::FS_SIZE);
+        let config = nvm.get_config();
+        info!(
+            "NVM filesystem mounted, {} KB free",
+            nvm.free_space() / 1024
+        );
+        info!("Loaded config: {:?}", log::dbg(&config));
+        info!("{} saved clips", nvm.clips().count());
+
+        // Keep a history of the boots in the NVM log file
+        let mut line = [0; 64];
+        let mut writer = ByteWriter::new(&mut line);
+        writeln!(writer, "Boot: {:?}, crashes: {}", rst, crash::count()).ok();
+        if let Err(e) = nvm.append_log(writer.as_str().as_bytes()) {
+            error!("Cannot write NVM log: {:?}", log::dbg(&e));
+        }
+
+        // SD card, saved clips are also written to it as files. Recording works without it.
+        #[cfg(feature = "sdcard")]
+        let card = setup_card(pac_periph.SDMMC1, &clocks);
 
         // OV9655
-        ov9655::init(pac_periph.I2C1, &mut rcc.apb1, clocks, &mut dly);
+        let mut cam = ov9655::init(
+            pac_periph.I2C1,
+            &mut rcc.apb1,
+            clocks,
+            &mut dly,
+            config.orientation,
+            config.frame_rate,
+        );
+
+        // Motion detection
+        let mot = MotionDetector::new(config.motion);
 
-        // Initialize frame buffers: One for capture and one for replay
-        let fb1 = FrameBuffer::new(sdram_ptr as u32, sdram_size as u32, FRAME_SIZE);
-        let fb2 = FrameBuffer::new(sdram_ptr as u32, sdram_size as u32, FRAME_SIZE);
+        // Initialize frame buffers: A few slots to capture into and a frame buffer for replay.
+        // Captured frames are compressed into the end of the SDRAM and recorded in the frame ring
+        // in between. Parking mode snapshots are all kept, they are timed by the parking timer.
+        let park = config.parking_interval_ms > 0;
+        let decimation = match park {
+            true => 1,
+            false => config.frame_rate.decimation(),
+        };
+        let slots_size = CAPTURE_SLOTS * FRAME_SIZE;
+        let fb_size = sdram_size as u32 - DELTA_BUF_SIZE - JPEG_BUF_SIZE;
+        let fb1 = FrameBuffer::new(sdram_ptr as u32, slots_size, FRAME_SIZE, decimation);
+        let fb2 = FrameBuffer::new(sdram_ptr as u32, fb_size, FRAME_SIZE, 1);
+        let (ring, delta, jpg) = unsafe {
+            (
+                core::slice::from_raw_parts_mut(
+                    (sdram_ptr as u32 + slots_size) as *mut u8,
+                    (fb_size - slots_size) as usize,
+                ),
+                core::slice::from_raw_parts_mut(
+                    (sdram_ptr as u32 + fb_size) as *mut u8,
+                    DELTA_BUF_SIZE as usize,
+                ),
+                core::slice::from_raw_parts_mut(
+                    (sdram_ptr as u32 + fb_size + DELTA_BUF_SIZE) as *mut u8,
+                    JPEG_BUF_SIZE as usize,
+                ),
+            )
+        };
+        let ring = FrameRing::new(ring);
+
+        // Recorded frames are JPEG images. With a quality of 0 they are recorded raw and delta
+        // coded when saved, which keeps the exact pixels. Each record says which it is.
+        let enc = match config.quality {
+            0 => None,
+            quality => Some(Encoder::new(quality.min(100) as u8)),
+        };
 
         // Allow RTT buffer to flush and give time to view screen prior to starting
-        rprintln!("Starting image capture...");
+        info!("Starting image capture...");
         dly.delay_ms(500_u32);
 
-        // Start capture
-        ov9655::start();
+        // Start capture. In parking mode, capture single frames on a timer and keep the camera
+        // asleep in between.
+        if park {
+            info!(
+                "Parking mode: One frame every {} ms",
+                config.parking_interval_ms
+            );
+            ov9655::set_snapshot_mode(true);
+            cam.set_sleep(true).unwrap();
+            timer::tim2_start(&clocks, config.parking_interval_ms);
+        } else {
+            ov9655::start();
+        }
+
+        // Monitor capture health, expecting either sensor frames or parking mode snapshots
+        let period_ms = match park {
+            true => config.parking_interval_ms,
+            false => 1000 / config.frame_rate.sensor_fps(),
+        };
+        let mon = CaptureMonitor::new(period_ms);
+
+        // USB device with a mass storage and a serial interface, the mass storage volume holds the
+        // saved clips
+        let mut vol = FatVolume::new();
+        set_volume_clips(&nvm, &mut vol);
+        *USB_BUS = Some(UsbBus::new(otg_fs, USB_EP_MEMORY));
+        let usb_bus = USB_BUS.as_ref().unwrap();
+        let msc = MscClass::new(usb_bus);
+        let serial = SerialPort::new(usb_bus);
+        let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x1209, 0x0001))
+            .manufacturer("dashcam-rs")
+            .product("Dashcam")
+            .serial_number("0001")
+            .composite_with_iads()
+            .build();
+
+        timer::tim5_start(&clocks, HEALTH_TICK_MS);
+        watchdog::start(WATCHDOG_TIMEOUT_MS);
 
         // Initialize static resources
         init::LateResources {
             nvm,
+            cam,
+            park,
+            mot,
+            mon,
             fb1,
             fb2,
+            raw: Capture {
+                frame: None,
+                count: 0,
+            },
+            ring,
+            events: EventQueue::new(),
+            enc,
+            jpg,
+            delta,
             but,
-            dly,
-            pbn: 0,
+            clk: clocks,
+            rst,
+            cmd: channels.down.0,
+            lines: LineReader::new(),
+            usb_dev,
+            msc,
+            serial,
+            link: Link::new(),
+            vol,
+            #[cfg(feature = "sdcard")]
+            card,
+            replaying: false,
         }
     }
 
-    // Idle task.
-    #[idle]
-    fn idle(_: idle::Context) -> ! {
+    // Idle task. Records the captured frames in the frame ring, compressing them takes longer
+    // than anything else the interrupts need to do in time. Saves the clips of the save events
+    // once their last frame was recorded, recording goes on in between writing their frames.
+    #[idle(resources = [raw, ring, events, enc, jpg, delta, nvm, vol, mon, card])]
+    fn idle(cx: idle::Context) -> ! {
+        static mut INDEX: [FrameEntry; MAX_CLIP_FRAMES] = [FrameEntry {
+            offset: 0,
+            time_ms: 0,
+            size: 0,
+        }; MAX_CLIP_FRAMES];
+
+        let mut res = cx.resources;
+        let mut recorder = Recorder {
+            raw: res.raw,
+            ring: res.ring,
+            mon: res.mon,
+            enc: res.enc,
+            jpg: res.jpg,
+        };
         loop {
-            // Release mode: Put the core to sleep, note that RTT messages may get delayed
+            watchdog::check_in(Task::Idle);
+
+            if recorder.record() {
+                continue;
+            }
+
+            // Save the oldest clip waiting to be saved, once all of its frames are recorded
+            let newest = recorder
+                .ring
+                .lock(|ring| ring.newest().map_or(0, |meta| meta.seq));
+            if let Some(seqs) = res.events.lock(|events| events.start(newest)) {
+                info!("Saving frames {} to {}", seqs.start(), seqs.end());
+                let result = save_clip(
+                    &mut recorder,
+                    &mut res.nvm,
+                    &mut res.delta,
+                    seqs,
+                    &mut INDEX[..],
+                );
+                res.events.lock(|events| events.pop());
+
+                match &result {
+                    Ok(number) => info!("Clip {} saved", number),
+                    // The oldest unlocked clips were reclaimed, what is left is locked
+                    Err(clip::Error::Io(flashfs::Error::Full))
+                    | Err(clip::Error::Io(flashfs::Error::TooManyFiles)) => {
+                        match res.nvm.lock(|nvm| nvm.clips().any(|clip| clip.locked)) {
+                            true => warn!("Storage is full of locked clips, clip not saved"),
+                            false => warn!("Clip does not fit in the storage, clip not saved"),
+                        }
+                    }
+                    // The free blocks left between locked clips are too split up for the clip
+                    Err(clip::Error::Io(flashfs::Error::Fragmented)) => {
+                        warn!("Storage is too fragmented by locked clips, clip not saved")
+                    }
+                    Err(e) => error!("Cannot save clip: {:?}", log::dbg(&e)),
+                };
+
+                // Copy the clip to the SD card
+                #[cfg(feature = "sdcard")]
+                {
+                    let clip = result
+                        .ok()
+                        .and_then(|number| res.nvm.lock(|nvm| nvm.clip(number)));
+                    if let (Some(clip), Some((card, volume))) = (clip, &mut *res.card) {
+                        match copy_to_card(&mut recorder, &mut res.nvm, clip, card, volume) {
+                            Ok(number) => info!("Clip saved to SD card as CLIP{:04}.CLP", number),
+                            Err(e) => error!("Cannot save clip to SD card: {:?}", log::dbg(&e)),
+                        }
+                    }
+                }
+
+                // Make the clip available on the USB mass storage device
+                let nvm = &mut res.nvm;
+                res.vol
+                    .lock(|vol| nvm.lock(|nvm| set_volume_clips(nvm, vol)));
+                continue;
+            }
+
+            // Release mode: Put the core to sleep until the next interrupt, unless a frame was
+            // captured since checking. Note that RTT messages may get delayed.
             #[cfg(not(debug_assertions))]
-            cortex_m::asm::wfi();
+            cortex_m::interrupt::free(|_| {
+                if recorder.raw.lock(|raw| raw.frame.is_none()) {
+                    cortex_m::asm::wfi();
+                }
+            });
 
             // Debug mode: Do a spin-loop here, want to see all RTT messages
             #[cfg(debug_assertions)]
@@ -143,77 +465,932 @@ const APP: () = {
     }
 
     // Handle DMA interrupts. A DMA DONE interrupt indicates a frame was captured in memory.
-    #[task(binds = DMA2_STREAM1, priority = 1, resources = [fb1])]
+    #[task(
+        binds = DMA2_STREAM1,
+        priority = 1,
+        resources = [fb1, raw, cam, &park, mot, mon],
+        spawn = [save_event, recover]
+    )]
     fn dma_isr(mut cx: dma_isr::Context) {
         // See if a frame capture completed, handle_dma_done will clear pending interrupt
-        if ov9655::handle_dma_done() {
-            // Update circular frame buffer, must be done in a lock since lower priority task
-            let address = cx.resources.fb1.lock(|fb1| fb1.update(true));
-
-            // Draw image on display using DMA2D
-            match display::draw_image(address, FRAME_WIDTH, FRAME_HEIGHT) {
-                true => rprintln!("Error: Cannot display image. Frame rate too fast!"),
-                false => (),
+        let frame_done = match ov9655::handle_dma_done() {
+            Ok(done) => done,
+            Err(e) => {
+                error!("Capture failed: {:?}", log::dbg(&e));
+                cx.spawn.recover().ok();
+                false
+            }
+        };
+
+        if frame_done {
+            // Parking mode: Snapshot is done, put the camera back to sleep until the next one
+            if *cx.resources.park {
+                if let Err(e) = cx.resources.cam.set_sleep(true) {
+                    error!("Cannot put camera to sleep: {:?}", log::dbg(&e));
+                }
+            }
+
+            // Update capture slots
+            let fb1 = &mut *cx.resources.fb1;
+            let address = fb1.update(true);
+
+            // Record the frame and draw it on the display using DMA2D, unless it was dropped by
+            // the frame buffer
+            if let Some(address) = address {
+                // Replaces the previous frame if the idle task did not get to it yet
+                let seq = fb1.num_captured() - 1;
+                let raw = &mut *cx.resources.raw;
+                raw.count = seq + 1;
+                if raw.frame.replace((address, seq)).is_some() {
+                    cx.resources.mon.lock(|mon| mon.record_drop());
+                }
+
+                match display::draw_image(address, FRAME_WIDTH, FRAME_HEIGHT) {
+                    true => {
+                        error!("Cannot display image. Frame rate too fast!");
+                        cx.resources.mon.lock(|mon| mon.display_drop());
+                    }
+                    false => (),
+                };
+
+                // Compare with the previous frame, save buffered video if something moved
+                let frame = unsafe {
+                    core::slice::from_raw_parts(address as *const u16, (FRAME_SIZE / 2) as usize)
+                };
+                if cx
+                    .resources
+                    .mot
+                    .update(frame, FRAME_WIDTH as usize, FRAME_HEIGHT as usize)
+                {
+                    info!("Motion detected!");
+                    cx.spawn.save_event().ok();
+                }
+            }
+        }
+    }
+
+    // Handle DCMI interrupts, which are only enabled for errors.
+    #[task(binds = DCMI, priority = 1, spawn = [recover])]
+    fn dcmi_isr(cx: dcmi_isr::Context) {
+        if let Err(e) = ov9655::handle_dcmi_error() {
+            error!("Capture failed: {:?}", log::dbg(&e));
+            cx.spawn.recover().ok();
+        }
+    }
+
+    // Handle health monitor timer interrupts. Resets the capture pipeline when frames stop
+    // arriving and feeds the watchdog, unless the pipeline could not be recovered. Also polls for
+    // commands from the host.
+    #[task(
+        binds = TIM5,
+        priority = 3,
+        resources = [mon, cmd, lines],
+        spawn = [recover, command]
+    )]
+    fn health_isr(cx: health_isr::Context) {
+        if !timer::tim5_isr() {
+            return;
+        }
+        timer::add_uptime(HEALTH_TICK_MS);
+
+        // Parse received commands, they are handled in a lower priority task
+        let mut buf = [0; 16];
+        loop {
+            let len = cx.resources.cmd.read(&mut buf);
+            for byte in &buf[..len] {
+                match cx.resources.lines.push(*byte) {
+                    Some(Ok(cmd)) => {
+                        if cx.spawn.command(cmd).is_err() {
+                            warn!("Command dropped, still busy: {:?}", log::dbg(&cmd));
+                        }
+                    }
+                    Some(Err(e)) => warn!("Invalid command: {:?}", log::dbg(&e)),
+                    None => (),
+                };
+            }
+
+            if len < buf.len() {
+                break;
+            }
+        }
+
+        // No frames are expected once capture was stopped on purpose
+        let frames = ov9655::get_stats().frames;
+        let health = match ov9655::is_started() {
+            true => cx.resources.mon.check(HEALTH_TICK_MS, frames),
+            false => {
+                cx.resources.mon.pause(frames);
+                Health::Ok
+            }
+        };
+
+        // Capture is healthy while it is recovering, but not after giving up
+        match health {
+            Health::Ok => watchdog::check_in(Task::Capture),
+            Health::Stalled => {
+                error!(
+                    "Capture stalled: {:?}",
+                    log::dbg(&cx.resources.mon.get_stats())
+                );
+                cx.spawn.recover().ok();
+                watchdog::check_in(Task::Capture);
+            }
+            Health::Failed => error!("Capture failed, waiting for watchdog reset"),
+        };
+
+        watchdog::service();
+    }
+
+    // Reset the capture pipeline after a capture error and resume capturing.
+    #[task(priority = 1, resources = [cam, fb1, &park, replaying])]
+    fn recover(mut cx: recover::Context) {
+        // Capture was stopped on purpose to replay a clip
+        if cx.resources.replaying.lock(|replaying| *replaying) {
+            return;
+        }
+
+        match cx.resources.cam.reset() {
+            Ok(()) => {
+                // DMA starts over with address 0, parking mode waits for the next snapshot
+                cx.resources.fb1.restart();
+                if *cx.resources.park {
+                    cx.resources.cam.set_sleep(true).ok();
+                } else {
+                    ov9655::start();
+                }
+
+                info!(
+                    "Capture pipeline reset: {:?}",
+                    log::dbg(&ov9655::get_stats())
+                );
+            }
+            Err(e) => error!("Cannot reset capture pipeline: {:?}", log::dbg(&e)),
+        };
+    }
+
+    // Handle a command received from the host.
+    #[task(
+        priority = 1,
+        capacity = 4,
+        resources = [nvm, cam, mon, &park, rst, vol, events, replaying],
+        spawn = [save_event, replay]
+    )]
+    fn command(mut cx: command::Context, cmd: Command) {
+        let replaying = cx.resources.replaying.lock(|replaying| *replaying);
+
+        match cmd {
+            Command::Status => {
+                info!(
+                    "{:?}, uptime {} ms, Unix time {:?}, reset cause {:?}",
+                    log::dbg(&dashcam_state(replaying, *cx.resources.park)),
+                    timer::uptime_ms(),
+                    log::dbg(&timer::unix_time()),
+                    log::dbg(cx.resources.rst)
+                );
+                info!("Capture: {:?}", log::dbg(&ov9655::get_stats()));
+                info!(
+                    "Frames: {:?}",
+                    log::dbg(&cx.resources.mon.lock(|mon| mon.get_stats()))
+                );
+                info!(
+                    "Clips: {} ({} locked), waiting to be saved: {}, crashes: {}, log messages dropped: {}",
+                    cx.resources.nvm.lock(|nvm| nvm.clips().count()),
+                    cx.resources
+                        .nvm
+                        .lock(|nvm| nvm.clips().filter(|clip| clip.locked).count()),
+                    cx.resources.events.lock(|events| events.len()),
+                    crash::count(),
+                    log::dropped()
+                );
+            }
+            Command::ListClips => cx.resources.nvm.lock(|nvm| {
+                let mut count = 0;
+                for number in 0..clip_store::MAX_CLIPS {
+                    if let Some(clip) = nvm.clip(number) {
+                        let frames = clip_frame_count(nvm, clip).unwrap_or(0);
+                        info!(
+                            "Clip {}: {} frames, {} bytes{}",
+                            number,
+                            frames,
+                            clip.size,
+                            if clip.locked { ", locked" } else { "" }
+                        );
+                        count += 1;
+                    }
+                }
+                if count == 0 {
+                    info!("No clips saved");
+                }
+            }),
+            Command::SaveNow => match replaying {
+                false => {
+                    cx.spawn.save_event().ok();
+                }
+                true => warn!("Cannot save video while replaying"),
+            },
+            Command::ReplayClip(clip) => {
+                cx.spawn.replay(clip as usize).ok();
+            }
+            Command::EraseClip(clip) => {
+                match cx.resources.nvm.lock(|nvm| nvm.erase_clip(clip as usize)) {
+                    Ok(()) => {
+                        info!("Clip {} erased", clip);
+                        let nvm = &mut cx.resources.nvm;
+                        cx.resources
+                            .vol
+                            .lock(|vol| nvm.lock(|nvm| set_volume_clips(nvm, vol)));
+                    }
+                    Err(flashfs::Error::NotFound) => warn!("No clip {}", clip),
+                    Err(e) => error!("Cannot erase clip: {:?}", log::dbg(&e)),
+                };
+            }
+            Command::LockClip(clip) | Command::UnlockClip(clip) => {
+                let locked = matches!(cmd, Command::LockClip(_));
+                match cx
+                    .resources
+                    .nvm
+                    .lock(|nvm| nvm.lock_clip(clip as usize, locked))
+                {
+                    Ok(()) if locked => info!("Clip {} locked", clip),
+                    Ok(()) => info!("Clip {} unlocked", clip),
+                    Err(flashfs::Error::NotFound) => warn!("No clip {}", clip),
+                    Err(e) => error!("Cannot lock clip: {:?}", log::dbg(&e)),
+                };
+            }
+            Command::SetTime(unix_time) => {
+                timer::set_time(unix_time);
+                info!("Unix time set to {}", unix_time);
+            }
+            Command::SetConfig(setting, value) => {
+                let cam = &mut *cx.resources.cam;
+                cx.resources
+                    .nvm
+                    .lock(|nvm| handle_set_config(nvm, cam, setting, value));
+            }
+        };
+    }
+
+    // Handle a save event from motion detection, the button or the host. The clip covers the
+    // configured windows before and after the newest recorded frame, the idle task saves it once
+    // the last frame of the window is recorded. Clips of events close to each other are merged.
+    #[task(priority = 2, resources = [nvm, ring, events, replaying])]
+    fn save_event(cx: save_event::Context) {
+        if *cx.resources.replaying {
+            warn!("Cannot save video while replaying");
+            return;
+        }
+
+        let config = cx.resources.nvm.get_config();
+        let trigger = cx.resources.ring.newest().map_or(0, |meta| meta.seq);
+        let seqs = event_window(trigger, config);
+        match cx.resources.events.push(seqs.clone()) {
+            true => info!(
+                "Save event at frame {}, frames {} to {}",
+                trigger,
+                seqs.start(),
+                seqs.end()
+            ),
+            false => warn!("Too many clips waiting to be saved, save event dropped"),
+        };
+    }
+
+    // Replay saved clip `number` on the display in a loop, capture is stopped until recording is
+    // resumed. The replayed frames overwrite the frame ring, so no clips may be waiting to be
+    // saved.
+    #[task(priority = 2, resources = [nvm, fb2, delta, clk, events, replaying])]
+    fn replay(cx: replay::Context, number: usize) {
+        if !cx.resources.events.is_empty() {
+            warn!("Cannot replay while clips are being saved");
+            return;
+        }
+        let clip = match cx.resources.nvm.clip(number) {
+            Some(clip) => clip,
+            None => {
+                warn!("No clip {}", number);
+                return;
+            }
+        };
+
+        // Stop capturing video
+        ov9655::stop();
+        *cx.resources.replaying = true;
+        let frame_period = load_clip(cx.resources.fb2, cx.resources.nvm, clip, cx.resources.delta);
+
+        // Frames are drawn from the timer interrupt, simulating the captured frame rate
+        info!(
+            "Playing back clip {}! Press button to resume recording.",
+            number
+        );
+        timer::tim2_start(cx.resources.clk, frame_period);
+    }
+
+    // Stop replaying and resume recording, which starts over with an empty frame ring.
+    #[task(priority = 2, resources = [nvm, ring, clk, &park, replaying], spawn = [recover])]
+    fn resume(cx: resume::Context) {
+        if *cx.resources.replaying {
+            // Parking mode: Back to capturing a frame on each timer interrupt
+            timer::tim2_stop();
+            if *cx.resources.park {
+                let interval = cx.resources.nvm.get_config().parking_interval_ms;
+                timer::tim2_start(cx.resources.clk, interval);
+            }
+
+            info!("Replay stopped, resuming capture");
+            cx.resources.ring.clear();
+            *cx.resources.replaying = false;
+            cx.spawn.recover().ok();
+        }
+    }
+
+    // Handle timer interrupts. While recording, this is parking mode: Wake up the camera and
+    // capture a single frame. While replaying, draw the next frame of the replayed clip.
+    #[task(binds = TIM2, priority = 1, resources = [cam, fb2, replaying])]
+    fn timer_isr(mut cx: timer_isr::Context) {
+        if timer::tim2_isr() {
+            match cx.resources.replaying.lock(|replaying| *replaying) {
+                false => match cx.resources.cam.set_sleep(false) {
+                    Ok(()) => ov9655::start(),
+                    Err(e) => error!("Cannot wake up camera: {:?}", log::dbg(&e)),
+                },
+                true => {
+                    // Play the replayed frames in a loop
+                    let address = cx
+                        .resources
+                        .fb2
+                        .lock(|fb2| fb2.next().or_else(|| fb2.rewind().next()));
+
+                    if let Some(address) = address {
+                        match display::draw_image(address, FRAME_WIDTH, FRAME_HEIGHT) {
+                            true => error!("Cannot display image. Frame rate too fast!"),
+                            false => (),
+                        };
+                    }
+                }
             };
         }
     }
 
-    // Handle a button interrupt. First press saves buffered video to NVM, second press reads
-    // saved video from NVM and plays it on the display in a loop.
-    #[task(binds = EXTI15_10, priority = 2, resources = [nvm, fb1, fb2, but, dly, pbn])]
+    // Handle USB interrupts. The host reads the saved clips from the mass storage device, or
+    // uses the protocol of the serial port to download and delete them.
+    #[task(
+        binds = OTG_FS,
+        priority = 2,
+        resources = [usb_dev, msc, serial, link, vol, nvm, &park, replaying],
+        spawn = [command]
+    )]
+    fn usb_isr(cx: usb_isr::Context) {
+        let msc = cx.resources.msc;
+        let serial = cx.resources.serial;
+        if cx.resources.usb_dev.poll(&mut [&mut *msc, &mut *serial]) {
+            msc.process(&mut FatDisk {
+                volume: cx.resources.vol,
+                storage: cx.resources.nvm,
+            });
+        }
+
+        let state = dashcam_state(*cx.resources.replaying, *cx.resources.park);
+        let clips = cx.resources.vol.clips();
+        let spawn = cx.spawn;
+        cx.resources.link.poll(
+            serial,
+            clips,
+            cx.resources.nvm,
+            || DeviceStatus {
+                state,
+                uptime_ms: timer::uptime_ms(),
+                unix_time: timer::unix_time().unwrap_or(0),
+                clips: clips.iter().filter(|clip| clip.len > 0).count() as u32,
+                crashes: crash::count(),
+            },
+            // Erased like with the `erase` command, which also updates the volume and logs it
+            |clip| spawn.command(Command::EraseClip(clip)).is_ok(),
+        );
+    }
+
+    // Handle a button interrupt. A press while recording is a save event, while replaying it
+    // resumes recording.
+    #[task(
+        binds = EXTI15_10,
+        priority = 2,
+        resources = [but, replaying],
+        spawn = [save_event, resume]
+    )]
     fn button_isr(cx: button_isr::Context) {
         // Clear pending interrupt
         cx.resources.but.clear_interrupt_pending_bit();
 
         // Handle button presses
-        *cx.resources.pbn += 1;
-        if *cx.resources.pbn == 1 {
-            handle_button1(cx.resources.fb1, cx.resources.nvm);
-        } else if *cx.resources.pbn == 2 {
-            handle_button2(cx.resources.fb2, cx.resources.nvm, cx.resources.dly);
-        }
+        match *cx.resources.replaying {
+            false => cx.spawn.save_event().ok(),
+            true => cx.spawn.resume().ok(),
+        };
+    }
+
+    // Interrupts used to dispatch software tasks.
+    extern "C" {
+        fn SPI2();
+        fn SPI3();
     }
 };
 
-/// Handle the first push button press.
-fn handle_button1(fb: &mut FrameBuffer, nvm: &mut NvmDriver) {
-    // Stop capturing video
-    ov9655::stop();
+/// State of the dash cam given whether a clip is being replayed and parking mode is used.
+fn dashcam_state(replaying: bool, park: bool) -> State {
+    match (replaying, park) {
+        (false, false) => State::Recording,
+        (false, true) => State::Parking,
+        (true, _) => State::Replaying,
+    }
+}
+
+/// Sequence numbers of the frames in the clip of a save event at frame `trigger`, with the
+/// windows before and after it set in `config`.
+fn event_window(trigger: u32, config: Config) -> RangeInclusive<u32> {
+    let pre = config.frames(config.pre_event);
+    let post = config.frames(config.post_event);
+    trigger.saturating_sub(pre)..=trigger.saturating_add(post)
+}
 
-    // Save buffered video to non-volatile memory
-    rprintln!("Saving frames to non-volatile memory!");
-    for address in fb {
-        nvm.write(address, FRAME_SIZE as usize).unwrap();
+/// Put the saved clips on the USB mass storage volume, clip `n` as file `CLIPnnnn.CLP`.
+fn set_volume_clips(nvm: &NvmDriver, vol: &mut FatVolume) {
+    let mut clips = [Clip::default(); clip_store::MAX_CLIPS];
+    let mut len = 0;
+    for clip in nvm.clips() {
+        clips[clip.number] = Clip {
+            addr: clip.number as u32 * CLIP_SPACING,
+            len: clip.size,
+            time: clip.time,
+        };
+        len = clip.number + 1;
     }
+    vol.set_clips(&clips[..len]);
+}
+
+/// Frames of `ring` in the window `seqs` which go in a clip: The newest ones in the same format
+/// as the newest frame of the window, up to `MAX_CLIP_FRAMES` of them.
+fn clip_frames<'a>(
+    ring: &'a FrameRing,
+    seqs: RangeInclusive<u32>,
+) -> impl ExactSizeIterator<Item = (Meta, &'a [u8])> {
+    let records = ring.window(seqs);
+    let newest = records.clone().last().map(|(_, _, meta)| meta);
+    let mut count = 0;
+    for (_, _, meta) in records.clone() {
+        match newest.is_some_and(|newest| newest.same_format(&meta)) {
+            true => count += 1,
+            false => count = 0,
+        }
+    }
+    let len = records.len();
+    records.with_data().skip(len - count.min(MAX_CLIP_FRAMES))
+}
+
+/// Header for a clip of the frames of `ring` in the window `seqs`, which were captured every
+/// `period_ms` until now. Raw frames are delta coded in the clip. Parking mode clips get a frame
+/// rate of 1 fps, their frames are timed by the parking interval in the frame index.
+fn clip_header(ring: &FrameRing, seqs: RangeInclusive<u32>, period_ms: u32) -> clip::Header {
+    let mut frames = clip_frames(ring, seqs);
+    let frame_count = frames.len() as u32;
+    let first = frames.next().map(|(meta, _)| meta);
+    let last = frames.last().map(|(meta, _)| meta).or(first);
+    let (first_seq, last_seq) = (first.map_or(0, |m| m.seq), last.map_or(0, |m| m.seq));
+    let duration = (last_seq - first_seq) * period_ms / 1000;
+    let (width, height, codec) = last.map_or((FRAME_WIDTH, FRAME_HEIGHT, Codec::Raw), |meta| {
+        (meta.width, meta.height, meta.codec)
+    });
+    clip::Header {
+        width,
+        height,
+        pixel_format: PixelFormat::Rgb565,
+        codec: match codec {
+            Codec::Raw => Codec::Delta,
+            codec => codec,
+        },
+        frame_rate: (1000 / period_ms).max(1) as u16,
+        frame_count,
+        start_time: timer::unix_time().map_or(0, |time| time.saturating_sub(duration)),
+    }
+}
+
+/// The frame captured last, handed from the capture task to the idle task.
+struct Capture {
+    /// Address and sequence number of the frame, until it is recorded.
+    frame: Option<(u32, u32)>,
+    /// Number of frames captured. Frame `seq + CAPTURE_SLOTS` is captured into the slot of frame
+    /// `seq`.
+    count: u32,
+}
+
+/// Records the captured frames in the frame ring, for the idle task.
+struct Recorder<'a, RAW, RING, MON> {
+    raw: RAW,
+    ring: RING,
+    mon: MON,
+    /// Compresses the frames, they are recorded raw without it.
+    enc: &'a mut Option<Encoder>,
+    /// Buffer the frames are compressed into.
+    jpg: &'a mut [u8],
+}
+
+impl<RAW, RING, MON> Recorder<'_, RAW, RING, MON>
+where
+    RAW: Mutex<T = Capture>,
+    RING: Mutex<T = FrameRing<'static>>,
+    MON: Mutex<T = CaptureMonitor>,
+{
+    /// Record the frame captured last, if one was captured since. Returns `false` if not.
+    fn record(&mut self) -> bool {
+        let (address, seq) = match self.raw.lock(|raw| raw.frame.take()) {
+            Some(raw) => raw,
+            None => return false,
+        };
+
+        let frame =
+            unsafe { core::slice::from_raw_parts(address as *const u8, FRAME_SIZE as usize) };
+        let jpg = &mut *self.jpg;
+        let (codec, data) = match self.enc {
+            Some(encoder) => match encoder.encode(frame, FRAME_WIDTH, FRAME_HEIGHT, jpg) {
+                Some(len) => (Codec::Jpeg, &jpg[..len]),
+                None => {
+                    error!("Frame {} too large to compress", seq);
+                    return true;
+                }
+            },
+            None => (Codec::Raw, frame),
+        };
+
+        // Drop the frame if the camera started capturing into its slot while it was compressed.
+        // Raw frames are copied below, which takes a fraction of a frame period, so their slot
+        // must not be next.
+        let spare = match codec {
+            Codec::Raw => 1,
+            _ => 0,
+        };
+        if self.raw.lock(|raw| raw.count) - seq + spare >= CAPTURE_SLOTS {
+            self.mon.lock(|mon| mon.record_drop());
+            return true;
+        }
+        let meta = Meta {
+            seq,
+            width: FRAME_WIDTH,
+            height: FRAME_HEIGHT,
+            codec,
+        };
 
-    rprintln!("Video saved! Press button to replay saved video.");
+        let (recorded, max_len) = self
+            .ring
+            .lock(|ring| (ring.push(meta, data), ring.max_frame_len()));
+        match (recorded, data.len() > max_len) {
+            (true, _) => (),
+            (false, true) => error!("Frame {} too large to record", seq),
+            // The ring is full of frames of a clip being saved
+            (false, false) => self.mon.lock(|mon| mon.record_drop()),
+        };
+        true
+    }
 }
 
-/// Handle the second push button press.
-fn handle_button2(fb: &mut FrameBuffer, nvm: &mut NvmDriver, dly: &mut Delay) {
-    // Read buffered video from non-volatile memory into a new frame buffer
-    rprintln!("Reading frames from non-volatile memory!");
-    let num_frames = nvm.get_write_ptr() / FRAME_SIZE;
-    for _ in 0..num_frames {
-        let address = fb.update(false);
-        nvm.read(address, FRAME_SIZE as usize).unwrap();
+/// Save the frames of the frame ring in the window `seqs` as a new clip in the NVM, keeping its
+/// index in `index`. The frames are written one at a time, recording the captured frames in
+/// between. Frames of the clip are protected in the frame ring until they are written. Returns
+/// the number of the clip.
+fn save_clip<RAW, RING, MON, NVM, DELTA>(
+    recorder: &mut Recorder<RAW, RING, MON>,
+    nvm: &mut NVM,
+    delta: &mut DELTA,
+    seqs: RangeInclusive<u32>,
+    index: &mut [FrameEntry],
+) -> Result<usize, ClipError>
+where
+    RAW: Mutex<T = Capture>,
+    RING: Mutex<T = FrameRing<'static>>,
+    MON: Mutex<T = CaptureMonitor>,
+    NVM: Mutex<T = NvmDriver>,
+    DELTA: Mutex<T = &'static mut [u8]>,
+{
+    let period_ms = nvm.lock(|nvm| nvm.get_config().period_ms());
+    let (header, first) = recorder.ring.lock(|ring| {
+        let first = clip_frames(ring, seqs.clone())
+            .next()
+            .map(|(meta, _)| meta.seq);
+        ring.protect(first);
+        (clip_header(ring, seqs.clone(), period_ms), first)
+    });
+
+    let time = timer::unix_time().unwrap_or(0);
+    let result = match first {
+        Some(first) => nvm
+            .lock(|nvm| nvm.create_clip(time))
+            .map_err(clip::Error::Io)
+            .and_then(|number| {
+                let seqs = first..=*seqs.end();
+                write_clip(recorder, nvm, delta, seqs, header, period_ms, index)?;
+                nvm.lock(|nvm| nvm.finish_clip()).map_err(clip::Error::Io)?;
+                Ok(number)
+            }),
+        None => Err(clip::Error::FrameCount),
+    };
+
+    if result.is_err() {
+        nvm.lock(|nvm| nvm.discard_clip());
     }
+    recorder.ring.lock(|ring| ring.protect(None));
+    result
+}
 
-    rprintln!("Playing back images in frame buffer!");
+/// Write the frames of the frame ring in the window `seqs` to the clip being written in the NVM,
+/// described by `header`, see `save_clip`. Frames are timed by their sequence number and the time
+/// between frames `period_ms`, so frames which could not be recorded leave a gap. Raw frames are
+/// delta coded into `delta` for a delta coded clip. Returns the size of the clip in bytes.
+fn write_clip<RAW, RING, MON, NVM, DELTA>(
+    recorder: &mut Recorder<RAW, RING, MON>,
+    nvm: &mut NVM,
+    delta: &mut DELTA,
+    seqs: RangeInclusive<u32>,
+    header: clip::Header,
+    period_ms: u32,
+    index: &mut [FrameEntry],
+) -> Result<u32, ClipError>
+where
+    RAW: Mutex<T = Capture>,
+    RING: Mutex<T = FrameRing<'static>>,
+    MON: Mutex<T = CaptureMonitor>,
+    NVM: Mutex<T = NvmDriver>,
+    DELTA: Mutex<T = &'static mut [u8]>,
+{
+    let mut writer = nvm.lock(|nvm| ClipWriter::new(nvm, header, index))?;
+    let (first, last) = (*seqs.start(), *seqs.end());
+    let mut next = first;
+    let mut prev = None;
     loop {
-        // Clone since we do this on a loop, exhausting the iterator each time
-        let curr_fb = fb.clone();
-
-        // Iterate on the frame buffer
-        for address in curr_fb {
-            // Draw image on display using DMA2D
-            match display::draw_image(address, FRAME_WIDTH, FRAME_HEIGHT) {
-                true => rprintln!("Error: Cannot display image. Frame rate too fast!"),
-                false => (),
-            };
+        watchdog::check_in(Task::Idle);
+        recorder.record();
+
+        // The written frame stays protected, it is the reference of the next delta coded one
+        let written = recorder.ring.lock(|ring| {
+            nvm.lock(|nvm| {
+                delta.lock(|delta| -> Result<Option<u32>, ClipError> {
+                    let (meta, frame) = match clip_frames(ring, next..=last).next() {
+                        Some(frame) => frame,
+                        None => return Ok(None),
+                    };
+                    let data = match header.codec {
+                        Codec::Delta => {
+                            let prev = prev
+                                .and_then(|prev| ring.window(prev..=prev).with_data().next())
+                                .map(|(_, data)| data);
+                            let len =
+                                codec::encode(frame, prev, header.width, header.height, delta)
+                                    .ok_or(clip::Error::BufferTooSmall)?;
+                            &delta[..len]
+                        }
+                        _ => frame,
+                    };
+                    writer.write_frame(nvm, data, (meta.seq - first) * period_ms)?;
+                    ring.protect(Some(meta.seq));
+                    Ok(Some(meta.seq))
+                })
+            })
+        })?;
+
+        match written {
+            Some(seq) if seq < last => {
+                prev = Some(seq);
+                next = seq + 1;
+            }
+            _ => break,
+        };
+    }
+    nvm.lock(|nvm| writer.finish(nvm))
+}
 
-            // Block to simulate captured frame rate
-            dly.delay_ms(FRAME_RATE);
+/// Setup the SD card and mount its FAT32 filesystem, if there is a card with one.
+#[cfg(feature = "sdcard")]
+fn setup_card(sdmmc: pac::SDMMC1, clocks: &Clocks) -> Option<(SdCard, Volume)> {
+    let mut card = match SdCard::new(sdmmc, clocks) {
+        Ok(card) => card,
+        Err(e) => {
+            warn!("No SD card: {:?}", log::dbg(&e));
+            return None;
         }
+    };
+
+    match Volume::mount(&mut card) {
+        Ok(volume) => {
+            info!("SD card successfully initialized!");
+            Some((card, volume))
+        }
+        Err(e) => {
+            warn!("No FAT32 filesystem on the SD card: {:?}", log::dbg(&e));
+            None
+        }
+    }
+}
+
+/// Copy saved clip `clip` of the NVM to a new clip file on the SD card, recording the captured
+/// frames in between. Returns the number of the clip file. A clip cut short by a full card is
+/// kept.
+#[cfg(feature = "sdcard")]
+fn copy_to_card<RAW, RING, MON, NVM>(
+    recorder: &mut Recorder<RAW, RING, MON>,
+    nvm: &mut NVM,
+    clip: StoredClip,
+    card: &mut SdCard,
+    volume: &mut Volume,
+) -> Result<u32, CopyError>
+where
+    RAW: Mutex<T = Capture>,
+    RING: Mutex<T = FrameRing<'static>>,
+    MON: Mutex<T = CaptureMonitor>,
+    NVM: Mutex<T = NvmDriver>,
+{
+    let mut file = volume
+        .create_clip(card, clip.time)
+        .map_err(CopyError::Card)?;
+    let mut buf = [0; 4096];
+    let mut offset = 0;
+    let mut result = Ok(());
+    while offset < clip.size && result.is_ok() {
+        watchdog::check_in(Task::Idle);
+        recorder.record();
+
+        let len = (clip.size - offset).min(buf.len() as u32) as usize;
+        let addr = clip.number as u32 * CLIP_SPACING + offset;
+        result = nvm
+            .lock(|nvm| nvm.read_at(addr, &mut buf[..len]))
+            .map_err(CopyError::Nvm)
+            .and_then(|_| {
+                volume
+                    .write(card, &mut file, &buf[..len])
+                    .map_err(CopyError::Card)
+            });
+        offset += len as u32;
     }
+
+    let number = file.number;
+    volume.close(card, file).map_err(CopyError::Card)?;
+    result.map(|_| number)
+}
+
+/// Errors copying a clip to the SD card.
+#[cfg(feature = "sdcard")]
+#[derive(Debug)]
+enum CopyError {
+    Nvm(flashfs::Error<qspi::QspiError>),
+    Card(fat32::Error<SdError>),
+}
+
+/// Read saved clip `clip` into `fb` for replay. Returns the time between frames for playback.
+fn load_clip(fb: &mut FrameBuffer, nvm: &mut NvmDriver, clip: StoredClip, delta: &mut [u8]) -> u32 {
+    // Read the clip from non-volatile memory into the frame buffer
+    info!("Reading frames from non-volatile memory!");
+    fb.clear();
+    watchdog::begin(Task::Save);
+    let result = read_clip(fb, nvm, clip, delta);
+    watchdog::end(Task::Save);
+
+    // Frames are played back at the frame rate they were saved with
+    match result {
+        Ok(frame_rate) => 1000 / frame_rate as u32,
+        Err(e) => {
+            error!("Cannot read saved clip: {:?}", log::dbg(&e));
+            nvm.get_config().frame_rate.period_ms()
+        }
+    }
+}
+
+/// Number of frames of saved clip `clip`, if it can be read.
+fn clip_frame_count(nvm: &mut NvmDriver, clip: StoredClip) -> Option<u32> {
+    ClipReader::open(&mut nvm.clip_source(clip.number), clip.size)
+        .ok()
+        .map(|reader| reader.header().frame_count)
+}
+
+/// Read the frames of saved clip `clip` into `fb`, coded frames are read into `delta` and decoded
+/// into the frame buffer. Returns the frame rate of the clip.
+fn read_clip(
+    fb: &mut FrameBuffer,
+    nvm: &mut NvmDriver,
+    clip: StoredClip,
+    delta: &mut [u8],
+) -> Result<u16, ClipError> {
+    let mut source = nvm.clip_source(clip.number);
+    let reader = ClipReader::open(&mut source, clip.size)?;
+    let header = *reader.header();
+
+    if (header.width, header.height) != (FRAME_WIDTH, FRAME_HEIGHT) {
+        return Err(clip::Error::Unsupported);
+    }
+
+    let mut prev = None;
+    for n in 0..header.frame_count {
+        let address = fb.update(false).unwrap();
+        let frame =
+            unsafe { core::slice::from_raw_parts_mut(address as *mut u8, FRAME_SIZE as usize) };
+        match header.codec {
+            Codec::Delta => {
+                let len = reader.read_frame(&mut source, n, delta)?;
+                let prev = prev.map(|address| unsafe {
+                    core::slice::from_raw_parts(address as *const u8, FRAME_SIZE as usize)
+                });
+                codec::decode(&delta[..len], prev, FRAME_WIDTH, FRAME_HEIGHT, frame)
+                    .map_err(|_| clip::Error::Corrupt)?;
+            }
+            Codec::Jpeg => {
+                let len = reader.read_frame(&mut source, n, delta)?;
+                jpeg::decode(&delta[..len], FRAME_WIDTH, FRAME_HEIGHT, frame)
+                    .map_err(|_| clip::Error::Corrupt)?;
+            }
+            Codec::Raw => {
+                reader.read_frame(&mut source, n, frame)?;
+            }
+        }
+        prev = Some(address);
+        watchdog::check_in(Task::Save);
+    }
+    Ok(header.frame_rate.max(1))
+}
+
+/// Handle a `set` command from the host. Orientation changes take effect right away, event
+/// windows with the next save event and all other settings after the next reset.
+fn handle_set_config(nvm: &mut NvmDriver, cam: &mut Ov9655, setting: Setting, value: u32) {
+    let config = nvm.get_config();
+    // Windows in seconds are limited at the current frame rate and parking interval
+    let fits = |window| config.frames(window) as usize <= MAX_CLIP_FRAMES;
+    let new_config = match setting {
+        Setting::Orientation => Orientation::from_u32(value).map(|orientation| Config {
+            orientation,
+            ..config
+        }),
+        Setting::FrameRate => FrameRate::from_u32(value).map(|frame_rate| Config {
+            frame_rate,
+            ..config
+        }),
+        Setting::ParkingInterval => Some(Config {
+            parking_interval_ms: value,
+            ..config
+        }),
+        Setting::MotionThreshold => {
+            let mut new_config = config;
+            new_config.motion.threshold = value;
+            Some(new_config)
+        }
+        Setting::MotionArea if value <= 100 => {
+            let mut new_config = config;
+            new_config.motion.area_pct = value;
+            Some(new_config)
+        }
+        Setting::MotionArea => None,
+        Setting::Quality if value <= 100 => Some(Config {
+            quality: value,
+            ..config
+        }),
+        Setting::Quality => None,
+        Setting::PreEvent if fits(Window::Seconds(value)) => Some(Config {
+            pre_event: Window::Seconds(value),
+            ..config
+        }),
+        Setting::PostEvent if fits(Window::Seconds(value)) => Some(Config {
+            post_event: Window::Seconds(value),
+            ..config
+        }),
+        Setting::PreEventFrames if fits(Window::Frames(value)) => Some(Config {
+            pre_event: Window::Frames(value),
+            ..config
+        }),
+        Setting::PostEventFrames if fits(Window::Frames(value)) => Some(Config {
+            post_event: Window::Frames(value),
+            ..config
+        }),
+        Setting::PreEvent
+        | Setting::PostEvent
+        | Setting::PreEventFrames
+        | Setting::PostEventFrames => None,
+    };
+
+    let new_config = match new_config {
+        Some(new_config) => new_config,
+        None => {
+            warn!("Invalid value {} for {:?}", value, log::dbg(&setting));
+            return;
+        }
+    };
+
+    if let Err(e) = nvm.store_config(new_config) {
+        error!("Cannot save config: {:?}", log::dbg(&e));
+        return;
+    }
+    info!("Saved config: {:?}", log::dbg(&new_config));
+
+    match setting {
+        Setting::Orientation => {
+            if let Err(e) = cam.set_orientation(new_config.orientation) {
+                error!("Cannot change orientation: {:?}", log::dbg(&e));
+            }
+        }
+        Setting::PreEvent
+        | Setting::PostEvent
+        | Setting::PreEventFrames
+        | Setting::PostEventFrames => (),
+        _ => info!("Reset to apply the new config"),
+    };
 }
@@ -0,0 +1,113 @@
+//! Motion detection for parking surveillance. Each frame is reduced to a small map of average
+//! luma values, which is compared against the map of the previous frame. Only depends on `core`,
+//! so the detection algorithm is tested on the host with synthetic frames, see
+//! `tools/fwtest/tests/motion.rs`.
+
+/// Number of horizontal cells in a luma map.
+pub const MAP_WIDTH: usize = 16;
+
+/// Number of vertical cells in a luma map.
+pub const MAP_HEIGHT: usize = 12;
+
+/// Number of cells in a luma map.
+pub const MAP_SIZE: usize = MAP_WIDTH * MAP_HEIGHT;
+
+/// Only every Nth pixel (horizontally and vertically) in a cell is sampled, to keep the ISR short.
+const SAMPLE_STEP: usize = 4;
+
+/// Downsampled luma (brightness) of a frame, one value per cell in row-major order.
+pub type LumaMap = [u8; MAP_SIZE];
+
+/// Motion detection settings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MotionSettings {
+    /// A cell has changed if its luma changed by more than this (0 - 255). 0 disables detection.
+    pub threshold: u32,
+    /// Motion is detected if at least this percentage of cells changed.
+    pub area_pct: u32,
+}
+
+/// Motion detector which remembers the luma map of the previous frame.
+pub struct MotionDetector {
+    settings: MotionSettings,
+    prev: Option<LumaMap>,
+}
+
+impl MotionDetector {
+    /// Create a new motion detector.
+    pub fn new(settings: MotionSettings) -> Self {
+        MotionDetector {
+            settings,
+            prev: None,
+        }
+    }
+
+    /// Process the next RGB565 frame. Returns `true` if there was motion since the last frame.
+    pub fn update(&mut self, frame: &[u16], width: usize, height: usize) -> bool {
+        if self.settings.threshold == 0 {
+            return false;
+        }
+
+        let curr = luma_map(frame, width, height);
+        let motion = match &self.prev {
+            Some(prev) => detect(prev, &curr, &self.settings),
+            None => false,
+        };
+
+        self.prev = Some(curr);
+        motion
+    }
+}
+
+/// Reduce a `width` x `height` RGB565 frame to a luma map by averaging the luma of each cell.
+pub fn luma_map(frame: &[u16], width: usize, height: usize) -> LumaMap {
+    assert!(width >= MAP_WIDTH && height >= MAP_HEIGHT && frame.len() >= width * height);
+
+    let cell_width = width / MAP_WIDTH;
+    let cell_height = height / MAP_HEIGHT;
+    let mut map = [0; MAP_SIZE];
+
+    for (i, cell) in map.iter_mut().enumerate() {
+        let x0 = (i % MAP_WIDTH) * cell_width;
+        let y0 = (i / MAP_WIDTH) * cell_height;
+
+        let mut sum: u32 = 0;
+        let mut num: u32 = 0;
+        for y in (y0..y0 + cell_height).step_by(SAMPLE_STEP) {
+            for x in (x0..x0 + cell_width).step_by(SAMPLE_STEP) {
+                sum += luma(frame[y * width + x]) as u32;
+                num += 1;
+            }
+        }
+
+        *cell = (sum / num) as u8;
+    }
+
+    map
+}
+
+/// Compare two luma maps. Returns `true` if enough cells changed by more than the threshold.
+pub fn detect(prev: &LumaMap, curr: &LumaMap, settings: &MotionSettings) -> bool {
+    let changed = prev
+        .iter()
+        .zip(curr.iter())
+        .filter(|(p, c)| (**p as i32 - **c as i32).unsigned_abs() > settings.threshold)
+        .count() as u32;
+
+    changed > 0 && changed * 100 >= settings.area_pct * (MAP_SIZE as u32)
+}
+
+/// Luma of an RGB565 pixel using the BT.601 weights (0.299 R + 0.587 G + 0.114 B).
+fn luma(pixel: u16) -> u8 {
+    // Expand each color to 8 bits
+    let r = ((pixel >> 11) & 0x1F) as u32;
+    let g = ((pixel >> 5) & 0x3F) as u32;
+    let b = (pixel & 0x1F) as u32;
+    let (r, g, b) = (
+        (r << 3) | (r >> 2),
+        (g << 2) | (g >> 4),
+        (b << 3) | (b >> 2),
+    );
+
+    ((77 * r + 150 * g + 29 * b) >> 8) as u8
+}
@@ -1,29 +1,33 @@
-//! Abstraction layer for reading and writing frames to non-volatile memory.
+//! Abstraction layer for reading and writing clips, the config and logs to non-volatile memory.
+//! They are kept as files of the flash filesystem, see `clip_store` for the clips.
 
+use crate::clip::{Sink, Source};
+use crate::clip_store::{ClipStore, StoredClip, CLIP_SPACING};
+use crate::config::{Config, CONFIG_WORDS};
+use crate::flashfs::{Error, FileSystem};
 use core::fmt;
 
-/// Memory device API used by the `NonVolatileMemory` driver.
-pub trait Mem {
-    type Error;
+pub use crate::flashfs::Mem;
 
-    /// Read `len` bytes at NVM address `src` address to RAM address `dst`.
-    fn read(&mut self, dst: u32, src: u32, len: usize) -> Result<(), Self::Error>;
+/// File holding the config record.
+const CONFIG_FILE: &str = "config";
 
-    /// Write `len` bytes at RAM address `src` to NVM address `dst`.
-    fn write(&mut self, dst: u32, src: u32, len: usize) -> Result<(), Self::Error>;
+/// File holding the log lines added with `append_log`.
+const LOG_FILE: &str = "log";
 
-    /// Erase the NVM device, such that any section of it can be written.
-    fn erase(&mut self) -> Result<(), Self::Error>;
-}
+/// The log is started over when it would grow beyond this size in bytes.
+const MAX_LOG_SIZE: u32 = 64 * 1024;
 
 /// Handle for the NVM driver.
 pub struct NonVolatileMemory<MEM> {
     /// Memory device handle.
     device: MEM,
-    /// Write pointer (NVM address).
-    write_ptr: u32,
-    /// Read pointer (NVM address).
-    read_ptr: u32,
+    /// Filesystem on the device.
+    fs: FileSystem,
+    /// The saved clips, and the one being written until `finish_clip` is called.
+    clips: ClipStore,
+    /// Cached copy of the config record.
+    config: Config,
 }
 
 impl<MEM, E> NonVolatileMemory<MEM>
@@ -31,31 +35,164 @@ where
     MEM: Mem<Error = E>,
     E: fmt::Debug,
 {
-    /// Initialize the NVM driver.
-    pub fn new(mut device: MEM, start_addr: u32) -> Self {
-        device.erase().expect("Could not erase NVM device!");
+    /// Initialize the NVM driver, mounting the filesystem on the first `size` bytes of `device`.
+    /// If there is none, a new one is created which erases the device. The clips saved before the
+    /// reset are kept, the free blocks are erased so writing the next one is fast.
+    pub fn new(mut device: MEM, size: u32) -> Self {
+        let mut fs = match FileSystem::mount(&mut device, size) {
+            Ok(fs) => fs,
+            Err(Error::NoFilesystem) | Err(Error::Corrupt) => {
+                FileSystem::format(&mut device, size).expect("Could not format NVM device!")
+            }
+            Err(e) => panic!("Could not mount NVM device: {:?}", e),
+        };
+
+        fs.erase_free(&mut device, || ())
+            .expect("Could not erase NVM device!");
+
+        let config = read_config(&mut device, &fs).unwrap_or_default();
+        let clips = ClipStore::new(&fs);
         NonVolatileMemory {
             device,
-            write_ptr: start_addr,
-            read_ptr: start_addr,
+            fs,
+            clips,
+            config,
         }
     }
 
-    pub fn get_write_ptr(&mut self) -> u32 {
-        self.write_ptr
+    /// The saved clips, by number.
+    pub fn clips(&self) -> impl Iterator<Item = StoredClip> + '_ {
+        self.clips.clips(&self.fs)
+    }
+
+    /// Clip `number`, if it is saved.
+    pub fn clip(&self, number: usize) -> Option<StoredClip> {
+        self.clips.clip(&self.fs, number)
+    }
+
+    /// Number of bytes which can still be written.
+    pub fn free_space(&self) -> u32 {
+        self.fs.free_space()
     }
 
-    /// Write `size` bytes located in RAM at `src_address` to non-volatile memory.
-    pub fn write(&mut self, src_address: u32, size: usize) -> Result<(), E> {
-        self.device.write(self.write_ptr, src_address, size)?;
-        self.write_ptr += size as u32;
+    /// Get the settings loaded from non-volatile memory, or the defaults if none were saved.
+    pub fn get_config(&self) -> Config {
+        self.config
+    }
+
+    /// Save new settings to non-volatile memory.
+    pub fn store_config(&mut self, config: Config) -> Result<(), Error<E>> {
+        let mut bytes = [0; CONFIG_WORDS * 4];
+        for (chunk, word) in bytes.chunks_mut(4).zip(config.to_words().iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+
+        self.fs
+            .write_file(&mut self.device, CONFIG_FILE, 0, &bytes)?;
+        self.config = config;
         Ok(())
     }
 
-    /// Read `size` bytes located in non-volatile memory to SDRAM at `dst_address`.
-    pub fn read(&mut self, dst_address: u32, size: usize) -> Result<(), E> {
-        self.device.read(dst_address, self.read_ptr, size)?;
-        self.read_ptr += size as u32;
+    /// Add `line` to the log file, starting it over when it gets too big.
+    pub fn append_log(&mut self, line: &[u8]) -> Result<(), Error<E>> {
+        let size = self.fs.metadata(LOG_FILE).map(|meta| meta.size);
+        let mut writer = match size {
+            Some(size) if size + line.len() as u32 <= MAX_LOG_SIZE => {
+                self.fs.append(&mut self.device, LOG_FILE)?
+            }
+            _ => self.fs.create(LOG_FILE, 0, 0)?,
+        };
+
+        self.fs.write(&mut self.device, &mut writer, line)?;
+        self.fs.close(&mut self.device, writer)
+    }
+
+    /// Start writing a new clip, saved at Unix time `time`. It is written as a `Sink`, and
+    /// becomes one of the saved clips with `finish_clip`. The oldest unlocked clips make room for
+    /// it. Returns the number of the clip.
+    pub fn create_clip(&mut self, time: u32) -> Result<usize, Error<E>> {
+        self.clips.create(&mut self.fs, &mut self.device, time)
+    }
+
+    /// Finish writing the clip. Returns its number, if one was being written.
+    pub fn finish_clip(&mut self) -> Result<Option<usize>, Error<E>> {
+        self.clips.finish(&mut self.fs, &mut self.device)
+    }
+
+    /// Stop writing the clip without saving it.
+    pub fn discard_clip(&mut self) {
+        self.clips.discard(&mut self.fs);
+    }
+
+    /// Remove saved clip `number`. Its blocks are erased when they are written again, so this
+    /// doesn't hold up recording.
+    pub fn erase_clip(&mut self, number: usize) -> Result<(), Error<E>> {
+        self.clips.remove(&mut self.fs, &mut self.device, number)
+    }
+
+    /// Lock saved clip `number`, so it is never reclaimed, or unlock it.
+    pub fn lock_clip(&mut self, number: usize, locked: bool) -> Result<(), Error<E>> {
+        self.clips
+            .lock(&mut self.fs, &mut self.device, number, locked)
+    }
+
+    /// Read `buf.len()` bytes of the saved clips starting at byte `addr` to `buf`. Clip `n` starts
+    /// at address `n * CLIP_SPACING`.
+    pub fn read_at(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.clips.read_at(&self.fs, &mut self.device, addr, buf)?;
         Ok(())
     }
+
+    /// Saved clip `number`, to be read as a `Source`.
+    pub fn clip_source(&mut self, number: usize) -> ClipSource<'_, MEM> {
+        ClipSource { nvm: self, number }
+    }
+}
+
+/// The clip is written to the clip being written, see `create_clip`.
+impl<MEM, E> Sink for NonVolatileMemory<MEM>
+where
+    MEM: Mem<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = Error<E>;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error<E>> {
+        self.clips.write(&mut self.fs, &mut self.device, data)
+    }
+}
+
+/// A saved clip of the NVM.
+pub struct ClipSource<'a, MEM> {
+    nvm: &'a mut NonVolatileMemory<MEM>,
+    number: usize,
+}
+
+impl<MEM, E> Source for ClipSource<'_, MEM>
+where
+    MEM: Mem<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = Error<E>;
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.nvm
+            .read_at(self.number as u32 * CLIP_SPACING + offset, buf)
+    }
+}
+
+/// Read the config record from its file. Returns `None` if there isn't a valid record, for
+/// example on first boot.
+fn read_config<MEM: Mem>(device: &mut MEM, fs: &FileSystem) -> Option<Config> {
+    let mut bytes = [0; CONFIG_WORDS * 4];
+    match fs.read(device, CONFIG_FILE, 0, &mut bytes) {
+        Ok(len) if len == bytes.len() => (),
+        _ => return None,
+    }
+
+    let mut words = [0_u32; CONFIG_WORDS];
+    for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+        *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    Config::from_words(&words)
 }
@@ -3,12 +3,21 @@
 mod parallel;
 mod pins;
 mod sccb;
+mod settings;
 
-use core::convert::TryInto;
-use sccb::{RegMap, SCCB};
+use core::{
+    convert::TryInto,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+use sccb::{RegMap, Register, SccbError, SCCB};
+pub use settings::{FrameRate, Orientation};
 use stm32f7xx_hal::{
     delay::Delay,
-    i2c::{BlockingI2c, Mode},
+    gpio::{
+        gpiob::{PB8, PB9},
+        Alternate, AF4,
+    },
+    i2c::{self, BlockingI2c, Mode},
     pac::I2C1,
     prelude::_embedded_hal_blocking_delay_DelayMs,
     rcc::{Clocks, APB1},
@@ -30,15 +39,126 @@ pub const FRAME_HEIGHT: u16 = 120;
 /// Number of total bytes in one frame using RGB565 format (2 pixels per byte).
 pub const FRAME_SIZE: u32 = (FRAME_WIDTH as u32) * (FRAME_HEIGHT as u32) * 2;
 
-/// Time between frames in milliseconds
-pub const FRAME_RATE: u32 = 33_u32;
+/// Number of 32-bit DMA transfers in one frame.
+const DMA_SIZE_WORDS: u32 = FRAME_SIZE / 4;
+
+/// Busy-wait after a sensor reset before writing registers, roughly 10 ms at 216 MHz.
+const RESET_DELAY_CYCLES: u32 = 2_160_000;
+
+/// Frame and error counters, see `get_stats`.
+static FRAMES: AtomicU32 = AtomicU32::new(0);
+static OVERRUNS: AtomicU32 = AtomicU32::new(0);
+static SYNC_ERRORS: AtomicU32 = AtomicU32::new(0);
+static DMA_ERRORS: AtomicU32 = AtomicU32::new(0);
+static I2C_ERRORS: AtomicU32 = AtomicU32::new(0);
+static RESETS: AtomicU32 = AtomicU32::new(0);
+
+/// Set by `start` and cleared by `stop`, see `is_started`.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+/// I2C1 driver connected to the OV9655 SCCB port.
+type SccbI2c = BlockingI2c<I2C1, PB8<Alternate<AF4>>, PB9<Alternate<AF4>>>;
+
+/// Capture pipeline errors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaptureError {
+    /// DCMI overrun, image data was lost.
+    Overrun,
+    /// DCMI synchronization error.
+    Sync,
+    /// DMA transfer, FIFO, or direct mode error.
+    Dma,
+}
+
+/// Capture pipeline frame and error counters.
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureStats {
+    /// Number of frames delivered by the DMA, including frames dropped by decimation.
+    pub frames: u32,
+    /// Number of DCMI overrun errors.
+    pub overruns: u32,
+    /// Number of DCMI synchronization errors.
+    pub sync_errors: u32,
+    /// Number of DMA errors.
+    pub dma_errors: u32,
+    /// Number of failed SCCB (I2C) operations.
+    pub i2c_errors: u32,
+    /// Number of capture pipeline resets.
+    pub resets: u32,
+}
+
+/// Handle for changing camera settings after `init`. Owns the SCCB (I2C) port.
+pub struct Ov9655 {
+    i2c: SccbI2c,
+    sccb: SCCB<SccbI2c>,
+    orientation: Orientation,
+    frame_rate: FrameRate,
+}
+
+impl Ov9655 {
+    /// Change the image orientation. Takes effect on the next frame.
+    pub fn set_orientation(
+        &mut self,
+        orientation: Orientation,
+    ) -> Result<(), SccbError<i2c::Error>> {
+        self.sccb
+            .modify_register(&mut self.i2c, Register::MVFP, 0x30, orientation.mvfp_bits())
+            .map_err(count_i2c_error)?;
+        self.orientation = orientation;
+        Ok(())
+    }
+
+    /// Enter or exit soft sleep (COM2 b4). The sensor stops outputting frames while asleep, but
+    /// register values are retained.
+    pub fn set_sleep(&mut self, sleep: bool) -> Result<(), SccbError<i2c::Error>> {
+        let val = if sleep { 0x10 } else { 0x00 };
+        self.sccb
+            .modify_register(&mut self.i2c, Register::COM_CNTRL_02, 0x10, val)
+            .map_err(count_i2c_error)
+    }
+
+    /// Reset the capture pipeline after an error: Stop capture, reset the DCMI and DMA2, then
+    /// reset and reconfigure the sensor. The capture mode (continuous or snapshot) is kept.
+    /// * User must setup ping-pong DMA addresses again and call `start` to resume capture.
+    pub fn reset(&mut self) -> Result<(), SccbError<i2c::Error>> {
+        RESETS.fetch_add(1, Ordering::Relaxed);
+        let snapshot = parallel::dcmi_is_snapshot_mode();
+        parallel::reset();
+
+        // Reset and reconfigure the sensor
+        self.sccb.reset(&mut self.i2c).map_err(count_i2c_error)?;
+        cortex_m::asm::delay(RESET_DELAY_CYCLES);
+
+        let mut reg_vals = RegMap::new();
+        get_config(&mut reg_vals, self.frame_rate);
+        self.sccb
+            .apply_config(&mut self.i2c, &reg_vals, false)
+            .map_err(count_i2c_error)?;
+        self.set_orientation(self.orientation)?;
+
+        // Setup DCMI and DMA2 again
+        parallel::dcmi_setup();
+        parallel::dma2_setup(DMA_SIZE_WORDS.try_into().unwrap());
+        parallel::dcmi_snapshot_mode(snapshot);
+
+        Ok(())
+    }
+}
 
 /// Initialize the OV9655 device driver.
 /// * Performs camera configuration using the SCCB (I2C) port.
 /// * Sets up DCMI and DMA2 to handle data capture.
 /// * User must call `start_capture` to begin capturing frames.
 /// * User must call `update_addrX` to setup ping-pong DMA addresses.
-pub fn init(i2c1: I2C1, apb1: &mut APB1, clocks: Clocks, delay: &mut Delay) {
+/// * Returns a handle for changing camera settings at runtime.
+pub fn init(
+    i2c1: I2C1,
+    apb1: &mut APB1,
+    clocks: Clocks,
+    delay: &mut Delay,
+    orientation: Orientation,
+    frame_rate: FrameRate,
+) -> Ov9655 {
     // Pin configuration
     let i2c_pins = pins::pin_config_stm32f746g_disco();
 
@@ -62,27 +182,48 @@ pub fn init(i2c1: I2C1, apb1: &mut APB1, clocks: Clocks, delay: &mut Delay) {
 
     // Generate register map
     let mut reg_vals = RegMap::new();
-    get_config(&mut reg_vals);
+    get_config(&mut reg_vals, frame_rate);
 
-    // Configure the OV9655 using the register map
+    // Configure the OV9655 using the register map, then the configured orientation
     sccb.apply_config(&mut i2c, &reg_vals, false).unwrap();
+    let mut cam = Ov9655 {
+        i2c,
+        sccb,
+        orientation,
+        frame_rate,
+    };
+    cam.set_orientation(orientation).unwrap();
 
     // Setup DCMI and DMA2 to transfer from the DCMI peripheral into memory
-    let dma_size_words = FRAME_SIZE / 4;
     parallel::dcmi_setup();
-    parallel::dma2_setup(dma_size_words.try_into().unwrap());
+    parallel::dma2_setup(DMA_SIZE_WORDS.try_into().unwrap());
+
+    cam
 }
 
 /// Start capturing frames continuously.
 pub fn start() {
+    STARTED.store(true, Ordering::Relaxed);
     parallel::start_capture();
 }
 
 /// Stop capturing frames.
 pub fn stop() {
+    STARTED.store(false, Ordering::Relaxed);
     parallel::stop_capture();
 }
 
+/// Returns `true` if capture was started and not stopped with `stop`. Capture may still have
+/// stalled due to an error.
+pub fn is_started() -> bool {
+    STARTED.load(Ordering::Relaxed)
+}
+
+/// Select snapshot mode, where `start` captures a single frame rather than capturing continuously.
+pub fn set_snapshot_mode(snapshot: bool) {
+    parallel::dcmi_snapshot_mode(snapshot);
+}
+
 /// Update camera frame data destination memory address 0.
 pub fn update_addr0(address: u32) {
     parallel::dma2_update_addr0(address);
@@ -94,12 +235,58 @@ pub fn update_addr1(address: u32) {
 }
 
 /// Handle the frame interrupt. Returns `true` if a frame capture completed, `false` otherwise.
-pub fn handle_dma_done() -> bool {
-    return parallel::dma2_isr();
+/// Returns an error if the DMA reported one, capture has stopped and `Ov9655::reset` is needed.
+pub fn handle_dma_done() -> Result<bool, CaptureError> {
+    let (dma_done, dma_error) = parallel::dma2_isr();
+    if dma_error {
+        DMA_ERRORS.fetch_add(1, Ordering::Relaxed);
+        Err(CaptureError::Dma)
+    } else {
+        if dma_done {
+            FRAMES.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(dma_done)
+    }
+}
+
+/// Handle the DCMI (error) interrupt. After an error, frames are no longer aligned with the DMA
+/// transfers and `Ov9655::reset` is needed.
+pub fn handle_dcmi_error() -> Result<(), CaptureError> {
+    let (overrun, sync_error) = parallel::dcmi_isr();
+    if overrun {
+        OVERRUNS.fetch_add(1, Ordering::Relaxed);
+    }
+    if sync_error {
+        SYNC_ERRORS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    match (overrun, sync_error) {
+        (true, _) => Err(CaptureError::Overrun),
+        (false, true) => Err(CaptureError::Sync),
+        (false, false) => Ok(()),
+    }
+}
+
+/// Get a snapshot of the capture pipeline error counters.
+pub fn get_stats() -> CaptureStats {
+    CaptureStats {
+        frames: FRAMES.load(Ordering::Relaxed),
+        overruns: OVERRUNS.load(Ordering::Relaxed),
+        sync_errors: SYNC_ERRORS.load(Ordering::Relaxed),
+        dma_errors: DMA_ERRORS.load(Ordering::Relaxed),
+        i2c_errors: I2C_ERRORS.load(Ordering::Relaxed),
+        resets: RESETS.load(Ordering::Relaxed),
+    }
+}
+
+/// Count a failed SCCB operation, for use with `map_err`.
+fn count_i2c_error<E>(e: E) -> E {
+    I2C_ERRORS.fetch_add(1, Ordering::Relaxed);
+    e
 }
 
 /// Given an empty `RegMap`, fill register values for QVGA or QQVGA resolution with RGB565.
-fn get_config(reg_vals: &mut RegMap) {
+fn get_config(reg_vals: &mut RegMap, frame_rate: FrameRate) {
     // 30 fps VGA with VarioPixel and RGB output data format
     reg_vals.insert(0x12, 0x63).unwrap();
 
@@ -122,9 +309,12 @@ fn get_config(reg_vals: &mut RegMap) {
     reg_vals.insert(0x74, 0x10).unwrap();
     reg_vals.insert(0x75, 0x10).unwrap();
 
-    // Set this 0x10 to flip video vertically
+    // No mirror or vertical flip, the orientation is set with `Ov9655::set_orientation`
     reg_vals.insert(0x1e, 0x00).unwrap();
 
+    // Clock prescaler: 0x01 for 30 fps, 0x03 for 15 fps
+    reg_vals.insert(0x11, frame_rate.clkrc_bits()).unwrap();
+
     // These registers are copied from the STM32F7 BSP, need to dig into them more
     reg_vals.insert(0x00, 0x00).unwrap();
     reg_vals.insert(0x01, 0x80).unwrap();
@@ -135,7 +325,6 @@ fn get_config(reg_vals: &mut RegMap) {
     reg_vals.insert(0x0b, 0x57).unwrap();
     reg_vals.insert(0x0e, 0x61).unwrap();
     reg_vals.insert(0x0f, 0x40).unwrap();
-    reg_vals.insert(0x11, 0x01).unwrap();
     reg_vals.insert(0x13, 0xc7).unwrap();
     reg_vals.insert(0x14, 0x3a).unwrap();
     reg_vals.insert(0x16, 0x24).unwrap();
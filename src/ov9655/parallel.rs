@@ -24,19 +24,56 @@ pub fn dcmi_setup() {
         .cr
         .write(|w| w.vspol().set_bit().hspol().clear_bit().cm().clear_bit());
 
-    // Enable all of the interrupts
-    dcmi_regs.ier.write(|w| {
-        w.line_ie()
-            .set_bit()
-            .vsync_ie()
-            .set_bit()
-            .err_ie()
-            .set_bit()
-            .ovr_ie()
-            .set_bit()
-            .frame_ie()
-            .set_bit()
-    });
+    // Enable the error interrupts only, frame completion is handled by the DMA interrupt
+    dcmi_regs
+        .ier
+        .write(|w| w.err_ie().set_bit().ovr_ie().set_bit());
+}
+
+/// Returns `true` if the DCMI is in snapshot mode.
+pub fn dcmi_is_snapshot_mode() -> bool {
+    let dcmi_regs = unsafe { &(*DCMI::ptr()) };
+
+    dcmi_regs.cr.read().cm().bit_is_set()
+}
+
+/// Read and clear DCMI interrupt status. Returns a pair of flags: (overrun, sync error).
+pub fn dcmi_isr() -> (bool, bool) {
+    let dcmi_regs = unsafe { &(*DCMI::ptr()) };
+
+    let status = dcmi_regs.mis.read();
+    let overrun = status.ovr_mis().bit_is_set();
+    let sync_error = status.err_mis().bit_is_set();
+
+    // Clear all interrupt flags
+    unsafe {
+        dcmi_regs.icr.write(|w| w.bits(0x1F));
+    }
+
+    (overrun, sync_error)
+}
+
+/// Reset the DCMI peripheral and disable DMA2, so both can be setup again. DMA2 is not reset in
+/// RCC since other streams may be in use (e.g. QSPI).
+pub fn reset() {
+    let dma2_regs = unsafe { &(*DMA2::ptr()) };
+    let rcc_regs = unsafe { &(*RCC::ptr()) };
+
+    // Stop capture and wait for the DMA stream to finish any ongoing transfer
+    stop_capture();
+    while dma2_regs.st[DMA_STREAM].cr.read().en().bit_is_set() {}
+
+    // Pulse the DCMI reset
+    rcc_regs.ahb2rstr.modify(|_, w| w.dcmirst().set_bit());
+    rcc_regs.ahb2rstr.modify(|_, w| w.dcmirst().clear_bit());
+}
+
+/// Select the DCMI capture mode. In snapshot mode, `start_capture` captures a single frame and
+/// the DCMI stops automatically afterwards. Otherwise frames are captured continuously.
+pub fn dcmi_snapshot_mode(snapshot: bool) {
+    let dcmi_regs = unsafe { &(*DCMI::ptr()) };
+
+    dcmi_regs.cr.modify(|_, w| w.cm().bit(snapshot));
 }
 
 /// Setup DMA2 to transfer image data from DCMI to memory. Does not update
@@ -138,19 +175,22 @@ pub fn dma2_update_addr1(address: u32) {
         .write(|w| w.m1a().bits(address));
 }
 
-/// Read and clear low interrupt status register and return `true` if the transfer is complete.
-pub fn dma2_isr() -> bool {
+/// Read and clear low interrupt status register. Returns a pair of flags: (transfer complete,
+/// error). An error is a transfer, FIFO, or direct mode error.
+pub fn dma2_isr() -> (bool, bool) {
     unsafe {
         let dma2_regs = &(*DMA2::ptr());
 
-        // Did the DMA done interrupt fire?
-        let dma_done = dma2_regs.lisr.read().tcif1().is_complete();
+        // Did the DMA done or an error interrupt fire?
+        let status = dma2_regs.lisr.read();
+        let dma_done = status.tcif1().is_complete();
+        let dma_error =
+            status.teif1().is_error() || status.dmeif1().is_error() || status.feif1().is_error();
 
-        // Read and clear interrupt status
-        let int_status = dma2_regs.lisr.read().bits();
-        dma2_regs.lifcr.write(|w| w.bits(int_status));
+        // Clear interrupt status
+        dma2_regs.lifcr.write(|w| w.bits(status.bits()));
 
-        dma_done
+        (dma_done, dma_error)
     }
 }
 
@@ -88,6 +88,18 @@ where
         self.write_register(i2c, Register::COM_CNTRL_07, reg | 0x80)
     }
 
+    /// Update the bits selected by `mask` in a register, leaving the other bits unchanged.
+    pub fn modify_register(
+        &self,
+        i2c: &mut I2C,
+        reg: u8,
+        mask: u8,
+        val: u8,
+    ) -> Result<(), SccbError<E>> {
+        let curr = self.read_register(i2c, reg)?;
+        self.write_register(i2c, reg, (curr & !mask) | (val & mask))
+    }
+
     /// Check the device ID matches the expected value.
     pub fn check_id(&self, i2c: &mut I2C) -> Result<(), SccbError<E>> {
         // Manf ID
@@ -143,12 +155,16 @@ const OV9655_MANF_ID: u16 = 0x7FA2;
 const OV9655_PROD_ID: u16 = 0x9657;
 
 /// Device register addresses.
-struct Register;
+pub struct Register;
 
 impl Register {
     // Common control registers
+    pub const COM_CNTRL_02: u8 = 0x09;
     pub const COM_CNTRL_07: u8 = 0x12;
 
+    // Mirror (b5) and vertical flip (b4) register
+    pub const MVFP: u8 = 0x1E;
+
     // Product ID registers
     pub const PROD_ID_MSB: u8 = 0x0A;
     pub const PROD_ID_LSB: u8 = 0x0B;
@@ -0,0 +1,89 @@
+//! Camera settings stored in the config record, independent of the hardware so they can be
+//! tested on the host.
+
+/// Image orientation, used to compensate for how the camera is mounted in the vehicle.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Orientation {
+    /// Image as captured by the sensor.
+    Normal = 0,
+    /// Image flipped top to bottom.
+    VerticalFlip = 1,
+    /// Image mirrored left to right.
+    HorizontalMirror = 2,
+    /// Image flipped and mirrored, for a camera mounted upside-down.
+    Rotate180 = 3,
+}
+
+impl Orientation {
+    /// Convert from the numeric representation used in the config record.
+    pub fn from_u32(val: u32) -> Option<Self> {
+        match val {
+            0 => Some(Orientation::Normal),
+            1 => Some(Orientation::VerticalFlip),
+            2 => Some(Orientation::HorizontalMirror),
+            3 => Some(Orientation::Rotate180),
+            _ => None,
+        }
+    }
+
+    /// Value of the MVFP register for this orientation: Mirror (b5) and vertical flip (b4).
+    pub fn mvfp_bits(self) -> u8 {
+        match self {
+            Orientation::Normal => 0x00,
+            Orientation::VerticalFlip => 0x10,
+            Orientation::HorizontalMirror => 0x20,
+            Orientation::Rotate180 => 0x30,
+        }
+    }
+}
+
+/// Target frame rate. The sensor itself only runs at 30 or 15 fps, lower rates are achieved by
+/// dropping frames in software (see `FrameRate::decimation`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameRate {
+    Fps30 = 30,
+    Fps15 = 15,
+    Fps10 = 10,
+    Fps5 = 5,
+    Fps1 = 1,
+}
+
+impl FrameRate {
+    /// Convert from frames per second, as used in the config record.
+    pub fn from_u32(fps: u32) -> Option<Self> {
+        match fps {
+            30 => Some(FrameRate::Fps30),
+            15 => Some(FrameRate::Fps15),
+            10 => Some(FrameRate::Fps10),
+            5 => Some(FrameRate::Fps5),
+            1 => Some(FrameRate::Fps1),
+            _ => None,
+        }
+    }
+
+    /// Time between frames in milliseconds.
+    pub fn period_ms(self) -> u32 {
+        1000 / (self as u32)
+    }
+
+    /// Number of frames delivered by the sensor for every frame that should be kept.
+    pub fn decimation(self) -> u32 {
+        self.sensor_fps() / (self as u32)
+    }
+
+    /// Frame rate the sensor runs at, chosen so it is a multiple of the target frame rate.
+    pub fn sensor_fps(self) -> u32 {
+        match self {
+            FrameRate::Fps30 | FrameRate::Fps10 => 30,
+            FrameRate::Fps15 | FrameRate::Fps5 | FrameRate::Fps1 => 15,
+        }
+    }
+
+    /// Value of the CLKRC register: Internal clock = input clock / (b[5:0] + 1).
+    pub fn clkrc_bits(self) -> u8 {
+        match self.sensor_fps() {
+            30 => 0x01,
+            _ => 0x03,
+        }
+    }
+}
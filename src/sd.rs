@@ -0,0 +1,121 @@
+//! SD card protocol in SD bus mode, as used by the SDMMC driver: command indices, card status and
+//! OCR bits, and decoding of the CSD register. See the SD Physical Layer Simplified Specification.
+//!
+//! The implementation does not depend on any hardware, so it can be tested on the host.
+
+/// Command indices. Application specific commands (`ACMD_*`) must be preceded by `APP_CMD`.
+pub struct Cmd;
+
+impl Cmd {
+    pub const GO_IDLE_STATE: u8 = 0;
+    pub const ALL_SEND_CID: u8 = 2;
+    pub const SEND_RELATIVE_ADDR: u8 = 3;
+    pub const SELECT_CARD: u8 = 7;
+    pub const SEND_IF_COND: u8 = 8;
+    pub const SEND_CSD: u8 = 9;
+    pub const STOP_TRANSMISSION: u8 = 12;
+    pub const SEND_STATUS: u8 = 13;
+    pub const SET_BLOCKLEN: u8 = 16;
+    pub const READ_SINGLE_BLOCK: u8 = 17;
+    pub const READ_MULTIPLE_BLOCK: u8 = 18;
+    pub const WRITE_BLOCK: u8 = 24;
+    pub const WRITE_MULTIPLE_BLOCK: u8 = 25;
+    pub const APP_CMD: u8 = 55;
+    pub const ACMD_SET_BUS_WIDTH: u8 = 6;
+    pub const ACMD_SD_SEND_OP_COND: u8 = 41;
+}
+
+/// Argument of `SEND_IF_COND`: 2.7-3.6 V supply and a check pattern, echoed by version 2 cards.
+pub const IF_COND_ARG: u32 = 0x1AA;
+
+/// OCR bit set once the card finished powering up.
+pub const OCR_POWER_UP: u32 = 1 << 31;
+
+/// OCR bit set by SDHC/SDXC cards, which are addressed in blocks. When sent by the host, it tells
+/// the card that such addressing is supported.
+pub const OCR_HIGH_CAPACITY: u32 = 1 << 30;
+
+/// OCR voltage window of 2.7-3.6 V, the card supply of the Discovery board is 3.3 V.
+pub const OCR_VOLTAGE_WINDOW: u32 = 0x00FF_8000;
+
+/// Argument of `ACMD_SET_BUS_WIDTH` for a 4 bit bus.
+pub const BUS_WIDTH_4: u32 = 0b10;
+
+/// Error bits of the card status returned in R1 responses.
+pub const STATUS_ERRORS: u32 = 0xFDFF_E008;
+
+/// Card state in the card status: Ready for data transfer commands.
+pub const STATE_TRANSFER: u32 = 4;
+
+/// Error bits set in card status `status`, if any.
+pub fn status_errors(status: u32) -> Option<u32> {
+    match status & STATUS_ERRORS {
+        0 => None,
+        errors => Some(errors),
+    }
+}
+
+/// Current state of the card in card status `status`.
+pub fn current_state(status: u32) -> u32 {
+    (status >> 9) & 0xF
+}
+
+/// Does the response to `SEND_IF_COND` echo the argument? Cards which don't respond at all are
+/// version 1 cards.
+pub fn if_cond_valid(response: u32) -> bool {
+    response & 0xFFF == IF_COND_ARG
+}
+
+/// Relative card address from the R6 response to `SEND_RELATIVE_ADDR`, shifted in place to be
+/// used as a command argument.
+pub fn rca_arg(response: u32) -> u32 {
+    response & 0xFFFF_0000
+}
+
+/// Address argument of read and write commands for block `lba`. Standard capacity cards are
+/// addressed in bytes, high capacity cards in blocks.
+pub fn data_address(lba: u32, high_capacity: bool) -> u32 {
+    match high_capacity {
+        true => lba,
+        false => lba * 512,
+    }
+}
+
+/// Card specific data register, as far as it is needed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Csd {
+    /// Capacity in blocks of 512 bytes.
+    pub num_blocks: u32,
+}
+
+impl Csd {
+    /// Decode the CSD from the words of an R2 response, most significant first. Returns `None`
+    /// for unknown CSD versions.
+    pub fn from_response(words: &[u32; 4]) -> Option<Csd> {
+        let num_blocks = match bits(words, 127, 126) {
+            // Version 1: Capacity is (C_SIZE + 1) * 2^(C_SIZE_MULT + 2) * 2^READ_BL_LEN bytes
+            0 => {
+                let read_bl_len = bits(words, 83, 80);
+                let c_size = bits(words, 73, 62);
+                let c_size_mult = bits(words, 49, 47);
+                let shift = (c_size_mult + 2 + read_bl_len).checked_sub(9)?;
+                (c_size + 1) << shift
+            }
+            // Version 2: Capacity is (C_SIZE + 1) * 512 KB
+            1 => (bits(words, 69, 48) + 1).checked_mul(1024)?,
+            _ => return None,
+        };
+
+        Some(Csd { num_blocks })
+    }
+}
+
+/// Bits `hi..=lo` of the 128 bit register in `words`.
+fn bits(words: &[u32; 4], hi: u32, lo: u32) -> u32 {
+    let mut value = 0;
+    for bit in (lo..=hi).rev() {
+        let word = words[3 - (bit / 32) as usize];
+        value = (value << 1) | ((word >> (bit % 32)) & 1);
+    }
+    value
+}
@@ -0,0 +1,104 @@
+//! A driver for using the STM32F7 TIM2 and TIM5 peripherals as periodic interrupt sources.
+//! Enables the timer clocks in RCC. Both are 32-bit timers, so long periods (seconds) are
+//! possible.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use stm32f7xx_hal::{
+    pac::{RCC, TIM2, TIM5},
+    rcc::Clocks,
+};
+
+/// Timer counter frequency after the prescaler, one tick is 0.1 ms.
+const TICK_HZ: u32 = 10_000;
+
+/// Time since boot in milliseconds, as counted by `add_uptime`.
+static UPTIME_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Unix time at boot in seconds, zero until set with `set_time`.
+static BOOT_TIME: AtomicU32 = AtomicU32::new(0);
+
+/// Helper macro for implementing the same periodic timer functions for TIM2 and TIM5.
+macro_rules! periodic_timer {
+    ($TIM:ident, $timen:ident, $start:ident, $stop:ident, $isr:ident) => {
+        /// Start the timer and generate an update interrupt every `period_ms` milliseconds.
+        pub fn $start(clocks: &Clocks, period_ms: u32) {
+            let rcc_regs = unsafe { &(*RCC::ptr()) };
+            let tim_regs = unsafe { &(*$TIM::ptr()) };
+
+            // Enable peripheral clock
+            rcc_regs.apb1enr.modify(|_, w| w.$timen().set_bit());
+
+            // APB1 timers run at twice the APB1 clock, unless the APB1 prescaler is 1
+            let timclk = match rcc_regs.cfgr.read().ppre1().bits() {
+                0b000..=0b011 => clocks.pclk1().0,
+                _ => clocks.pclk1().0 * 2,
+            };
+
+            unsafe {
+                // Configure prescaler and period
+                tim_regs
+                    .psc
+                    .write(|w| w.psc().bits((timclk / TICK_HZ - 1) as u16));
+                tim_regs
+                    .arr
+                    .write(|w| w.bits(period_ms * (TICK_HZ / 1000) - 1));
+
+                // Load the prescaler with an update event, then clear the interrupt it causes
+                tim_regs.egr.write(|w| w.ug().set_bit());
+                tim_regs.sr.write(|w| w.bits(0));
+            }
+
+            // Enable the update interrupt and start counting
+            tim_regs.dier.write(|w| w.uie().set_bit());
+            tim_regs.cr1.modify(|_, w| w.cen().set_bit());
+        }
+
+        /// Stop the timer, no more interrupts will be generated.
+        #[allow(dead_code)]
+        pub fn $stop() {
+            let tim_regs = unsafe { &(*$TIM::ptr()) };
+
+            tim_regs.cr1.modify(|_, w| w.cen().clear_bit());
+            tim_regs.dier.write(|w| w.uie().clear_bit());
+        }
+
+        /// Clear the update interrupt and return `true` if the period elapsed.
+        pub fn $isr() -> bool {
+            let tim_regs = unsafe { &(*$TIM::ptr()) };
+
+            let elapsed = tim_regs.sr.read().uif().bit_is_set();
+            tim_regs.sr.modify(|_, w| w.uif().clear_bit());
+
+            elapsed
+        }
+    };
+}
+
+periodic_timer!(TIM2, tim2en, tim2_start, tim2_stop, tim2_isr);
+periodic_timer!(TIM5, tim5en, tim5_start, tim5_stop, tim5_isr);
+
+/// Advance the uptime by `ms` milliseconds. Call from a periodic timer interrupt.
+pub fn add_uptime(ms: u32) {
+    UPTIME_MS.fetch_add(ms, Ordering::Relaxed);
+}
+
+/// Get the time since boot in milliseconds, with the resolution of the `add_uptime` calls.
+pub fn uptime_ms() -> u32 {
+    UPTIME_MS.load(Ordering::Relaxed)
+}
+
+/// Set the wall clock time to `unix_time` seconds. There is no RTC, so the time is lost on reset.
+pub fn set_time(unix_time: u32) {
+    BOOT_TIME.store(
+        unix_time.saturating_sub(uptime_ms() / 1000),
+        Ordering::Relaxed,
+    );
+}
+
+/// Get the wall clock time in Unix seconds, or `None` if it was never set.
+pub fn unix_time() -> Option<u32> {
+    match BOOT_TIME.load(Ordering::Relaxed) {
+        0 => None,
+        boot_time => Some(boot_time + uptime_ms() / 1000),
+    }
+}
@@ -0,0 +1,313 @@
+//! Read-only FAT16 volume synthesized on the fly from the saved clips. Nothing but the clip data
+//! is stored: The boot sector, file allocation tables and root directory are generated when the
+//! host reads them, and each clip is a file with its data read straight from non-volatile memory.
+//!
+//! The volume has a fixed size, with the clips allocated one after the other:
+//!
+//! ```text
+//! Block     0: Boot sector
+//! Block     1: File allocation table, 2 copies of FAT_BLOCKS blocks
+//! Block   129: Root directory, ROOT_ENTRIES entries
+//! Block   161: Data, BLOCKS_PER_CLUSTER blocks per cluster
+//! ```
+//!
+//! The synthesis does not depend on any hardware, so it can be tested on the host.
+
+use super::scsi::{BlockDevice, BLOCK_SIZE};
+use crate::date::fat_date_time;
+
+/// Maximum number of clips on the volume.
+pub const MAX_CLIPS: usize = 16;
+
+/// Volume size in blocks, 32 MB is more than the non-volatile memory can hold.
+pub const NUM_BLOCKS: u32 = 65536;
+
+/// Allocation unit size in blocks.
+pub const BLOCKS_PER_CLUSTER: u32 = 4;
+
+/// Size of one file allocation table in blocks.
+pub const FAT_BLOCKS: u32 = 64;
+
+/// Number of root directory entries.
+pub const ROOT_ENTRIES: u32 = 512;
+
+const NUM_FATS: u32 = 2;
+const FAT_START: u32 = 1;
+const ROOT_START: u32 = FAT_START + NUM_FATS * FAT_BLOCKS;
+const DATA_START: u32 = ROOT_START + ROOT_ENTRIES * DIR_ENTRY_LEN / BLOCK_SIZE as u32;
+const CLUSTER_BYTES: u32 = BLOCKS_PER_CLUSTER * BLOCK_SIZE as u32;
+const DIR_ENTRY_LEN: u32 = 32;
+
+/// Number of clusters in the data area. The FAT type is decided by this number alone, it must be
+/// between 4085 and 65524 for FAT16.
+const NUM_CLUSTERS: u32 = (NUM_BLOCKS - DATA_START) / BLOCKS_PER_CLUSTER;
+
+/// Cluster numbers start at 2, the first two FAT entries are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+const VOLUME_LABEL: &[u8; 11] = b"DASHCAM    ";
+
+/// Storage holding the clip data.
+pub trait ClipStorage {
+    type Error;
+
+    /// Read `buf.len()` bytes at address `addr`. The length is always a multiple of 4, and `buf`
+    /// is word aligned if the block buffer passed to `FatVolume::read_block` is.
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+/// A saved clip.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Clip {
+    /// Storage address of the clip data.
+    pub addr: u32,
+    /// Length in bytes.
+    pub len: u32,
+    /// Unix time the clip was saved, 0 if unknown.
+    pub time: u32,
+}
+
+impl Clip {
+    fn clusters(&self) -> u32 {
+        self.len.div_ceil(CLUSTER_BYTES)
+    }
+}
+
+/// The synthesized volume.
+pub struct FatVolume {
+    clips: [Clip; MAX_CLIPS],
+    num_clips: usize,
+    generation: u32,
+}
+
+impl Default for FatVolume {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FatVolume {
+    /// Create an empty volume.
+    pub fn new() -> Self {
+        FatVolume {
+            clips: [Clip::default(); MAX_CLIPS],
+            num_clips: 0,
+            generation: 0,
+        }
+    }
+
+    /// Replace the clips on the volume. Returns the number of clips which fit, the others are
+    /// left out.
+    pub fn set_clips(&mut self, clips: &[Clip]) -> usize {
+        let mut clusters = 0;
+        self.num_clips = 0;
+        for clip in clips.iter().take(MAX_CLIPS) {
+            clusters += clip.clusters();
+            if clusters > NUM_CLUSTERS {
+                break;
+            }
+
+            self.clips[self.num_clips] = *clip;
+            self.num_clips += 1;
+        }
+
+        self.generation = self.generation.wrapping_add(1);
+        self.num_clips
+    }
+
+    /// Clips on the volume, clip `n` is the file `CLIPnnnn.CLP`. Clips with a length of 0 are
+    /// erased, they have no file.
+    pub fn clips(&self) -> &[Clip] {
+        &self.clips[..self.num_clips]
+    }
+
+    /// Read block `lba` of the volume, reading clip data from `storage`.
+    pub fn read_block<S: ClipStorage>(
+        &self,
+        lba: u32,
+        buf: &mut [u8; BLOCK_SIZE],
+        storage: &mut S,
+    ) -> Result<(), S::Error> {
+        buf.iter_mut().for_each(|b| *b = 0);
+
+        if lba == 0 {
+            self.boot_sector(buf);
+        } else if lba < ROOT_START {
+            self.fat_block((lba - FAT_START) % FAT_BLOCKS, buf);
+        } else if lba < DATA_START {
+            self.root_block(lba - ROOT_START, buf);
+        } else if lba < NUM_BLOCKS {
+            return self.data_block(lba - DATA_START, buf, storage);
+        }
+
+        Ok(())
+    }
+
+    fn boot_sector(&self, buf: &mut [u8; BLOCK_SIZE]) {
+        buf[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        buf[3..11].copy_from_slice(b"DASHCAM ");
+        put_u16(buf, 11, BLOCK_SIZE as u16);
+        buf[13] = BLOCKS_PER_CLUSTER as u8;
+        put_u16(buf, 14, FAT_START as u16);
+        buf[16] = NUM_FATS as u8;
+        put_u16(buf, 17, ROOT_ENTRIES as u16);
+        // The block count doesn't fit the 16 bit field, it is in the 32 bit field at 32
+        put_u16(buf, 19, 0);
+        buf[21] = 0xF8;
+        put_u16(buf, 22, FAT_BLOCKS as u16);
+        put_u16(buf, 24, 32);
+        put_u16(buf, 26, 64);
+        put_u32(buf, 28, 0);
+        put_u32(buf, 32, NUM_BLOCKS);
+        buf[36] = 0x80;
+        buf[38] = 0x29;
+        put_u32(buf, 39, 0xDA5C_A000);
+        buf[43..54].copy_from_slice(VOLUME_LABEL);
+        buf[54..62].copy_from_slice(b"FAT16   ");
+        buf[510..512].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    /// Block `index` of a file allocation table. Each clip is a single chain of clusters.
+    fn fat_block(&self, index: u32, buf: &mut [u8; BLOCK_SIZE]) {
+        let entries_per_block = BLOCK_SIZE as u32 / 2;
+        for i in 0..entries_per_block {
+            let cluster = index * entries_per_block + i;
+            let entry = match cluster {
+                0 => 0xFFF8,
+                1 => 0xFFFF,
+                _ => match self.find_cluster(cluster) {
+                    Some((clip, first)) if cluster == first + clip.clusters() - 1 => 0xFFFF,
+                    Some(_) => cluster as u16 + 1,
+                    None => 0,
+                },
+            };
+            put_u16(buf, 2 * i as usize, entry);
+        }
+    }
+
+    /// Block `index` of the root directory, holding the volume label and one entry per clip.
+    /// The entries of erased clips are left out, an unused entry would end the directory.
+    fn root_block(&self, index: u32, buf: &mut [u8; BLOCK_SIZE]) {
+        let entries_per_block = BLOCK_SIZE as u32 / DIR_ENTRY_LEN;
+        let mut first = FIRST_CLUSTER;
+        let mut entry = 1;
+        for (n, clip) in self.clips().iter().enumerate() {
+            if clip.len == 0 {
+                continue;
+            }
+
+            if entry / entries_per_block == index {
+                let offset = (entry % entries_per_block * DIR_ENTRY_LEN) as usize;
+                let entry = &mut buf[offset..offset + DIR_ENTRY_LEN as usize];
+                dir_entry(entry, &clip_name(n), 0x01, clip.time, first, clip.len);
+            }
+            entry += 1;
+            first += clip.clusters();
+        }
+
+        if index == 0 {
+            dir_entry(
+                &mut buf[..DIR_ENTRY_LEN as usize],
+                VOLUME_LABEL,
+                0x08,
+                0,
+                0,
+                0,
+            );
+        }
+    }
+
+    /// Block `index` of the data area, read from the clip it belongs to.
+    fn data_block<S: ClipStorage>(
+        &self,
+        index: u32,
+        buf: &mut [u8; BLOCK_SIZE],
+        storage: &mut S,
+    ) -> Result<(), S::Error> {
+        let cluster = index / BLOCKS_PER_CLUSTER + FIRST_CLUSTER;
+        let (clip, first) = match self.find_cluster(cluster) {
+            Some(found) => found,
+            None => return Ok(()),
+        };
+
+        let offset =
+            (cluster - first) * CLUSTER_BYTES + (index % BLOCKS_PER_CLUSTER) * BLOCK_SIZE as u32;
+        if offset >= clip.len {
+            return Ok(());
+        }
+
+        let len = (clip.len - offset).min(BLOCK_SIZE as u32) as usize;
+
+        // Reads are rounded up to whole words, the bytes after the end of the clip are cleared
+        storage.read(clip.addr + offset, &mut buf[..(len + 3) & !3])?;
+        buf[len..].iter_mut().for_each(|b| *b = 0);
+        Ok(())
+    }
+
+    /// Find the clip `cluster` belongs to. Returns the clip and its first cluster.
+    fn find_cluster(&self, cluster: u32) -> Option<(&Clip, u32)> {
+        let mut first = FIRST_CLUSTER;
+        for clip in self.clips() {
+            if cluster >= first && cluster < first + clip.clusters() {
+                return Some((clip, first));
+            }
+            first += clip.clusters();
+        }
+        None
+    }
+}
+
+/// The volume together with the storage holding the clips, read by the host as a block device.
+pub struct FatDisk<'a, S> {
+    pub volume: &'a FatVolume,
+    pub storage: &'a mut S,
+}
+
+impl<S: ClipStorage> BlockDevice for FatDisk<'_, S> {
+    type Error = S::Error;
+
+    fn num_blocks(&self) -> u32 {
+        NUM_BLOCKS
+    }
+
+    fn generation(&self) -> u32 {
+        self.volume.generation
+    }
+
+    fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), S::Error> {
+        self.volume.read_block(lba, buf, self.storage)
+    }
+}
+
+/// 8.3 file name of clip `n`, without the dot.
+fn clip_name(n: usize) -> [u8; 11] {
+    let mut name = *b"CLIP0000CLP";
+    let mut n = n;
+    for digit in name[4..8].iter_mut().rev() {
+        *digit = b'0' + (n % 10) as u8;
+        n /= 10;
+    }
+    name
+}
+
+/// Fill in directory entry `entry`.
+fn dir_entry(entry: &mut [u8], name: &[u8; 11], attributes: u8, time: u32, cluster: u32, len: u32) {
+    let (date, time) = fat_date_time(time);
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    put_u16(entry, 14, time);
+    put_u16(entry, 16, date);
+    put_u16(entry, 18, date);
+    put_u16(entry, 22, time);
+    put_u16(entry, 24, date);
+    put_u16(entry, 26, cluster as u16);
+    put_u32(entry, 28, len);
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,255 @@
+//! Serves the requests of the `proto` protocol received on the USB serial port.
+
+use super::{
+    fat::{Clip, ClipStorage},
+    proto::{
+        encode_frame, ClipInfo, DeviceStatus, ErrorCode, FrameDecoder, Request, Response,
+        MAX_CHUNK_LEN, MAX_FRAME_LEN, MAX_MESSAGE_LEN,
+    },
+};
+use crate::log;
+use usb_device::bus::UsbBus;
+use usbd_serial::SerialPort;
+
+/// Maximum number of bytes read from the serial port at once.
+const RX_LEN: usize = 64;
+
+/// Responses still to be sent for the current request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Pending {
+    None,
+    /// Clip list, continuing at clip `next`.
+    List {
+        next: usize,
+    },
+    /// Clip data, continuing at `offset` until `end`.
+    Read {
+        clip: Clip,
+        offset: u32,
+        end: u32,
+    },
+}
+
+/// Chunk buffer, word aligned for DMA.
+#[repr(align(4))]
+struct Chunk([u8; MAX_CHUNK_LEN]);
+
+/// State of the protocol on the USB serial port.
+pub struct Link {
+    decoder: FrameDecoder,
+    rx: [u8; RX_LEN],
+    rx_pos: usize,
+    rx_len: usize,
+    /// Frame being sent, `tx[tx_pos..tx_len]` is left to send.
+    tx: [u8; MAX_FRAME_LEN],
+    tx_pos: usize,
+    tx_len: usize,
+    pending: Pending,
+    chunk: Chunk,
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Link {
+    pub fn new() -> Self {
+        Link {
+            decoder: FrameDecoder::new(),
+            rx: [0; RX_LEN],
+            rx_pos: 0,
+            rx_len: 0,
+            tx: [0; MAX_FRAME_LEN],
+            tx_pos: 0,
+            tx_len: 0,
+            pending: Pending::None,
+            chunk: Chunk([0; MAX_CHUNK_LEN]),
+        }
+    }
+
+    /// Receive requests from `port` and send responses as far as the port allows. The clips are
+    /// read from `storage`. `status` is called for `Status` requests, `delete` is called to start
+    /// erasing a clip and returns `false` if the device is busy. Must be called after each USB
+    /// event.
+    pub fn poll<B, S, F, D>(
+        &mut self,
+        port: &mut SerialPort<B>,
+        clips: &[Clip],
+        storage: &mut S,
+        status: F,
+        mut delete: D,
+    ) where
+        B: UsbBus,
+        S: ClipStorage,
+        F: Fn() -> DeviceStatus,
+        D: FnMut(u32) -> bool,
+    {
+        loop {
+            // Finish sending the current frame first
+            while self.tx_pos < self.tx_len {
+                match port.write(&self.tx[self.tx_pos..self.tx_len]) {
+                    Ok(len) => self.tx_pos += len,
+                    Err(_) => return,
+                };
+            }
+
+            if self.pending != Pending::None {
+                self.continue_request(clips, storage);
+                continue;
+            }
+
+            // Then handle the next request
+            if self.rx_pos == self.rx_len {
+                match port.read(&mut self.rx) {
+                    Ok(0) | Err(_) => return,
+                    Ok(len) => {
+                        self.rx_pos = 0;
+                        self.rx_len = len;
+                    }
+                };
+            }
+
+            while self.rx_pos < self.rx_len && self.is_idle() {
+                let byte = self.rx[self.rx_pos];
+                self.rx_pos += 1;
+
+                let request = match self.decoder.push(byte) {
+                    Some(Ok(message)) => Request::decode(message),
+                    Some(Err(e)) => {
+                        warn!("Invalid USB serial frame: {:?}", log::dbg(&e));
+                        continue;
+                    }
+                    None => continue,
+                };
+
+                match request {
+                    Some(request) => self.start_request(request, clips, &status, &mut delete),
+                    None => self.send(Response::Error(ErrorCode::InvalidRequest)),
+                };
+            }
+        }
+    }
+
+    /// Is there nothing left to send for the current request?
+    fn is_idle(&self) -> bool {
+        self.tx_pos == self.tx_len && self.pending == Pending::None
+    }
+
+    /// Handle a new request, sending the first response.
+    fn start_request<F, D>(&mut self, request: Request, clips: &[Clip], status: &F, delete: &mut D)
+    where
+        F: Fn() -> DeviceStatus,
+        D: FnMut(u32) -> bool,
+    {
+        let find = |clip: u32| clips.get(clip as usize).filter(|c| c.len > 0);
+        let response = match request {
+            Request::Status => Response::Status(status()),
+            Request::ListClips => {
+                self.pending = Pending::List { next: 0 };
+                self.continue_list(clips);
+                return;
+            }
+            Request::ClipInfo(n) => match find(n) {
+                Some(clip) => Response::Clip(clip_info(n, clip)),
+                None => Response::Error(ErrorCode::UnknownClip),
+            },
+            Request::ReadClip {
+                clip: n,
+                offset,
+                len,
+            } => match find(n) {
+                Some(clip) => {
+                    let offset = offset.min(clip.len);
+                    self.pending = Pending::Read {
+                        clip: *clip,
+                        offset,
+                        end: offset + len.min(clip.len - offset),
+                    };
+                    return;
+                }
+                None => Response::Error(ErrorCode::UnknownClip),
+            },
+            Request::DeleteClip(n) => match find(n) {
+                Some(_) if delete(n) => Response::Done,
+                Some(_) => Response::Error(ErrorCode::Busy),
+                None => Response::Error(ErrorCode::UnknownClip),
+            },
+        };
+
+        self.send(response);
+    }
+
+    /// Send the next response of a request with several responses.
+    fn continue_request<S: ClipStorage>(&mut self, clips: &[Clip], storage: &mut S) {
+        match self.pending {
+            Pending::None => (),
+            Pending::List { .. } => self.continue_list(clips),
+            Pending::Read { clip, offset, end } if offset < end => {
+                let len = (end - offset).min(MAX_CHUNK_LEN as u32) as usize;
+                let buf = &mut self.chunk.0[..(len + 3) & !3];
+                match storage.read(clip.addr + offset, buf) {
+                    Ok(()) => {
+                        self.pending = Pending::Read {
+                            clip,
+                            offset: offset + len as u32,
+                            end,
+                        };
+                        let data = &self.chunk.0[..len];
+                        let mut message = [0; MAX_MESSAGE_LEN];
+                        let message_len = Response::Chunk { offset, data }.encode(&mut message);
+                        self.send_message(&message[..message_len]);
+                    }
+                    Err(_) => {
+                        error!("Cannot read clip at {:#X}", clip.addr + offset);
+                        self.pending = Pending::None;
+                        self.send(Response::Error(ErrorCode::Storage));
+                    }
+                };
+            }
+            Pending::Read { .. } => {
+                self.pending = Pending::None;
+                self.send(Response::Done);
+            }
+        };
+    }
+
+    fn continue_list(&mut self, clips: &[Clip]) {
+        let next = match self.pending {
+            Pending::List { next } => next,
+            _ => return,
+        };
+
+        // Erased clips are left out
+        match clips.iter().enumerate().skip(next).find(|(_, c)| c.len > 0) {
+            Some((n, clip)) => {
+                self.pending = Pending::List { next: n + 1 };
+                self.send(Response::Clip(clip_info(n as u32, clip)));
+            }
+            None => {
+                self.pending = Pending::None;
+                self.send(Response::Done);
+            }
+        };
+    }
+
+    fn send(&mut self, response: Response) {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = response.encode(&mut message);
+        self.send_message(&message[..len]);
+    }
+
+    fn send_message(&mut self, message: &[u8]) {
+        self.tx_len = encode_frame(message, &mut self.tx);
+        self.tx_pos = 0;
+    }
+}
+
+fn clip_info(n: u32, clip: &Clip) -> ClipInfo {
+    ClipInfo {
+        clip: n,
+        len: clip.len,
+        time: clip.time,
+    }
+}
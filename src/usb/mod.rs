@@ -0,0 +1,110 @@
+//! USB mass storage device on the USB OTG FS port (CN13), exposing the saved clips as files on a
+//! read-only FAT volume. The volume is synthesized on the fly, see `fat`.
+
+pub mod fat;
+pub mod link;
+pub mod msc;
+pub mod proto;
+pub mod scsi;
+
+use crate::flashfs::Error;
+use crate::nvm::{Mem, NonVolatileMemory};
+use core::fmt;
+use fat::ClipStorage;
+use stm32f7xx_hal::{
+    pac::{GPIOA, OTG_FS_GLOBAL, RCC},
+    rcc::Clocks,
+};
+use synopsys_usb_otg::UsbPeripheral;
+
+/// USB bus driver for the OTG FS peripheral.
+pub type UsbBus = synopsys_usb_otg::UsbBus<OtgFs>;
+
+/// Frequency of the 48 MHz clock required by the OTG FS peripheral.
+const CLK48_HZ: u32 = 48_000_000;
+
+/// The OTG FS peripheral, used in device mode.
+pub struct OtgFs {
+    hclk_hz: u32,
+}
+
+unsafe impl UsbPeripheral for OtgFs {
+    const REGISTERS: *const () = OTG_FS_GLOBAL::ptr() as *const ();
+    const HIGH_SPEED: bool = false;
+    const FIFO_DEPTH_WORDS: usize = 320;
+    const ENDPOINT_COUNT: usize = 6;
+
+    fn enable() {
+        let rcc = unsafe { &(*RCC::ptr()) };
+        cortex_m::interrupt::free(|_| {
+            rcc.ahb2enr.modify(|_, w| w.otgfsen().set_bit());
+            rcc.ahb2rstr.modify(|_, w| w.otgfsrst().set_bit());
+            rcc.ahb2rstr.modify(|_, w| w.otgfsrst().clear_bit());
+        });
+    }
+
+    fn ahb_frequency_hz(&self) -> u32 {
+        self.hclk_hz
+    }
+}
+
+/// Setup the 48 MHz clock and the pins of the OTG FS peripheral.
+/// * The 48 MHz clock is the Q output of the main PLL. The HAL doesn't configure it, so the PLL is
+///   stopped and restarted with SYSCLK running from HSE in the meantime.
+/// * Peripherals are stolen, so this should only be done during init!
+pub fn init(clocks: &Clocks) -> OtgFs {
+    let rcc = unsafe { &(*RCC::ptr()) };
+
+    // Main PLL VCO frequency, which is divided by P to get SYSCLK
+    let pllp = (rcc.pllcfgr.read().pllp().bits() as u32 + 1) * 2;
+    let vco_hz = clocks.sysclk().0 * pllp;
+    let pllq = vco_hz / CLK48_HZ;
+    assert!(pllq * CLK48_HZ == vco_hz && (2..=15).contains(&pllq));
+
+    if rcc.pllcfgr.read().pllq().bits() as u32 != pllq {
+        rcc.cfgr.modify(|_, w| w.sw().hse());
+        while !rcc.cfgr.read().sws().is_hse() {}
+        rcc.cr.modify(|_, w| w.pllon().clear_bit());
+        while rcc.cr.read().pllrdy().bit_is_set() {}
+
+        rcc.pllcfgr
+            .modify(|_, w| unsafe { w.pllq().bits(pllq as u8) });
+
+        rcc.cr.modify(|_, w| w.pllon().set_bit());
+        while rcc.cr.read().pllrdy().bit_is_clear() {}
+        rcc.cfgr.modify(|_, w| w.sw().pll());
+        while !rcc.cfgr.read().sws().is_pll() {}
+    }
+
+    // Select the main PLL as the 48 MHz clock source
+    rcc.dckcfgr2.modify(|_, w| w.ck48msel().clear_bit());
+
+    // Configure PA11 (DM) and PA12 (DP) as AF10, the port is already split by the OV9655 driver
+    rcc.ahb1enr.modify(|_, w| w.gpioaen().set_bit());
+    let gpioa = unsafe { &(*GPIOA::ptr()) };
+    cortex_m::interrupt::free(|_| unsafe {
+        gpioa
+            .moder
+            .modify(|r, w| w.bits(r.bits() & !(0xF << 22) | (0xA << 22)));
+        gpioa.ospeedr.modify(|r, w| w.bits(r.bits() | (0xF << 22)));
+        gpioa
+            .afrh
+            .modify(|r, w| w.bits(r.bits() & !(0xFF << 12) | (0xAA << 12)));
+    });
+
+    OtgFs {
+        hclk_hz: clocks.hclk().0,
+    }
+}
+
+impl<MEM, E> ClipStorage for NonVolatileMemory<MEM>
+where
+    MEM: Mem<Error = E>,
+    E: fmt::Debug,
+{
+    type Error = Error<E>;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Error<E>> {
+        self.read_at(addr, buf)
+    }
+}
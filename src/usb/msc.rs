@@ -0,0 +1,267 @@
+//! USB mass storage class using the Bulk-Only Transport (BOT). Each command is a command block
+//! wrapper (CBW) sent by the host, an optional data stage and a command status wrapper (CSW) sent
+//! by the device. The commands themselves are handled by the `scsi` module.
+
+use super::scsi::{BlockDevice, Cbw, Csw, Data, Scsi, Status, BLOCK_SIZE, MAX_REPLY_LEN};
+use usb_device::{
+    class_prelude::*,
+    control::{Recipient, Request, RequestType},
+    Result,
+};
+
+/// Maximum packet size of the bulk endpoints, fixed for full speed.
+const PACKET_SIZE: usize = 64;
+
+const CLASS_MASS_STORAGE: u8 = 0x08;
+const SUBCLASS_SCSI: u8 = 0x06;
+const PROTOCOL_BOT: u8 = 0x50;
+
+const REQUEST_GET_MAX_LUN: u8 = 0xFE;
+const REQUEST_RESET: u8 = 0xFF;
+
+/// Stage of the current command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Stage {
+    Command,
+    DataIn,
+    DataOut,
+    Status,
+}
+
+/// Block buffer, word aligned for DMA.
+#[repr(align(4))]
+struct Block([u8; BLOCK_SIZE]);
+
+/// Mass storage class with a single read-only logical unit.
+pub struct MscClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    ep_in: EndpointIn<'a, B>,
+    ep_out: EndpointOut<'a, B>,
+    scsi: Scsi,
+    stage: Stage,
+    /// Status of the current command, the residue counts down while data is transferred.
+    csw: Csw,
+    /// Bytes left of the data stage expected by the host.
+    remaining: u32,
+    /// Length of the last packet sent in the data stage, 0 if none was sent.
+    last_len: usize,
+    /// Data being sent, `buf[pos..len]` is left to send.
+    buf: Block,
+    pos: usize,
+    len: usize,
+    /// Blocks to send after `buf`, starting at block `lba`.
+    lba: u32,
+    blocks: u32,
+}
+
+impl<'a, B: UsbBus> MscClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        MscClass {
+            iface: alloc.interface(),
+            ep_in: alloc.bulk(PACKET_SIZE as u16),
+            ep_out: alloc.bulk(PACKET_SIZE as u16),
+            scsi: Scsi::new(),
+            stage: Stage::Command,
+            csw: Csw {
+                tag: 0,
+                residue: 0,
+                status: Status::Passed,
+            },
+            remaining: 0,
+            last_len: 0,
+            buf: Block([0; BLOCK_SIZE]),
+            pos: 0,
+            len: 0,
+            lba: 0,
+            blocks: 0,
+        }
+    }
+
+    /// Move commands from the host along as far as the endpoints allow, reading blocks from
+    /// `dev`. Must be called after each USB event.
+    pub fn process<D: BlockDevice>(&mut self, dev: &mut D) {
+        loop {
+            let progress = match self.stage {
+                Stage::Command => self.receive_command(dev),
+                Stage::DataIn => self.send_data(dev),
+                Stage::DataOut => self.discard_data(),
+                Stage::Status => self.send_status(),
+            };
+
+            if !progress {
+                break;
+            }
+        }
+    }
+
+    fn receive_command<D: BlockDevice>(&mut self, dev: &mut D) -> bool {
+        let mut packet = [0; PACKET_SIZE];
+        let cbw = match self.ep_out.read(&mut packet) {
+            Ok(len) => Cbw::parse(&packet[..len]),
+            Err(_) => return false,
+        };
+
+        // Invalid commands are ignored until the host does a reset recovery
+        let cbw = match cbw {
+            Some(cbw) => cbw,
+            None => {
+                warn!("Invalid USB mass storage command");
+                self.ep_in.stall();
+                self.ep_out.stall();
+                return false;
+            }
+        };
+
+        let mut reply = [0; MAX_REPLY_LEN];
+        let (data, status) = match cbw.lun {
+            0 => self.scsi.command(&cbw.command, dev, &mut reply),
+            _ => (Data::None, Status::Failed),
+        };
+
+        self.csw = Csw {
+            tag: cbw.tag,
+            residue: cbw.data_len,
+            status,
+        };
+        self.remaining = cbw.data_len;
+        self.last_len = 0;
+        self.pos = 0;
+        self.len = 0;
+        self.blocks = 0;
+
+        let stage = match (data, cbw.data_in) {
+            (Data::None, _) => None,
+            (Data::Reply(len), true) => {
+                self.buf.0[..len].copy_from_slice(&reply[..len]);
+                self.len = len;
+                Some(Stage::DataIn)
+            }
+            (Data::Blocks { lba, count }, true) => {
+                self.lba = lba;
+                self.blocks = count;
+                Some(Stage::DataIn)
+            }
+            (Data::Discard, false) => Some(Stage::DataOut),
+            // The host expects data in the other direction
+            _ => {
+                self.csw.status = Status::PhaseError;
+                None
+            }
+        };
+
+        // Without data for the host, a data stage it expects ends with a short packet right away.
+        // Data from the host is refused with a stall, so it isn't taken for the next command.
+        self.stage = match stage {
+            Some(stage) => stage,
+            None if cbw.data_len == 0 => Stage::Status,
+            None if cbw.data_in => Stage::DataIn,
+            None => {
+                self.ep_out.stall();
+                Stage::Status
+            }
+        };
+
+        true
+    }
+
+    fn send_data<D: BlockDevice>(&mut self, dev: &mut D) -> bool {
+        while self.remaining > 0 {
+            if self.pos == self.len {
+                if self.blocks == 0 {
+                    break;
+                }
+
+                if dev.read_block(self.lba, &mut self.buf.0).is_err() {
+                    error!("Cannot read block {}", self.lba);
+                    self.scsi.read_error();
+                    self.csw.status = Status::Failed;
+                    self.blocks = 0;
+                    break;
+                }
+                self.lba += 1;
+                self.blocks -= 1;
+                self.pos = 0;
+                self.len = BLOCK_SIZE;
+            }
+
+            let len = (self.len - self.pos)
+                .min(PACKET_SIZE)
+                .min(self.remaining as usize);
+            match self.ep_in.write(&self.buf.0[self.pos..self.pos + len]) {
+                Ok(_) => {
+                    self.last_len = len;
+                    self.pos += len;
+                    self.remaining -= len as u32;
+                    self.csw.residue -= len as u32;
+                }
+                Err(_) => return false,
+            };
+        }
+
+        // Less data than the host expects, end the transfer with a short packet
+        let full = self.last_len == 0 || self.last_len == PACKET_SIZE;
+        if self.remaining > 0 && full && self.ep_in.write(&[]).is_err() {
+            return false;
+        }
+
+        self.remaining = 0;
+        self.stage = Stage::Status;
+        true
+    }
+
+    fn discard_data(&mut self) -> bool {
+        let mut packet = [0; PACKET_SIZE];
+        while self.remaining > 0 {
+            match self.ep_out.read(&mut packet) {
+                Ok(len) => self.remaining = self.remaining.saturating_sub(len as u32),
+                Err(_) => return false,
+            };
+        }
+
+        self.stage = Stage::Status;
+        true
+    }
+
+    fn send_status(&mut self) -> bool {
+        match self.ep_in.write(&self.csw.to_bytes()) {
+            Ok(_) => {
+                self.stage = Stage::Command;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Is `request` a class request for this interface?
+    fn is_class_request(&self, request: &Request) -> bool {
+        request.request_type == RequestType::Class
+            && request.recipient == Recipient::Interface
+            && request.index == u8::from(self.iface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for MscClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(self.iface, CLASS_MASS_STORAGE, SUBCLASS_SCSI, PROTOCOL_BOT)?;
+        writer.endpoint(&self.ep_in)?;
+        writer.endpoint(&self.ep_out)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.stage = Stage::Command;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        if self.is_class_request(xfer.request()) && xfer.request().request == REQUEST_GET_MAX_LUN {
+            xfer.accept_with(&[0]).ok();
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        if self.is_class_request(xfer.request()) && xfer.request().request == REQUEST_RESET {
+            self.stage = Stage::Command;
+            xfer.accept().ok();
+        }
+    }
+}
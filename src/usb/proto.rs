@@ -0,0 +1,432 @@
+//! Framed binary protocol of the USB serial port, used to download and manage saved clips. The
+//! host sends requests, the device answers each with one or more responses:
+//!
+//! ```text
+//! Status                      Status
+//! ListClips                   Clip for each clip, then Done
+//! ClipInfo <clip>             Clip
+//! ReadClip <clip> <off> <len> Chunk for each part of the data, then Done
+//! DeleteClip <clip>           Done once the erase is started
+//! ```
+//!
+//! Any request can be answered with `Error` instead. A message is a type byte followed by its
+//! fields in little endian. Each message is sent as a frame: The message and its CRC-32 (little
+//! endian) are COBS encoded, so frames contain no 0 bytes, and followed by a 0 byte. Frames which
+//! are damaged in transit fail the CRC check and are dropped, a clip download continues at the
+//! offset of the first missing chunk.
+//!
+//! The codec does not depend on any hardware, so it is shared with the host tool and tested on the
+//! host.
+
+use crate::crc::crc32;
+
+/// Maximum length of the data in a `Chunk` response.
+pub const MAX_CHUNK_LEN: usize = 256;
+
+/// Maximum length of a message, which is the longest `Chunk` response.
+pub const MAX_MESSAGE_LEN: usize = 1 + 4 + MAX_CHUNK_LEN;
+
+/// Maximum length of an encoded frame including the 0 byte at the end. COBS adds one byte per
+/// 254 bytes, plus one.
+pub const MAX_FRAME_LEN: usize = MAX_RAW_LEN + MAX_RAW_LEN / 254 + 2;
+
+/// Maximum length of a message and its CRC.
+const MAX_RAW_LEN: usize = MAX_MESSAGE_LEN + 4;
+
+mod tag {
+    pub const STATUS: u8 = 0x01;
+    pub const LIST_CLIPS: u8 = 0x02;
+    pub const CLIP_INFO: u8 = 0x03;
+    pub const READ_CLIP: u8 = 0x04;
+    pub const DELETE_CLIP: u8 = 0x05;
+
+    pub const STATUS_REPLY: u8 = 0x81;
+    pub const CLIP: u8 = 0x82;
+    pub const CHUNK: u8 = 0x83;
+    pub const DONE: u8 = 0x84;
+    pub const ERROR: u8 = 0x85;
+}
+
+/// A request from the host.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Request {
+    Status,
+    ListClips,
+    ClipInfo(u32),
+    /// Read `len` bytes of clip `clip` starting at `offset`. The length is limited to the end of
+    /// the clip.
+    ReadClip {
+        clip: u32,
+        offset: u32,
+        len: u32,
+    },
+    DeleteClip(u32),
+}
+
+/// A response from the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Response<'a> {
+    Status(DeviceStatus),
+    Clip(ClipInfo),
+    /// Clip data at `offset` bytes into the clip.
+    Chunk {
+        offset: u32,
+        data: &'a [u8],
+    },
+    /// The request is complete.
+    Done,
+    Error(ErrorCode),
+}
+
+/// State of the dash cam.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    Recording = 0,
+    Parking = 1,
+    Saved = 2,
+    Replaying = 3,
+}
+
+impl State {
+    pub fn from_u8(value: u8) -> Option<State> {
+        match value {
+            0 => Some(State::Recording),
+            1 => Some(State::Parking),
+            2 => Some(State::Saved),
+            3 => Some(State::Replaying),
+            _ => None,
+        }
+    }
+}
+
+/// Answer to a `Status` request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceStatus {
+    pub state: State,
+    pub uptime_ms: u32,
+    /// Unix time in seconds, 0 if not set.
+    pub unix_time: u32,
+    pub clips: u32,
+    pub crashes: u32,
+}
+
+/// Description of a saved clip.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClipInfo {
+    pub clip: u32,
+    /// Length in bytes.
+    pub len: u32,
+    /// Unix time the clip was saved, 0 if unknown.
+    pub time: u32,
+}
+
+/// Reasons a request fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCode {
+    /// The request could not be decoded.
+    InvalidRequest = 1,
+    UnknownClip = 2,
+    /// The device can't handle the request right now, try again later.
+    Busy = 3,
+    /// Reading the clip failed.
+    Storage = 4,
+}
+
+impl ErrorCode {
+    pub fn from_u8(value: u8) -> Option<ErrorCode> {
+        match value {
+            1 => Some(ErrorCode::InvalidRequest),
+            2 => Some(ErrorCode::UnknownClip),
+            3 => Some(ErrorCode::Busy),
+            4 => Some(ErrorCode::Storage),
+            _ => None,
+        }
+    }
+}
+
+impl Request {
+    /// Encode the request into `buf`, returns the length of the message.
+    pub fn encode(&self, buf: &mut [u8; MAX_MESSAGE_LEN]) -> usize {
+        let mut writer = Writer { buf, len: 0 };
+        match *self {
+            Request::Status => writer.u8(tag::STATUS),
+            Request::ListClips => writer.u8(tag::LIST_CLIPS),
+            Request::ClipInfo(clip) => {
+                writer.u8(tag::CLIP_INFO);
+                writer.u32(clip);
+            }
+            Request::ReadClip { clip, offset, len } => {
+                writer.u8(tag::READ_CLIP);
+                writer.u32(clip);
+                writer.u32(offset);
+                writer.u32(len);
+            }
+            Request::DeleteClip(clip) => {
+                writer.u8(tag::DELETE_CLIP);
+                writer.u32(clip);
+            }
+        };
+        writer.len
+    }
+
+    /// Decode a request message. Returns `None` if it is malformed.
+    pub fn decode(message: &[u8]) -> Option<Request> {
+        let mut reader = Reader { buf: message };
+        let request = match reader.u8()? {
+            tag::STATUS => Request::Status,
+            tag::LIST_CLIPS => Request::ListClips,
+            tag::CLIP_INFO => Request::ClipInfo(reader.u32()?),
+            tag::READ_CLIP => Request::ReadClip {
+                clip: reader.u32()?,
+                offset: reader.u32()?,
+                len: reader.u32()?,
+            },
+            tag::DELETE_CLIP => Request::DeleteClip(reader.u32()?),
+            _ => return None,
+        };
+        reader.end(request)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Encode the response into `buf`, returns the length of the message. Chunks longer than
+    /// `MAX_CHUNK_LEN` are truncated.
+    pub fn encode(&self, buf: &mut [u8; MAX_MESSAGE_LEN]) -> usize {
+        let mut writer = Writer { buf, len: 0 };
+        match *self {
+            Response::Status(status) => {
+                writer.u8(tag::STATUS_REPLY);
+                writer.u8(status.state as u8);
+                writer.u32(status.uptime_ms);
+                writer.u32(status.unix_time);
+                writer.u32(status.clips);
+                writer.u32(status.crashes);
+            }
+            Response::Clip(info) => {
+                writer.u8(tag::CLIP);
+                writer.u32(info.clip);
+                writer.u32(info.len);
+                writer.u32(info.time);
+            }
+            Response::Chunk { offset, data } => {
+                writer.u8(tag::CHUNK);
+                writer.u32(offset);
+                writer.bytes(&data[..data.len().min(MAX_CHUNK_LEN)]);
+            }
+            Response::Done => writer.u8(tag::DONE),
+            Response::Error(code) => {
+                writer.u8(tag::ERROR);
+                writer.u8(code as u8);
+            }
+        };
+        writer.len
+    }
+
+    /// Decode a response message. Returns `None` if it is malformed.
+    pub fn decode(message: &'a [u8]) -> Option<Response<'a>> {
+        let mut reader = Reader { buf: message };
+        let response = match reader.u8()? {
+            tag::STATUS_REPLY => Response::Status(DeviceStatus {
+                state: State::from_u8(reader.u8()?)?,
+                uptime_ms: reader.u32()?,
+                unix_time: reader.u32()?,
+                clips: reader.u32()?,
+                crashes: reader.u32()?,
+            }),
+            tag::CLIP => Response::Clip(ClipInfo {
+                clip: reader.u32()?,
+                len: reader.u32()?,
+                time: reader.u32()?,
+            }),
+            tag::CHUNK => {
+                let offset = reader.u32()?;
+                let data = reader.rest();
+                match data.len() <= MAX_CHUNK_LEN {
+                    true => Response::Chunk { offset, data },
+                    false => return None,
+                }
+            }
+            tag::DONE => Response::Done,
+            tag::ERROR => Response::Error(ErrorCode::from_u8(reader.u8()?)?),
+            _ => return None,
+        };
+        reader.end(response)
+    }
+}
+
+/// Encode `message` into a frame in `frame`, returns the length of the frame.
+pub fn encode_frame(message: &[u8], frame: &mut [u8; MAX_FRAME_LEN]) -> usize {
+    let message = &message[..message.len().min(MAX_MESSAGE_LEN)];
+    let crc = crc32(message).to_le_bytes();
+
+    // COBS: Each group of up to 254 bytes without 0 is prefixed by its length plus one. A 0 byte
+    // ends a group, a group of 254 bytes ends on its own.
+    let mut code_pos = 0;
+    let mut len = 1;
+    for &byte in message.iter().chain(crc.iter()) {
+        if byte != 0 {
+            frame[len] = byte;
+            len += 1;
+        }
+
+        if byte == 0 || len - code_pos == 255 {
+            frame[code_pos] = (len - code_pos) as u8;
+            code_pos = len;
+            len += 1;
+        }
+    }
+    frame[code_pos] = (len - code_pos) as u8;
+
+    frame[len] = 0;
+    len + 1
+}
+
+/// Reasons a received frame is dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameError {
+    TooLong,
+    /// The COBS encoding is invalid.
+    Malformed,
+    /// The CRC does not match, the frame was damaged.
+    Crc,
+}
+
+/// Assembles received bytes into frames and decodes them.
+pub struct FrameDecoder {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// The current frame is too long and is skipped until its end.
+    overflow: bool,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            overflow: false,
+        }
+    }
+
+    /// Add a received byte. Returns the message when a frame is complete. Empty frames are
+    /// ignored.
+    pub fn push(&mut self, byte: u8) -> Option<Result<&[u8], FrameError>> {
+        if byte != 0 {
+            match self.len == self.buf.len() {
+                true => self.overflow = true,
+                false => {
+                    self.buf[self.len] = byte;
+                    self.len += 1;
+                }
+            };
+            return None;
+        }
+
+        let len = core::mem::replace(&mut self.len, 0);
+        if core::mem::replace(&mut self.overflow, false) {
+            return Some(Err(FrameError::TooLong));
+        }
+        if len == 0 {
+            return None;
+        }
+
+        let len = match decode_cobs(&mut self.buf[..len]) {
+            Ok(len) => len,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let (message, crc) = self.buf[..len].split_at(len - 4);
+        match crc32(message).to_le_bytes() == crc {
+            true => Some(Ok(message)),
+            false => Some(Err(FrameError::Crc)),
+        }
+    }
+}
+
+/// Decode COBS encoded `buf` in place, returns the decoded length including the CRC.
+fn decode_cobs(buf: &mut [u8]) -> Result<usize, FrameError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < buf.len() {
+        let code = buf[read] as usize;
+        if code == 0 || read + code > buf.len() {
+            return Err(FrameError::Malformed);
+        }
+
+        buf.copy_within(read + 1..read + code, write);
+        write += code - 1;
+        read += code;
+
+        // Groups shorter than 254 bytes were ended by a 0, except for the last one
+        if code < 255 && read < buf.len() {
+            buf[write] = 0;
+            write += 1;
+        }
+    }
+
+    match write >= 4 {
+        true => Ok(write),
+        false => Err(FrameError::Malformed),
+    }
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8; MAX_MESSAGE_LEN],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn u8(&mut self, value: u8) {
+        self.bytes(&[value]);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let (&value, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(value)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        match self.buf.len() >= 4 {
+            true => {
+                let (value, rest) = self.buf.split_at(4);
+                self.buf = rest;
+                Some(u32::from_le_bytes([value[0], value[1], value[2], value[3]]))
+            }
+            false => None,
+        }
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.buf)
+    }
+
+    /// Return `value` if all bytes were read, otherwise the message is malformed.
+    fn end<T>(&self, value: T) -> Option<T> {
+        match self.buf.is_empty() {
+            true => Some(value),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! SCSI command handling for a read-only USB mass storage device, using the Bulk-Only Transport
+//! (BOT) wrappers. Only the commands needed by common hosts are supported, writes are rejected
+//! since the medium is write protected.
+//!
+//! The handling does not depend on any hardware, so it can be tested on the host.
+
+/// Size of a block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// Length of a command block wrapper in bytes.
+pub const CBW_LEN: usize = 31;
+
+/// Length of a command status wrapper in bytes.
+pub const CSW_LEN: usize = 13;
+
+/// Maximum length of the replies to commands without block data.
+pub const MAX_REPLY_LEN: usize = 36;
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;
+const CSW_SIGNATURE: u32 = 0x5342_5355;
+
+/// Operation codes of the supported commands.
+mod op {
+    pub const TEST_UNIT_READY: u8 = 0x00;
+    pub const REQUEST_SENSE: u8 = 0x03;
+    pub const INQUIRY: u8 = 0x12;
+    pub const START_STOP_UNIT: u8 = 0x1B;
+    pub const MODE_SENSE_6: u8 = 0x1A;
+    pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+    pub const READ_FORMAT_CAPACITIES: u8 = 0x23;
+    pub const READ_CAPACITY_10: u8 = 0x25;
+    pub const READ_10: u8 = 0x28;
+    pub const WRITE_10: u8 = 0x2A;
+    pub const VERIFY_10: u8 = 0x2F;
+    pub const MODE_SENSE_10: u8 = 0x5A;
+}
+
+/// Block storage read by the host.
+pub trait BlockDevice {
+    type Error;
+
+    /// Number of blocks.
+    fn num_blocks(&self) -> u32;
+
+    /// Changes whenever the contents change, so the host can be told to read them again.
+    fn generation(&self) -> u32;
+
+    /// Read block `lba` into `buf`.
+    fn read_block(&mut self, lba: u32, buf: &mut [u8; BLOCK_SIZE]) -> Result<(), Self::Error>;
+}
+
+/// Command block wrapper, sent by the host to start a command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cbw {
+    pub tag: u32,
+    /// Number of bytes the host expects to transfer in the data stage.
+    pub data_len: u32,
+    /// The data stage goes from the device to the host.
+    pub data_in: bool,
+    pub lun: u8,
+    /// Command block, unused bytes are zero.
+    pub command: [u8; 16],
+}
+
+impl Cbw {
+    /// Parse a command block wrapper. Returns `None` if it is not valid.
+    pub fn parse(bytes: &[u8]) -> Option<Cbw> {
+        if bytes.len() != CBW_LEN || le_u32(&bytes[0..4]) != CBW_SIGNATURE {
+            return None;
+        }
+
+        let command_len = bytes[14] as usize;
+        if command_len == 0 || command_len > 16 {
+            return None;
+        }
+
+        let mut command = [0; 16];
+        command[..command_len].copy_from_slice(&bytes[15..15 + command_len]);
+        Some(Cbw {
+            tag: le_u32(&bytes[4..8]),
+            data_len: le_u32(&bytes[8..12]),
+            data_in: bytes[12] & 0x80 != 0,
+            lun: bytes[13] & 0x0F,
+            command,
+        })
+    }
+}
+
+/// Command status, reported in the command status wrapper.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    Passed = 0,
+    Failed = 1,
+    PhaseError = 2,
+}
+
+/// Command status wrapper, sent to the host to finish a command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Csw {
+    pub tag: u32,
+    /// Number of bytes the host expected but were not transferred.
+    pub residue: u32,
+    pub status: Status,
+}
+
+impl Csw {
+    pub fn to_bytes(&self) -> [u8; CSW_LEN] {
+        let mut bytes = [0; CSW_LEN];
+        bytes[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.residue.to_le_bytes());
+        bytes[12] = self.status as u8;
+        bytes
+    }
+}
+
+/// Sense data describing why the last command failed, see SPC-4 section 4.5.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NONE: Sense = Sense::new(0x00, 0x00);
+    pub const INVALID_COMMAND: Sense = Sense::new(0x05, 0x20);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21);
+    pub const INVALID_FIELD: Sense = Sense::new(0x05, 0x24);
+    pub const MEDIUM_CHANGED: Sense = Sense::new(0x06, 0x28);
+    pub const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27);
+    pub const READ_ERROR: Sense = Sense::new(0x03, 0x11);
+
+    const fn new(key: u8, asc: u8) -> Sense {
+        Sense { key, asc, ascq: 0 }
+    }
+}
+
+/// Data stage of a command.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Data {
+    /// No data.
+    None,
+    /// Send the first `len` bytes of the reply buffer.
+    Reply(usize),
+    /// Send `count` blocks starting at block `lba`.
+    Blocks { lba: u32, count: u32 },
+    /// Receive and discard the data sent by the host.
+    Discard,
+}
+
+/// SCSI command handler for a single logical unit.
+pub struct Scsi {
+    sense: Sense,
+    /// Generation of the block device contents last seen by the host.
+    generation: u32,
+}
+
+impl Default for Scsi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scsi {
+    pub fn new() -> Self {
+        Scsi {
+            sense: Sense::NONE,
+            generation: 0,
+        }
+    }
+
+    /// Handle a command for block device `dev`. Replies without block data are written to
+    /// `reply`. Returns the data stage and the status of the command. When a command fails, the
+    /// host can ask for the reason with REQUEST SENSE.
+    pub fn command<D: BlockDevice>(
+        &mut self,
+        command: &[u8; 16],
+        dev: &D,
+        reply: &mut [u8; MAX_REPLY_LEN],
+    ) -> (Data, Status) {
+        let num_blocks = dev.num_blocks();
+
+        // Tell the host once that it has to read the medium again
+        let changed = self.generation != dev.generation();
+
+        let result = match command[0] {
+            op::REQUEST_SENSE => {
+                let sense = core::mem::replace(&mut self.sense, Sense::NONE);
+                reply[..18].copy_from_slice(&[
+                    0x70, 0, sense.key, 0, 0, 0, 0, 10, 0, 0, 0, 0, sense.asc, sense.ascq, 0, 0, 0,
+                    0,
+                ]);
+                Ok(Data::Reply(18))
+            }
+            op::INQUIRY if command[1] & 0x01 != 0 => Err(Sense::INVALID_FIELD),
+            op::INQUIRY => {
+                reply[..8].copy_from_slice(&[0x00, 0x80, 0x04, 0x02, 31, 0, 0, 0]);
+                reply[8..16].copy_from_slice(b"Dashcam ");
+                reply[16..32].copy_from_slice(b"Saved clips     ");
+                reply[32..36].copy_from_slice(b"0.4 ");
+                Ok(Data::Reply(36))
+            }
+            _ if changed => {
+                self.generation = dev.generation();
+                Err(Sense::MEDIUM_CHANGED)
+            }
+            op::TEST_UNIT_READY
+            | op::START_STOP_UNIT
+            | op::PREVENT_ALLOW_MEDIUM_REMOVAL
+            | op::VERIFY_10 => Ok(Data::None),
+            op::MODE_SENSE_6 => {
+                // No mode pages, only the header with the write protect bit set
+                reply[..4].copy_from_slice(&[3, 0, 0x80, 0]);
+                Ok(Data::Reply(4))
+            }
+            op::MODE_SENSE_10 => {
+                reply[..8].copy_from_slice(&[0, 6, 0, 0x80, 0, 0, 0, 0]);
+                Ok(Data::Reply(8))
+            }
+            op::READ_FORMAT_CAPACITIES => {
+                reply[..4].copy_from_slice(&[0, 0, 0, 8]);
+                reply[4..8].copy_from_slice(&num_blocks.to_be_bytes());
+                reply[8..12].copy_from_slice(&(BLOCK_SIZE as u32 | 0x0200_0000).to_be_bytes());
+                Ok(Data::Reply(12))
+            }
+            op::READ_CAPACITY_10 => {
+                reply[..4].copy_from_slice(&(num_blocks - 1).to_be_bytes());
+                reply[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                Ok(Data::Reply(8))
+            }
+            op::READ_10 => {
+                let lba = be_u32(&command[2..6]);
+                let count = u16::from_be_bytes([command[7], command[8]]) as u32;
+                match lba.checked_add(count) {
+                    Some(end) if end <= num_blocks => Ok(Data::Blocks { lba, count }),
+                    _ => Err(Sense::LBA_OUT_OF_RANGE),
+                }
+            }
+            op::WRITE_10 => {
+                self.sense = Sense::WRITE_PROTECTED;
+                return (Data::Discard, Status::Failed);
+            }
+            _ => Err(Sense::INVALID_COMMAND),
+        };
+
+        match result {
+            Ok(data) => (data, Status::Passed),
+            Err(sense) => {
+                self.sense = sense;
+                (Data::None, Status::Failed)
+            }
+        }
+    }
+
+    /// Report that reading a block failed during the data stage.
+    pub fn read_error(&mut self) {
+        self.sense = Sense::READ_ERROR;
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -1,7 +1,42 @@
 //! Miscellaneous helper functions.
 
-use core::panic::PanicInfo;
-use rtt_target::rprintln;
+use crate::{crash, log, watchdog};
+use core::{
+    fmt::{self, Write},
+    panic::PanicInfo,
+};
+
+/// Formatting target which fills a fixed size byte buffer, silently dropping what doesn't fit.
+pub struct ByteWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> ByteWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        ByteWriter { buf, len: 0 }
+    }
+
+    /// Get what was written so far.
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl Write for ByteWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            // Only whole characters, leaving room for a terminating zero
+            if self.len + c.len_utf8() >= self.buf.len() {
+                break;
+            }
+
+            self.len += c.encode_utf8(&mut self.buf[self.len..]).len();
+        }
+
+        Ok(())
+    }
+}
 
 /// Sets `size` items of type T located at `addr` to `val`.
 #[allow(dead_code)]
@@ -14,25 +49,37 @@ pub fn memory_set<T: Copy>(addr: u32, size: usize, val: T) {
     }
 }
 
-/// Prints `size` bytes located at `addr` using RTT.
+/// Logs `size` bytes located at `addr`.
 #[allow(dead_code)]
 pub fn memory_get(addr: u32, size: usize) {
-    rprintln!("{} bytes located at address {:X}:", size, addr);
+    info!("{} bytes located at address {:X}:", size, addr);
 
     for i in 0..size {
         unsafe {
             let curr: *mut u8 = (addr + i as u32) as *mut u8;
             let val: u8 = core::ptr::read_volatile(curr);
-            rprintln!("\t{:X}", val);
+            info!("\t{:X}", val);
         }
     }
 }
 
-/// Custom handler to use RTT when a panic occurs.
+/// Time to keep the log output available after a panic before the device resets.
+const PANIC_RESET_MS: u32 = 1000;
+
+/// Custom handler to use the log and the crash log when a panic occurs. The device is reset by
+/// the watchdog afterwards, even if the panic happened before the watchdog was started.
 #[inline(never)]
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    rprintln!("Panicked!");
-    rprintln!("{:?}", _info);
+    // Stop all tasks, so nothing services the watchdog anymore
+    cortex_m::interrupt::disable();
+
+    // Log the crash before anything else can go wrong
+    crash::log_panic(_info);
+
+    error!("Panicked!");
+    error!("{}", log::display(_info));
+
+    watchdog::start(PANIC_RESET_MS);
     loop {}
 }
@@ -0,0 +1,160 @@
+//! A driver for the STM32F7 independent watchdog (IWDG). Once started, the IWDG cannot be
+//! stopped and resets the device unless it is fed before the timeout expires.
+//!
+//! Tasks check in with `check_in` and `service` only feeds the watchdog once every required task
+//! has checked in, so a single hung task is enough to reset the device.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use stm32f7xx_hal::pac::{DBGMCU, IWDG, RCC};
+
+/// The IWDG is clocked by the ~32 kHz LSI, divided by 256 one tick is 8 ms.
+const TICK_MS: u32 = 8;
+
+/// Prescaler register value for a divider of 256.
+const PRESCALER_256: u8 = 0b110;
+
+/// Key register values.
+const KEY_FEED: u16 = 0xAAAA;
+const KEY_UNLOCK: u16 = 0x5555;
+const KEY_START: u16 = 0xCCCC;
+
+/// RCC_CSR reset flags.
+const CSR_LPWRRSTF: u32 = 1 << 31;
+const CSR_WWDGRSTF: u32 = 1 << 30;
+const CSR_IWDGRSTF: u32 = 1 << 29;
+const CSR_SFTRSTF: u32 = 1 << 28;
+const CSR_PORRSTF: u32 = 1 << 27;
+const CSR_PINRSTF: u32 = 1 << 26;
+const CSR_BORRSTF: u32 = 1 << 25;
+
+/// Tasks which must check in before the watchdog is fed (bit mask).
+static REQUIRED: AtomicU32 = AtomicU32::new(Task::Capture as u32 | Task::Idle as u32);
+
+/// Tasks which checked in since the watchdog was last fed (bit mask).
+static CHECKINS: AtomicU32 = AtomicU32::new(0);
+
+/// Tasks supervised by the watchdog.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Task {
+    /// Capture pipeline, checks in while frames arrive as expected.
+    Capture = 1 << 0,
+    /// Saving or loading video, only supervised while busy (see `begin`).
+    Save = 1 << 1,
+    /// Idle loop, checks in whenever no other task is running.
+    Idle = 1 << 2,
+}
+
+/// Cause of the most recent reset.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResetCause {
+    /// Independent watchdog timeout.
+    IndependentWatchdog,
+    /// Window watchdog timeout.
+    WindowWatchdog,
+    /// Software reset request.
+    Software,
+    /// Illegal low-power mode entry.
+    LowPower,
+    /// Power on or power down.
+    PowerOn,
+    /// Supply voltage dropped below the brown-out threshold.
+    BrownOut,
+    /// NRST pin, e.g. the reset button or a debugger.
+    Pin,
+    /// No reset flag was set.
+    Unknown,
+}
+
+/// Start the watchdog with a timeout of `timeout_ms` milliseconds (32 seconds max). The watchdog
+/// is frozen while the core is halted by a debugger.
+pub fn start(timeout_ms: u32) {
+    let iwdg_regs = unsafe { &(*IWDG::ptr()) };
+    let dbgmcu_regs = unsafe { &(*DBGMCU::ptr()) };
+
+    let reload = (timeout_ms / TICK_MS).min(0xFFF) as u16;
+
+    // Don't reset the device while debugging
+    dbgmcu_regs
+        .apb1_fz
+        .modify(|_, w| w.dbg_iwdg_stop().set_bit());
+
+    unsafe {
+        // Start the watchdog, then unlock and program the prescaler and reload registers
+        iwdg_regs.kr.write(|w| w.key().bits(KEY_START));
+        iwdg_regs.kr.write(|w| w.key().bits(KEY_UNLOCK));
+        iwdg_regs.pr.write(|w| w.pr().bits(PRESCALER_256));
+        iwdg_regs.rlr.write(|w| w.rl().bits(reload));
+    }
+
+    // Wait for the registers to be updated in the LSI clock domain
+    while iwdg_regs.sr.read().bits() != 0 {}
+
+    feed();
+}
+
+/// Reload the watchdog counter.
+fn feed() {
+    let iwdg_regs = unsafe { &(*IWDG::ptr()) };
+
+    unsafe {
+        iwdg_regs.kr.write(|w| w.key().bits(KEY_FEED));
+    }
+}
+
+/// Report that `task` is making progress.
+pub fn check_in(task: Task) {
+    CHECKINS.fetch_or(task as u32, Ordering::Relaxed);
+}
+
+/// Feed the watchdog if all required tasks checked in since it was last fed. Returns `true` if
+/// the watchdog was fed. Call this periodically, well within the watchdog timeout.
+pub fn service() -> bool {
+    let required = REQUIRED.load(Ordering::Relaxed);
+    if CHECKINS.load(Ordering::Relaxed) & required == required {
+        CHECKINS.store(0, Ordering::Relaxed);
+        feed();
+        true
+    } else {
+        false
+    }
+}
+
+/// Mark the start of long running work in `task`, which must check in until `end` is called. The
+/// idle loop cannot run in the meantime, so it is excused from checking in.
+pub fn begin(task: Task) {
+    REQUIRED.fetch_or(task as u32, Ordering::Relaxed);
+    REQUIRED.fetch_and(!(Task::Idle as u32), Ordering::Relaxed);
+}
+
+/// Mark the end of long running work in `task`.
+pub fn end(task: Task) {
+    REQUIRED.fetch_and(!(task as u32), Ordering::Relaxed);
+    REQUIRED.fetch_or(Task::Idle as u32, Ordering::Relaxed);
+}
+
+/// Read and clear the reset flags to determine why the device was reset. Call once at boot.
+pub fn reset_cause() -> ResetCause {
+    let rcc_regs = unsafe { &(*RCC::ptr()) };
+
+    let flags = rcc_regs.csr.read().bits();
+    rcc_regs.csr.modify(|_, w| w.rmvf().set_bit());
+
+    // A power on reset also sets the brown-out and pin flags, so check in order of precedence
+    if flags & CSR_IWDGRSTF != 0 {
+        ResetCause::IndependentWatchdog
+    } else if flags & CSR_WWDGRSTF != 0 {
+        ResetCause::WindowWatchdog
+    } else if flags & CSR_SFTRSTF != 0 {
+        ResetCause::Software
+    } else if flags & CSR_LPWRRSTF != 0 {
+        ResetCause::LowPower
+    } else if flags & CSR_PORRSTF != 0 {
+        ResetCause::PowerOn
+    } else if flags & CSR_BORRSTF != 0 {
+        ResetCause::BrownOut
+    } else if flags & CSR_PINRSTF != 0 {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    }
+}
@@ -0,0 +1,464 @@
+//! AVI (RIFF) writer for uncompressed or MJPEG video, one stream with a frame index.
+
+use crate::{
+    clip::{ClipReader, Codec, Error, PixelFormat},
+    codec,
+};
+use std::{convert::Infallible, io};
+
+/// Pixel format of the AVI file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// 24 bit BGR, which every player supports.
+    Bgr24,
+    /// 16 bit RGB565 as saved by the dash cam, smaller but not supported everywhere.
+    Rgb565,
+}
+
+impl Format {
+    fn bits(self) -> u16 {
+        match self {
+            Format::Bgr24 => 24,
+            Format::Rgb565 => 16,
+        }
+    }
+}
+
+/// Video stream of the AVI file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Video {
+    /// Uncompressed bitmaps, from raw clips.
+    Bitmap(Format),
+    /// JPEG images, copied from compressed clips.
+    Mjpeg,
+}
+
+impl Video {
+    fn bits(self) -> u16 {
+        match self {
+            Video::Bitmap(format) => format.bits(),
+            Video::Mjpeg => 24,
+        }
+    }
+
+    /// Compression of the bitmap header.
+    fn compression(self) -> u32 {
+        match self {
+            Video::Bitmap(Format::Bgr24) => BI_RGB,
+            Video::Bitmap(Format::Rgb565) => BI_BITFIELDS,
+            Video::Mjpeg => u32::from_le_bytes(*b"MJPG"),
+        }
+    }
+
+    /// Chunk ID of the frames of stream 0.
+    fn chunk_id(self) -> &'static [u8; 4] {
+        match self {
+            Video::Bitmap(_) => b"00db",
+            Video::Mjpeg => b"00dc",
+        }
+    }
+}
+
+/// `BI_RGB` and `BI_BITFIELDS` of the bitmap header.
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+
+/// Flags of the main header and the index.
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// Convert the clip in `data` to an AVI file. Raw and delta coded clips are converted to `format`,
+/// JPEG clips are written as MJPEG.
+pub fn convert(data: &mut [u8], format: Format) -> io::Result<Vec<u8>> {
+    let len = data.len() as u32;
+    let reader = ClipReader::open(data, len).map_err(clip_error)?;
+    reader.verify(data).map_err(clip_error)?;
+
+    let header = *reader.header();
+    if header.pixel_format != PixelFormat::Rgb565 {
+        return Err(clip_error(Error::Unsupported));
+    }
+
+    let width = header.width as usize;
+    let height = header.height as usize;
+    let video = match header.codec {
+        Codec::Raw | Codec::Delta => Video::Bitmap(format),
+        Codec::Jpeg => Video::Mjpeg,
+    };
+    let mut frames = Vec::new();
+    let mut pixels: Option<Vec<u8>> = None;
+    for n in 0..header.frame_count {
+        let entry = reader.frame(data, n).map_err(clip_error)?;
+        let mut frame = vec![0; entry.size as usize];
+        reader.read_frame(data, n, &mut frame).map_err(clip_error)?;
+
+        // Delta coded frames are decoded against the previous one
+        if header.codec == Codec::Delta {
+            let mut decoded = vec![0; width * height * 2];
+            codec::decode(
+                &frame,
+                pixels.as_deref(),
+                header.width,
+                header.height,
+                &mut decoded,
+            )
+            .map_err(|_| clip_error(Error::Corrupt))?;
+            pixels = Some(decoded.clone());
+            frame = decoded;
+        }
+
+        match video {
+            Video::Bitmap(_) if frame.len() != width * height * 2 => {
+                return Err(clip_error(Error::Corrupt))
+            }
+            Video::Bitmap(format) => frames.push(bitmap(&frame, width, height, format)),
+            Video::Mjpeg => frames.push(frame),
+        }
+    }
+
+    Ok(write(
+        &frames,
+        width,
+        height,
+        header.frame_rate as u32,
+        video,
+    ))
+}
+
+fn clip_error(e: Error<Infallible>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Cannot read clip: {:?}", e),
+    )
+}
+
+/// Convert RGB565 pixels to the rows of a bitmap, which are bottom up and padded to 4 bytes.
+fn bitmap(pixels: &[u8], width: usize, height: usize, format: Format) -> Vec<u8> {
+    let stride = row_len(width, format);
+    let mut bitmap = vec![0; stride * height];
+    for (y, row) in pixels.chunks(width * 2).enumerate() {
+        let out = &mut bitmap[(height - 1 - y) * stride..][..stride];
+        for (x, pixel) in row.chunks(2).enumerate() {
+            match format {
+                Format::Bgr24 => {
+                    let [r, g, b] = rgb565_to_rgb888(u16::from_le_bytes([pixel[0], pixel[1]]));
+                    out[x * 3..x * 3 + 3].copy_from_slice(&[b, g, r]);
+                }
+                Format::Rgb565 => out[x * 2..x * 2 + 2].copy_from_slice(pixel),
+            }
+        }
+    }
+    bitmap
+}
+
+/// Expand an RGB565 pixel to 8 bits per color, repeating the high bits so white stays white.
+pub fn rgb565_to_rgb888(pixel: u16) -> [u8; 3] {
+    let r = (pixel >> 11) as u8 & 0x1F;
+    let g = (pixel >> 5) as u8 & 0x3F;
+    let b = pixel as u8 & 0x1F;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+fn row_len(width: usize, format: Format) -> usize {
+    (width * format.bits() as usize / 8).div_ceil(4) * 4
+}
+
+/// Write an AVI file holding `frames`, each a bitmap or JPEG image of `width` by `height` pixels.
+fn write(frames: &[Vec<u8>], width: usize, height: usize, fps: u32, video: Video) -> Vec<u8> {
+    let image_len = width * video.bits() as usize / 8 * height;
+    let frame_len = match video {
+        Video::Bitmap(format) => row_len(width, format) * height,
+        Video::Mjpeg => frames.iter().map(Vec::len).max().unwrap_or(0),
+    };
+    let fps = fps.max(1);
+
+    let mut avih = Vec::new();
+    put_u32(&mut avih, 1_000_000 / fps);
+    put_u32(&mut avih, frame_len as u32 * fps);
+    put_u32(&mut avih, 0);
+    put_u32(&mut avih, AVIF_HASINDEX);
+    put_u32(&mut avih, frames.len() as u32);
+    put_u32(&mut avih, 0);
+    put_u32(&mut avih, 1);
+    put_u32(&mut avih, frame_len as u32);
+    put_u32(&mut avih, width as u32);
+    put_u32(&mut avih, height as u32);
+    avih.extend_from_slice(&[0; 16]);
+
+    let mut strh = Vec::new();
+    strh.extend_from_slice(b"vids");
+    match video {
+        Video::Bitmap(_) => put_u32(&mut strh, 0),
+        Video::Mjpeg => strh.extend_from_slice(b"MJPG"),
+    }
+    put_u32(&mut strh, 0);
+    put_u32(&mut strh, 0);
+    put_u32(&mut strh, 0);
+    put_u32(&mut strh, 1);
+    put_u32(&mut strh, fps);
+    put_u32(&mut strh, 0);
+    put_u32(&mut strh, frames.len() as u32);
+    put_u32(&mut strh, frame_len as u32);
+    put_u32(&mut strh, u32::MAX);
+    put_u32(&mut strh, 0);
+    put_u16(&mut strh, 0);
+    put_u16(&mut strh, 0);
+    put_u16(&mut strh, width as u16);
+    put_u16(&mut strh, height as u16);
+
+    let mut strf = Vec::new();
+    put_u32(&mut strf, 40);
+    put_u32(&mut strf, width as u32);
+    put_u32(&mut strf, height as u32);
+    put_u16(&mut strf, 1);
+    put_u16(&mut strf, video.bits());
+    put_u32(&mut strf, video.compression());
+    put_u32(&mut strf, image_len as u32);
+    strf.extend_from_slice(&[0; 16]);
+    if video == Video::Bitmap(Format::Rgb565) {
+        for mask in &[0xF800, 0x07E0, 0x001F] {
+            put_u32(&mut strf, *mask);
+        }
+    }
+
+    let mut strl = Vec::new();
+    chunk(&mut strl, b"strh", &strh);
+    chunk(&mut strl, b"strf", &strf);
+    let mut hdrl = Vec::new();
+    chunk(&mut hdrl, b"avih", &avih);
+    list(&mut hdrl, b"strl", &strl);
+
+    // Index offsets are relative to the "movi" type of the list
+    let mut movi = Vec::new();
+    let mut idx1 = Vec::new();
+    for frame in frames {
+        idx1.extend_from_slice(video.chunk_id());
+        put_u32(&mut idx1, AVIIF_KEYFRAME);
+        put_u32(&mut idx1, movi.len() as u32 + 4);
+        put_u32(&mut idx1, frame.len() as u32);
+        chunk(&mut movi, video.chunk_id(), frame);
+    }
+
+    let mut avi = Vec::new();
+    avi.extend_from_slice(b"AVI ");
+    list(&mut avi, b"hdrl", &hdrl);
+    list(&mut avi, b"movi", &movi);
+    chunk(&mut avi, b"idx1", &idx1);
+
+    let mut riff = Vec::new();
+    chunk(&mut riff, b"RIFF", &avi);
+    riff
+}
+
+/// Append a chunk, padded to an even size.
+fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    put_u32(out, data.len() as u32);
+    out.extend_from_slice(data);
+    if !data.len().is_multiple_of(2) {
+        out.push(0);
+    }
+}
+
+fn list(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+    chunk(out, b"LIST", &body);
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clip::{ClipWriter, FrameEntry, Header, Sink};
+
+    struct Buffer(Vec<u8>);
+
+    impl Sink for Buffer {
+        type Error = Infallible;
+
+        fn write(&mut self, data: &[u8]) -> Result<(), Infallible> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    /// A clip of `frames` of 6 x 2 pixels at 5 frames per second.
+    fn write_clip(codec: Codec, frames: &[Vec<u8>]) -> Vec<u8> {
+        let header = Header {
+            width: 6,
+            height: 2,
+            pixel_format: PixelFormat::Rgb565,
+            codec,
+            frame_rate: 5,
+            frame_count: frames.len() as u32,
+            start_time: 0,
+        };
+        let mut buf = Buffer(Vec::new());
+        let mut index = vec![FrameEntry::default(); frames.len()];
+        let mut writer = ClipWriter::new(&mut buf, header, &mut index).unwrap();
+        for (n, frame) in frames.iter().enumerate() {
+            writer.write_frame(&mut buf, frame, n as u32 * 200).unwrap();
+        }
+        writer.finish(&mut buf).unwrap();
+        buf.0
+    }
+
+    /// A raw clip of 3 frames, with pixel (0, 0) red, green or blue.
+    fn clip() -> Vec<u8> {
+        let frames: Vec<_> = [0xF800_u16, 0x07E0, 0x001F]
+            .iter()
+            .map(|color| {
+                let mut frame = vec![0xFF; 6 * 2 * 2];
+                frame[..2].copy_from_slice(&color.to_le_bytes());
+                frame
+            })
+            .collect();
+        write_clip(Codec::Raw, &frames)
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            data[offset],
+            data[offset + 1],
+            data[offset + 2],
+            data[offset + 3],
+        ])
+    }
+
+    /// Find the chunk or list `id` in `data`, returning its body.
+    fn find<'a>(data: &'a [u8], id: &[u8]) -> &'a [u8] {
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let len = u32_at(data, offset + 4) as usize;
+            let body = &data[offset + 8..offset + 8 + len];
+            if &data[offset..offset + 4] == id
+                || (&data[offset..offset + 4] == b"LIST" && &body[..4] == id)
+            {
+                return body;
+            }
+            offset += 8 + len + len % 2;
+        }
+        panic!("No chunk {:?}", std::str::from_utf8(id));
+    }
+
+    #[test]
+    fn bgr24() {
+        let avi = convert(&mut clip(), Format::Bgr24).unwrap();
+        assert_eq!(&avi[..4], b"RIFF");
+        assert_eq!(u32_at(&avi, 4) as usize, avi.len() - 8);
+        assert_eq!(&avi[8..12], b"AVI ");
+
+        let hdrl = find(&avi[12..], b"hdrl");
+        let avih = find(&hdrl[4..], b"avih");
+        assert_eq!(u32_at(avih, 0), 200_000);
+        assert_eq!(u32_at(avih, 16), 3);
+        assert_eq!((u32_at(avih, 32), u32_at(avih, 36)), (6, 2));
+        let strf = find(&find(&hdrl[4..], b"strl")[4..], b"strf");
+        assert_eq!(strf.len(), 40);
+
+        // Rows are bottom up and padded, the first pixel is on the second row
+        let movi = find(&avi[12..], b"movi");
+        let idx1 = find(&avi[12..], b"idx1");
+        assert_eq!(idx1.len(), 3 * 16);
+        for (n, bgr) in [[0, 0, 0xFF], [0, 0xFF, 0], [0xFF, 0, 0]]
+            .iter()
+            .enumerate()
+        {
+            let entry = &idx1[n * 16..];
+            assert_eq!(&entry[..4], b"00db");
+            let offset = u32_at(entry, 8) as usize;
+            let frame = &movi[offset + 8..][..u32_at(entry, 12) as usize];
+            assert_eq!(frame.len(), 2 * 20);
+            assert_eq!(&frame[20..23], bgr);
+            assert_eq!(&frame[23..38], &[0xFF; 15]);
+            assert_eq!(&frame[38..40], &[0, 0]);
+        }
+    }
+
+    #[test]
+    fn rgb565() {
+        let avi = convert(&mut clip(), Format::Rgb565).unwrap();
+        let hdrl = find(&avi[12..], b"hdrl");
+        let strf = find(&find(&hdrl[4..], b"strl")[4..], b"strf");
+        assert_eq!(strf.len(), 52);
+        assert_eq!(u32_at(strf, 16), BI_BITFIELDS);
+
+        let movi = find(&avi[12..], b"movi");
+        let frame = find(&movi[4..], b"00db");
+        assert_eq!(frame.len(), 2 * 12);
+        assert_eq!(&frame[12..14], &0xF800_u16.to_le_bytes());
+    }
+
+    #[test]
+    fn delta() {
+        // Decodes to the same frames as the raw clip
+        let raw = convert(&mut clip(), Format::Bgr24).unwrap();
+        let mut data = clip();
+        let len = data.len() as u32;
+        let reader = ClipReader::open(&mut data[..], len).unwrap();
+        let mut frames = Vec::new();
+        let mut prev: Option<Vec<u8>> = None;
+        for n in 0..3 {
+            let mut frame = vec![0; 6 * 2 * 2];
+            reader.read_frame(&mut data[..], n, &mut frame).unwrap();
+            let mut out = vec![0; codec::max_len(6, 2)];
+            let len = codec::encode(&frame, prev.as_deref(), 6, 2, &mut out).unwrap();
+            out.truncate(len);
+            frames.push(out);
+            prev = Some(frame);
+        }
+        let avi = convert(&mut write_clip(Codec::Delta, &frames), Format::Bgr24).unwrap();
+        assert_eq!(avi, raw);
+
+        // Without the first frame, the second can't be decoded
+        assert!(convert(&mut write_clip(Codec::Delta, &frames[1..]), Format::Bgr24).is_err());
+    }
+
+    #[test]
+    fn mjpeg() {
+        // JPEG images are copied, chunks are padded to an even size
+        let frames = [
+            vec![0xFF, 0xD8, 1, 0xFF, 0xD9],
+            vec![0xFF, 0xD8, 2, 3, 0xFF, 0xD9],
+        ];
+        let avi = convert(&mut write_clip(Codec::Jpeg, &frames), Format::Rgb565).unwrap();
+        let hdrl = find(&avi[12..], b"hdrl");
+        let strl = find(&hdrl[4..], b"strl");
+        assert_eq!(&find(&strl[4..], b"strh")[4..8], b"MJPG");
+        let strf = find(&strl[4..], b"strf");
+        assert_eq!(strf.len(), 40);
+        assert_eq!(&strf[16..20], b"MJPG");
+
+        let movi = find(&avi[12..], b"movi");
+        let idx1 = find(&avi[12..], b"idx1");
+        for (n, data) in frames.iter().enumerate() {
+            let entry = &idx1[n * 16..];
+            assert_eq!(&entry[..4], b"00dc");
+            let offset = u32_at(entry, 8) as usize;
+            assert_eq!(&movi[offset..offset + 4], b"00dc");
+            assert_eq!(&movi[offset + 8..][..u32_at(entry, 12) as usize], &data[..]);
+        }
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(rgb565_to_rgb888(0xFFFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(rgb565_to_rgb888(0), [0, 0, 0]);
+        assert_eq!(rgb565_to_rgb888(0x8410), [0x84, 0x82, 0x84]);
+    }
+
+    #[test]
+    fn damaged() {
+        let mut clip = clip();
+        clip[50] ^= 1;
+        assert!(convert(&mut clip, Format::Bgr24).is_err());
+    }
+}
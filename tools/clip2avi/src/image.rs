@@ -0,0 +1,163 @@
+//! Finds the clips in a clip file or a flash image holding the filesystem of the dash cam.
+
+use crate::clip::ClipReader;
+use crate::flashfs::{self, FileSystem, Mem, BLOCK_SIZE, MAX_BLOCKS, META_BLOCKS};
+use std::convert::Infallible;
+
+/// The firmware leaves the last 64 KB of the QSPI flash out of the filesystem.
+const RESERVED: u32 = 0x10000;
+
+/// A flash image in memory.
+pub struct Image(pub Vec<u8>);
+
+impl Mem for Image {
+    type Error = Infallible;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), Infallible> {
+        let start = addr as usize;
+        buf.copy_from_slice(&self.0[start..start + buf.len()]);
+        Ok(())
+    }
+
+    /// Programming flash can only clear bits.
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), Infallible> {
+        for (byte, new) in self.0[addr as usize..].iter_mut().zip(buf) {
+            *byte &= new;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self) -> Result<(), Infallible> {
+        self.0.fill(0xFF);
+        Ok(())
+    }
+
+    fn erase_region(&mut self, addr: u32, len: usize) -> Result<(), Infallible> {
+        let start = (addr - addr % BLOCK_SIZE) as usize;
+        let end = (addr as usize + len).div_ceil(BLOCK_SIZE as usize) * BLOCK_SIZE as usize;
+        self.0[start..end].fill(0xFF);
+        Ok(())
+    }
+}
+
+/// The clips in `data`, with their names. A clip file is a single clip named `name`, in a flash
+/// image each file of the filesystem which is a clip is returned.
+pub fn find_clips(data: Vec<u8>, name: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let len = data.len() as u32;
+    let mut image = Image(data);
+    if ClipReader::open(&mut image.0[..], len).is_ok() {
+        return Ok(vec![(name.to_string(), image.0)]);
+    }
+
+    let fs = mount(&mut image)?;
+    let mut clips = Vec::new();
+    for meta in fs.files() {
+        let mut data = vec![0; meta.size as usize];
+        fs.read(&mut image, meta.name(), 0, &mut data)
+            .map_err(|e| format!("Cannot read {}: {:?}", meta.name(), e))?;
+        if ClipReader::open(&mut data[..], meta.size).is_ok() {
+            clips.push((meta.name().to_string(), data));
+        }
+    }
+
+    clips.sort();
+    Ok(clips)
+}
+
+/// Mount the filesystem, which covers either the whole image or all but the reserved end.
+fn mount(image: &mut Image) -> Result<FileSystem, String> {
+    let len = image.0.len() as u32 / BLOCK_SIZE * BLOCK_SIZE;
+    let mut result = Err(flashfs::Error::NoFilesystem);
+    for size in [len, len.saturating_sub(RESERVED)] {
+        let blocks = size / BLOCK_SIZE;
+        if blocks <= META_BLOCKS || blocks > MAX_BLOCKS {
+            continue;
+        }
+        result = FileSystem::mount(image, size);
+        if result.is_ok() {
+            break;
+        }
+    }
+
+    result.map_err(|e| match e {
+        flashfs::Error::NoFilesystem => "Neither a clip nor a flash image".to_string(),
+        e => format!("Cannot mount flash image: {:?}", e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clip::{ClipWriter, Codec, FrameEntry, Header, PixelFormat, Sink};
+
+    struct Buffer(Vec<u8>);
+
+    impl Sink for Buffer {
+        type Error = Infallible;
+
+        fn write(&mut self, data: &[u8]) -> Result<(), Infallible> {
+            self.0.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn clip(frame_count: u32) -> Vec<u8> {
+        let header = Header {
+            width: 4,
+            height: 2,
+            pixel_format: PixelFormat::Rgb565,
+            codec: Codec::Raw,
+            frame_rate: 30,
+            frame_count,
+            start_time: 0,
+        };
+        let mut buf = Buffer(Vec::new());
+        let mut index = vec![FrameEntry::default(); frame_count as usize];
+        let mut writer = ClipWriter::new(&mut buf, header, &mut index).unwrap();
+        for n in 0..frame_count {
+            writer
+                .write_frame(&mut buf, &[n as u8; 16], n * 33)
+                .unwrap();
+        }
+        writer.finish(&mut buf).unwrap();
+        buf.0
+    }
+
+    /// An image of `blocks`, with a filesystem on all but `reserved` bytes at the end.
+    fn image(blocks: u32, reserved: u32) -> Image {
+        let mut image = Image(vec![0; (blocks * BLOCK_SIZE) as usize]);
+        let size = blocks * BLOCK_SIZE - reserved;
+        let mut fs = FileSystem::format(&mut image, size).unwrap();
+        fs.write_file(&mut image, "clip", 0, &clip(3)).unwrap();
+        fs.write_file(&mut image, "config", 0, &[1, 2, 3, 4])
+            .unwrap();
+        fs.write_file(&mut image, "clip2", 0, &clip(1)).unwrap();
+        image
+    }
+
+    #[test]
+    fn clip_file() {
+        let clips = find_clips(clip(2), "CLIP0001").unwrap();
+        assert_eq!(clips, [("CLIP0001".to_string(), clip(2))]);
+    }
+
+    #[test]
+    fn flash_image() {
+        for &reserved in &[0, RESERVED] {
+            let clips = find_clips(image(32, reserved).0, "flash").unwrap();
+            assert_eq!(
+                clips,
+                [
+                    ("clip".to_string(), clip(3)),
+                    ("clip2".to_string(), clip(1))
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn neither() {
+        assert!(find_clips(vec![0xFF; 100_000], "data").is_err());
+        assert!(find_clips(Vec::new(), "empty").is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! Exports the clips of the dash cam as AVI videos, which any player can open.
+//!
+//! ```text
+//! clip2avi CLIP0001.CLP
+//! clip2avi --format rgb565 --out videos flash.bin
+//! ```
+//!
+//! The input is either a clip file, as copied from the USB drive or SD card or downloaded with
+//! `dashctl`, or an image of the QSPI flash holding its filesystem. Each clip is written to
+//! `<name>.avi` in the output directory. JPEG clips are exported as MJPEG, raw and delta coded
+//! clips as bitmaps in `--format`.
+
+mod avi;
+mod image;
+
+#[allow(dead_code)]
+#[path = "../../../src/clip.rs"]
+mod clip;
+
+#[allow(dead_code)]
+#[path = "../../../src/codec.rs"]
+mod codec;
+
+#[path = "../../../src/crc.rs"]
+mod crc;
+
+#[allow(dead_code)]
+#[path = "../../../src/flashfs.rs"]
+mod flashfs;
+
+use avi::Format;
+use std::{env, fs, path::Path, process};
+
+const USAGE: &str = "Usage: clip2avi [--format <bgr24|rgb565>] [--out <dir>] <input>";
+
+/// Command line options.
+struct Options {
+    input: String,
+    format: Format,
+    out: String,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    if let Err(e) = run(&options) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let input = Path::new(&options.input);
+    let data = fs::read(input).map_err(|e| format!("Cannot read {}: {}", options.input, e))?;
+    let name = input
+        .file_stem()
+        .map_or("clip".into(), |stem| stem.to_string_lossy());
+
+    let clips = image::find_clips(data, &name)?;
+    if clips.is_empty() {
+        return Err("No clips found".into());
+    }
+
+    for (name, mut data) in clips {
+        let file = Path::new(&options.out).join(format!("{}.avi", name));
+        match avi::convert(&mut data, options.format) {
+            Ok(avi) => {
+                fs::write(&file, avi)
+                    .map_err(|e| format!("Cannot write {}: {}", file.display(), e))?;
+                println!("{} -> {}", name, file.display());
+            }
+            Err(e) => eprintln!("Skipping {}: {}", name, e),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut format = Format::Bgr24;
+    let mut out = ".".to_string();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = match args.next().ok_or("Missing format")?.as_str() {
+                    "bgr24" => Format::Bgr24,
+                    "rgb565" => Format::Rgb565,
+                    other => return Err(format!("Unknown format: {}", other)),
+                }
+            }
+            "--out" => out = args.next().ok_or("Missing output directory")?,
+            _ if !arg.starts_with("--") && input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    Ok(Options {
+        input: input.ok_or("Missing input file")?,
+        format,
+        out,
+    })
+}
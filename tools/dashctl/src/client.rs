@@ -0,0 +1,425 @@
+//! Host side of the USB serial protocol.
+
+use crate::proto::{
+    encode_frame, ClipInfo, DeviceStatus, ErrorCode, FrameDecoder, FrameError, Request, Response,
+    MAX_FRAME_LEN, MAX_MESSAGE_LEN,
+};
+use std::io::{self, ErrorKind, Read, Write};
+
+/// Number of reads without any data before giving up. Reads of the serial port time out after
+/// 0.1 s.
+const MAX_IDLE_READS: u32 = 30;
+
+/// Number of times a request is repeated because of damaged frames.
+const MAX_RETRIES: u32 = 5;
+
+/// Talks to the dash cam over `port`.
+pub struct Client<T> {
+    port: T,
+    decoder: FrameDecoder,
+    rx: Vec<u8>,
+    rx_pos: usize,
+}
+
+impl<T: Read + Write> Client<T> {
+    pub fn new(port: T) -> Self {
+        Client {
+            port,
+            decoder: FrameDecoder::new(),
+            rx: Vec::new(),
+            rx_pos: 0,
+        }
+    }
+
+    pub fn status(&mut self) -> io::Result<DeviceStatus> {
+        self.call(Request::Status, |response| match response {
+            Response::Status(status) => Some(status),
+            _ => None,
+        })
+    }
+
+    pub fn info(&mut self, clip: u32) -> io::Result<ClipInfo> {
+        self.call(Request::ClipInfo(clip), |response| match response {
+            Response::Clip(info) => Some(info),
+            _ => None,
+        })
+    }
+
+    /// Start erasing a clip. The device is busy until the erase is done.
+    pub fn delete(&mut self, clip: u32) -> io::Result<()> {
+        self.call(Request::DeleteClip(clip), |response| match response {
+            Response::Done => Some(()),
+            _ => None,
+        })
+    }
+
+    pub fn list(&mut self) -> io::Result<Vec<ClipInfo>> {
+        for _ in 0..=MAX_RETRIES {
+            self.send(Request::ListClips)?;
+
+            let mut clips = Vec::new();
+            let mut damaged = false;
+            loop {
+                match self.receive()? {
+                    Ok(message) => match Response::decode(&message) {
+                        Some(Response::Clip(info)) => clips.push(info),
+                        Some(Response::Done) => break,
+                        response => return Err(unexpected(response)),
+                    },
+                    Err(_) => damaged = true,
+                };
+            }
+
+            if !damaged {
+                return Ok(clips);
+            }
+        }
+
+        Err(too_many_retries())
+    }
+
+    /// Download `len` bytes of clip `clip`, calling `progress` with the number of bytes received
+    /// so far. After damaged frames, the download is resumed at the first missing byte.
+    pub fn read_clip<F: FnMut(usize)>(
+        &mut self,
+        clip: u32,
+        len: u32,
+        mut progress: F,
+    ) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len as usize);
+        let mut retries = 0;
+        while data.len() < len as usize {
+            let offset = data.len() as u32;
+            self.send(Request::ReadClip {
+                clip,
+                offset,
+                len: len - offset,
+            })?;
+
+            // Chunks after a missing one are skipped
+            let mut damaged = false;
+            loop {
+                match self.receive()? {
+                    Ok(message) => match Response::decode(&message) {
+                        Some(Response::Chunk {
+                            offset,
+                            data: chunk,
+                        }) if offset as usize == data.len() => {
+                            data.extend_from_slice(chunk);
+                            progress(data.len());
+                        }
+                        Some(Response::Chunk { .. }) => damaged = true,
+                        Some(Response::Done) => break,
+                        response => return Err(unexpected(response)),
+                    },
+                    Err(_) => damaged = true,
+                };
+            }
+
+            if data.len() < len as usize {
+                if !damaged {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "Clip ended early"));
+                }
+
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    return Err(too_many_retries());
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    /// Send a request with a single response, which is converted by `convert`. The request is
+    /// repeated if the response is damaged.
+    fn call<R, F>(&mut self, request: Request, convert: F) -> io::Result<R>
+    where
+        F: Fn(Response) -> Option<R>,
+    {
+        for _ in 0..=MAX_RETRIES {
+            self.send(request)?;
+            if let Ok(message) = self.receive()? {
+                let response = Response::decode(&message);
+                return match response.and_then(&convert) {
+                    Some(result) => Ok(result),
+                    None => Err(unexpected(response)),
+                };
+            }
+        }
+
+        Err(too_many_retries())
+    }
+
+    fn send(&mut self, request: Request) -> io::Result<()> {
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = request.encode(&mut message);
+        let mut frame = [0; MAX_FRAME_LEN];
+        let len = encode_frame(&message[..len], &mut frame);
+        self.port.write_all(&frame[..len])?;
+        self.port.flush()
+    }
+
+    /// Receive the next frame, returns its message or why it was dropped.
+    fn receive(&mut self) -> io::Result<Result<Vec<u8>, FrameError>> {
+        let mut idle_reads = 0;
+        loop {
+            while self.rx_pos < self.rx.len() {
+                let byte = self.rx[self.rx_pos];
+                self.rx_pos += 1;
+                if let Some(result) = self.decoder.push(byte) {
+                    return Ok(result.map(|message| message.to_vec()));
+                }
+            }
+
+            self.rx.resize(MAX_FRAME_LEN, 0);
+            let len = self.port.read(&mut self.rx)?;
+            self.rx.truncate(len);
+            self.rx_pos = 0;
+
+            idle_reads = match len {
+                0 => idle_reads + 1,
+                _ => 0,
+            };
+            if idle_reads == MAX_IDLE_READS {
+                return Err(io::Error::new(
+                    ErrorKind::TimedOut,
+                    "No response from the dash cam",
+                ));
+            }
+        }
+    }
+}
+
+fn unexpected(response: Option<Response>) -> io::Error {
+    match response {
+        Some(Response::Error(code)) => device_error(code),
+        Some(response) => io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected response: {:?}", response),
+        ),
+        None => io::Error::new(ErrorKind::InvalidData, "Malformed response"),
+    }
+}
+
+fn device_error(code: ErrorCode) -> io::Error {
+    let message = match code {
+        ErrorCode::InvalidRequest => "The dash cam does not understand the request",
+        ErrorCode::UnknownClip => "No such clip",
+        ErrorCode::Busy => "The dash cam is busy, try again later",
+        ErrorCode::Storage => "The dash cam cannot read the clip",
+    };
+    io::Error::other(message)
+}
+
+fn too_many_retries() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "Too many damaged frames")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{State, MAX_CHUNK_LEN};
+    use std::collections::VecDeque;
+
+    /// Simulated dash cam, answering requests like the firmware does.
+    struct FakeDevice {
+        clips: Vec<Vec<u8>>,
+        decoder: FrameDecoder,
+        output: VecDeque<u8>,
+        /// Number of response frames sent so far.
+        frames: usize,
+        /// Response frames to damage, by number.
+        damage: Vec<usize>,
+        /// Respond at all.
+        online: bool,
+    }
+
+    impl FakeDevice {
+        fn new(clips: Vec<Vec<u8>>) -> Self {
+            FakeDevice {
+                clips,
+                decoder: FrameDecoder::new(),
+                output: VecDeque::new(),
+                frames: 0,
+                damage: Vec::new(),
+                online: true,
+            }
+        }
+
+        fn respond(&mut self, response: Response) {
+            let mut message = [0; MAX_MESSAGE_LEN];
+            let len = response.encode(&mut message);
+            let mut frame = [0; MAX_FRAME_LEN];
+            let len = encode_frame(&message[..len], &mut frame);
+
+            if self.damage.contains(&self.frames) {
+                frame[len / 2] ^= 0x01;
+            }
+            self.frames += 1;
+            self.output.extend(&frame[..len]);
+        }
+
+        fn handle(&mut self, request: Request) {
+            let clip = |n: u32| self.clips.get(n as usize).cloned();
+            match request {
+                Request::Status => self.respond(Response::Status(DeviceStatus {
+                    state: State::Saved,
+                    uptime_ms: 1000,
+                    unix_time: 0,
+                    clips: self.clips.len() as u32,
+                    crashes: 0,
+                })),
+                Request::ListClips => {
+                    for n in 0..self.clips.len() {
+                        let info = ClipInfo {
+                            clip: n as u32,
+                            len: self.clips[n].len() as u32,
+                            time: 0,
+                        };
+                        self.respond(Response::Clip(info));
+                    }
+                    self.respond(Response::Done);
+                }
+                Request::ClipInfo(n) => match clip(n) {
+                    Some(data) => self.respond(Response::Clip(ClipInfo {
+                        clip: n,
+                        len: data.len() as u32,
+                        time: 0,
+                    })),
+                    None => self.respond(Response::Error(ErrorCode::UnknownClip)),
+                },
+                Request::ReadClip {
+                    clip: n,
+                    offset,
+                    len,
+                } => match clip(n) {
+                    Some(data) => {
+                        let end = (offset + len).min(data.len() as u32) as usize;
+                        let mut offset = offset as usize;
+                        while offset < end {
+                            let chunk_end = end.min(offset + MAX_CHUNK_LEN);
+                            self.respond(Response::Chunk {
+                                offset: offset as u32,
+                                data: &data[offset..chunk_end],
+                            });
+                            offset = chunk_end;
+                        }
+                        self.respond(Response::Done);
+                    }
+                    None => self.respond(Response::Error(ErrorCode::UnknownClip)),
+                },
+                Request::DeleteClip(n) => match clip(n) {
+                    Some(_) => self.respond(Response::Done),
+                    None => self.respond(Response::Error(ErrorCode::UnknownClip)),
+                },
+            }
+        }
+    }
+
+    impl Write for FakeDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            for &byte in buf {
+                let request = match self.decoder.push(byte) {
+                    Some(Ok(message)) => Request::decode(message),
+                    _ => continue,
+                };
+                if self.online {
+                    self.handle(request.expect("Malformed request"));
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for FakeDevice {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.output.len()).min(100);
+            for (byte, out) in buf.iter_mut().zip(self.output.drain(..len)) {
+                *byte = out;
+            }
+            Ok(len)
+        }
+    }
+
+    fn clip(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn requests() {
+        let mut client = Client::new(FakeDevice::new(vec![clip(10), clip(0)]));
+
+        assert_eq!(client.status().unwrap().clips, 2);
+        assert_eq!(client.info(0).unwrap().len, 10);
+        assert_eq!(
+            client.list().unwrap(),
+            vec![
+                ClipInfo {
+                    clip: 0,
+                    len: 10,
+                    time: 0
+                },
+                ClipInfo {
+                    clip: 1,
+                    len: 0,
+                    time: 0
+                }
+            ]
+        );
+        assert!(client.delete(1).is_ok());
+
+        let e = client.info(5).unwrap_err();
+        assert_eq!(e.to_string(), "No such clip");
+    }
+
+    #[test]
+    fn download() {
+        let data = clip(10 * MAX_CHUNK_LEN + 3);
+        let mut client = Client::new(FakeDevice::new(vec![data.clone()]));
+
+        let mut last = 0;
+        let received = client
+            .read_clip(0, data.len() as u32, |len| last = len)
+            .unwrap();
+        assert!(received == data);
+        assert_eq!(last, data.len());
+    }
+
+    #[test]
+    fn damaged_frames() {
+        let data = clip(10 * MAX_CHUNK_LEN);
+        let mut device = FakeDevice::new(vec![data.clone()]);
+
+        // Damage chunks of the first download, the first chunk of the resumed download and the
+        // final Done of the last one
+        device.damage = vec![2, 5, 9, 11, 21];
+        let mut client = Client::new(device);
+        assert!(client.read_clip(0, data.len() as u32, |_| ()).unwrap() == data);
+
+        // Single responses are requested again
+        client.port.damage = vec![client.port.frames];
+        assert_eq!(client.info(0).unwrap().len, data.len() as u32);
+    }
+
+    #[test]
+    fn short_clip() {
+        let mut client = Client::new(FakeDevice::new(vec![clip(100)]));
+        let e = client.read_clip(0, 200, |_| ()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut device = FakeDevice::new(vec![]);
+        device.online = false;
+        let mut client = Client::new(device);
+
+        let e = client.status().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,163 @@
+//! Downloads and manages the clips of the dash cam over its USB serial port.
+//!
+//! ```text
+//! dashctl list
+//! dashctl get 0 clip.clp
+//! ```
+//!
+//! The protocol is described in `src/usb/proto.rs` of the firmware.
+
+mod client;
+mod port;
+
+#[path = "../../../src/crc.rs"]
+mod crc;
+
+#[allow(dead_code)]
+#[path = "../../../src/date.rs"]
+mod date;
+
+#[allow(dead_code)]
+#[path = "../../../src/usb/proto.rs"]
+mod proto;
+
+use client::Client;
+use date::Date;
+use std::{
+    env, fs,
+    io::{self, Write},
+    process,
+};
+
+const USAGE: &str = "Usage: dashctl [--port <device>] <status | list | info <clip> | \
+                     get <clip> [file] | delete <clip>>";
+
+/// Serial port used unless `--port` is given.
+const DEFAULT_PORT: &str = "/dev/ttyACM0";
+
+/// What to do with the dash cam.
+enum Action {
+    Status,
+    List,
+    Info(u32),
+    Get(u32, Option<String>),
+    Delete(u32),
+}
+
+/// Command line options.
+struct Options {
+    port: String,
+    action: Action,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let port = port::open(&options.port).unwrap_or_else(|e| {
+        eprintln!("Cannot open {}: {}", options.port, e);
+        process::exit(1);
+    });
+
+    if let Err(e) = run(&mut Client::new(port), options.action) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(client: &mut Client<fs::File>, action: Action) -> io::Result<()> {
+    match action {
+        Action::Status => {
+            let status = client.status()?;
+            println!("State:   {:?}", status.state);
+            println!(
+                "Uptime:  {}.{:03} s",
+                status.uptime_ms / 1000,
+                status.uptime_ms % 1000
+            );
+            println!("Time:    {}", format_time(status.unix_time));
+            println!("Clips:   {}", status.clips);
+            println!("Crashes: {}", status.crashes);
+        }
+        Action::List => {
+            for info in client.list()? {
+                println!(
+                    "{:>4} {:>10} bytes  {}",
+                    info.clip,
+                    info.len,
+                    format_time(info.time)
+                );
+            }
+        }
+        Action::Info(clip) => {
+            let info = client.info(clip)?;
+            println!("{} bytes, saved {}", info.len, format_time(info.time));
+        }
+        Action::Get(clip, file) => {
+            let info = client.info(clip)?;
+            let file = file.unwrap_or_else(|| format!("CLIP{:04}.CLP", clip));
+            let data = client.read_clip(clip, info.len, |len| {
+                eprint!("\r{} / {} bytes", len, info.len);
+                let _ = io::stderr().flush();
+            })?;
+            eprintln!();
+            fs::write(&file, data)?;
+            eprintln!("Saved {}", file);
+        }
+        Action::Delete(clip) => client.delete(clip)?,
+    };
+
+    Ok(())
+}
+
+/// Format Unix time `secs` as UTC, without pulling in a date library.
+fn format_time(secs: u32) -> String {
+    if secs == 0 {
+        return "unknown".into();
+    }
+
+    let Date { year, month, day } = Date::from_unix(secs);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut port = DEFAULT_PORT.to_string();
+    let mut words = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = args.next().ok_or("Missing port")?,
+            _ if !arg.starts_with("--") => words.push(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    let clip = |word: Option<&String>| {
+        let word = word.ok_or("Missing clip number")?;
+        word.parse::<u32>()
+            .map_err(|_| format!("Invalid clip number: {}", word))
+    };
+
+    let action = match words.first().map(String::as_str) {
+        Some("status") if words.len() == 1 => Action::Status,
+        Some("list") if words.len() == 1 => Action::List,
+        Some("info") if words.len() <= 2 => Action::Info(clip(words.get(1))?),
+        Some("get") if words.len() <= 3 => Action::Get(clip(words.get(1))?, words.get(2).cloned()),
+        Some("delete") if words.len() <= 2 => Action::Delete(clip(words.get(1))?),
+        Some(word) => return Err(format!("Unexpected command: {}", word)),
+        None => return Err("Missing command".into()),
+    };
+
+    Ok(Options { port, action })
+}
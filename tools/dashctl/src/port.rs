@@ -0,0 +1,33 @@
+//! Serial port access on Linux.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+};
+
+/// Open the serial port at `path` in raw mode. Reads return no data after 0.1 s without any.
+pub fn open(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let fd = file.as_raw_fd();
+
+    // The baud rate doesn't matter for USB serial ports
+    unsafe {
+        let mut tio: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut tio) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        libc::cfmakeraw(&mut tio);
+        tio.c_cc[libc::VMIN] = 0;
+        tio.c_cc[libc::VTIME] = 1;
+        if libc::tcsetattr(fd, libc::TCSANOW, &tio) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Drop anything left over from an earlier run
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+
+    Ok(file)
+}
@@ -0,0 +1,125 @@
+//! Decodes the frames of a dump to RGB888 pixels.
+
+/// Pixel format of the frames in a dump.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// 16 bit RGB565, as the camera is configured by the firmware.
+    Rgb565,
+    /// YUV 4:2:2 with the bytes ordered Y0 U Y1 V, two pixels sharing their color.
+    Yuyv,
+}
+
+/// Geometry and pixel layout of the frames in a dump.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub width: usize,
+    pub height: usize,
+    pub format: Format,
+    /// Swap the two bytes of each 16 bit word, for big endian pixels or UYVY.
+    pub swap: bool,
+}
+
+impl Layout {
+    /// Number of bytes in one frame, both formats use 2 bytes per pixel.
+    pub fn frame_len(&self) -> usize {
+        self.width * self.height * 2
+    }
+
+    /// Decode a frame of `frame_len` bytes to RGB888 pixels, row by row from the top left.
+    pub fn decode(&self, frame: &[u8]) -> Vec<u8> {
+        assert_eq!(frame.len(), self.frame_len());
+        let mut rgb = Vec::with_capacity(self.width * self.height * 3);
+        let words = frame.chunks(2).map(|word| {
+            if self.swap {
+                [word[1], word[0]]
+            } else {
+                [word[0], word[1]]
+            }
+        });
+
+        match self.format {
+            Format::Rgb565 => {
+                for word in words {
+                    rgb.extend_from_slice(&rgb565_to_rgb888(u16::from_le_bytes(word)));
+                }
+            }
+            Format::Yuyv => {
+                let bytes: Vec<u8> = words.flatten().collect();
+                for pair in bytes.chunks(4) {
+                    let (u, v) = (pair[1], pair[3]);
+                    rgb.extend_from_slice(&yuv_to_rgb888(pair[0], u, v));
+                    rgb.extend_from_slice(&yuv_to_rgb888(pair[2], u, v));
+                }
+            }
+        }
+        rgb
+    }
+}
+
+/// Expand an RGB565 pixel to 8 bits per color, repeating the high bits so white stays white.
+pub fn rgb565_to_rgb888(pixel: u16) -> [u8; 3] {
+    let r = (pixel >> 11) as u8 & 0x1F;
+    let g = (pixel >> 5) as u8 & 0x3F;
+    let b = pixel as u8 & 0x1F;
+    [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+}
+
+/// Convert a YUV pixel with the BT.601 video range (Y from 16 to 235) to RGB888.
+pub fn yuv_to_rgb888(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let c = 298 * (y as i32 - 16);
+    let d = u as i32 - 128;
+    let e = v as i32 - 128;
+    let clamp = |x: i32| ((x + 128) >> 8).clamp(0, 255) as u8;
+    [
+        clamp(c + 409 * e),
+        clamp(c - 100 * d - 208 * e),
+        clamp(c + 516 * d),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout(format: Format, swap: bool) -> Layout {
+        Layout {
+            width: 2,
+            height: 1,
+            format,
+            swap,
+        }
+    }
+
+    #[test]
+    fn rgb565() {
+        let frame = [0x00, 0xF8, 0x1F, 0x00];
+        assert_eq!(
+            layout(Format::Rgb565, false).decode(&frame),
+            [0xFF, 0, 0, 0, 0, 0xFF]
+        );
+
+        let swapped = [0xF8, 0x00, 0x00, 0x1F];
+        assert_eq!(
+            layout(Format::Rgb565, true).decode(&swapped),
+            [0xFF, 0, 0, 0, 0, 0xFF]
+        );
+        assert_eq!(rgb565_to_rgb888(0xFFFF), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(rgb565_to_rgb888(0x8410), [0x84, 0x82, 0x84]);
+    }
+
+    #[test]
+    fn yuv() {
+        assert_eq!(yuv_to_rgb888(16, 128, 128), [0, 0, 0]);
+        assert_eq!(yuv_to_rgb888(235, 128, 128), [0xFF, 0xFF, 0xFF]);
+        assert_eq!(yuv_to_rgb888(81, 90, 240), [0xFF, 0, 0]);
+        assert_eq!(yuv_to_rgb888(0, 0, 0), [0, 135, 0]);
+
+        // Two gray pixels sharing their color, in YUYV and UYVY order
+        let rgb = [0, 0, 0, 0xFF, 0xFF, 0xFF];
+        assert_eq!(
+            layout(Format::Yuyv, false).decode(&[16, 128, 235, 128]),
+            rgb
+        );
+        assert_eq!(layout(Format::Yuyv, true).decode(&[128, 16, 128, 235]), rgb);
+    }
+}
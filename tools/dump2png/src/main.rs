@@ -0,0 +1,144 @@
+//! Converts the frames in a binary dump of the SDRAM or the QSPI flash of the dash cam to PNG
+//! images, for looking at individual frames of the camera pipeline.
+//!
+//! ```text
+//! dump2png sdram.bin --frames 10-19
+//! dump2png --width 160 --height 120 --swap --out frames flash.bin
+//! ```
+//!
+//! The dump holds frames of `--width` x `--height` pixels back to back, starting at `--offset`
+//! bytes. Frame `n` is written to `<name>_<n>.png` in the output directory.
+
+mod frame;
+
+use frame::{Format, Layout};
+use std::{
+    env,
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+    process,
+};
+
+/// Default geometry, the QVGA `FRAME_WIDTH` and `FRAME_HEIGHT` of the firmware.
+const WIDTH: usize = 320;
+const HEIGHT: usize = 240;
+
+const USAGE: &str = "Usage: dump2png [--width <pixels>] [--height <pixels>] [--offset <bytes>] \
+                     [--frames <first>[-<last>]] [--swap] [--yuv] [--out <dir>] <dump>";
+
+/// Command line options.
+struct Options {
+    input: String,
+    layout: Layout,
+    offset: usize,
+    first: usize,
+    last: Option<usize>,
+    out: String,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    if let Err(e) = run(&options) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let input = Path::new(&options.input);
+    let data = fs::read(input).map_err(|e| format!("Cannot read {}: {}", options.input, e))?;
+    let name = input
+        .file_stem()
+        .map_or("frame".into(), |stem| stem.to_string_lossy());
+
+    let frame_len = options.layout.frame_len();
+    let available = data.len().saturating_sub(options.offset) / frame_len;
+    let last = options.last.unwrap_or_else(|| available.saturating_sub(1));
+    if available == 0 || last >= available || options.first > last {
+        return Err(format!(
+            "Frames {}-{} not in dump, which holds {} frames",
+            options.first, last, available
+        ));
+    }
+
+    for n in options.first..=last {
+        let start = options.offset + n * frame_len;
+        let rgb = options.layout.decode(&data[start..start + frame_len]);
+        let file = Path::new(&options.out).join(format!("{}_{:04}.png", name, n));
+        write_png(&file, &options.layout, &rgb)
+            .map_err(|e| format!("Cannot write {}: {}", file.display(), e))?;
+        println!("{}", file.display());
+    }
+
+    Ok(())
+}
+
+fn write_png(file: &Path, layout: &Layout, rgb: &[u8]) -> Result<(), png::EncodingError> {
+    let file = BufWriter::new(File::create(file)?);
+    let mut encoder = png::Encoder::new(file, layout.width as u32, layout.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(rgb)
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut input = None;
+    let mut layout = Layout {
+        width: WIDTH,
+        height: HEIGHT,
+        format: Format::Rgb565,
+        swap: false,
+    };
+    let mut offset = 0;
+    let (mut first, mut last) = (0, None);
+    let mut out = ".".to_string();
+
+    while let Some(arg) = args.next() {
+        let mut value = |name| args.next().ok_or(format!("Missing {}", name));
+        match arg.as_str() {
+            "--width" => layout.width = parse_number(&value("width")?)?,
+            "--height" => layout.height = parse_number(&value("height")?)?,
+            "--offset" => offset = parse_number(&value("offset")?)?,
+            "--frames" => {
+                let range = value("frames")?;
+                let mut bounds = range.splitn(2, '-');
+                first = parse_number(bounds.next().unwrap_or_default())?;
+                last = Some(bounds.next().map_or(Ok(first), parse_number)?);
+            }
+            "--swap" => layout.swap = true,
+            "--yuv" => layout.format = Format::Yuyv,
+            "--out" => out = value("output directory")?,
+            _ if !arg.starts_with("--") && input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    // YUV pixels come in pairs
+    let odd = layout.format == Format::Yuyv && !layout.width.is_multiple_of(2);
+    if layout.frame_len() == 0 || odd {
+        return Err("Invalid frame geometry".into());
+    }
+
+    Ok(Options {
+        input: input.ok_or("Missing dump file")?,
+        layout,
+        offset,
+        first,
+        last,
+        out,
+    })
+}
+
+/// Parse a decimal or `0x` prefixed hexadecimal number.
+fn parse_number(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Invalid number: {}", s))
+}
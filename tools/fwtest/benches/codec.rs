@@ -0,0 +1,93 @@
+//! Compares the lossless codec with the JPEG encoder on sample frames of a simulated drive, with
+//! and without sensor noise. Times are on the host, the target is a lot slower but the ratios
+//! between the codecs are similar.
+
+use fwtest::{codec, jpeg::Encoder, samples};
+use std::time::{Duration, Instant};
+
+const WIDTH: u16 = 320;
+const HEIGHT: u16 = 240;
+const FRAMES: usize = 100;
+
+fn main() {
+    println!(
+        "{:<22} {:>10} {:>8} {:>12} {:>12}",
+        "codec", "bytes", "ratio", "encode", "decode"
+    );
+    for &noise in &[0, 1, 3] {
+        let frames: Vec<_> = (0..FRAMES)
+            .map(|n| samples::drive(WIDTH as usize, HEIGHT as usize, n, noise))
+            .collect();
+        println!("Noise {}:", noise);
+        lossless(&frames);
+        for &quality in &[50, 75, 90] {
+            jpeg(&frames, quality);
+        }
+    }
+}
+
+fn lossless(frames: &[Vec<u8>]) {
+    let mut out = vec![0; codec::max_len(WIDTH, HEIGHT)];
+    let mut coded = Vec::new();
+    let start = Instant::now();
+    for (n, frame) in frames.iter().enumerate() {
+        let prev = n.checked_sub(1).map(|p| &frames[p][..]);
+        let len = codec::encode(frame, prev, WIDTH, HEIGHT, &mut out).unwrap();
+        coded.push(out[..len].to_vec());
+    }
+    let encode = start.elapsed();
+
+    let mut decoded = vec![vec![0; frames[0].len()]; 2];
+    let start = Instant::now();
+    for (n, data) in coded.iter().enumerate() {
+        let (a, b) = decoded.split_at_mut(1);
+        let (prev, pixels) = match n % 2 {
+            0 => (&b[0], &mut a[0]),
+            _ => (&a[0], &mut b[0]),
+        };
+        let prev = Some(&prev[..]).filter(|_| n > 0);
+        codec::decode(data, prev, WIDTH, HEIGHT, pixels).unwrap();
+        assert!(*pixels == frames[n]);
+    }
+    let decode = start.elapsed();
+
+    let bytes = coded.iter().map(Vec::len).sum();
+    report("lossless", bytes, frames, encode, Some(decode));
+}
+
+fn jpeg(frames: &[Vec<u8>], quality: u8) {
+    let encoder = Encoder::new(quality);
+    let mut out = vec![0; frames[0].len()];
+    let start = Instant::now();
+    let bytes = frames
+        .iter()
+        .map(|frame| encoder.encode(frame, WIDTH, HEIGHT, &mut out).unwrap())
+        .sum();
+    let encode = start.elapsed();
+    report(&format!("jpeg q{}", quality), bytes, frames, encode, None);
+}
+
+/// Print the average size and time per frame.
+fn report(
+    name: &str,
+    bytes: usize,
+    frames: &[Vec<u8>],
+    encode: Duration,
+    decode: Option<Duration>,
+) {
+    let raw: usize = frames.iter().map(Vec::len).sum();
+    let per_frame = |time: Duration| {
+        format!(
+            "{:.2} ms",
+            time.as_secs_f64() * 1000.0 / frames.len() as f64
+        )
+    };
+    println!(
+        "  {:<20} {:>10} {:>7.1}x {:>12} {:>12}",
+        name,
+        bytes / frames.len(),
+        raw as f64 / bytes as f64,
+        per_frame(encode),
+        decode.map_or("-".into(), per_frame)
+    );
+}
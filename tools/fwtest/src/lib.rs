@@ -0,0 +1,79 @@
+//! Host builds of the firmware modules which don't depend on hardware, so they can be tested
+//! with `cargo test` instead of on the target. The modules are included from the firmware
+//! sources unchanged.
+
+#![no_std]
+
+extern crate std;
+
+/// Log macros of the firmware, printing to stderr.
+macro_rules! error {
+    ($($arg:tt)*) => { std::eprintln!($($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { std::eprintln!($($arg)*) };
+}
+
+pub mod samples;
+pub mod sim;
+
+#[path = "../../../src/block.rs"]
+pub mod block;
+
+#[path = "../../../src/clip.rs"]
+pub mod clip;
+
+#[path = "../../../src/clip_store.rs"]
+pub mod clip_store;
+
+#[path = "../../../src/codec.rs"]
+pub mod codec;
+
+#[path = "../../../src/command.rs"]
+pub mod command;
+
+#[path = "../../../src/config.rs"]
+pub mod config;
+
+#[path = "../../../src/crc.rs"]
+pub mod crc;
+
+#[path = "../../../src/date.rs"]
+pub mod date;
+
+#[path = "../../../src/event.rs"]
+pub mod event;
+
+#[path = "../../../src/usb/fat.rs"]
+pub mod fat;
+
+#[path = "../../../src/fat32.rs"]
+pub mod fat32;
+
+#[path = "../../../src/flashfs.rs"]
+pub mod flashfs;
+
+#[path = "../../../src/frame_ring.rs"]
+pub mod frame_ring;
+
+#[path = "../../../src/jpeg.rs"]
+pub mod jpeg;
+
+#[path = "../../../src/motion.rs"]
+pub mod motion;
+
+#[path = "../../../src/usb/msc.rs"]
+pub mod msc;
+
+#[path = "../../../src/ov9655/settings.rs"]
+pub mod ov9655;
+
+#[path = "../../../src/usb/proto.rs"]
+pub mod proto;
+
+#[path = "../../../src/usb/scsi.rs"]
+pub mod scsi;
+
+#[path = "../../../src/sd.rs"]
+pub mod sd;
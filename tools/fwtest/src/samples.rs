@@ -0,0 +1,52 @@
+//! Sample frames for testing and benchmarking the codecs, standing in for camera footage.
+
+use std::vec::Vec;
+
+/// Frame `n` of a simulated drive, in RGB565 (little endian): A static sky gradient at the top,
+/// a road with lane markings moving down the frame in the middle and a flat bonnet at the bottom.
+/// A car drives along the road. With `noise` > 0, sensor noise of up to `noise` steps changes the
+/// low bits of some pixels in every frame.
+pub fn drive(width: usize, height: usize, n: usize, noise: u32) -> Vec<u8> {
+    let mut seed = 0x2545_F491_u32.wrapping_mul(n as u32 + 1);
+    let mut pixels = Vec::with_capacity(width * height * 2);
+    for y in 0..height {
+        for x in 0..width {
+            let (mut r, mut g, mut b) = if y < height / 3 {
+                // Sky, lighter towards the horizon
+                let light = y * 31 / (height / 3);
+                (10 + light / 3, 30 + light, 31)
+            } else if y < height * 4 / 5 {
+                // Road, lane markings move towards the camera
+                let center = x as isize - width as isize / 2;
+                let depth = y - height / 3;
+                let lane = (center.unsigned_abs() * 8 / (depth + 8)).is_multiple_of(16);
+                let dash = (depth / 4 + n) % 6 < 3;
+                let car = x > width / 3 + n % 20 && x < width / 2 + n % 20 && depth < 30;
+                match (car, lane && dash) {
+                    (true, _) => (25, 5, 5),
+                    (_, true) => (30, 60, 30),
+                    _ => (12 + (x / 7 + y / 5) % 2, 26, 12),
+                }
+            } else {
+                // Bonnet
+                (4, 8, 6)
+            };
+
+            if noise > 0 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                if seed.is_multiple_of(4) {
+                    let step = (seed >> 8) % (noise + 1);
+                    r = (r + step as usize).min(31);
+                    g = (g + step as usize * 2).min(63);
+                    b = (b + step as usize).min(31);
+                }
+            }
+
+            let pixel = (r << 11 | g << 5 | b) as u16;
+            pixels.extend_from_slice(&pixel.to_le_bytes());
+        }
+    }
+    pixels
+}
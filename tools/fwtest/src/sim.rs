@@ -0,0 +1,242 @@
+//! Simulated storage devices: A NOR flash standing in for the QSPI flash, a block device
+//! standing in for the SD card and a buffer collecting clips in tests.
+
+use crate::block::{BlockStorage, BLOCK_SIZE};
+use crate::clip::Sink;
+use crate::fat::ClipStorage;
+use crate::flashfs::Mem;
+use core::convert::Infallible;
+use std::{vec, vec::Vec};
+
+/// Size of the QSPI flash in bytes.
+pub const SIM_FLASH_SIZE: usize = 16 * 1024 * 1024;
+
+/// Size of the smallest erasable unit in bytes, like the subsectors of the QSPI flash.
+pub const SUBSECTOR_SIZE: usize = 4096;
+
+/// Errors of the simulated flash.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SimError {
+    /// The access is outside of the flash.
+    OutOfRange,
+    /// The length is not a multiple of 4, which DMA transfers of the QSPI driver require, or not
+    /// a multiple of the block size.
+    Unaligned,
+    /// The power was cut, see `SimFlash::cut_power_after`.
+    PowerLoss,
+}
+
+/// Simulated NOR flash: Erasing sets whole subsectors to 0xFF, programming can only clear bits.
+#[derive(Clone)]
+pub struct SimFlash {
+    data: Vec<u8>,
+    /// Number of times each subsector was erased.
+    erase_counts: Vec<u32>,
+    /// Programs and erases left until the power is cut.
+    power_left: Option<usize>,
+}
+
+impl SimFlash {
+    /// Create an erased flash of `size` bytes.
+    pub fn new(size: usize) -> Self {
+        SimFlash {
+            data: vec![0xFF; size],
+            erase_counts: vec![0; size.div_ceil(SUBSECTOR_SIZE)],
+            power_left: None,
+        }
+    }
+
+    /// Contents of the flash.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Number of times each subsector was erased.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.erase_counts
+    }
+
+    /// Cut the power after `ops` more programs and erases. The one after is left half done, it
+    /// and all following ones fail with `SimError::PowerLoss` until `restore_power` is called.
+    pub fn cut_power_after(&mut self, ops: usize) {
+        self.power_left = Some(ops);
+    }
+
+    /// Restore the power, like after a reboot.
+    pub fn restore_power(&mut self) {
+        self.power_left = None;
+    }
+
+    /// Program `bytes` at address `addr`.
+    pub fn program(&mut self, addr: u32, bytes: &[u8]) -> Result<(), SimError> {
+        let range = self.range(addr, bytes.len())?;
+        let powered = self.use_power();
+        let len = match powered {
+            true => bytes.len(),
+            false => bytes.len() / 2,
+        };
+        for (byte, new) in self.data[range].iter_mut().zip(&bytes[..len]) {
+            *byte &= new;
+        }
+
+        match powered {
+            true => Ok(()),
+            false => Err(SimError::PowerLoss),
+        }
+    }
+
+    /// Erase the subsectors holding the `len` bytes at address `addr`.
+    pub fn erase(&mut self, addr: u32, len: usize) -> Result<(), SimError> {
+        let range = self.range(addr, len)?;
+        let start = range.start / SUBSECTOR_SIZE * SUBSECTOR_SIZE;
+        let end = range.end.div_ceil(SUBSECTOR_SIZE) * SUBSECTOR_SIZE;
+        let end = end.min(self.data.len());
+        let powered = self.use_power();
+        let end = match powered {
+            true => end,
+            false => start + SUBSECTOR_SIZE.min(end - start) / 2,
+        };
+        self.data[start..end].iter_mut().for_each(|b| *b = 0xFF);
+        self.erase_counts[start / SUBSECTOR_SIZE..end.div_ceil(SUBSECTOR_SIZE)]
+            .iter_mut()
+            .for_each(|count| *count += 1);
+
+        match powered {
+            true => Ok(()),
+            false => Err(SimError::PowerLoss),
+        }
+    }
+
+    /// Count an operation, returns `false` if the power is cut during it.
+    fn use_power(&mut self) -> bool {
+        match &mut self.power_left {
+            Some(0) => false,
+            Some(left) => {
+                *left -= 1;
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn range(&self, addr: u32, len: usize) -> Result<core::ops::Range<usize>, SimError> {
+        let start = addr as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(SimError::OutOfRange),
+        }
+    }
+}
+
+impl ClipStorage for SimFlash {
+    type Error = SimError;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), SimError> {
+        if !buf.len().is_multiple_of(4) {
+            return Err(SimError::Unaligned);
+        }
+
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+}
+
+impl Mem for SimFlash {
+    type Error = SimError;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), SimError> {
+        let range = self.range(addr, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, buf: &[u8]) -> Result<(), SimError> {
+        self.program(addr, buf)
+    }
+
+    fn erase(&mut self) -> Result<(), SimError> {
+        self.erase(0, self.data.len())
+    }
+
+    fn erase_region(&mut self, addr: u32, len: usize) -> Result<(), SimError> {
+        self.erase(addr, len)
+    }
+}
+
+/// Simulated block device, counting the transfers so tests can check how it is accessed.
+pub struct SimDisk {
+    data: Vec<u8>,
+    /// Number of `read_blocks` calls.
+    pub reads: usize,
+    /// Number of `write_blocks` calls.
+    pub writes: usize,
+}
+
+impl SimDisk {
+    /// Create a device of `num_blocks` blocks, filled with zeros.
+    pub fn new(num_blocks: u32) -> Self {
+        SimDisk {
+            data: vec![0; num_blocks as usize * BLOCK_SIZE],
+            reads: 0,
+            writes: 0,
+        }
+    }
+
+    /// Contents of the device.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Mutable contents of the device, for setting up tests.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn range(&self, lba: u32, len: usize) -> Result<core::ops::Range<usize>, SimError> {
+        if !len.is_multiple_of(BLOCK_SIZE) {
+            return Err(SimError::Unaligned);
+        }
+
+        let start = lba as usize * BLOCK_SIZE;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(SimError::OutOfRange),
+        }
+    }
+}
+
+impl BlockStorage for SimDisk {
+    type Error = SimError;
+
+    fn num_blocks(&self) -> u32 {
+        (self.data.len() / BLOCK_SIZE) as u32
+    }
+
+    fn read_blocks(&mut self, lba: u32, buf: &mut [u8]) -> Result<(), SimError> {
+        let range = self.range(lba, buf.len())?;
+        buf.copy_from_slice(&self.data[range]);
+        self.reads += 1;
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u32, buf: &[u8]) -> Result<(), SimError> {
+        let range = self.range(lba, buf.len())?;
+        self.data[range].copy_from_slice(buf);
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+/// Collects the bytes of a clip in memory.
+#[derive(Default)]
+pub struct SimBuffer(pub Vec<u8>);
+
+impl Sink for SimBuffer {
+    type Error = Infallible;
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Infallible> {
+        self.0.extend_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use fwtest::clip::{
+    ClipReader, ClipWriter, Codec, Error, FrameEntry, Header, PixelFormat, Sink, HEADER_LEN,
+    INDEX_ENTRY_LEN, TRAILER_LEN,
+};
+use fwtest::config::Config;
+use fwtest::jpeg::{self, Encoder};
+use fwtest::{samples, sim::SimBuffer};
+use std::convert::Infallible;
+
+/// Fails when the clip would grow beyond a size.
+struct Limited(usize);
+
+impl Sink for Limited {
+    type Error = ();
+
+    fn write(&mut self, data: &[u8]) -> Result<(), ()> {
+        self.0 = self.0.checked_sub(data.len()).ok_or(())?;
+        Ok(())
+    }
+}
+
+fn header(frame_count: u32) -> Header {
+    Header {
+        width: 320,
+        height: 240,
+        pixel_format: PixelFormat::Rgb565,
+        codec: Codec::Raw,
+        frame_rate: 15,
+        frame_count,
+        start_time: 1_609_459_200,
+    }
+}
+
+fn frame(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 + i / 256) as u8 ^ seed).collect()
+}
+
+/// A clip of `frames`, taken 66 ms apart.
+fn write_clip(frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut buf = SimBuffer::default();
+    let mut index = vec![FrameEntry::default(); frames.len()];
+    let mut writer = ClipWriter::new(&mut buf, header(frames.len() as u32), &mut index).unwrap();
+    for (n, frame) in frames.iter().enumerate() {
+        writer.write_frame(&mut buf, frame, n as u32 * 66).unwrap();
+    }
+    let len = writer.finish(&mut buf).unwrap();
+    assert_eq!(len as usize, buf.0.len());
+    buf.0
+}
+
+fn open(clip: &mut [u8]) -> Result<ClipReader, Error<Infallible>> {
+    let len = clip.len() as u32;
+    ClipReader::open(clip, len)
+}
+
+#[test]
+fn round_trip() {
+    let frames: Vec<_> = [100, 0, 5000, 1, 300]
+        .iter()
+        .enumerate()
+        .map(|(n, &len)| frame(len, n as u8))
+        .collect();
+    let mut clip = write_clip(&frames);
+    let len: usize = frames.iter().map(|f| f.len()).sum();
+    assert_eq!(
+        clip.len(),
+        HEADER_LEN + len + frames.len() * INDEX_ENTRY_LEN + TRAILER_LEN
+    );
+
+    let reader = open(&mut clip).unwrap();
+    assert_eq!(*reader.header(), header(5));
+    reader.verify(&mut clip[..]).unwrap();
+
+    let mut offset = HEADER_LEN as u32;
+    let mut buf = [0; 6000];
+    for (n, data) in frames.iter().enumerate() {
+        let entry = reader.frame(&mut clip[..], n as u32).unwrap();
+        assert_eq!(
+            entry,
+            FrameEntry {
+                offset,
+                time_ms: n as u32 * 66,
+                size: data.len() as u32
+            }
+        );
+        offset += entry.size;
+
+        let size = reader
+            .read_frame(&mut clip[..], n as u32, &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..size], &data[..]);
+    }
+
+    let mut small = [0; 4999];
+    assert_eq!(
+        reader.read_frame(&mut clip[..], 2, &mut small),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn empty() {
+    let mut clip = write_clip(&[]);
+    assert_eq!(clip.len(), HEADER_LEN + TRAILER_LEN);
+    let reader = open(&mut clip).unwrap();
+    assert_eq!(reader.header().frame_count, 0);
+    reader.verify(&mut clip[..]).unwrap();
+}
+
+#[test]
+fn frame_count() {
+    let mut buf = SimBuffer::default();
+    let mut index = [FrameEntry::default(); 2];
+    assert!(matches!(
+        ClipWriter::new(&mut buf, header(3), &mut index),
+        Err(Error::FrameCount)
+    ));
+
+    // Too few frames
+    let mut writer = ClipWriter::new(&mut buf, header(2), &mut index).unwrap();
+    writer.write_frame(&mut buf, &[1, 2], 0).unwrap();
+    assert_eq!(writer.finish(&mut buf), Err(Error::FrameCount));
+
+    // Too many frames
+    let mut index = [FrameEntry::default(); 2];
+    let mut writer = ClipWriter::new(&mut buf, header(1), &mut index).unwrap();
+    writer.write_frame(&mut buf, &[1, 2], 0).unwrap();
+    assert_eq!(
+        writer.write_frame(&mut buf, &[3], 66),
+        Err(Error::FrameCount)
+    );
+}
+
+#[test]
+fn write_errors() {
+    let mut index = [FrameEntry::default(); 1];
+    assert!(matches!(
+        ClipWriter::new(&mut Limited(10), header(1), &mut index),
+        Err(Error::Io(()))
+    ));
+
+    let mut sink = Limited(HEADER_LEN + 100);
+    let mut writer = ClipWriter::new(&mut sink, header(1), &mut index).unwrap();
+    writer.write_frame(&mut sink, &[0; 100], 0).unwrap();
+    assert_eq!(writer.finish(&mut sink), Err(Error::Io(())));
+}
+
+#[test]
+fn not_a_clip() {
+    assert_eq!(open(&mut []).err(), Some(Error::NoClip));
+    assert_eq!(open(&mut [0xFF; 1000]).err(), Some(Error::NoClip));
+
+    let mut raw = frame(1000, 0);
+    assert_eq!(open(&mut raw).err(), Some(Error::NoClip));
+}
+
+#[test]
+fn damaged() {
+    let frames = [frame(1000, 1), frame(1000, 2)];
+    let clip = write_clip(&frames);
+
+    // Damaged header
+    let mut damaged = clip.clone();
+    damaged[9] ^= 1;
+    assert_eq!(open(&mut damaged).err(), Some(Error::Corrupt));
+
+    // Damaged frame, only found by checking the CRC
+    let mut damaged = clip.clone();
+    damaged[HEADER_LEN + 1500] ^= 1;
+    let reader = open(&mut damaged).unwrap();
+    assert_eq!(reader.verify(&mut damaged[..]), Err(Error::Crc));
+
+    // Damaged index, pointing beyond the frames
+    let mut damaged = clip.clone();
+    let index = HEADER_LEN + 2000;
+    damaged[index + INDEX_ENTRY_LEN + 9] = 0x10;
+    let reader = open(&mut damaged).unwrap();
+    assert_eq!(reader.frame(&mut damaged[..], 0).unwrap().size, 1000);
+    assert_eq!(
+        reader.frame(&mut damaged[..], 1).err(),
+        Some(Error::Corrupt)
+    );
+
+    // Cut short
+    let mut short = clip[..clip.len() - 1].to_vec();
+    assert_eq!(open(&mut short).err(), Some(Error::Corrupt));
+
+    // Trailing bytes
+    let mut long = clip.clone();
+    long.extend_from_slice(&[0xFF; 4]);
+    assert_eq!(open(&mut long).err(), Some(Error::Corrupt));
+}
+
+#[test]
+fn unsupported() {
+    let mut clip = write_clip(&[frame(10, 1)]);
+
+    // Newer version, with a valid header CRC
+    let mut newer = clip.clone();
+    newer[4] = 2;
+    let crc = fwtest::crc::crc32(&newer[..HEADER_LEN - 4]);
+    newer[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(open(&mut newer).err(), Some(Error::Version));
+
+    // Unknown codec
+    clip[7] = 0x7F;
+    let crc = fwtest::crc::crc32(&clip[..HEADER_LEN - 4]);
+    clip[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    assert_eq!(open(&mut clip).err(), Some(Error::Unsupported));
+}
+
+#[test]
+fn default_config_clip() {
+    // Frames are recorded like the firmware does with the default settings, and the saved clip
+    // is replayed
+    let config = Config::default();
+    let encoder = match config.quality {
+        0 => None,
+        quality => Some(Encoder::new(quality.min(100) as u8)),
+    };
+    let frames: Vec<_> = (0..5).map(|n| samples::drive(320, 240, n, 2)).collect();
+
+    let header = Header {
+        width: 320,
+        height: 240,
+        pixel_format: PixelFormat::Rgb565,
+        codec: encoder.as_ref().map_or(Codec::Raw, |_| Codec::Jpeg),
+        frame_rate: config.frame_rate as u16,
+        frame_count: frames.len() as u32,
+        start_time: 1_609_459_200,
+    };
+    let mut buf = SimBuffer::default();
+    let mut index = vec![FrameEntry::default(); frames.len()];
+    let mut writer = ClipWriter::new(&mut buf, header, &mut index).unwrap();
+    let mut coded = vec![0; frames[0].len()];
+    for (n, frame) in frames.iter().enumerate() {
+        let data = match &encoder {
+            Some(encoder) => {
+                let len = encoder.encode(frame, 320, 240, &mut coded).unwrap();
+                &coded[..len]
+            }
+            None => &frame[..],
+        };
+        let time = n as u32 * config.period_ms();
+        writer.write_frame(&mut buf, data, time).unwrap();
+    }
+    let len = writer.finish(&mut buf).unwrap();
+
+    let mut clip = buf.0;
+    let reader = ClipReader::open(&mut clip[..], len).unwrap();
+    assert_eq!(*reader.header(), header);
+    let mut decoded = vec![0; frames[0].len()];
+    for (n, frame) in frames.iter().enumerate() {
+        let len = reader
+            .read_frame(&mut clip[..], n as u32, &mut coded)
+            .unwrap();
+        match reader.header().codec {
+            Codec::Jpeg => jpeg::decode(&coded[..len], 320, 240, &mut decoded).unwrap(),
+            _ => decoded.copy_from_slice(&coded[..len]),
+        }
+
+        // Within a few steps of the frame on average
+        let error = frame
+            .chunks(2)
+            .zip(decoded.chunks(2))
+            .map(|(a, b)| {
+                let a = u16::from_le_bytes([a[0], a[1]]);
+                let b = u16::from_le_bytes([b[0], b[1]]);
+                [(11, 0x1F), (5, 0x3F), (0, 0x1F)]
+                    .iter()
+                    .map(|&(shift, mask)| {
+                        ((a >> shift & mask) as i32 - (b >> shift & mask) as i32).abs()
+                    })
+                    .sum::<i32>()
+            })
+            .sum::<i32>() as f64
+            / (frame.len() / 2) as f64;
+        assert!(error < 3.0, "frame {}: {:.2}", n, error);
+    }
+}
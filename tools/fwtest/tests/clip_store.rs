@@ -0,0 +1,262 @@
+use fwtest::{
+    clip_store::{ClipStore, StoredClip, CLIP_SPACING, MAX_CLIPS},
+    flashfs::{Error, FileSystem, BLOCK_SIZE, MAX_EXTENTS},
+    sim::{SimError, SimFlash},
+};
+
+/// A small flash keeps the tests fast, and fills up quickly.
+const FLASH_SIZE: u32 = 64 * BLOCK_SIZE;
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8 ^ seed).collect()
+}
+
+/// Save a clip with `data` written in parts. Returns its number.
+fn save(
+    store: &mut ClipStore,
+    fs: &mut FileSystem,
+    flash: &mut SimFlash,
+    time: u32,
+    data: &[u8],
+) -> usize {
+    let number = store.create(fs, flash, time).unwrap();
+    for chunk in data.chunks(1000) {
+        store.write(fs, flash, chunk).unwrap();
+    }
+    assert_eq!(store.finish(fs, flash), Ok(Some(number)));
+    number
+}
+
+fn read(store: &ClipStore, fs: &FileSystem, flash: &mut SimFlash, number: usize) -> Vec<u8> {
+    let size = store.clip(fs, number).unwrap().size as usize;
+    let mut buf = vec![0; size + 10];
+    let addr = number as u32 * CLIP_SPACING;
+    assert_eq!(store.read_at(fs, flash, addr, &mut buf), Ok(size));
+    buf.truncate(size);
+    buf
+}
+
+#[test]
+fn clips() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    assert_eq!(store.clips(&fs).count(), 0);
+
+    let (a, b) = (data(5000, 1), data(BLOCK_SIZE as usize * 2, 2));
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 100, &a), 0);
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 200, &b), 1);
+
+    // The clip being written is not saved yet
+    assert_eq!(store.create(&mut fs, &mut flash, 300), Ok(2));
+    store.write(&mut fs, &mut flash, &[1, 2, 3]).unwrap();
+    assert_eq!(store.writing(), Some(2));
+    assert_eq!(store.clip(&fs, 2), None);
+    assert_eq!(
+        store.clips(&fs).collect::<Vec<_>>(),
+        [
+            StoredClip {
+                number: 0,
+                size: 5000,
+                time: 100,
+                serial: 0,
+                locked: false,
+            },
+            StoredClip {
+                number: 1,
+                size: 2 * BLOCK_SIZE,
+                time: 200,
+                serial: 1,
+                locked: false,
+            },
+        ]
+    );
+    store.discard(&mut fs);
+    assert_eq!(store.writing(), None);
+    assert_eq!(store.finish(&mut fs, &mut flash), Ok(None));
+    assert_eq!(store.write(&mut fs, &mut flash, &[1]), Err(Error::NotFound));
+
+    // Clips are found again after mounting, reads stop at the end of a clip
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let store = ClipStore::new(&fs);
+    assert_eq!(read(&store, &fs, &mut flash, 0), a);
+    assert_eq!(read(&store, &fs, &mut flash, 1), b);
+    let mut buf = [0; 8];
+    assert_eq!(
+        store.read_at(&fs, &mut flash, CLIP_SPACING - 4, &mut buf),
+        Ok(0)
+    );
+    assert_eq!(store.read_at(&fs, &mut flash, 4996, &mut buf), Ok(4));
+    assert_eq!(buf[..4], a[4996..]);
+    assert_eq!(
+        store.read_at(&fs, &mut flash, 2 * CLIP_SPACING, &mut buf),
+        Err(Error::NotFound)
+    );
+}
+
+#[test]
+fn numbers() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    for n in 0..MAX_CLIPS {
+        assert_eq!(save(&mut store, &mut fs, &mut flash, 0, &[n as u8]), n);
+    }
+    for n in 0..MAX_CLIPS {
+        store.lock(&mut fs, &mut flash, n, true).unwrap();
+    }
+    assert_eq!(
+        store.create(&mut fs, &mut flash, 0),
+        Err(Error::TooManyFiles)
+    );
+    for n in 0..MAX_CLIPS {
+        store.lock(&mut fs, &mut flash, n, false).unwrap();
+    }
+
+    // Free numbers are reused, the serial numbers keep counting across mounts
+    store.remove(&mut fs, &mut flash, 3).unwrap();
+    store.remove(&mut fs, &mut flash, 10).unwrap();
+    assert_eq!(store.remove(&mut fs, &mut flash, 10), Err(Error::NotFound));
+    assert_eq!(
+        store.remove(&mut fs, &mut flash, MAX_CLIPS),
+        Err(Error::NotFound)
+    );
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 0, b"new"), 3);
+
+    let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 0, b"newer"), 10);
+    let serials: Vec<_> = store.clips(&fs).map(|clip| clip.serial).collect();
+    assert_eq!(serials, [0, 1, 2, 12, 4, 5, 6, 7, 8, 9, 13, 11]);
+    assert_eq!(read(&store, &fs, &mut flash, 10), b"newer");
+}
+
+#[test]
+fn full() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    let free = fs.free_space();
+    let number = save(
+        &mut store,
+        &mut fs,
+        &mut flash,
+        0,
+        &data(free as usize / 2, 3),
+    );
+    store.lock(&mut fs, &mut flash, number, true).unwrap();
+
+    // A clip which doesn't fit can be discarded, which frees its blocks
+    store.create(&mut fs, &mut flash, 0).unwrap();
+    let chunk = data(10_000, 4);
+    let err = loop {
+        if let Err(e) = store.write(&mut fs, &mut flash, &chunk) {
+            break e;
+        }
+    };
+    assert_eq!(err, Error::Full);
+    store.discard(&mut fs);
+    assert_eq!(fs.free_space(), free / 2);
+    assert_eq!(store.clips(&fs).count(), 1);
+}
+
+#[test]
+fn retention() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    let quarter = (fs.free_space() / BLOCK_SIZE / 4 * BLOCK_SIZE) as usize;
+    for n in 0..3 {
+        save(&mut store, &mut fs, &mut flash, n, &data(quarter, n as u8));
+    }
+    assert_eq!(
+        store.lock(&mut fs, &mut flash, 3, true),
+        Err(Error::NotFound)
+    );
+    store.lock(&mut fs, &mut flash, 1, true).unwrap();
+
+    // The oldest clip makes room while writing, locked clips stay
+    let d = data(2 * quarter, 3);
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 3, &d), 3);
+    let numbers: Vec<_> = store.clips(&fs).map(|clip| clip.number).collect();
+    assert_eq!(numbers, [1, 2, 3]);
+    assert_eq!(read(&store, &fs, &mut flash, 1), data(quarter, 1));
+    assert_eq!(read(&store, &fs, &mut flash, 3), d);
+
+    // Locks are kept across mounts
+    let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let mut store = ClipStore::new(&fs);
+    let locked: Vec<_> = store.clips(&fs).map(|clip| clip.locked).collect();
+    assert_eq!(locked, [true, false, false]);
+    let e = data(2 * quarter, 4);
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 4, &e), 0);
+    let numbers: Vec<_> = store.clips(&fs).map(|clip| clip.number).collect();
+    assert_eq!(numbers, [0, 1]);
+    assert_eq!(read(&store, &fs, &mut flash, 0), e);
+
+    // Storage full of locked clips
+    store.lock(&mut fs, &mut flash, 0, true).unwrap();
+    let free = fs.free_space();
+    store.create(&mut fs, &mut flash, 5).unwrap();
+    assert_eq!(
+        store.write(&mut fs, &mut flash, &data(2 * quarter, 5)),
+        Err(Error::Full)
+    );
+    store.discard(&mut fs);
+    assert_eq!(fs.free_space(), free);
+    assert_eq!(store.clips(&fs).count(), 2);
+
+    // Unlocked clips are reclaimed again
+    store.lock(&mut fs, &mut flash, 1, false).unwrap();
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 5, &d), 2);
+    let numbers: Vec<_> = store.clips(&fs).map(|clip| clip.number).collect();
+    assert_eq!(numbers, [0, 2]);
+
+    // With all numbers taken, the oldest unlocked clip gives up its number
+    store.remove(&mut fs, &mut flash, 2).unwrap();
+    for n in 1..MAX_CLIPS {
+        assert_eq!(save(&mut store, &mut fs, &mut flash, 6, &[n as u8]), n);
+    }
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 7, b"last"), 1);
+    assert_eq!(read(&store, &fs, &mut flash, 1), b"last");
+    assert_eq!(store.clip(&fs, 0).map(|clip| clip.locked), Some(true));
+}
+
+#[test]
+fn fragmented() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    // Clips 0 and 2, then clips 1 and 3 are written at the same time and get every other block.
+    // Removing clips 0 and 1 leaves gaps of one block.
+    let pairs = MAX_EXTENTS / 2 + 1;
+    let block = data(BLOCK_SIZE as usize, 1);
+    for (gap, kept) in [(0, 2), (1, 3)] {
+        let mut a = fs
+            .create::<SimError>(&format!("clip{}", gap), 0, gap)
+            .unwrap();
+        let mut b = fs
+            .create::<SimError>(&format!("clip{}", kept), 0, kept)
+            .unwrap();
+        for _ in 0..pairs {
+            fs.write(&mut flash, &mut a, &block).unwrap();
+            fs.write(&mut flash, &mut b, &block).unwrap();
+        }
+        fs.close(&mut flash, a).unwrap();
+        fs.close(&mut flash, b).unwrap();
+    }
+    let mut store = ClipStore::new(&fs);
+    store.remove(&mut fs, &mut flash, 0).unwrap();
+    store.remove(&mut fs, &mut flash, 1).unwrap();
+
+    // A clip taking all free blocks needs more extents than a file can have, until the clips
+    // between the gaps are reclaimed
+    let free = fs.free_space() as usize;
+    assert!(free / BLOCK_SIZE as usize > MAX_EXTENTS + 1);
+    let d = data(free, 2);
+    assert_eq!(save(&mut store, &mut fs, &mut flash, 2, &d), 0);
+    let numbers: Vec<_> = store.clips(&fs).map(|clip| clip.number).collect();
+    assert_eq!(numbers, [0]);
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(read(&store, &fs, &mut flash, 0), d);
+}
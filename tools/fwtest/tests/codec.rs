@@ -0,0 +1,137 @@
+use fwtest::{
+    codec::{self, Error},
+    samples,
+};
+
+/// Encode and decode frames in order, checking the pixels are exact. Returns the coded sizes.
+fn round_trip(frames: &[Vec<u8>], width: u16, height: u16) -> Vec<usize> {
+    let mut out = vec![0; codec::max_len(width, height)];
+    let mut decoded = vec![0; frames[0].len()];
+    let mut sizes = Vec::new();
+    for (n, frame) in frames.iter().enumerate() {
+        let prev = n.checked_sub(1).map(|p| &frames[p][..]);
+        let len = codec::encode(frame, prev, width, height, &mut out).unwrap();
+
+        let prev_decoded = decoded.clone();
+        let prev = n.checked_sub(1).map(|_| &prev_decoded[..]);
+        codec::decode(&out[..len], prev, width, height, &mut decoded).unwrap();
+        assert!(decoded == *frame, "Frame {}", n);
+        sizes.push(len);
+    }
+    sizes
+}
+
+fn drive(n: usize, noise: u32) -> Vec<u8> {
+    samples::drive(320, 240, n, noise)
+}
+
+#[test]
+fn lossless() {
+    let frames: Vec<_> = (0..10).map(|n| drive(n, 0)).collect();
+    let sizes = round_trip(&frames, 320, 240);
+
+    // The first frame is coded on its own, the others mostly skip the sky and bonnet
+    let raw = 320 * 240 * 2;
+    assert!(sizes[0] < raw / 5, "{} bytes", sizes[0]);
+    for &size in &sizes[1..] {
+        assert!(size < raw / 10, "{} bytes", size);
+    }
+}
+
+#[test]
+fn noise() {
+    let frames: Vec<_> = (0..5).map(|n| drive(n, 3)).collect();
+    let sizes = round_trip(&frames, 320, 240);
+    assert!(sizes.iter().all(|&size| size < 320 * 240 * 2));
+}
+
+#[test]
+fn static_scene() {
+    let frame = drive(0, 0);
+    let sizes = round_trip(&[frame.clone(), frame.clone(), frame], 320, 240);
+    assert_eq!(sizes[1..], [300, 300]);
+}
+
+#[test]
+fn worst_case() {
+    // Random pixels don't pack, every tile is stored raw
+    let mut seed = 1_u32;
+    let frames: Vec<Vec<u8>> = (0..2)
+        .map(|_| {
+            (0..37 * 21 * 2)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect()
+        })
+        .collect();
+    let sizes = round_trip(&frames, 37, 21);
+    assert_eq!(sizes, [codec::max_len(37, 21); 2]);
+    assert_eq!(codec::max_len(37, 21), 3 * 2 + 37 * 21 * 2);
+}
+
+#[test]
+fn partial_tiles() {
+    for &(width, height) in &[(1, 1), (17, 9), (37, 21), (160, 120)] {
+        let frames: Vec<_> = (0..3)
+            .map(|n| samples::drive(width, height, n, 1))
+            .collect();
+        round_trip(&frames, width as u16, height as u16);
+    }
+}
+
+#[test]
+fn buffer_too_small() {
+    let (prev, frame) = (drive(0, 1), drive(1, 1));
+    let mut out = vec![0; codec::max_len(320, 240)];
+    let len = codec::encode(&frame, Some(&prev), 320, 240, &mut out).unwrap();
+
+    let mut exact = vec![0; len];
+    assert_eq!(
+        codec::encode(&frame, Some(&prev), 320, 240, &mut exact),
+        Some(len)
+    );
+    assert_eq!(exact, out[..len]);
+
+    for &size in &[0, 100, len / 2, len - 1] {
+        let mut small = vec![0; size];
+        assert_eq!(
+            codec::encode(&frame, Some(&prev), 320, 240, &mut small),
+            None
+        );
+    }
+}
+
+#[test]
+fn corrupt() {
+    let (prev, frame) = (drive(0, 1), drive(1, 1));
+    let mut out = vec![0; codec::max_len(320, 240)];
+    let len = codec::encode(&frame, Some(&prev), 320, 240, &mut out).unwrap();
+    let mut decoded = vec![0; frame.len()];
+
+    assert_eq!(
+        codec::decode(&out[..len], None, 320, 240, &mut decoded),
+        Err(Error::NoPrevious)
+    );
+    for end in [0, 1, len / 2, len - 1] {
+        assert_eq!(
+            codec::decode(&out[..end], Some(&prev), 320, 240, &mut decoded),
+            Err(Error::Corrupt)
+        );
+    }
+    out[len] = 0;
+    assert_eq!(
+        codec::decode(&out[..len + 1], Some(&prev), 320, 240, &mut decoded),
+        Err(Error::Corrupt)
+    );
+
+    // Damaged data decodes to wrong pixels or fails, but never panics
+    let mut seed = 7_u32;
+    for _ in 0..200 {
+        let mut damaged = out[..len].to_vec();
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        damaged[(seed >> 8) as usize % len] ^= (seed >> 24) as u8 | 1;
+        codec::decode(&damaged, Some(&prev), 320, 240, &mut decoded).ok();
+    }
+}
@@ -0,0 +1,124 @@
+use fwtest::command::{parse, Command, LineReader, ParseError, Setting, MAX_LINE_LEN};
+
+/// Feed `input` to a line reader and collect the results.
+fn read_lines(input: &[u8]) -> Vec<Result<Command, ParseError>> {
+    let mut reader = LineReader::new();
+    input.iter().filter_map(|&b| reader.push(b)).collect()
+}
+
+#[test]
+fn commands() {
+    assert_eq!(parse("status"), Ok(Command::Status));
+    assert_eq!(parse("list"), Ok(Command::ListClips));
+    assert_eq!(parse("save"), Ok(Command::SaveNow));
+    assert_eq!(parse("replay 0"), Ok(Command::ReplayClip(0)));
+    assert_eq!(parse("erase 12"), Ok(Command::EraseClip(12)));
+    assert_eq!(parse("lock 3"), Ok(Command::LockClip(3)));
+    assert_eq!(parse("unlock 0x3"), Ok(Command::UnlockClip(3)));
+    assert_eq!(
+        parse("set time 1700000000"),
+        Ok(Command::SetTime(1_700_000_000))
+    );
+}
+
+#[test]
+fn settings() {
+    assert_eq!(
+        parse("set orientation 3"),
+        Ok(Command::SetConfig(Setting::Orientation, 3))
+    );
+    assert_eq!(
+        parse("set fps 15"),
+        Ok(Command::SetConfig(Setting::FrameRate, 15))
+    );
+    assert_eq!(
+        parse("set parking 0x1388"),
+        Ok(Command::SetConfig(Setting::ParkingInterval, 5000))
+    );
+    assert_eq!(
+        parse("set motion_threshold 20"),
+        Ok(Command::SetConfig(Setting::MotionThreshold, 20))
+    );
+    assert_eq!(
+        parse("set motion_area 5"),
+        Ok(Command::SetConfig(Setting::MotionArea, 5))
+    );
+    assert_eq!(
+        parse("set quality 90"),
+        Ok(Command::SetConfig(Setting::Quality, 90))
+    );
+    assert_eq!(
+        parse("set pre_event 30"),
+        Ok(Command::SetConfig(Setting::PreEvent, 30))
+    );
+    assert_eq!(
+        parse("set post_frames 150"),
+        Ok(Command::SetConfig(Setting::PostEventFrames, 150))
+    );
+}
+
+#[test]
+fn case_and_whitespace() {
+    assert_eq!(parse("  STATUS "), Ok(Command::Status));
+    assert_eq!(parse("Replay\t7"), Ok(Command::ReplayClip(7)));
+    assert_eq!(
+        parse("SET FPS 0X0A"),
+        Ok(Command::SetConfig(Setting::FrameRate, 10))
+    );
+}
+
+#[test]
+fn errors() {
+    assert_eq!(parse(""), Err(ParseError::Empty));
+    assert_eq!(parse("reboot"), Err(ParseError::UnknownCommand));
+    assert_eq!(parse("replay"), Err(ParseError::MissingArgument));
+    assert_eq!(parse("replay one"), Err(ParseError::InvalidNumber));
+    assert_eq!(parse("replay -1"), Err(ParseError::InvalidNumber));
+    assert_eq!(parse("erase 4294967296"), Err(ParseError::InvalidNumber));
+    assert_eq!(parse("set"), Err(ParseError::MissingArgument));
+    assert_eq!(parse("set fps"), Err(ParseError::MissingArgument));
+    assert_eq!(parse("set brightness 3"), Err(ParseError::UnknownSetting));
+    assert_eq!(parse("status now"), Err(ParseError::TooManyArguments));
+    assert_eq!(parse("replay 1 2"), Err(ParseError::TooManyArguments));
+}
+
+#[test]
+fn lines() {
+    assert_eq!(
+        read_lines(b"status\r\nreplay 2\n\n\rbogus\n"),
+        vec![
+            Ok(Command::Status),
+            Ok(Command::ReplayClip(2)),
+            Err(ParseError::UnknownCommand)
+        ]
+    );
+
+    // Nothing is returned until the line is complete
+    assert_eq!(read_lines(b"save"), vec![]);
+}
+
+#[test]
+fn long_lines() {
+    // A line of exactly the maximum length is fine, longer ones are skipped entirely
+    let mut input = vec![b' '; MAX_LINE_LEN - 4];
+    input.extend_from_slice(b"list\n");
+    input.extend(vec![b'x'; MAX_LINE_LEN + 1]);
+    input.extend_from_slice(b"\nsave\n");
+
+    assert_eq!(
+        read_lines(&input),
+        vec![
+            Ok(Command::ListClips),
+            Err(ParseError::TooLong),
+            Ok(Command::SaveNow)
+        ]
+    );
+}
+
+#[test]
+fn invalid_utf8() {
+    assert_eq!(
+        read_lines(b"\xFF\xFE\n"),
+        vec![Err(ParseError::UnknownCommand)]
+    );
+}
@@ -0,0 +1,64 @@
+use fwtest::config::{Config, Window, CONFIG_WORDS};
+use fwtest::ov9655::{FrameRate, Orientation};
+
+#[test]
+fn words() {
+    let config = Config {
+        orientation: Orientation::Rotate180,
+        frame_rate: FrameRate::Fps5,
+        quality: 0,
+        ..Config::default()
+    };
+    let words = config.to_words();
+    assert_eq!(Config::from_words(&words), Some(config));
+    assert_eq!(
+        Config::from_words(&Config::default().to_words()),
+        Some(Config::default())
+    );
+
+    // Erased and corrupt records
+    assert_eq!(Config::from_words(&[0xFFFF_FFFF; CONFIG_WORDS]), None);
+    for i in 0..CONFIG_WORDS {
+        let mut corrupt = words;
+        corrupt[i] ^= 0x100;
+        assert_eq!(Config::from_words(&corrupt), None, "word {}", i);
+    }
+}
+
+#[test]
+fn parking() {
+    let mut config = Config::default();
+    assert_eq!(config.period_ms(), 33);
+
+    // Parking mode records one frame every parking interval, regardless of the frame rate
+    config.parking_interval_ms = 2000;
+    assert_eq!(config.period_ms(), 2000);
+    assert_eq!(config.frames(Window::Seconds(30)), 15);
+    assert_eq!(config.frames(Window::Seconds(1)), 0);
+    config.parking_interval_ms = 1;
+    assert_eq!(config.frames(Window::Seconds(u32::MAX)), u32::MAX);
+    config.parking_interval_ms = 2000;
+    assert_eq!(Config::from_words(&config.to_words()), Some(config));
+}
+
+#[test]
+fn windows() {
+    let config = Config {
+        frame_rate: FrameRate::Fps10,
+        pre_event: Window::Frames(100),
+        post_event: Window::Seconds(5),
+        ..Config::default()
+    };
+    assert_eq!(config.frames(config.pre_event), 100);
+    assert_eq!(config.frames(config.post_event), 50);
+    assert_eq!(config.frames(Window::Seconds(u32::MAX)), u32::MAX);
+
+    // Windows in frames and in seconds are kept apart in the record
+    let words = config.to_words();
+    assert_eq!(Config::from_words(&words), Some(config));
+    let default = Config::default();
+    assert_eq!(
+        (default.pre_event, default.post_event),
+        (Window::Seconds(30), Window::Seconds(10))
+    );
+}
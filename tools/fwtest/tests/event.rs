@@ -0,0 +1,90 @@
+use fwtest::event::{EventQueue, MAX_EVENTS};
+
+fn windows(queue: &EventQueue) -> Vec<(u32, u32)> {
+    queue
+        .windows()
+        .map(|seqs| (*seqs.start(), *seqs.end()))
+        .collect()
+}
+
+#[test]
+fn queue() {
+    let mut queue = EventQueue::new();
+    assert!(queue.is_empty());
+    assert_eq!(queue.start(1000), None);
+
+    assert!(queue.push(0..=100));
+    assert!(queue.push(200..=300));
+    assert_eq!(queue.len(), 2);
+
+    // Clips are saved in turn, once their last frame is recorded
+    assert_eq!(queue.start(99), None);
+    assert_eq!(queue.start(100), Some(0..=100));
+    assert!(queue.is_saving());
+    assert_eq!(queue.start(300), None);
+    queue.pop();
+    assert!(!queue.is_saving());
+    assert_eq!(queue.start(250), None);
+    assert_eq!(queue.start(301), Some(200..=300));
+    queue.pop();
+    assert!(queue.is_empty());
+    queue.pop();
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn merge() {
+    let mut queue = EventQueue::new();
+    assert!(queue.push(10..=50));
+
+    // Overlapping or adjacent windows make one clip
+    assert!(queue.push(40..=80));
+    assert!(queue.push(81..=90));
+    assert!(queue.push(20..=30));
+    assert_eq!(windows(&queue), [(10, 90)]);
+    assert!(queue.push(92..=100));
+    assert_eq!(windows(&queue), [(10, 90), (92, 100)]);
+
+    // Only the newest window grows
+    assert!(queue.push(85..=120));
+    assert_eq!(windows(&queue), [(10, 90), (85, 120)]);
+}
+
+#[test]
+fn linked() {
+    // A window overlapping the one being saved continues after it
+    let mut queue = EventQueue::new();
+    assert!(queue.push(10..=50));
+    assert_eq!(queue.start(50), Some(10..=50));
+    assert!(queue.push(30..=70));
+    assert_eq!(windows(&queue), [(10, 50), (51, 70)]);
+    assert!(queue.push(60..=80));
+    assert_eq!(windows(&queue), [(10, 50), (51, 80)]);
+
+    // Nothing is left of a window inside the one being saved
+    queue.pop();
+    assert_eq!(queue.start(80), Some(51..=80));
+    assert!(queue.push(60..=70));
+    assert!(queue.push(80..=80));
+    assert_eq!(windows(&queue), [(51, 80)]);
+    assert!(queue.push(75..=u32::MAX));
+    assert_eq!(windows(&queue), [(51, 80), (81, u32::MAX)]);
+    queue.pop();
+    assert_eq!(queue.start(u32::MAX), Some(81..=u32::MAX));
+    assert!(queue.push(u32::MAX..=u32::MAX));
+    assert_eq!(queue.len(), 1);
+}
+
+#[test]
+fn full() {
+    let mut queue = EventQueue::new();
+    for n in 0..MAX_EVENTS as u32 {
+        assert!(queue.push(n * 100..=n * 100 + 10));
+    }
+    assert!(!queue.push(10_000..=10_010));
+
+    // Merging needs no room
+    assert!(queue.push(705..=720));
+    assert_eq!(queue.len(), MAX_EVENTS);
+    assert_eq!(windows(&queue).last(), Some(&(700, 720)));
+}
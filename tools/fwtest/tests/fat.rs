@@ -0,0 +1,275 @@
+use fwtest::{
+    fat::{Clip, FatDisk, FatVolume, MAX_CLIPS, NUM_BLOCKS},
+    scsi::{BlockDevice, BLOCK_SIZE},
+    sim::{SimFlash, SIM_FLASH_SIZE},
+};
+
+/// A file found on the volume.
+#[derive(Debug)]
+struct File {
+    name: String,
+    attributes: u8,
+    date: u16,
+    time: u16,
+    data: Vec<u8>,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]]) as usize
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ]) as usize
+}
+
+/// Read the volume like a host would: Find the layout in the boot sector, then read the files in
+/// the root directory by following their cluster chains.
+fn read_files(volume: &FatVolume, flash: &mut SimFlash) -> Vec<File> {
+    let mut disk = FatDisk {
+        volume,
+        storage: flash,
+    };
+    let mut read = |lba: usize| {
+        let mut buf = [0; BLOCK_SIZE];
+        disk.read_block(lba as u32, &mut buf).unwrap();
+        buf
+    };
+
+    let boot = read(0);
+    assert_eq!(&boot[510..], &[0x55, 0xAA]);
+    assert_eq!(&boot[54..62], b"FAT16   ");
+    assert_eq!(u16_at(&boot, 11), BLOCK_SIZE);
+    assert_eq!(u32_at(&boot, 32), NUM_BLOCKS as usize);
+
+    let blocks_per_cluster = boot[13] as usize;
+    let fat_start = u16_at(&boot, 14);
+    let num_fats = boot[16] as usize;
+    let root_entries = u16_at(&boot, 17);
+    let fat_blocks = u16_at(&boot, 22);
+    let root_start = fat_start + num_fats * fat_blocks;
+    let data_start = root_start + root_entries * 32 / BLOCK_SIZE;
+
+    // FAT16 is decided by the number of clusters
+    let clusters = (NUM_BLOCKS as usize - data_start) / blocks_per_cluster;
+    assert!((4085..65525).contains(&clusters));
+
+    // All copies of the FAT are the same
+    let fat: Vec<u8> = (0..fat_blocks).flat_map(|i| read(fat_start + i)).collect();
+    for copy in 1..num_fats {
+        let other: Vec<u8> = (0..fat_blocks)
+            .flat_map(|i| read(fat_start + copy * fat_blocks + i))
+            .collect();
+        assert_eq!(fat, other);
+    }
+    assert_eq!(u16_at(&fat, 0), 0xFFF8);
+
+    let root: Vec<u8> = (root_start..data_start).flat_map(&mut read).collect();
+    let mut files = Vec::new();
+    for entry in root.chunks(32).take_while(|entry| entry[0] != 0) {
+        let attributes = entry[11];
+        if attributes & 0x08 != 0 {
+            assert_eq!(&entry[..11], b"DASHCAM    ");
+            continue;
+        }
+
+        let len = u32_at(entry, 28);
+        let mut data = Vec::new();
+        let mut cluster = u16_at(entry, 26);
+        while data.len() < len {
+            assert!((2..clusters + 2).contains(&cluster));
+            let first_block = data_start + (cluster - 2) * blocks_per_cluster;
+            for block in first_block..first_block + blocks_per_cluster {
+                data.extend_from_slice(&read(block));
+            }
+            cluster = u16_at(&fat, 2 * cluster);
+        }
+        assert!(len == 0 || cluster >= 0xFFF8, "Chain doesn't end");
+
+        // Slack space after the end of a file is cleared
+        assert!(data[len.min(data.len())..].iter().all(|&b| b == 0));
+        data.truncate(len);
+
+        let name = String::from_utf8(entry[..11].to_vec()).unwrap();
+        files.push(File {
+            name: format!("{}.{}", name[..8].trim_end(), name[8..].trim_end()),
+            attributes,
+            date: u16_at(entry, 24) as u16,
+            time: u16_at(entry, 22) as u16,
+            data,
+        });
+    }
+    files
+}
+
+/// Program a clip of `len` bytes at `addr` with a pattern depending on `seed`.
+fn program_clip(flash: &mut SimFlash, addr: u32, len: usize, seed: u8) -> Vec<u8> {
+    let data: Vec<u8> = (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+        .collect();
+    flash.program(addr, &data).unwrap();
+    data
+}
+
+#[test]
+fn empty() {
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let volume = FatVolume::new();
+    assert!(read_files(&volume, &mut flash).is_empty());
+}
+
+#[test]
+fn clips() {
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+
+    // Lengths around cluster boundaries, including lengths which are not whole words
+    let lengths = [153_600, 2048, 2049, 1, 0, 5000];
+    let mut clips = Vec::new();
+    let mut contents = Vec::new();
+    let mut addr = 0;
+    for (n, &len) in lengths.iter().enumerate() {
+        contents.push(program_clip(&mut flash, addr, len, n as u8));
+        clips.push(Clip {
+            addr,
+            len: len as u32,
+            time: 0,
+        });
+        addr += (len as u32 + 0xFFF) & !0xFFF;
+    }
+
+    let mut volume = FatVolume::new();
+    assert_eq!(volume.set_clips(&clips), clips.len());
+    assert_eq!(volume.clips(), &clips[..]);
+
+    // The clip of length 0 is erased and has no file
+    let files = read_files(&volume, &mut flash);
+    assert_eq!(files.len(), clips.len() - 1);
+    let saved = contents
+        .iter()
+        .enumerate()
+        .filter(|(_, data)| !data.is_empty());
+    for (file, (n, data)) in files.iter().zip(saved) {
+        assert_eq!(file.name, format!("CLIP{:04}.CLP", n));
+        assert_eq!(file.attributes, 0x01, "Files are read-only");
+        assert!(file.data == *data, "Clip {} differs", n);
+    }
+}
+
+#[test]
+fn erased_clips() {
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let erased = Clip::default();
+    let data = program_clip(&mut flash, 0x1000, 3000, 3);
+    let clip = Clip {
+        addr: 0x1000,
+        len: 3000,
+        time: 0,
+    };
+
+    // Clips after erased ones are still listed, with their numbers
+    let mut volume = FatVolume::new();
+    assert_eq!(volume.set_clips(&[erased, erased, clip]), 3);
+    let files = read_files(&volume, &mut flash);
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].name, "CLIP0002.CLP");
+    assert!(files[0].data == data);
+
+    assert_eq!(volume.set_clips(&[erased; MAX_CLIPS]), MAX_CLIPS);
+    assert!(read_files(&volume, &mut flash).is_empty());
+}
+
+#[test]
+fn full_flash() {
+    // A single clip filling the whole flash fits on the volume
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let data = program_clip(&mut flash, 0, SIM_FLASH_SIZE, 7);
+    let clip = Clip {
+        addr: 0,
+        len: SIM_FLASH_SIZE as u32,
+        time: 0,
+    };
+
+    let mut volume = FatVolume::new();
+    assert_eq!(volume.set_clips(&[clip]), 1);
+    let files = read_files(&volume, &mut flash);
+    assert!(files[0].data == data);
+}
+
+#[test]
+fn too_many_clips() {
+    let clip = Clip {
+        addr: 0,
+        len: 4,
+        time: 0,
+    };
+    let mut volume = FatVolume::new();
+    assert_eq!(volume.set_clips(&[clip; MAX_CLIPS + 1]), MAX_CLIPS);
+
+    // Clips which don't fit the volume are left out
+    let big = Clip {
+        addr: 0,
+        len: 20 * 1024 * 1024,
+        time: 0,
+    };
+    assert_eq!(volume.set_clips(&[big, big]), 1);
+}
+
+#[test]
+fn timestamps() {
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let clips = [
+        // 2023-11-14 22:13:21
+        Clip {
+            addr: 0,
+            len: 4,
+            time: 1_700_000_001,
+        },
+        // 2000-02-29 00:00:00
+        Clip {
+            addr: 0,
+            len: 4,
+            time: 951_782_400,
+        },
+        // Unknown, before the FAT epoch
+        Clip {
+            addr: 0,
+            len: 4,
+            time: 0,
+        },
+    ];
+
+    let mut volume = FatVolume::new();
+    volume.set_clips(&clips);
+    let files = read_files(&volume, &mut flash);
+
+    assert_eq!(files[0].date, (43 << 9) | (11 << 5) | 14);
+    assert_eq!(files[0].time, (22 << 11) | (13 << 5) | 10);
+    assert_eq!(files[1].date, (20 << 9) | (2 << 5) | 29);
+    assert_eq!(files[1].time, 0);
+    assert_eq!(files[2].date, (1 << 5) | 1);
+}
+
+#[test]
+fn read_errors() {
+    // Errors of the storage are passed on
+    let mut flash = SimFlash::new(4096);
+    let clip = Clip {
+        addr: 0,
+        len: 8192,
+        time: 0,
+    };
+    let mut volume = FatVolume::new();
+    volume.set_clips(&[clip]);
+
+    let mut disk = FatDisk {
+        volume: &volume,
+        storage: &mut flash,
+    };
+    let mut buf = [0; BLOCK_SIZE];
+    assert!(disk.read_block(161, &mut buf).is_ok());
+    assert!(disk.read_block(161 + 8, &mut buf).is_err());
+}
@@ -0,0 +1,325 @@
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use fwtest::{
+    block::BLOCK_SIZE,
+    fat32::{Error, Volume},
+    sim::SimDisk,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+/// Smallest FAT32 volumes have 65525 clusters, with 512 byte clusters this is a bit over 32 MB.
+const VOLUME_BLOCKS: u32 = 70_000;
+
+/// 2020-12-31 23:59:58 and 2021-01-01 12:00:00 UTC.
+const NEW_YEARS_EVE: u32 = 1_609_459_198;
+const NEW_YEAR: u32 = 1_609_502_400;
+
+/// Format a FAT32 volume of `blocks` blocks, with clusters of `cluster_blocks` blocks.
+fn format(blocks: u32, cluster_blocks: u32) -> Vec<u8> {
+    let mut image = Cursor::new(vec![0; blocks as usize * BLOCK_SIZE]);
+    let options = FormatVolumeOptions::new()
+        .fat_type(FatType::Fat32)
+        .bytes_per_cluster(cluster_blocks * BLOCK_SIZE as u32)
+        .volume_label(*b"DASHCAM    ");
+    fatfs::format_volume(&mut image, options).unwrap();
+    image.into_inner()
+}
+
+fn disk_with(image: &[u8]) -> SimDisk {
+    let mut disk = SimDisk::new((image.len() / BLOCK_SIZE) as u32);
+    disk.data_mut().copy_from_slice(image);
+    disk
+}
+
+/// Write the contents of `disk` to a disk image file named after the test.
+fn image_file(disk: &SimDisk, test: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("fwtest-{}-{}.img", test, std::process::id()));
+    fs::write(&path, disk.data()).unwrap();
+    path
+}
+
+/// Open the filesystem in a disk image file with `fatfs`, starting at block `start`.
+fn open(path: &PathBuf, start: u32) -> FileSystem<Partition> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let offset = start as u64 * BLOCK_SIZE as u64;
+    file.seek(SeekFrom::Start(offset)).unwrap();
+    FileSystem::new(Partition { file, offset }, FsOptions::new()).unwrap()
+}
+
+/// A disk image file, seen from block `offset / BLOCK_SIZE` on.
+struct Partition {
+    file: File,
+    offset: u64,
+}
+
+impl Read for Partition {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for Partition {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Partition {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => SeekFrom::Start(pos + self.offset),
+            pos => pos,
+        };
+        Ok(self.file.seek(pos)? - self.offset)
+    }
+}
+
+fn clip_data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 + i / 509) as u8 ^ seed).collect()
+}
+
+/// Save `data` as a clip at `time`, in chunks of `chunk` bytes. Returns the clip number.
+fn save(disk: &mut SimDisk, volume: &mut Volume, time: u32, data: &[u8], chunk: usize) -> u32 {
+    let mut file = volume.create_clip(disk, time).unwrap();
+    for part in data.chunks(chunk) {
+        volume.write(disk, &mut file, part).unwrap();
+    }
+    assert_eq!(file.size(), data.len() as u32);
+    let number = file.number;
+    volume.close(disk, file).unwrap();
+    number
+}
+
+fn read_file(fs: &FileSystem<Partition>, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut file = fs.root_dir().open_file(path).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn clips() {
+    let mut disk = disk_with(&format(VOLUME_BLOCKS, 1));
+    let mut volume = Volume::mount(&mut disk).unwrap();
+
+    // Block aligned and odd sizes, written in odd and whole block chunks
+    let first = clip_data(10 * BLOCK_SIZE, 1);
+    let second = clip_data(3 * BLOCK_SIZE + 77, 2);
+    let third = clip_data(1000, 3);
+    assert_eq!(save(&mut disk, &mut volume, NEW_YEARS_EVE, &first, 700), 1);
+    assert_eq!(
+        save(
+            &mut disk,
+            &mut volume,
+            NEW_YEARS_EVE,
+            &second,
+            4 * BLOCK_SIZE
+        ),
+        2
+    );
+    assert_eq!(save(&mut disk, &mut volume, NEW_YEAR, &third, 333), 1);
+
+    // An empty clip
+    let file = volume.create_clip(&mut disk, NEW_YEAR).unwrap();
+    assert_eq!(file.number, 2);
+    volume.close(&mut disk, file).unwrap();
+
+    let path = image_file(&disk, "clips");
+    {
+        let fs = open(&path, 0);
+        assert_eq!(read_file(&fs, "20201231/CLIP0001.CLP"), first);
+        assert_eq!(read_file(&fs, "20201231/CLIP0002.CLP"), second);
+        assert_eq!(read_file(&fs, "20210101/CLIP0001.CLP"), third);
+        assert!(read_file(&fs, "20210101/CLIP0002.CLP").is_empty());
+
+        let dirs: Vec<_> = fs.root_dir().iter().map(|e| e.unwrap()).collect();
+        let names: Vec<_> = dirs.iter().map(|e| e.file_name()).collect();
+        assert_eq!(names, ["20201231", "20210101"]);
+        assert!(dirs.iter().all(|e| e.is_dir()));
+
+        // Timestamps of the directories and files
+        let entry = fs
+            .root_dir()
+            .open_dir("20201231")
+            .unwrap()
+            .iter()
+            .map(|e| e.unwrap())
+            .find(|e| e.file_name() == "CLIP0001.CLP")
+            .unwrap();
+        let modified = entry.modified();
+        assert_eq!(
+            (modified.date.year, modified.date.month, modified.date.day),
+            (2020, 12, 31)
+        );
+        assert_eq!(
+            (modified.time.hour, modified.time.min, modified.time.sec),
+            (23, 59, 58)
+        );
+        let modified = dirs[1].modified();
+        assert_eq!(
+            (modified.date.year, modified.date.month, modified.date.day),
+            (2021, 1, 1)
+        );
+
+        // Allocated clusters are accounted for
+        let stats = fs.stats().unwrap();
+        let used = 1 + 2 + (10 + 4 + 2);
+        assert_eq!(stats.total_clusters() - stats.free_clusters(), used);
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn existing_files() {
+    // Files written by a computer, with long names and clips numbered up to 5, on a volume with
+    // clusters of two blocks
+    let mut image = Cursor::new(format(2 * VOLUME_BLOCKS, 2));
+    {
+        let fs = FileSystem::new(&mut image, FsOptions::new()).unwrap();
+        let dir = fs.root_dir().create_dir("20201231").unwrap();
+        dir.create_file("A long file name.txt")
+            .unwrap()
+            .write_all(b"Hello")
+            .unwrap();
+        dir.create_file("CLIP0005.CLP").unwrap();
+        dir.create_file("CLIP0003.CLP").unwrap();
+        dir.create_file("CLIP0009.TXT").unwrap();
+        dir.create_dir("CLIP0007.CLP").unwrap();
+        fs.root_dir().create_file("README.TXT").unwrap();
+    }
+
+    let mut disk = disk_with(image.get_ref());
+    let mut volume = Volume::mount(&mut disk).unwrap();
+    let data = clip_data(20 * BLOCK_SIZE + 5, 4);
+    assert_eq!(save(&mut disk, &mut volume, NEW_YEARS_EVE, &data, 4096), 6);
+
+    let path = image_file(&disk, "existing_files");
+    {
+        let fs = open(&path, 0);
+        assert_eq!(read_file(&fs, "20201231/CLIP0006.CLP"), data);
+        assert_eq!(read_file(&fs, "20201231/A long file name.txt"), b"Hello");
+        let count = fs.root_dir().open_dir("20201231").unwrap().iter().count();
+        assert_eq!(count, 2 + 6);
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn partition() {
+    // MBR with a FAT32 partition starting at 1 MB, after an unused entry
+    let start = 2048;
+    let volume_image = format(VOLUME_BLOCKS, 1);
+    let mut disk = SimDisk::new(start + VOLUME_BLOCKS);
+    let data = disk.data_mut();
+    data[start as usize * BLOCK_SIZE..].copy_from_slice(&volume_image);
+    let entry = &mut data[446 + 16..446 + 32];
+    entry[4] = 0x0C;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&VOLUME_BLOCKS.to_le_bytes());
+    data[510] = 0x55;
+    data[511] = 0xAA;
+
+    let mut volume = Volume::mount(&mut disk).unwrap();
+    let clip = clip_data(5 * BLOCK_SIZE, 5);
+    save(&mut disk, &mut volume, NEW_YEAR, &clip, BLOCK_SIZE);
+
+    let path = image_file(&disk, "partition");
+    {
+        let fs = open(&path, start);
+        assert_eq!(read_file(&fs, "20210101/CLIP0001.CLP"), clip);
+    }
+    fs::remove_file(path).unwrap();
+
+    // Nothing written before the partition
+    assert!(disk.data()[BLOCK_SIZE..start as usize * BLOCK_SIZE]
+        .iter()
+        .all(|&b| b == 0));
+}
+
+#[test]
+fn many_dates() {
+    // More directories than fit in the first cluster of the root directory
+    let mut disk = disk_with(&format(VOLUME_BLOCKS, 1));
+    let mut volume = Volume::mount(&mut disk).unwrap();
+    for day in 0..40 {
+        let clip = clip_data(100 + day, day as u8);
+        save(
+            &mut disk,
+            &mut volume,
+            NEW_YEAR + day as u32 * 86400,
+            &clip,
+            64,
+        );
+    }
+
+    // Remounting continues with the next clip numbers
+    let mut volume = Volume::mount(&mut disk).unwrap();
+    save(&mut disk, &mut volume, NEW_YEAR, &[1, 2, 3], 3);
+
+    let path = image_file(&disk, "many_dates");
+    {
+        let fs = open(&path, 0);
+        assert_eq!(fs.root_dir().iter().count(), 40);
+        assert_eq!(read_file(&fs, "20210101/CLIP0002.CLP"), [1, 2, 3]);
+        assert_eq!(read_file(&fs, "20210209/CLIP0001.CLP"), clip_data(139, 39));
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn full() {
+    let mut disk = disk_with(&format(VOLUME_BLOCKS, 1));
+    let mut volume = Volume::mount(&mut disk).unwrap();
+
+    // The clip is cut off when the volume is full, but can still be closed
+    let chunk = vec![0xA5; 64 * BLOCK_SIZE];
+    let mut file = volume.create_clip(&mut disk, NEW_YEAR).unwrap();
+    let err = loop {
+        if let Err(err) = volume.write(&mut disk, &mut file, &chunk) {
+            break err;
+        }
+    };
+    assert_eq!(err, Error::Full);
+    let size = file.size();
+    volume.close(&mut disk, file).unwrap();
+
+    let path = image_file(&disk, "full");
+    {
+        let fs = open(&path, 0);
+        let data = read_file(&fs, "20210101/CLIP0001.CLP");
+        assert_eq!(data.len(), size as usize);
+        assert!(data.iter().all(|&b| b == 0xA5));
+        assert_eq!(fs.stats().unwrap().free_clusters(), 0);
+    }
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn no_filesystem() {
+    // Blank card
+    let mut disk = SimDisk::new(VOLUME_BLOCKS);
+    assert_eq!(Volume::mount(&mut disk).err(), Some(Error::NoFilesystem));
+
+    // FAT16 has too few clusters
+    let mut image = Cursor::new(vec![0; VOLUME_BLOCKS as usize * BLOCK_SIZE]);
+    let options = FormatVolumeOptions::new().fat_type(FatType::Fat16);
+    fatfs::format_volume(&mut image, options).unwrap();
+    let mut disk = disk_with(image.get_ref());
+    assert_eq!(Volume::mount(&mut disk).err(), Some(Error::NoFilesystem));
+
+    // The volume must fit on the device
+    let image = format(VOLUME_BLOCKS, 1);
+    let mut disk = disk_with(&image[..(VOLUME_BLOCKS as usize - 1) * BLOCK_SIZE]);
+    assert_eq!(Volume::mount(&mut disk).err(), Some(Error::NoFilesystem));
+}
@@ -0,0 +1,445 @@
+use fwtest::{
+    flashfs::{Error, FileSystem, BLOCK_SIZE, MAX_EXTENTS, MAX_FILES, META_BLOCKS},
+    sim::{SimError, SimFlash},
+};
+
+/// A small flash keeps the tests fast, and fills up quickly.
+const FLASH_SIZE: u32 = 64 * BLOCK_SIZE;
+const DATA_BLOCKS: u32 = FLASH_SIZE / BLOCK_SIZE - META_BLOCKS;
+
+fn data(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8 ^ seed).collect()
+}
+
+fn contents(fs: &FileSystem, flash: &mut SimFlash, name: &str) -> Option<Vec<u8>> {
+    let size = fs.metadata(name)?.size as usize;
+    let mut buf = vec![0; size + 10];
+    let len = fs.read(flash, name, 0, &mut buf).unwrap();
+    assert_eq!(len, size);
+    buf.truncate(len);
+    Some(buf)
+}
+
+/// Free space must match the sizes of the files.
+fn check_free_space(fs: &FileSystem) {
+    let used: u32 = fs.files().map(|f| f.size.div_ceil(BLOCK_SIZE)).sum();
+    assert_eq!(fs.free_space(), (DATA_BLOCKS - used) * BLOCK_SIZE);
+}
+
+#[test]
+fn files() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    assert_eq!(
+        FileSystem::mount(&mut flash, FLASH_SIZE).err(),
+        Some(Error::NoFilesystem)
+    );
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(fs.files().count(), 0);
+    assert_eq!(fs.free_space(), DATA_BLOCKS * BLOCK_SIZE);
+
+    // Written in odd chunks, across blocks
+    let clip = data(3 * BLOCK_SIZE as usize + 123, 1);
+    let mut writer = fs.create::<SimError>("clip0001", 1_609_459_200, 5).unwrap();
+    for chunk in clip.chunks(1000) {
+        fs.write(&mut flash, &mut writer, chunk).unwrap();
+    }
+    assert_eq!(writer.size(), clip.len() as u32);
+    assert_eq!(fs.metadata("clip0001"), None);
+    fs.close(&mut flash, writer).unwrap();
+    fs.write_file(&mut flash, "config", 0, &[1, 2, 3, 4])
+        .unwrap();
+    fs.write_file(&mut flash, "empty", 0, &[]).unwrap();
+
+    // Everything is still there after mounting again
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let meta = fs.metadata("clip0001").unwrap();
+    assert_eq!(meta.name(), "clip0001");
+    assert_eq!(
+        (meta.size, meta.time, meta.flags),
+        (clip.len() as u32, 1_609_459_200, 5)
+    );
+    assert_eq!(contents(&fs, &mut flash, "clip0001").unwrap(), clip);
+    assert_eq!(contents(&fs, &mut flash, "config").unwrap(), [1, 2, 3, 4]);
+    assert_eq!(contents(&fs, &mut flash, "empty").unwrap(), []);
+    let mut names: Vec<_> = fs.files().map(|f| f.name().to_string()).collect();
+    names.sort();
+    assert_eq!(names, ["clip0001", "config", "empty"]);
+    check_free_space(&fs);
+
+    // Reads at an offset and past the end
+    let mut buf = [0; 100];
+    assert_eq!(
+        fs.read(&mut flash, "clip0001", 4090, &mut buf).unwrap(),
+        100
+    );
+    assert_eq!(&buf[..], &clip[4090..4190]);
+    let end = clip.len() as u32 - 30;
+    assert_eq!(fs.read(&mut flash, "clip0001", end, &mut buf).unwrap(), 30);
+    assert_eq!(fs.read(&mut flash, "config", 10, &mut buf).unwrap(), 0);
+
+    // Mounting a different size fails
+    assert_eq!(
+        FileSystem::mount(&mut flash, FLASH_SIZE * 2).err(),
+        Some(Error::NoFilesystem)
+    );
+}
+
+#[test]
+fn replace_and_remove() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    fs.write_file(&mut flash, "a", 0, &data(20_000, 1)).unwrap();
+    fs.write_file(&mut flash, "b", 0, &data(5000, 2)).unwrap();
+
+    // The old version stays readable until the new one is closed
+    let mut writer = fs.create::<SimError>("a", 0, 0).unwrap();
+    fs.write(&mut flash, &mut writer, &data(100, 3)).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "a").unwrap(), data(20_000, 1));
+    fs.close(&mut flash, writer).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "a").unwrap(), data(100, 3));
+    check_free_space(&fs);
+
+    fs.remove(&mut flash, "b").unwrap();
+    assert_eq!(fs.metadata("b"), None);
+    assert_eq!(fs.remove(&mut flash, "b"), Err(Error::NotFound));
+    check_free_space(&fs);
+
+    // Flags change without rewriting the file
+    fs.set_flags(&mut flash, "a", 7).unwrap();
+    assert_eq!(fs.metadata("a").unwrap().flags, 7);
+    assert_eq!(fs.set_flags(&mut flash, "b", 7), Err(Error::NotFound));
+    check_free_space(&fs);
+
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "a").unwrap(), data(100, 3));
+    assert_eq!(fs.metadata("a").unwrap().flags, 7);
+    assert_eq!(fs.metadata("b"), None);
+    check_free_space(&fs);
+}
+
+#[test]
+fn names_and_limits() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    for name in ["", "seventeen bytes!!", "nul\0"] {
+        assert_eq!(
+            fs.write_file(&mut flash, name, 0, &[1]),
+            Err(Error::BadName)
+        );
+    }
+    fs.write_file(&mut flash, "sixteen bytes!!!", 0, &[1])
+        .unwrap();
+
+    for n in 1..MAX_FILES {
+        fs.write_file(&mut flash, &format!("file{}", n), 0, &[])
+            .unwrap();
+    }
+    assert_eq!(
+        fs.write_file(&mut flash, "one more", 0, &[]),
+        Err(Error::TooManyFiles)
+    );
+
+    // Replacing a file is still possible
+    fs.write_file(&mut flash, "file1", 0, &[2]).unwrap();
+    assert_eq!(fs.files().count(), MAX_FILES);
+}
+
+#[test]
+fn full() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    // The file keeps what fitted
+    let mut writer = fs.create::<SimError>("big", 0, 0).unwrap();
+    let chunk = data(10_000, 4);
+    let err = loop {
+        if let Err(e) = fs.write(&mut flash, &mut writer, &chunk) {
+            break e;
+        }
+    };
+    assert_eq!(err, Error::Full);
+    assert_eq!(writer.size(), DATA_BLOCKS * BLOCK_SIZE);
+    fs.close(&mut flash, writer).unwrap();
+    assert_eq!(fs.free_space(), 0);
+
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let big = contents(&fs, &mut flash, "big").unwrap();
+    assert!(big.chunks(chunk.len()).all(|c| *c == chunk[..c.len()]));
+}
+
+#[test]
+fn discard() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    let old = data(BLOCK_SIZE as usize + 10, 5);
+    fs.write_file(&mut flash, "clip", 0, &old).unwrap();
+    let free = fs.free_space();
+
+    // A new file which is discarded leaves no trace
+    let mut writer = fs.create::<SimError>("new", 0, 0).unwrap();
+    fs.write(&mut flash, &mut writer, &data(3 * BLOCK_SIZE as usize, 6))
+        .unwrap();
+    assert_eq!(fs.free_space(), free - 3 * BLOCK_SIZE);
+    fs.discard(writer);
+    assert_eq!(fs.free_space(), free);
+    assert_eq!(fs.metadata("new"), None);
+
+    // An appended file keeps its blocks and contents
+    let mut writer = fs.append(&mut flash, "clip").unwrap();
+    fs.write(&mut flash, &mut writer, &data(2 * BLOCK_SIZE as usize, 7))
+        .unwrap();
+    fs.discard(writer);
+    assert_eq!(fs.free_space(), free);
+    check_free_space(&fs);
+
+    let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "clip").unwrap(), old);
+    fs.write_file(&mut flash, "full", 0, &data(free as usize, 8))
+        .unwrap();
+    assert_eq!(fs.free_space(), 0);
+}
+
+#[test]
+fn fragmented() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    // Two files written at the same time get every other block, so each block is an extent
+    let block = data(BLOCK_SIZE as usize, 8);
+    let mut a = fs.create::<SimError>("a", 0, 0).unwrap();
+    let mut b = fs.create::<SimError>("b", 0, 0).unwrap();
+    for _ in 0..MAX_EXTENTS {
+        fs.write(&mut flash, &mut a, &block).unwrap();
+        fs.write(&mut flash, &mut b, &block).unwrap();
+    }
+    assert_eq!(fs.write(&mut flash, &mut a, &block), Err(Error::Fragmented));
+    fs.close(&mut flash, a).unwrap();
+    fs.close(&mut flash, b).unwrap();
+    check_free_space(&fs);
+
+    // The gaps can be used again
+    fs.remove(&mut flash, "b").unwrap();
+    let c = data(MAX_EXTENTS * BLOCK_SIZE as usize, 9);
+    fs.write_file(&mut flash, "c", 0, &c).unwrap();
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "c").unwrap(), c);
+    assert_eq!(
+        contents(&fs, &mut flash, "a").unwrap(),
+        block.repeat(MAX_EXTENTS)
+    );
+    check_free_space(&fs);
+}
+
+#[test]
+fn contiguous() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    // A file continues in the block after its end once it is free, instead of the next block
+    // round robin which would need another extent
+    let block = data(BLOCK_SIZE as usize, 10);
+    let mut a = fs.create::<SimError>("a", 0, 0).unwrap();
+    for n in 0..2 * MAX_EXTENTS {
+        fs.write(&mut flash, &mut a, &block).unwrap();
+        fs.write_file(&mut flash, "b", 0, &data(100, n as u8))
+            .unwrap();
+        fs.remove(&mut flash, "b").unwrap();
+    }
+    fs.close(&mut flash, a).unwrap();
+    check_free_space(&fs);
+
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(
+        contents(&fs, &mut flash, "a").unwrap(),
+        block.repeat(2 * MAX_EXTENTS)
+    );
+}
+
+#[test]
+fn append() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    fs.write_file(&mut flash, "log", 0, b"boot\n").unwrap();
+
+    let mut expected = b"boot\n".to_vec();
+    for n in 0..20 {
+        let line = data(700 + n, n as u8);
+        let mut writer = fs.append(&mut flash, "log").unwrap();
+        fs.write(&mut flash, &mut writer, &line).unwrap();
+        fs.close(&mut flash, writer).unwrap();
+        expected.extend(line);
+
+        fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        assert_eq!(contents(&fs, &mut flash, "log").unwrap(), expected);
+    }
+    check_free_space(&fs);
+
+    // An append which was not closed leaves data after the end of the file, the next one
+    // continues in a copy of the last block
+    let mut writer = fs.append(&mut flash, "log").unwrap();
+    fs.write(&mut flash, &mut writer, &[0; 10]).unwrap();
+    let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    let mut writer = fs.append(&mut flash, "log").unwrap();
+    fs.write(&mut flash, &mut writer, b"end").unwrap();
+    fs.close(&mut flash, writer).unwrap();
+    expected.extend(b"end");
+    assert_eq!(contents(&fs, &mut flash, "log").unwrap(), expected);
+    check_free_space(&fs);
+
+    assert_eq!(
+        fs.append(&mut flash, "missing").err(),
+        Some(Error::NotFound)
+    );
+}
+
+#[test]
+fn compaction() {
+    // Enough changes to go around the metadata blocks several times
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    fs.write_file(&mut flash, "keep", 0, &data(9000, 9))
+        .unwrap();
+    for n in 0..1000_u32 {
+        fs.write_file(&mut flash, "counter", 0, &n.to_le_bytes())
+            .unwrap();
+        if n % 97 == 0 {
+            fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        }
+    }
+
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(
+        contents(&fs, &mut flash, "counter").unwrap(),
+        999_u32.to_le_bytes()
+    );
+    assert_eq!(contents(&fs, &mut flash, "keep").unwrap(), data(9000, 9));
+    check_free_space(&fs);
+}
+
+/// Files after each step of `changes`, sorted by name.
+fn expected_states() -> Vec<Vec<(&'static str, Vec<u8>)>> {
+    let initial = vec![
+        ("a", data(3 * 4096 + 100, 1)),
+        ("b", data(5000, 2)),
+        ("log", data(700, 3)),
+    ];
+    let mut states = vec![initial.clone()];
+    let mut state = initial;
+
+    state[0].1 = data(2 * 4096 + 50, 4);
+    states.push(state.clone());
+    state.remove(1);
+    states.push(state.clone());
+    state[1].1.extend(data(5000, 5));
+    states.push(state.clone());
+    state.insert(1, ("c", data(6000, 6)));
+    states.push(state);
+    states
+}
+
+/// Replace a file, remove one, append to one and create one.
+fn changes(fs: &mut FileSystem, flash: &mut SimFlash) -> Result<(), Error<SimError>> {
+    fs.write_file(flash, "a", 0, &data(2 * 4096 + 50, 4))?;
+    fs.remove(flash, "b")?;
+    let mut writer = fs.append(flash, "log")?;
+    fs.write(flash, &mut writer, &data(5000, 5))?;
+    fs.close(flash, writer)?;
+    fs.write_file(flash, "c", 0, &data(6000, 6))
+}
+
+#[test]
+fn power_loss() {
+    let states = expected_states();
+
+    // With the metadata block nearly full, so the changes need a compaction
+    let mut initial = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut initial, FLASH_SIZE).unwrap();
+    for _ in 0..28 {
+        fs.write_file(&mut initial, "a", 0, &[]).unwrap();
+    }
+    for (name, data) in &states[0] {
+        fs.write_file(&mut initial, name, 0, data).unwrap();
+    }
+
+    // Cut the power at every possible point
+    for ops in 0.. {
+        let mut flash = initial.clone();
+        let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        flash.cut_power_after(ops);
+        let result = changes(&mut fs, &mut flash);
+        flash.restore_power();
+
+        // The files are as they were after one of the changes
+        let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        let names: Vec<_> = fs.files().map(|f| f.name().to_string()).collect();
+        let mut files: Vec<_> = names
+            .into_iter()
+            .map(|name| {
+                let data = contents(&fs, &mut flash, &name).unwrap();
+                (name, data)
+            })
+            .collect();
+        files.sort();
+        let found = states.iter().position(|state| {
+            state.len() == files.len()
+                && state
+                    .iter()
+                    .zip(&files)
+                    .all(|((name, data), (n, d))| name == n && data == d)
+        });
+        assert!(found.is_some(), "Unexpected files after {} operations", ops);
+        check_free_space(&fs);
+
+        // And can still be written
+        fs.write_file(&mut flash, "new", 0, &data(5000, 7)).unwrap();
+        let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        assert_eq!(contents(&fs, &mut flash, "new").unwrap(), data(5000, 7));
+
+        if result.is_ok() {
+            assert_eq!(found, Some(states.len() - 1));
+            break;
+        }
+    }
+}
+
+#[test]
+fn wear() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+
+    // Clips of different sizes replacing each other, and a small file changed often
+    for n in 0..500_usize {
+        let len = (n * 7919 % 10 + 1) * 4000;
+        fs.write_file(&mut flash, "clip", 0, &data(len, n as u8))
+            .unwrap();
+        fs.write_file(&mut flash, "config", 0, &n.to_le_bytes())
+            .unwrap();
+        if n % 50 == 0 {
+            fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+        }
+    }
+
+    let counts = flash.erase_counts();
+    let (meta, data) = counts.split_at(META_BLOCKS as usize);
+    let spread = |counts: &[u32]| counts.iter().max().unwrap() - counts.iter().min().unwrap();
+    assert!(data.iter().all(|&count| count > 0));
+    assert!(spread(data) <= 2, "Data erase counts {:?}", data);
+    assert!(spread(meta) <= 1, "Metadata erase counts {:?}", meta);
+}
+
+#[test]
+fn damaged_metadata() {
+    let mut flash = SimFlash::new(FLASH_SIZE as usize);
+    let mut fs = FileSystem::format(&mut flash, FLASH_SIZE).unwrap();
+    fs.write_file(&mut flash, "a", 0, &data(100, 1)).unwrap();
+
+    // A record damaged by a power loss is ignored, and the log continues in the next block
+    flash.program(128 * 2 + 50, &[0]).unwrap();
+    let mut fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert!(fs.metadata("a").is_some());
+    fs.write_file(&mut flash, "b", 0, &[1]).unwrap();
+    let fs = FileSystem::mount(&mut flash, FLASH_SIZE).unwrap();
+    assert_eq!(contents(&fs, &mut flash, "a").unwrap(), data(100, 1));
+    assert_eq!(contents(&fs, &mut flash, "b").unwrap(), [1]);
+}
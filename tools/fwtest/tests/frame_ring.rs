@@ -0,0 +1,225 @@
+use fwtest::{
+    clip::Codec,
+    frame_ring::{FrameRing, Meta, RECORD_HEADER_LEN},
+};
+use std::collections::VecDeque;
+
+fn frame(len: usize, seq: u32) -> Vec<u8> {
+    (0..len).map(|i| (i as u32 * 13 + seq) as u8).collect()
+}
+
+fn meta(seq: u32) -> Meta {
+    Meta {
+        seq,
+        width: 320,
+        height: 240,
+        codec: Codec::Jpeg,
+    }
+}
+
+fn frames(ring: &FrameRing) -> Vec<(u32, Vec<u8>)> {
+    ring.frames()
+        .map(|(meta, data)| (meta.seq, data.to_vec()))
+        .collect()
+}
+
+fn seqs(ring: &FrameRing) -> Vec<u32> {
+    ring.iter().map(|(_, _, meta)| meta.seq).collect()
+}
+
+#[test]
+fn push() {
+    let mut mem = vec![0; 1000];
+    let mut ring = FrameRing::new(&mut mem);
+    assert_eq!(ring.num_frames(), 0);
+    assert_eq!(ring.iter().next(), None);
+    assert_eq!(ring.frames().next(), None);
+
+    // Odd sizes are padded
+    for seq in 0..5 {
+        assert!(ring.push(meta(seq), &frame(61 + seq as usize, seq)));
+    }
+    assert_eq!(ring.num_frames(), 5);
+    assert_eq!(ring.iter().len(), 5);
+    let expected: Vec<_> = (0..5)
+        .map(|seq| (seq, frame(61 + seq as usize, seq)))
+        .collect();
+    assert_eq!(frames(&ring), expected);
+
+    ring.clear();
+    assert_eq!(ring.num_frames(), 0);
+    assert_eq!(ring.iter().count(), 0);
+}
+
+#[test]
+fn wrap_around() {
+    // Room for 4 records of 100 bytes, a 5th one evicts the oldest
+    let mut mem = vec![0; 4 * (RECORD_HEADER_LEN + 100) + 50];
+    let mut ring = FrameRing::new(&mut mem);
+    for seq in 0..4 {
+        assert!(ring.push(meta(seq), &frame(100, seq)));
+    }
+    assert!(ring.push(meta(4), &frame(100, 4)));
+    assert_eq!(seqs(&ring), [1, 2, 3, 4]);
+
+    // A larger record evicts more than one
+    assert!(ring.push(meta(5), &frame(250, 5)));
+    assert_eq!(seqs(&ring), [4, 5]);
+    assert_eq!(frames(&ring)[1], (5, frame(250, 5)));
+
+    // Many times around
+    for seq in 6..100 {
+        assert!(ring.push(meta(seq), &frame(30 + seq as usize % 70, seq)));
+        let newest = ring.frames().last().unwrap();
+        assert_eq!(newest, (meta(seq), &frame(30 + seq as usize % 70, seq)[..]));
+    }
+}
+
+#[test]
+fn records() {
+    // Frames of different resolutions and codecs share the ring
+    let mut mem = vec![0; 1000];
+    let base = mem.as_ptr() as usize;
+    let mut ring = FrameRing::new(&mut mem);
+    let small = Meta {
+        width: 160,
+        height: 120,
+        codec: Codec::Raw,
+        ..meta(1)
+    };
+    assert!(ring.push(meta(0), &frame(10, 0)));
+    assert!(ring.push(small, &frame(20, 1)));
+
+    let records: Vec<_> = ring.iter().collect();
+    assert_eq!(
+        records,
+        [
+            (base + RECORD_HEADER_LEN, 10, meta(0)),
+            (base + 2 * RECORD_HEADER_LEN + 12, 20, small)
+        ]
+    );
+    assert!(!records[0].2.same_format(&records[1].2));
+    assert!(meta(0).same_format(&meta(5)));
+    assert_eq!(frames(&ring), [(0, frame(10, 0)), (1, frame(20, 1))]);
+}
+
+#[test]
+fn window() {
+    // Room for 19 frames: 10 to 29, without 15 which was not recorded
+    let mut mem = vec![0; 19 * (RECORD_HEADER_LEN + 20)];
+    let mut ring = FrameRing::new(&mut mem);
+    for seq in (0..30).filter(|&seq| seq != 15) {
+        assert!(ring.push(meta(seq), &frame(20, seq)));
+    }
+    assert_eq!(
+        seqs(&ring),
+        (10..30).filter(|&seq| seq != 15).collect::<Vec<_>>()
+    );
+
+    let window = |first, last| -> Vec<u32> {
+        ring.window(first..=last)
+            .with_data()
+            .map(|(meta, data)| {
+                assert_eq!(data, &frame(20, meta.seq)[..]);
+                meta.seq
+            })
+            .collect()
+    };
+    assert_eq!(window(12, 14), [12, 13, 14]);
+    assert_eq!(window(14, 17), [14, 16, 17]);
+    assert_eq!(window(15, 15), []);
+    assert_eq!(window(0, 11), [10, 11]);
+    assert_eq!(window(28, 100), [28, 29]);
+    assert_eq!(window(30, 40), []);
+    assert_eq!(window(0, 5), []);
+    assert_eq!(ring.window(0..=u32::MAX).len(), 19);
+    assert_eq!(ring.window(20..=24).len(), 5);
+}
+
+#[test]
+fn protect() {
+    // Room for 4 records of 100 bytes
+    let mut mem = vec![0; 4 * (RECORD_HEADER_LEN + 100)];
+    let mut ring = FrameRing::new(&mut mem);
+    assert_eq!(ring.newest(), None);
+    for seq in 0..4 {
+        assert!(ring.push(meta(seq), &frame(100, seq)));
+    }
+    assert_eq!(ring.newest(), Some(meta(3)));
+
+    // Frames older than the protected ones are still evicted
+    ring.protect(Some(2));
+    assert!(ring.push(meta(4), &frame(100, 4)));
+    assert!(ring.push(meta(5), &frame(100, 5)));
+    assert!(!ring.push(meta(6), &frame(100, 6)));
+    assert!(!ring.push(meta(7), &frame(10, 7)));
+    assert_eq!(seqs(&ring), [2, 3, 4, 5]);
+    assert_eq!(ring.newest(), Some(meta(5)));
+
+    // Releasing them makes room again
+    ring.protect(Some(3));
+    assert!(ring.push(meta(8), &frame(100, 8)));
+    ring.protect(None);
+    assert!(ring.push(meta(9), &frame(200, 9)));
+    assert_eq!(seqs(&ring), [8, 9]);
+
+    ring.clear();
+    assert_eq!(ring.newest(), None);
+}
+
+#[test]
+fn sizes() {
+    let mut mem = vec![0; 203];
+    let mut ring = FrameRing::new(&mut mem);
+    assert_eq!(ring.max_frame_len(), 200 - RECORD_HEADER_LEN);
+    assert!(!ring.push(meta(0), &frame(ring.max_frame_len() + 1, 0)));
+    assert_eq!(ring.num_frames(), 0);
+
+    // Exactly filling the memory, then replacing the only frame
+    assert!(ring.push(meta(1), &frame(ring.max_frame_len(), 1)));
+    assert!(ring.push(meta(2), &frame(ring.max_frame_len(), 2)));
+    assert_eq!(frames(&ring), [(2, frame(200 - RECORD_HEADER_LEN, 2))]);
+
+    // Empty frames are fine too
+    assert!(ring.push(meta(3), &[]));
+    assert!(ring.push(meta(4), &frame(100, 4)));
+    assert_eq!(frames(&ring), [(3, vec![]), (4, frame(100, 4))]);
+
+    // Too large frames leave the ring alone
+    assert!(!ring.push(meta(5), &frame(1000, 5)));
+    assert_eq!(ring.num_frames(), 2);
+}
+
+#[test]
+fn random() {
+    // The ring holds the newest frames which fit, compared with a simple model
+    let mut mem = vec![0; 4096];
+    let mut ring = FrameRing::new(&mut mem);
+    let mut model = VecDeque::new();
+    let mut seed = 7_u32;
+    let mut evicted = false;
+    for seq in 0..5000 {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let len = (seed >> 16) as usize % 900;
+        let data = frame(len, seq);
+        assert!(ring.push(meta(seq), &data));
+        model.push_back((seq, data));
+
+        let got = frames(&ring);
+        let skip = model.len() - got.len();
+        assert!(got.iter().eq(model.iter().skip(skip)), "Frame {}", seq);
+        model.drain(..skip);
+        evicted |= skip > 0;
+
+        // Frames are only evicted to make room, the space lost at the end and in front of the
+        // oldest frame is less than a frame each
+        let used: usize = model
+            .iter()
+            .map(|(_, d)| RECORD_HEADER_LEN + d.len().div_ceil(4) * 4)
+            .sum();
+        assert!(used <= 4096);
+        if evicted {
+            assert!(used > 4096 - 2 * (RECORD_HEADER_LEN + 900), "Frame {}", seq);
+        }
+    }
+}
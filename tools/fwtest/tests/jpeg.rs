@@ -0,0 +1,215 @@
+use fwtest::jpeg::{decode, Encoder, Error};
+use jpeg_decoder::{Decoder, PixelFormat};
+
+/// A frame with smooth gradients, a sharp edged box and a noisy band, in RGB565.
+fn frame(width: usize, height: usize) -> Vec<u8> {
+    let mut seed = 1_u32;
+    let mut pixels = Vec::with_capacity(width * height * 2);
+    for y in 0..height {
+        for x in 0..width {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let (r, g, b) = if x > width / 4 && x < width / 2 && y > height / 4 && y < height / 2 {
+                (31, 0, 4)
+            } else if y > height * 3 / 4 {
+                let noise = (seed >> 16) as usize % 8;
+                (10 + noise, 30 + noise, 10 + noise)
+            } else {
+                (x * 31 / width, y * 63 / height, 31 - x * 31 / width)
+            };
+            let pixel = (r << 11 | g << 5 | b) as u16;
+            pixels.extend_from_slice(&pixel.to_le_bytes());
+        }
+    }
+    pixels
+}
+
+/// A frame with a smooth gradient, in RGB565.
+fn gradient(width: usize, height: usize) -> Vec<u8> {
+    (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            let r = x * 31 / width;
+            let pixel = r << 11 | (y * 63 / height) << 5 | (31 - r);
+            (pixel as u16).to_le_bytes()
+        })
+        .collect()
+}
+
+fn rgb888(pixels: &[u8]) -> Vec<u8> {
+    pixels
+        .chunks(2)
+        .flat_map(|p| {
+            let p = u16::from_le_bytes([p[0], p[1]]);
+            let (r, g, b) = ((p >> 11) as u8, (p >> 5 & 0x3F) as u8, (p & 0x1F) as u8);
+            vec![r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+        })
+        .collect()
+}
+
+/// Encode and decode a frame, returning the size of the image and the peak signal to noise
+/// ratio of the decoded pixels in dB.
+fn round_trip(pixels: &[u8], width: u16, height: u16, quality: u8) -> (usize, f64) {
+    let mut out = vec![0; pixels.len() * 2 + 1000];
+    let len = Encoder::new(quality)
+        .encode(pixels, width, height, &mut out)
+        .unwrap();
+
+    let mut decoder = Decoder::new(&out[..len]);
+    let decoded = decoder.decode().unwrap();
+    let info = decoder.info().unwrap();
+    assert_eq!((info.width, info.height), (width, height));
+    assert_eq!(info.pixel_format, PixelFormat::RGB24);
+
+    (len, psnr(&rgb888(pixels), &decoded))
+}
+
+/// Peak signal to noise ratio of `decoded` in dB.
+fn psnr(expected: &[u8], decoded: &[u8]) -> f64 {
+    assert_eq!(decoded.len(), expected.len());
+    let mse = expected
+        .iter()
+        .zip(decoded)
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum::<f64>()
+        / expected.len() as f64;
+    10.0 * (255.0 * 255.0 / mse).log10()
+}
+
+fn encode(pixels: &[u8], width: u16, height: u16, quality: u8) -> Vec<u8> {
+    let mut out = vec![0; pixels.len() * 2 + 1000];
+    let len = Encoder::new(quality)
+        .encode(pixels, width, height, &mut out)
+        .unwrap();
+    out.truncate(len);
+    out
+}
+
+#[test]
+fn decodes() {
+    let (len, psnr) = round_trip(&frame(320, 240), 320, 240, 75);
+    assert!(psnr > 30.0, "PSNR {:.1} dB", psnr);
+    assert!(len < 320 * 240 * 2 / 10, "{} bytes", len);
+}
+
+#[test]
+fn quality() {
+    let mut last = (0, 0.0);
+    for &quality in &[10, 50, 75, 95, 100] {
+        let (len, psnr) = round_trip(&frame(160, 120), 160, 120, quality);
+        assert!(len > last.0 && psnr > last.1, "Quality {}", quality);
+        last = (len, psnr);
+    }
+
+    assert_eq!(Encoder::new(0).quality(), 1);
+    assert_eq!(Encoder::new(200).quality(), 100);
+}
+
+#[test]
+fn partial_blocks() {
+    // Sizes which are not multiples of the 16 x 16 pixels coded together
+    for &(width, height) in &[(1, 1), (17, 9), (37, 21), (160, 120)] {
+        let pixels = gradient(width as usize, height as usize);
+        let (_, psnr) = round_trip(&pixels, width, height, 90);
+        assert!(psnr > 30.0, "{} x {}: PSNR {:.1} dB", width, height, psnr);
+    }
+}
+
+#[test]
+fn flat() {
+    // Sky and bonnet compress well, 0xFF bytes in the coded data are stuffed
+    for &pixel in &[0x0000_u16, 0xFFFF, 0x001F] {
+        let pixels: Vec<u8> = (0..320 * 240).flat_map(|_| pixel.to_le_bytes()).collect();
+        let mut out = vec![0; 20_000];
+        let len = Encoder::new(90)
+            .encode(&pixels, 320, 240, &mut out)
+            .unwrap();
+        assert!(len < 5000, "{} bytes", len);
+
+        let decoded = Decoder::new(&out[..len]).decode().unwrap();
+        let expected = rgb888(&pixels[..2]);
+        for rgb in decoded.chunks(3) {
+            for (&a, &b) in rgb.iter().zip(&expected) {
+                assert!((a as i32 - b as i32).abs() <= 2, "{:?}", rgb);
+            }
+        }
+    }
+}
+
+#[test]
+fn buffer_too_small() {
+    let pixels = frame(64, 48);
+    let encoder = Encoder::new(75);
+    let mut out = vec![0; pixels.len()];
+    let len = encoder.encode(&pixels, 64, 48, &mut out).unwrap();
+
+    let mut exact = vec![0; len];
+    assert_eq!(encoder.encode(&pixels, 64, 48, &mut exact), Some(len));
+    assert_eq!(exact, out[..len]);
+
+    for &size in &[0, 100, len / 2, len - 1] {
+        let mut small = vec![0; size];
+        assert_eq!(encoder.encode(&pixels, 64, 48, &mut small), None);
+    }
+}
+
+#[test]
+fn decoder() {
+    // Sizes which are not multiples of the 16 x 16 pixels coded together
+    for &(width, height) in &[(1, 1), (17, 9), (37, 21), (160, 120), (320, 240)] {
+        let pixels = frame(width as usize, height as usize);
+        for &quality in &[10, 75, 100] {
+            let image = encode(&pixels, width, height, quality);
+            let mut decoded = vec![0; pixels.len()];
+            decode(&image, width, height, &mut decoded).unwrap();
+
+            // As close to the frame as the independent decoder, which interpolates the chroma
+            // instead of repeating it
+            let reference = Decoder::new(&image[..]).decode().unwrap();
+            let expected = rgb888(&pixels);
+            let diff = psnr(&expected, &reference) - psnr(&expected, &rgb888(&decoded));
+            assert!(
+                diff < 1.5,
+                "{} x {} at {}: {:.2} dB worse",
+                width,
+                height,
+                quality,
+                diff
+            );
+        }
+    }
+}
+
+#[test]
+fn decode_errors() {
+    let pixels = frame(64, 48);
+    let image = encode(&pixels, 64, 48, 75);
+    let mut decoded = vec![0; pixels.len()];
+
+    // Images of another size
+    assert_eq!(
+        decode(&image, 48, 64, &mut decoded),
+        Err(Error::Unsupported)
+    );
+
+    // Cut short before the coded data
+    let scan = image.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+    for &len in &[0, 1, 2, 10, scan, scan + 5] {
+        assert_eq!(
+            decode(&image[..len], 64, 48, &mut decoded),
+            Err(Error::Corrupt)
+        );
+    }
+
+    // Progressive images
+    let mut progressive = image.clone();
+    let frame = image.windows(2).position(|w| w == [0xFF, 0xC0]).unwrap();
+    progressive[frame + 1] = 0xC2;
+    assert_eq!(
+        decode(&progressive, 64, 48, &mut decoded),
+        Err(Error::Unsupported)
+    );
+
+    // Missing coded data reads as padding, without panicking
+    let _ = decode(&image[..scan + 20], 64, 48, &mut decoded);
+    let _ = decode(&image[..image.len() - 2], 64, 48, &mut decoded);
+}
@@ -0,0 +1,89 @@
+use fwtest::motion::{detect, luma_map, MotionDetector, MotionSettings, MAP_WIDTH};
+
+const WIDTH: usize = 32;
+const HEIGHT: usize = 24;
+
+const BLACK: u16 = 0x0000;
+const GRAY: u16 = 0x8410;
+const NOISE: u16 = 0x8C51;
+const WHITE: u16 = 0xFFFF;
+
+/// A frame of `background`, plus a `foreground` box covering the left `box_cols` columns of
+/// cells.
+fn synthetic_frame(background: u16, foreground: u16, box_cols: usize) -> Vec<u16> {
+    let box_width = box_cols * (WIDTH / MAP_WIDTH);
+    (0..WIDTH * HEIGHT)
+        .map(|i| match i % WIDTH < box_width {
+            true => foreground,
+            false => background,
+        })
+        .collect()
+}
+
+fn settings(threshold: u32, area_pct: u32) -> MotionSettings {
+    MotionSettings {
+        threshold,
+        area_pct,
+    }
+}
+
+#[test]
+fn luma() {
+    let black = luma_map(&synthetic_frame(BLACK, BLACK, 0), WIDTH, HEIGHT);
+    assert!(black.iter().all(|&luma| luma == 0));
+    let white = luma_map(&synthetic_frame(WHITE, WHITE, 0), WIDTH, HEIGHT);
+    assert!(white.iter().all(|&luma| luma == 255));
+
+    // The box covers the left quarter of the cells
+    let object = luma_map(&synthetic_frame(BLACK, WHITE, MAP_WIDTH / 4), WIDTH, HEIGHT);
+    for (i, &luma) in object.iter().enumerate() {
+        let expected = if i % MAP_WIDTH < MAP_WIDTH / 4 {
+            255
+        } else {
+            0
+        };
+        assert_eq!(luma, expected, "cell {}", i);
+    }
+}
+
+#[test]
+fn threshold() {
+    let background = luma_map(&synthetic_frame(GRAY, GRAY, 0), WIDTH, HEIGHT);
+    assert!(!detect(&background, &background, &settings(20, 10)));
+
+    // A small brightness change of the whole frame triggers only above the threshold
+    let noise = luma_map(&synthetic_frame(GRAY, NOISE, MAP_WIDTH), WIDTH, HEIGHT);
+    let change = noise[0] - background[0];
+    assert!(!detect(&background, &noise, &settings(change as u32, 10)));
+    assert!(detect(
+        &background,
+        &noise,
+        &settings(change as u32 - 1, 10)
+    ));
+
+    // A bright object covering 25% of the frame
+    let object = luma_map(&synthetic_frame(GRAY, WHITE, MAP_WIDTH / 4), WIDTH, HEIGHT);
+    assert!(detect(&background, &object, &settings(20, 10)));
+    assert!(detect(&background, &object, &settings(20, 25)));
+    assert!(!detect(&background, &object, &settings(20, 26)));
+    assert!(!detect(&background, &object, &settings(20, 50)));
+}
+
+#[test]
+fn detector() {
+    let background = synthetic_frame(GRAY, GRAY, 0);
+    let object = synthetic_frame(GRAY, WHITE, MAP_WIDTH / 4);
+
+    // Never triggers on the first frame, then compares each frame with the previous one
+    let mut detector = MotionDetector::new(settings(20, 10));
+    assert!(!detector.update(&object, WIDTH, HEIGHT));
+    assert!(!detector.update(&object, WIDTH, HEIGHT));
+    assert!(detector.update(&background, WIDTH, HEIGHT));
+    assert!(!detector.update(&background, WIDTH, HEIGHT));
+    assert!(detector.update(&object, WIDTH, HEIGHT));
+
+    // A threshold of 0 disables detection
+    let mut detector = MotionDetector::new(settings(0, 0));
+    assert!(!detector.update(&background, WIDTH, HEIGHT));
+    assert!(!detector.update(&object, WIDTH, HEIGHT));
+}
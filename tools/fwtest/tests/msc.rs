@@ -0,0 +1,174 @@
+use fwtest::{
+    fat::{FatDisk, FatVolume},
+    msc::MscClass,
+    scsi::{Status, CSW_LEN},
+    sim::{SimFlash, SIM_FLASH_SIZE},
+};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use usb_device::{
+    bus::{PollResult, UsbBus, UsbBusAllocator},
+    endpoint::{EndpointAddress, EndpointType},
+    prelude::*,
+    Result, UsbDirection,
+};
+
+/// The host side of the simulated bus.
+#[derive(Default)]
+struct Host {
+    /// Packets sent to the device, not read by it yet.
+    sent: VecDeque<Vec<u8>>,
+    /// Packets received from the device.
+    received: Vec<Vec<u8>>,
+    stalled: Vec<EndpointAddress>,
+    next_index: usize,
+}
+
+/// Simulated USB bus, which connects the bulk endpoints of the device to the host.
+struct SimBus(Arc<Mutex<Host>>);
+
+impl UsbBus for SimBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        _ep_type: EndpointType,
+        _max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut host = self.0.lock().unwrap();
+        Ok(ep_addr.unwrap_or_else(|| {
+            host.next_index += 1;
+            EndpointAddress::from_parts(host.next_index, ep_dir)
+        }))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, _addr: u8) {}
+
+    fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().received.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut host = self.0.lock().unwrap();
+        if host.stalled.contains(&ep_addr) {
+            return Err(UsbError::WouldBlock);
+        }
+        let packet = host.sent.pop_front().ok_or(UsbError::WouldBlock)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut host = self.0.lock().unwrap();
+        host.stalled.retain(|&addr| addr != ep_addr);
+        if stalled {
+            host.stalled.push(ep_addr);
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        self.0.lock().unwrap().stalled.contains(&ep_addr)
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        PollResult::None
+    }
+}
+
+/// Command block wrapper of command `command` with a data stage of `data_len` bytes.
+fn cbw(tag: u32, data_len: u32, data_in: bool, command: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0; 31];
+    bytes[0..4].copy_from_slice(b"USBC");
+    bytes[4..8].copy_from_slice(&tag.to_le_bytes());
+    bytes[8..12].copy_from_slice(&data_len.to_le_bytes());
+    bytes[12] = if data_in { 0x80 } else { 0 };
+    bytes[14] = command.len() as u8;
+    bytes[15..15 + command.len()].copy_from_slice(command);
+    bytes
+}
+
+/// Tag, residue and status of a command status wrapper.
+fn csw(packet: &[u8]) -> (u32, u32, u8) {
+    assert_eq!(packet.len(), CSW_LEN);
+    assert_eq!(&packet[0..4], b"USBS");
+    let word =
+        |i: usize| u32::from_le_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]]);
+    (word(4), word(8), packet[12])
+}
+
+#[test]
+fn data_stages() {
+    let host = Arc::new(Mutex::new(Host::default()));
+    let alloc = UsbBusAllocator::new(SimBus(host.clone()));
+    let mut msc = MscClass::new(&alloc);
+    let _device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x1209, 0x0001)).build();
+
+    let volume = FatVolume::new();
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let mut disk = FatDisk {
+        volume: &volume,
+        storage: &mut flash,
+    };
+
+    // Send `packets` and return the packets received in response
+    let mut run = |packets: &[Vec<u8>]| {
+        host.lock().unwrap().sent.extend(packets.iter().cloned());
+        msc.process(&mut disk);
+        let mut host = host.lock().unwrap();
+        (core::mem::take(&mut host.received), host.stalled.len())
+    };
+
+    // INQUIRY, data as expected by the host
+    let (received, stalled) = run(&[cbw(1, 36, true, &[0x12, 0, 0, 0, 36, 0])]);
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].len(), 36);
+    assert_eq!(csw(&received[1]), (1, 0, Status::Passed as u8));
+    assert_eq!(stalled, 0);
+
+    // TEST UNIT READY, no data stage
+    let (received, stalled) = run(&[cbw(2, 0, false, &[0x00, 0, 0, 0, 0, 0])]);
+    assert_eq!(received.len(), 1);
+    assert_eq!(csw(&received[0]).0, 2);
+    assert_eq!(stalled, 0);
+
+    // READ(10) beyond the end fails without data, the data stage ends with a short packet
+    let read = [0x28, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 1, 0];
+    let (received, stalled) = run(&[cbw(3, 512, true, &read)]);
+    assert_eq!(received.len(), 2);
+    assert!(received[0].is_empty());
+    assert_eq!(csw(&received[1]), (3, 512, Status::Failed as u8));
+    assert_eq!(stalled, 0);
+
+    // TEST UNIT READY with data from the host, which is refused and not taken for a command
+    let data = cbw(5, 0, false, &[0x00, 0, 0, 0, 0, 0]);
+    let (received, stalled) = run(&[cbw(4, 31, false, &[0x00, 0, 0, 0, 0, 0]), data]);
+    assert_eq!(received.len(), 1);
+    assert_eq!(csw(&received[0]), (4, 31, Status::Passed as u8));
+    assert_eq!(stalled, 1);
+
+    // The host clears the stall, drops its data and carries on with the next command
+    host.lock().unwrap().stalled.clear();
+    host.lock().unwrap().sent.clear();
+    let (received, stalled) = run(&[cbw(6, 0, false, &[0x00, 0, 0, 0, 0, 0])]);
+    assert_eq!(received.len(), 1);
+    assert_eq!(csw(&received[0]).0, 6);
+    assert_eq!(stalled, 0);
+
+    // INQUIRY with data from the host is a phase error
+    let (received, stalled) = run(&[cbw(7, 36, false, &[0x12, 0, 0, 0, 36, 0])]);
+    assert_eq!(received.len(), 1);
+    assert_eq!(csw(&received[0]), (7, 36, Status::PhaseError as u8));
+    assert_eq!(stalled, 1);
+}
@@ -0,0 +1,162 @@
+use fwtest::{
+    crc::{crc32, Crc32},
+    proto::{
+        encode_frame, ClipInfo, DeviceStatus, ErrorCode, FrameDecoder, FrameError, Request,
+        Response, State, MAX_CHUNK_LEN, MAX_FRAME_LEN, MAX_MESSAGE_LEN,
+    },
+};
+
+/// Encode `message` into a frame.
+fn frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = [0; MAX_FRAME_LEN];
+    let len = encode_frame(message, &mut frame);
+    frame[..len].to_vec()
+}
+
+/// Feed `input` to a frame decoder and collect the results.
+fn decode_frames(input: &[u8]) -> Vec<Result<Vec<u8>, FrameError>> {
+    let mut decoder = FrameDecoder::new();
+    input
+        .iter()
+        .filter_map(|&b| decoder.push(b).map(|r| r.map(|m| m.to_vec())))
+        .collect()
+}
+
+fn request_round_trip(request: Request) {
+    let mut message = [0; MAX_MESSAGE_LEN];
+    let len = request.encode(&mut message);
+
+    let frames = decode_frames(&frame(&message[..len]));
+    assert_eq!(frames.len(), 1);
+    let decoded = frames[0].as_ref().unwrap();
+    assert_eq!(Request::decode(decoded), Some(request));
+}
+
+fn response_round_trip(response: Response) {
+    let mut message = [0; MAX_MESSAGE_LEN];
+    let len = response.encode(&mut message);
+
+    let frames = decode_frames(&frame(&message[..len]));
+    assert_eq!(frames.len(), 1);
+    let decoded = frames[0].as_ref().unwrap();
+    assert_eq!(Response::decode(decoded), Some(response));
+}
+
+#[test]
+fn crc() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let mut crc = Crc32::new();
+    crc.update(b"1234");
+    crc.update(b"56789");
+    assert_eq!(crc.finish(), 0xCBF4_3926);
+}
+
+#[test]
+fn requests() {
+    request_round_trip(Request::Status);
+    request_round_trip(Request::ListClips);
+    request_round_trip(Request::ClipInfo(3));
+    request_round_trip(Request::ReadClip {
+        clip: 1,
+        offset: 0x0102_0304,
+        len: u32::MAX,
+    });
+    request_round_trip(Request::DeleteClip(0));
+}
+
+#[test]
+fn responses() {
+    response_round_trip(Response::Status(DeviceStatus {
+        state: State::Parking,
+        uptime_ms: 123_456,
+        unix_time: 1_700_000_000,
+        clips: 2,
+        crashes: 0,
+    }));
+    response_round_trip(Response::Clip(ClipInfo {
+        clip: 7,
+        len: 153_600,
+        time: 0,
+    }));
+    response_round_trip(Response::Done);
+    response_round_trip(Response::Error(ErrorCode::UnknownClip));
+
+    // Chunks of all lengths, with and without 0 bytes
+    let zeros = [0; MAX_CHUNK_LEN];
+    let data: Vec<u8> = (0..MAX_CHUNK_LEN).map(|i| (i % 255) as u8 + 1).collect();
+    for len in 0..=MAX_CHUNK_LEN {
+        response_round_trip(Response::Chunk {
+            offset: len as u32,
+            data: &zeros[..len],
+        });
+        response_round_trip(Response::Chunk {
+            offset: 0,
+            data: &data[..len],
+        });
+    }
+}
+
+#[test]
+fn malformed_messages() {
+    assert_eq!(Request::decode(&[]), None);
+    assert_eq!(Request::decode(&[0x7F]), None);
+    assert_eq!(Request::decode(&[0x03, 1, 0, 0]), None);
+    assert_eq!(Request::decode(&[0x01, 0]), None);
+    assert_eq!(Response::decode(&[0x85, 0]), None);
+    assert_eq!(Response::decode(&[0x81, 9, 0, 0, 0, 0]), None);
+
+    let mut chunk = vec![0x83, 0, 0, 0, 0];
+    chunk.extend(vec![1; MAX_CHUNK_LEN + 1]);
+    assert_eq!(Response::decode(&chunk), None);
+}
+
+#[test]
+fn framing() {
+    // Frames contain no 0 bytes except the one at the end
+    let message: Vec<u8> = (0..MAX_MESSAGE_LEN).map(|i| (i % 3) as u8).collect();
+    let encoded = frame(&message);
+    assert!(encoded.len() <= MAX_FRAME_LEN);
+    assert_eq!(encoded.iter().position(|&b| b == 0), Some(encoded.len() - 1));
+
+    // The longest frame has no 0 bytes at all
+    let message = [0xAA; MAX_MESSAGE_LEN];
+    assert!(frame(&message).len() <= MAX_FRAME_LEN);
+
+    // Several frames in a row, empty frames are ignored
+    let mut input = vec![0, 0];
+    input.extend(frame(&[1, 2, 3]));
+    input.extend(frame(&[0]));
+    input.push(0);
+    assert_eq!(decode_frames(&input), vec![Ok(vec![1, 2, 3]), Ok(vec![0])]);
+}
+
+#[test]
+fn damaged_frames() {
+    let good = frame(&[0x04, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+    // A flipped bit fails the CRC check, the next frame is fine
+    let mut input = good.clone();
+    input[3] ^= 0x10;
+    input.extend(&good);
+    let frames = decode_frames(&input);
+    assert_eq!(frames[0], Err(FrameError::Crc));
+    assert!(frames[1].is_ok());
+
+    // A lost byte breaks the COBS encoding or the CRC
+    let mut input = good.clone();
+    input.remove(0);
+    assert!(decode_frames(&input)[0].is_err());
+
+    // Too short for a CRC
+    assert_eq!(decode_frames(&[2, 1, 0]), vec![Err(FrameError::Malformed)]);
+
+    // A frame which is too long is dropped entirely, the decoder recovers at the next 0
+    let mut input = vec![1; MAX_FRAME_LEN + 10];
+    input.push(0);
+    input.extend(&good);
+    let frames = decode_frames(&input);
+    assert_eq!(frames[0], Err(FrameError::TooLong));
+    assert!(frames[1].is_ok());
+}
@@ -0,0 +1,190 @@
+use fwtest::{
+    fat::{Clip, FatDisk, FatVolume, NUM_BLOCKS},
+    scsi::{Cbw, Csw, Data, Scsi, Sense, Status, CBW_LEN, MAX_REPLY_LEN},
+    sim::{SimFlash, SIM_FLASH_SIZE},
+};
+
+/// Run command `command` and return the data stage, status and reply.
+fn run(scsi: &mut Scsi, volume: &FatVolume, command: &[u8]) -> (Data, Status, Vec<u8>) {
+    let mut flash = SimFlash::new(SIM_FLASH_SIZE);
+    let disk = FatDisk {
+        volume,
+        storage: &mut flash,
+    };
+
+    let mut block = [0; 16];
+    block[..command.len()].copy_from_slice(command);
+    let mut reply = [0; MAX_REPLY_LEN];
+    let (data, status) = scsi.command(&block, &disk, &mut reply);
+    let reply = match data {
+        Data::Reply(len) => reply[..len].to_vec(),
+        _ => Vec::new(),
+    };
+    (data, status, reply)
+}
+
+/// Sense data reported by REQUEST SENSE.
+fn sense(scsi: &mut Scsi, volume: &FatVolume) -> Sense {
+    let (_, status, reply) = run(scsi, volume, &[0x03, 0, 0, 0, 18, 0]);
+    assert_eq!(status, Status::Passed);
+    Sense {
+        key: reply[2],
+        asc: reply[12],
+        ascq: reply[13],
+    }
+}
+
+fn read_10(lba: u32, count: u16) -> Vec<u8> {
+    let mut command = vec![0x28, 0];
+    command.extend_from_slice(&lba.to_be_bytes());
+    command.push(0);
+    command.extend_from_slice(&count.to_be_bytes());
+    command.push(0);
+    command
+}
+
+#[test]
+fn wrappers() {
+    let mut bytes = [0; CBW_LEN];
+    bytes[0..4].copy_from_slice(b"USBC");
+    bytes[4..8].copy_from_slice(&0x1234_5678_u32.to_le_bytes());
+    bytes[8..12].copy_from_slice(&512_u32.to_le_bytes());
+    bytes[12] = 0x80;
+    bytes[14] = 10;
+    bytes[15..25].copy_from_slice(&read_10(7, 1));
+
+    let cbw = Cbw::parse(&bytes).unwrap();
+    assert_eq!(cbw.tag, 0x1234_5678);
+    assert_eq!(cbw.data_len, 512);
+    assert!(cbw.data_in);
+    assert_eq!(cbw.lun, 0);
+    assert_eq!(&cbw.command[..10], &read_10(7, 1)[..]);
+    assert_eq!(&cbw.command[10..], &[0; 6]);
+
+    // Wrong signature, length or command length
+    assert_eq!(Cbw::parse(&bytes[..30]), None);
+    bytes[14] = 17;
+    assert_eq!(Cbw::parse(&bytes), None);
+    bytes[14] = 10;
+    bytes[0] = b'X';
+    assert_eq!(Cbw::parse(&bytes), None);
+
+    let csw = Csw {
+        tag: 0x1234_5678,
+        residue: 3,
+        status: Status::Failed,
+    };
+    assert_eq!(
+        csw.to_bytes(),
+        [b'U', b'S', b'B', b'S', 0x78, 0x56, 0x34, 0x12, 3, 0, 0, 0, 1]
+    );
+}
+
+#[test]
+fn identify() {
+    let mut scsi = Scsi::new();
+    let volume = FatVolume::new();
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x12, 0, 0, 0, 36, 0]);
+    assert_eq!(status, Status::Passed);
+    assert_eq!(reply.len(), 36);
+    assert_eq!(reply[1], 0x80, "Removable");
+    assert_eq!(&reply[8..16], b"Dashcam ");
+
+    // Vital product data pages are not supported
+    let (_, status, _) = run(&mut scsi, &volume, &[0x12, 1, 0x80, 0, 36, 0]);
+    assert_eq!(status, Status::Failed);
+    assert_eq!(sense(&mut scsi, &volume), Sense::INVALID_FIELD);
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x25]);
+    assert_eq!(status, Status::Passed);
+    assert_eq!(reply[..4], (NUM_BLOCKS - 1).to_be_bytes());
+    assert_eq!(reply[4..], 512_u32.to_be_bytes());
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x23]);
+    assert_eq!(status, Status::Passed);
+    assert_eq!(reply[4..8], NUM_BLOCKS.to_be_bytes());
+    assert_eq!(reply[8], 0x02, "Formatted media");
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x00]);
+    assert_eq!((status, reply.len()), (Status::Passed, 0));
+}
+
+#[test]
+fn read() {
+    let mut scsi = Scsi::new();
+    let volume = FatVolume::new();
+
+    let (data, status, _) = run(&mut scsi, &volume, &read_10(NUM_BLOCKS - 2, 2));
+    assert_eq!(status, Status::Passed);
+    assert_eq!(
+        data,
+        Data::Blocks {
+            lba: NUM_BLOCKS - 2,
+            count: 2
+        }
+    );
+
+    for command in [read_10(NUM_BLOCKS - 2, 3), read_10(u32::MAX, 2)].iter() {
+        let (data, status, _) = run(&mut scsi, &volume, command);
+        assert_eq!((data, status), (Data::None, Status::Failed));
+        assert_eq!(sense(&mut scsi, &volume), Sense::LBA_OUT_OF_RANGE);
+    }
+
+    // Sense data is cleared once reported
+    assert_eq!(sense(&mut scsi, &volume), Sense::NONE);
+}
+
+#[test]
+fn write_protected() {
+    let mut scsi = Scsi::new();
+    let volume = FatVolume::new();
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x1A, 0, 0x3F, 0, 192, 0]);
+    assert_eq!(status, Status::Passed);
+    assert_eq!(reply[2] & 0x80, 0x80);
+
+    let (_, status, reply) = run(&mut scsi, &volume, &[0x5A, 0, 0x3F, 0, 0, 0, 0, 0, 192, 0]);
+    assert_eq!(status, Status::Passed);
+    assert_eq!(reply[3] & 0x80, 0x80);
+
+    // The data sent with a write is discarded
+    let mut write = read_10(0, 1);
+    write[0] = 0x2A;
+    let (data, status, _) = run(&mut scsi, &volume, &write);
+    assert_eq!((data, status), (Data::Discard, Status::Failed));
+    assert_eq!(sense(&mut scsi, &volume), Sense::WRITE_PROTECTED);
+}
+
+#[test]
+fn unknown_command() {
+    let mut scsi = Scsi::new();
+    let volume = FatVolume::new();
+
+    let (data, status, _) = run(&mut scsi, &volume, &[0x35]);
+    assert_eq!((data, status), (Data::None, Status::Failed));
+    assert_eq!(sense(&mut scsi, &volume), Sense::INVALID_COMMAND);
+}
+
+#[test]
+fn medium_changed() {
+    let mut scsi = Scsi::new();
+    let mut volume = FatVolume::new();
+    let clip = Clip {
+        addr: 0,
+        len: 4,
+        time: 0,
+    };
+    volume.set_clips(&[clip]);
+
+    // Identifying the device doesn't report the change
+    let (_, status, _) = run(&mut scsi, &volume, &[0x12, 0, 0, 0, 36, 0]);
+    assert_eq!(status, Status::Passed);
+
+    // The change is reported once by the next command
+    let (_, status, _) = run(&mut scsi, &volume, &read_10(0, 1));
+    assert_eq!(status, Status::Failed);
+    assert_eq!(sense(&mut scsi, &volume), Sense::MEDIUM_CHANGED);
+    let (_, status, _) = run(&mut scsi, &volume, &read_10(0, 1));
+    assert_eq!(status, Status::Passed);
+}
@@ -0,0 +1,142 @@
+use fwtest::{
+    block::{read_bytes, write_bytes, BlockStorage, BLOCK_SIZE},
+    sd::{self, Csd},
+    sim::{SimDisk, SimError},
+};
+
+/// Set bits `hi..=lo` of the 128 bit register in `words`, most significant word first.
+fn set_bits(words: &mut [u32; 4], hi: u32, lo: u32, value: u32) {
+    for bit in lo..=hi {
+        let word = &mut words[3 - (bit / 32) as usize];
+        let mask = 1 << (bit % 32);
+        match (value >> (bit - lo)) & 1 {
+            1 => *word |= mask,
+            _ => *word &= !mask,
+        };
+    }
+}
+
+#[test]
+fn csd_v1() {
+    // 2 GB standard capacity card: 3840 * 2^(7 + 2) * 2^10 bytes
+    let mut words = [0; 4];
+    set_bits(&mut words, 127, 126, 0);
+    set_bits(&mut words, 83, 80, 10);
+    set_bits(&mut words, 73, 62, 3839);
+    set_bits(&mut words, 49, 47, 7);
+    let csd = Csd::from_response(&words).unwrap();
+    assert_eq!(csd.num_blocks, 3840 * 512 * 1024 / 512);
+
+    // Neighbouring bits must not leak into the fields
+    let mut noisy = [!0; 4];
+    set_bits(&mut noisy, 127, 126, 0);
+    set_bits(&mut noisy, 83, 80, 9);
+    set_bits(&mut noisy, 73, 62, 0);
+    set_bits(&mut noisy, 49, 47, 0);
+    assert_eq!(Csd::from_response(&noisy).unwrap().num_blocks, 4);
+}
+
+#[test]
+fn csd_v2() {
+    // 8 GB high capacity card
+    let mut words = [0; 4];
+    set_bits(&mut words, 127, 126, 1);
+    set_bits(&mut words, 69, 48, 15159);
+    let csd = Csd::from_response(&words).unwrap();
+    assert_eq!(csd.num_blocks, 15160 * 1024);
+
+    // Unknown versions and capacities beyond 2 TB are rejected
+    set_bits(&mut words, 127, 126, 2);
+    assert_eq!(Csd::from_response(&words), None);
+    set_bits(&mut words, 127, 126, 1);
+    set_bits(&mut words, 69, 48, 0x3F_FFFF);
+    assert_eq!(Csd::from_response(&words), None);
+}
+
+#[test]
+fn responses() {
+    // Ready for data in the transfer state
+    let status = (sd::STATE_TRANSFER << 9) | (1 << 8);
+    assert_eq!(sd::status_errors(status), None);
+    assert_eq!(sd::current_state(status), sd::STATE_TRANSFER);
+
+    // Out of range and illegal command, the card locked bit is no error
+    let status = (1 << 31) | (1 << 25) | (1 << 22) | (sd::STATE_TRANSFER << 9);
+    assert_eq!(sd::status_errors(status), Some((1 << 31) | (1 << 22)));
+
+    assert!(sd::if_cond_valid(0x1AA));
+    assert!(!sd::if_cond_valid(0x0AA));
+    assert_eq!(sd::rca_arg(0xB368_0500), 0xB368_0000);
+
+    assert_eq!(sd::data_address(3, true), 3);
+    assert_eq!(sd::data_address(3, false), 3 * 512);
+}
+
+#[test]
+fn whole_blocks() {
+    let mut disk = SimDisk::new(64);
+    let data: Vec<u8> = (0..8 * BLOCK_SIZE).map(|i| (i * 13) as u8).collect();
+
+    // Aligned accesses are passed through as a single transfer
+    write_bytes(&mut disk, 4 * BLOCK_SIZE as u32, &data).unwrap();
+    assert_eq!((disk.reads, disk.writes), (0, 1));
+    assert_eq!(&disk.data()[4 * BLOCK_SIZE..12 * BLOCK_SIZE], &data[..]);
+
+    let mut read = vec![0; data.len()];
+    read_bytes(&mut disk, 4 * BLOCK_SIZE as u32, &mut read).unwrap();
+    assert_eq!((disk.reads, disk.writes), (1, 1));
+    assert_eq!(read, data);
+}
+
+#[test]
+fn partial_blocks() {
+    let mut disk = SimDisk::new(64);
+    disk.data_mut().iter_mut().for_each(|b| *b = 0xEE);
+
+    // Odd address and length, spanning a partial block at each end
+    let addr = 3 * BLOCK_SIZE + 100;
+    let data: Vec<u8> = (0..3 * BLOCK_SIZE + 7).map(|i| (i * 7) as u8).collect();
+    write_bytes(&mut disk, addr as u32, &data).unwrap();
+    assert_eq!(disk.writes, 3);
+
+    // The rest of the partial blocks is preserved
+    let end = addr + data.len();
+    assert_eq!(&disk.data()[addr..end], &data[..]);
+    assert!(disk.data()[3 * BLOCK_SIZE..addr].iter().all(|&b| b == 0xEE));
+    assert!(disk.data()[end..8 * BLOCK_SIZE].iter().all(|&b| b == 0xEE));
+
+    let mut read = vec![0; data.len()];
+    read_bytes(&mut disk, addr as u32, &mut read).unwrap();
+    assert_eq!(read, data);
+
+    // Within a single block
+    let mut read = [0; 10];
+    read_bytes(&mut disk, addr as u32 + 5, &mut read).unwrap();
+    assert_eq!(&read, &data[5..15]);
+    write_bytes(&mut disk, 1, &[1, 2, 3]).unwrap();
+    assert_eq!(&disk.data()[..5], &[0xEE, 1, 2, 3, 0xEE]);
+
+    // Nothing to do
+    let (reads, writes) = (disk.reads, disk.writes);
+    read_bytes(&mut disk, 7, &mut []).unwrap();
+    write_bytes(&mut disk, 7, &[]).unwrap();
+    assert_eq!((disk.reads, disk.writes), (reads, writes));
+}
+
+#[test]
+fn out_of_range() {
+    let mut disk = SimDisk::new(4);
+    assert_eq!(disk.num_blocks(), 4);
+
+    let mut buf = [0; BLOCK_SIZE];
+    let last = 3 * BLOCK_SIZE as u32;
+    assert!(read_bytes(&mut disk, last, &mut buf).is_ok());
+    assert_eq!(
+        read_bytes(&mut disk, last + 1, &mut buf),
+        Err(SimError::OutOfRange)
+    );
+    assert_eq!(
+        write_bytes(&mut disk, last + 1, &buf),
+        Err(SimError::OutOfRange)
+    );
+}
@@ -0,0 +1,155 @@
+//! Decode log frames into messages.
+
+use crate::{
+    format,
+    wire::{Level, Reader},
+};
+
+/// A decoded log message.
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub level: Level,
+    pub timestamp_ms: u32,
+    /// Module path of the call site.
+    pub target: String,
+    pub text: String,
+}
+
+/// Decode the payload of a frame. `lookup` returns the format string at an address and length
+/// on the target. Returns `None` if the frame is malformed.
+pub fn decode<'a, F>(payload: &[u8], lookup: F) -> Option<Message>
+where
+    F: Fn(u32, u32) -> Option<&'a str>,
+{
+    let mut reader = Reader::new(payload);
+    let header = reader.header()?;
+
+    let mut args = Vec::new();
+    while let Some(arg) = reader.arg() {
+        args.push(arg);
+    }
+
+    let (target, text) = match lookup(header.format_addr, header.format_len) {
+        Some(format) => {
+            let mut parts = format.splitn(2, '\0');
+            let target = parts.next().unwrap_or_default();
+            let format = parts.next().unwrap_or_default();
+            (target.to_string(), format::render(format, &args))
+        }
+        None => (
+            "?".to_string(),
+            format!(
+                "<unknown format string at {:#010X}, does the ELF file match?>",
+                header.format_addr
+            ),
+        ),
+    };
+
+    Some(Message {
+        level: header.level,
+        timestamp_ms: header.timestamp_ms,
+        target,
+        text,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{Arg, Header, Writer, MAX_PAYLOAD_LEN};
+
+    const FORMAT_ADDR: u32 = 0x0800_1234;
+    const FORMAT: &str = "dashcam_rs::ov9655\0Frame {} of {:#06X}: {} {}";
+
+    fn lookup(addr: u32, len: u32) -> Option<&'static str> {
+        match addr == FORMAT_ADDR && len as usize == FORMAT.len() {
+            true => Some(FORMAT),
+            false => None,
+        }
+    }
+
+    fn encode(header: &Header, args: &[Arg]) -> Vec<u8> {
+        let mut buf = [0; MAX_PAYLOAD_LEN];
+        let mut writer = Writer::new(&mut buf);
+        assert!(writer.header(header));
+        for arg in args {
+            writer.arg(arg);
+        }
+
+        let len = writer.written();
+        buf[..len].to_vec()
+    }
+
+    fn header(format_len: usize) -> Header {
+        Header {
+            level: Level::Warn,
+            timestamp_ms: 123_456,
+            format_addr: FORMAT_ADDR,
+            format_len: format_len as u32,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let args = [
+            Arg::Unsigned(300),
+            Arg::Unsigned(0xAB),
+            Arg::Signed(-1),
+            Arg::Str("ok"),
+        ];
+        let payload = encode(&header(FORMAT.len()), &args);
+
+        let message = decode(&payload, lookup).unwrap();
+        assert_eq!(message.level, Level::Warn);
+        assert_eq!(message.timestamp_ms, 123_456);
+        assert_eq!(message.target, "dashcam_rs::ov9655");
+        assert_eq!(message.text, "Frame 300 of 0x00AB: -1 ok");
+    }
+
+    #[test]
+    fn args_round_trip() {
+        let args = [
+            Arg::Unsigned(u64::MAX),
+            Arg::Signed(i64::MIN),
+            Arg::Signed(i64::MAX),
+            Arg::Bool(false),
+            Arg::F32(-2.5),
+            Arg::Str(""),
+        ];
+        let payload = encode(&header(0), &args);
+
+        let mut reader = Reader::new(&payload);
+        reader.header().unwrap();
+        for arg in args.iter() {
+            assert_eq!(reader.arg().as_ref(), Some(arg));
+        }
+        assert_eq!(reader.arg(), None);
+    }
+
+    #[test]
+    fn truncated_args() {
+        // Arguments which don't fit are left out entirely
+        let long = "x".repeat(MAX_PAYLOAD_LEN);
+        let payload = encode(&header(FORMAT.len()), &[Arg::Unsigned(1), Arg::Str(&long)]);
+
+        let message = decode(&payload, lookup).unwrap();
+        assert_eq!(message.text, "Frame 1 of <?>: <?> <?>");
+    }
+
+    #[test]
+    fn unknown_format() {
+        let payload = encode(&header(3), &[]);
+
+        let message = decode(&payload, lookup).unwrap();
+        assert_eq!(message.target, "?");
+        assert!(message
+            .text
+            .starts_with("<unknown format string at 0x08001234"));
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(decode(&[], lookup), None);
+        assert_eq!(decode(&[7, 0, 0, 0, 0, 0, 0], lookup), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! Just enough of an ELF parser to read constant data, like format strings, from a firmware
+//! image by its target address.
+
+/// Section header flag for sections which occupy memory on the target.
+const SHF_ALLOC: u32 = 0x2;
+
+/// Section header type for sections without data in the file, like `.bss`.
+const SHT_NOBITS: u32 = 8;
+
+/// A section with data loaded to the target.
+struct Section {
+    addr: u32,
+    offset: u32,
+    size: u32,
+}
+
+/// A 32-bit little endian ELF file.
+pub struct Elf {
+    data: Vec<u8>,
+    sections: Vec<Section>,
+}
+
+impl Elf {
+    /// Parse the section headers of an ELF file.
+    pub fn parse(data: Vec<u8>) -> Result<Self, String> {
+        if data.len() < 0x34 || &data[..4] != b"\x7fELF" {
+            return Err("Not an ELF file".into());
+        }
+        if data[4] != 1 || data[5] != 1 {
+            return Err("Not a 32-bit little endian ELF file".into());
+        }
+
+        let shoff = read_u32(&data, 0x20).ok_or("Truncated ELF header")? as usize;
+        let shentsize = read_u16(&data, 0x2E).ok_or("Truncated ELF header")? as usize;
+        let shnum = read_u16(&data, 0x30).ok_or("Truncated ELF header")? as usize;
+
+        let mut sections = Vec::new();
+        for i in 0..shnum {
+            let base = shoff + i * shentsize;
+            let field = |offset| read_u32(&data, base + offset).ok_or("Truncated section header");
+
+            let sh_type = field(0x04)?;
+            let flags = field(0x08)?;
+            if flags & SHF_ALLOC == 0 || sh_type == SHT_NOBITS {
+                continue;
+            }
+
+            sections.push(Section {
+                addr: field(0x0C)?,
+                offset: field(0x10)?,
+                size: field(0x14)?,
+            });
+        }
+
+        Ok(Elf { data, sections })
+    }
+
+    /// Read `len` bytes located at `addr` on the target.
+    pub fn read(&self, addr: u32, len: u32) -> Option<&[u8]> {
+        let section = self.sections.iter().find(|s| {
+            addr >= s.addr && (addr as u64 + len as u64) <= s.addr as u64 + s.size as u64
+        })?;
+
+        let start = (section.offset + (addr - section.addr)) as usize;
+        self.data.get(start..start + len as usize)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
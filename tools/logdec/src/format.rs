@@ -0,0 +1,171 @@
+//! Render a Rust format string with decoded arguments. Supports the subset of the format syntax
+//! used by the firmware: Escaped braces, alternate form, zero padding, width, precision and the
+//! `x`, `X`, `b`, `o` and `?` types.
+
+use crate::wire::Arg;
+
+/// Placeholder for arguments which were left out because the frame was full.
+const MISSING: &str = "<?>";
+
+/// A parsed `{:...}` format spec.
+#[derive(Default)]
+struct Spec {
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    ty: Option<char>,
+}
+
+/// Render `format`, substituting the placeholders with `args` in order.
+pub fn render(format: &str, args: &[Arg]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let spec: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                match args.next() {
+                    Some(arg) => out.push_str(&render_arg(arg, &parse_spec(&spec))),
+                    None => out.push_str(MISSING),
+                };
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Parse what is between the braces of a placeholder. Argument positions are ignored.
+fn parse_spec(spec: &str) -> Spec {
+    let mut parsed = Spec::default();
+    let spec = match spec.find(':') {
+        Some(i) => &spec[i + 1..],
+        None => return parsed,
+    };
+
+    let mut chars = spec.chars().peekable();
+    if chars.peek() == Some(&'#') {
+        parsed.alternate = true;
+        chars.next();
+    }
+    if chars.peek() == Some(&'0') {
+        parsed.zero = true;
+        chars.next();
+    }
+    while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+        parsed.width = parsed.width * 10 + digit as usize;
+        chars.next();
+    }
+    if chars.peek() == Some(&'.') {
+        chars.next();
+        let mut precision = 0;
+        while let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+            precision = precision * 10 + digit as usize;
+            chars.next();
+        }
+        parsed.precision = Some(precision);
+    }
+    parsed.ty = chars.next();
+
+    parsed
+}
+
+/// Render a single argument according to its spec.
+fn render_arg(arg: &Arg, spec: &Spec) -> String {
+    let (prefix, digits) = match *arg {
+        Arg::Unsigned(val) => render_int(val, spec),
+        Arg::Signed(val) if val < 0 && !is_radix(spec) => {
+            let (_, digits) = render_int(val.unsigned_abs(), spec);
+            ("-", digits)
+        }
+        Arg::Signed(val) => render_int(val as u64, spec),
+        Arg::Bool(val) => return pad_left(&val.to_string(), spec.width),
+        Arg::Str(val) => return format!("{:<width$}", val, width = spec.width),
+        Arg::F32(val) => match spec.precision {
+            Some(precision) => ("", format!("{:.*}", precision, val)),
+            None => ("", val.to_string()),
+        },
+    };
+
+    if spec.zero {
+        let width = spec.width.saturating_sub(prefix.len());
+        format!("{}{:0>width$}", prefix, digits, width = width)
+    } else {
+        pad_left(&format!("{}{}", prefix, digits), spec.width)
+    }
+}
+
+/// Render an integer in the radix of the spec, returns the prefix and the digits.
+fn render_int(val: u64, spec: &Spec) -> (&'static str, String) {
+    let (prefix, digits) = match spec.ty {
+        Some('x') => ("0x", format!("{:x}", val)),
+        Some('X') => ("0x", format!("{:X}", val)),
+        Some('b') => ("0b", format!("{:b}", val)),
+        Some('o') => ("0o", format!("{:o}", val)),
+        _ => ("", val.to_string()),
+    };
+
+    match spec.alternate {
+        true => (prefix, digits),
+        false => ("", digits),
+    }
+}
+
+fn is_radix(spec: &Spec) -> bool {
+    matches!(spec.ty, Some('x') | Some('X') | Some('b') | Some('o'))
+}
+
+fn pad_left(val: &str, width: usize) -> String {
+    format!("{:>width$}", val, width = width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain() {
+        assert_eq!(
+            render("Hello {}!", &[Arg::Str("Dashcam")]),
+            "Hello Dashcam!"
+        );
+        assert_eq!(render("{{}} {}", &[Arg::Bool(true)]), "{} true");
+        assert_eq!(
+            render("{} {:?}", &[Arg::Signed(-5), Arg::Str("Fps30")]),
+            "-5 Fps30"
+        );
+    }
+
+    #[test]
+    fn hex() {
+        let args = [Arg::Unsigned(0xBEEF)];
+        assert_eq!(render("{:X}", &args), "BEEF");
+        assert_eq!(render("{:x}", &args), "beef");
+        assert_eq!(render("{:#010X}", &args), "0x0000BEEF");
+        assert_eq!(render("{:08b}", &[Arg::Unsigned(5)]), "00000101");
+    }
+
+    #[test]
+    fn width_and_precision() {
+        assert_eq!(render("[{:5}]", &[Arg::Unsigned(42)]), "[   42]");
+        assert_eq!(render("[{:05}]", &[Arg::Signed(-42)]), "[-0042]");
+        assert_eq!(render("{:.2}", &[Arg::F32(1.5)]), "1.50");
+    }
+
+    #[test]
+    fn missing() {
+        assert_eq!(render("{} {}", &[Arg::Unsigned(1)]), "1 <?>");
+    }
+}
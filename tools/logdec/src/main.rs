@@ -0,0 +1,134 @@
+//! Decodes the binary log stream of the dash cam firmware into text.
+//!
+//! The stream is read from stdin, for example from the RTT server of OpenOCD:
+//!
+//! ```text
+//! nc localhost 9090 | logdec target/thumbv7em-none-eabihf/debug/dashcam-rs --level info
+//! ```
+//!
+//! The ELF file must be the one running on the target, as format strings are looked up in it.
+
+mod decode;
+mod elf;
+mod format;
+
+#[allow(dead_code)]
+#[path = "../../../src/log/wire.rs"]
+mod wire;
+
+use elf::Elf;
+use std::{
+    env, fs,
+    io::{self, BufWriter, ErrorKind, Read, Write},
+    process,
+};
+use wire::Level;
+
+const USAGE: &str =
+    "Usage: logdec <elf> [--level <error|warn|info|debug|trace>] [--target <prefix>]";
+
+/// Command line options.
+struct Options {
+    elf: String,
+    level: Level,
+    target: String,
+}
+
+fn main() {
+    let options = parse_args(env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let elf = fs::read(&options.elf)
+        .map_err(|e| e.to_string())
+        .and_then(Elf::parse)
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot read {}: {}", options.elf, e);
+            process::exit(1);
+        });
+
+    if let Err(e) = run(
+        &elf,
+        &options,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+    ) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Decode frames from `input` until it ends.
+fn run(
+    elf: &Elf,
+    options: &Options,
+    input: &mut dyn Read,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    let mut output = BufWriter::new(output);
+    let lookup = |addr, len| {
+        elf.read(addr, len)
+            .and_then(|s| std::str::from_utf8(s).ok())
+    };
+
+    loop {
+        // Each frame is a length byte followed by the payload
+        let mut len = [0; 1];
+        match input.read_exact(&mut len) {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return output.flush(),
+            result => result?,
+        };
+        let mut payload = vec![0; len[0] as usize];
+        input.read_exact(&mut payload)?;
+
+        let message = match decode::decode(&payload, lookup) {
+            Some(message) => message,
+            None => {
+                writeln!(output, "<malformed frame: {:02X?}>", payload)?;
+                continue;
+            }
+        };
+
+        if message.level <= options.level && message.target.starts_with(&options.target) {
+            writeln!(
+                output,
+                "{:>5}.{:03} {:<5} {}: {}",
+                message.timestamp_ms / 1000,
+                message.timestamp_ms % 1000,
+                message.level.as_str(),
+                message.target,
+                message.text
+            )?;
+            output.flush()?;
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        elf: String::new(),
+        level: Level::Trace,
+        target: String::new(),
+    };
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => {
+                let level = args.next().ok_or("Missing level")?;
+                options.level = (0..=Level::Trace as u8)
+                    .filter_map(Level::from_u8)
+                    .find(|l| l.as_str().eq_ignore_ascii_case(&level))
+                    .ok_or(format!("Unknown level: {}", level))?;
+            }
+            "--target" => options.target = args.next().ok_or("Missing target")?,
+            _ if options.elf.is_empty() && !arg.starts_with("--") => options.elf = arg,
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+
+    match options.elf.is_empty() {
+        true => Err("Missing ELF file".into()),
+        false => Ok(options),
+    }
+}